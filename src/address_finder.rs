@@ -85,11 +85,11 @@ impl AddressFinder {
                 if j != 0 {
                     j = lps[j - 1];
                 } else {
-                    i = i + 1;
+                    i += 1;
                 }
             }
         }
-        return 0;
+        0
     }
 
     #[allow(dead_code)]
@@ -205,7 +205,7 @@ impl AddressFinder {
 }
 
 #[allow(dead_code)]
-fn compute_lps_array(pat: &Vec<u8>, m: usize, lps: &mut Vec<usize>) {
+fn compute_lps_array(pat: &[u8], m: usize, lps: &mut [usize]) {
     let mut len = 0;
     lps[0] = 0;
     let mut i = 1;
@@ -0,0 +1,149 @@
+use std::{fmt, io, time::Duration};
+
+use crate::shutdown::wait_for_shutdown;
+
+/*
+ *
+ * What can go wrong while attaching. Plain Rust, no windows.
+ *
+ * The DLL is built with panic = 'abort', a panic there takes the game down with it. Everything
+ * attach() does returns an AttachError instead: transient ones are retried, the others unload
+ * the DLL after logging why.
+ *
+ * */
+
+#[derive(Debug)]
+pub enum AttachError {
+    //The game hasn't created its window yet. Transient.
+    NoWindow,
+    //The game's module couldn't be queried.
+    ModuleInfo { base: usize, size: usize },
+    PresentNotFound,
+    //Installing the present hook failed.
+    Hook(String),
+    //Creating the objects shared with the producer failed.
+    SharedObjects(io::Error),
+    //Binding the socket talking to the producer failed.
+    Socket(io::Error),
+    //The log file couldn't be opened, logs only go to stdout. Not fatal.
+    Logging(io::Error),
+    //Creating the D3D objects of the overlay failed, on the first present.
+    Rendering(String),
+}
+
+impl AttachError {
+    ///Worth trying again a bit later.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, AttachError::NoWindow)
+    }
+}
+
+impl fmt::Display for AttachError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AttachError::NoWindow => write!(f, "could not get the game's window"),
+            AttachError::ModuleInfo { base, size } => write!(
+                f,
+                "could not get the module base/size. Base: {} Size: {}",
+                base, size
+            ),
+            AttachError::PresentNotFound => {
+                write!(f, "could not find the address of DirectX11 Present")
+            }
+            AttachError::Hook(e) => write!(f, "could not hook Present: {}", e),
+            AttachError::SharedObjects(e) => {
+                write!(
+                    f,
+                    "could not create the objects shared with the producer: {}",
+                    e
+                )
+            }
+            AttachError::Socket(e) => write!(f, "could not bind the input socket: {}", e),
+            AttachError::Logging(e) => write!(f, "could not open the log file: {}", e),
+            AttachError::Rendering(e) => {
+                write!(f, "could not create the overlay's D3D objects: {}", e)
+            }
+        }
+    }
+}
+
+///Calls f until it succeeds, it fails with an error that isn't transient, timeout elapsed
+///or a shutdown is requested. The last error is returned.
+pub fn retry<T>(
+    timeout: Duration,
+    interval: Duration,
+    mut f: impl FnMut() -> Result<T, AttachError>,
+) -> Result<T, AttachError> {
+    let mut waited = Duration::ZERO;
+    loop {
+        match f() {
+            Err(e) if e.is_transient() && waited < timeout => {
+                log::debug!("{}, trying again.", e);
+                if wait_for_shutdown(interval) {
+                    return Err(e);
+                }
+                waited += interval;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(1);
+
+    //Fails with the errors in order, then succeeds. Counts the calls.
+    fn failing(
+        errors: Vec<AttachError>,
+        calls: &mut usize,
+    ) -> impl FnMut() -> Result<usize, AttachError> + '_ {
+        let mut errors = errors.into_iter();
+        move || {
+            *calls += 1;
+            match errors.next() {
+                Some(e) => Err(e),
+                None => Ok(*calls),
+            }
+        }
+    }
+
+    #[test]
+    fn succeeds_after_transient_failures() {
+        let mut calls = 0;
+        let errors = (0..3).map(|_| AttachError::NoWindow).collect();
+        let result = retry(INTERVAL * 10, INTERVAL, failing(errors, &mut calls));
+        assert_eq!(result.unwrap(), 4);
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn gives_up_once_the_timeout_elapsed() {
+        let mut calls = 0;
+        let errors = (0..100).map(|_| AttachError::NoWindow).collect();
+        let result = retry(INTERVAL * 3, INTERVAL, failing(errors, &mut calls));
+        assert!(matches!(result, Err(AttachError::NoWindow)));
+        //Once right away, then once per interval until the timeout.
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn fatal_errors_return_right_away() {
+        let mut calls = 0;
+        let errors = vec![AttachError::NoWindow, AttachError::PresentNotFound];
+        let result = retry(INTERVAL * 10, INTERVAL, failing(errors, &mut calls));
+        assert!(matches!(result, Err(AttachError::PresentNotFound)));
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    fn zero_timeout_tries_once() {
+        let mut calls = 0;
+        let errors = vec![AttachError::NoWindow];
+        let result = retry(Duration::ZERO, INTERVAL, failing(errors, &mut calls));
+        assert!(matches!(result, Err(AttachError::NoWindow)));
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,129 @@
+use std::{process::ExitCode, time::Duration};
+
+use chrono::Local;
+use external_dx11_overlay::{
+    platform::platform,
+    simulator::{ConnectError, Simulator, SimulatorConfig},
+    ui::header::MAX_SLOTS,
+};
+use fern::Dispatch;
+
+/*
+ *
+ * Headless producer, to exercise the DLL without a patched BlishHUD.
+ * Waits for a game to publish itself in the discovery table, pairs with it, then publishes
+ * synthetic frames and logs what the DLL does. See src/simulator.rs.
+ *
+ * */
+
+const USAGE: &str = "Usage: simulator [options]
+  --namespace <name>   Namespace of the shared objects (default: BlishHUD)
+  --global             Use Global\\ objects instead of Local\\ ones
+  --pid <pid>          Only pair with this game
+  --slots <count>      Number of texture slots (default: 2)
+  --interval-ms <ms>   Time between frames (default: 16)
+  --frames <count>     Stop after this many frames (default: never)
+  --capture            Ask for keyboard capture
+  --input <address>    Where input events are received (default: 127.0.0.1:49152)
+  --no-input           Don't receive input events
+  --verbose            Log debug messages";
+
+struct Options {
+    config: SimulatorConfig,
+    interval: Duration,
+    frames: Option<u64>,
+    verbose: bool,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        config: SimulatorConfig::default(),
+        interval: Duration::from_millis(16),
+        frames: None,
+        verbose: false,
+    };
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--namespace" => options.config.namespace = value()?,
+            "--global" => options.config.global = true,
+            "--pid" => options.config.game_pid = Some(parse(&value()?)?),
+            "--slots" => options.config.slot_count = parse(&value()?)?,
+            "--interval-ms" => options.interval = Duration::from_millis(parse(&value()?)?),
+            "--frames" => options.frames = Some(parse(&value()?)?),
+            "--capture" => options.config.keyboard_capture = true,
+            "--input" => options.config.input_address = Some(parse(&value()?)?),
+            "--no-input" => options.config.input_address = None,
+            "--verbose" => options.verbose = true,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if options.config.slot_count == 0 || options.config.slot_count > MAX_SLOTS {
+        return Err(format!("1 to {} slots are supported", MAX_SLOTS));
+    }
+    Ok(options)
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value \"{}\"", value))
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    Dispatch::new()
+        .level(if options.verbose {
+            log::LevelFilter::Debug
+        } else {
+            log::LevelFilter::Info
+        })
+        .chain(std::io::stdout())
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "[{}] [simulator] [{}] {}",
+                Local::now().format("%H:%M:%S%.3f"),
+                record.level(),
+                message
+            ))
+        })
+        .apply()
+        .ok();
+
+    if !cfg!(windows) {
+        log::warn!("Not on Windows, the platform is an in-memory mock: no game will ever show up.");
+    }
+
+    log::info!(
+        "Waiting for a game in namespace {}.",
+        options.config.namespace
+    );
+    let mut simulator = loop {
+        match Simulator::connect(platform(), &options.config) {
+            Ok(simulator) => break simulator,
+            Err(ConnectError::NoGame) => std::thread::sleep(Duration::from_millis(500)),
+            Err(e) => {
+                log::error!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+    log::info!(
+        "Publishing frames every {} ms with slots {:x?}.",
+        options.interval.as_millis(),
+        simulator.slots()
+    );
+    simulator.run(options.interval, options.frames);
+    ExitCode::SUCCESS
+}
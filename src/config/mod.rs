@@ -0,0 +1,530 @@
+use std::{fmt, fs, net::SocketAddr, path::Path, sync::OnceLock};
+
+use self::toml::{Entry, Value, quote};
+
+use crate::{
+    debug::debug_overlay::Corner,
+    logging::filter::{format_module_levels, parse_module_levels},
+    ui::{discovery::MAX_NAMESPACE_LEN, liveness::ProbeKind},
+};
+
+pub mod toml;
+
+/*
+ *
+ * Configuration of the whole DLL, read once from CONFIG_PATH at attach time. Only the log
+ * levels can be read again later, see logging::reload_log_levels.
+ *
+ * Every setting has a default, so a missing file, section or key is never an error.
+ * Invalid values are reported with their line number and the default is used instead.
+ * A commented file with every default is written on the first run.
+ *
+ * */
+
+pub const CONFIG_PATH: &str = "addons/LOADER_public/config.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathsConfig {
+    pub logs_dir: String,
+    pub keybinds: String,
+    pub blish_exe: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputConfig {
+    //Where input events are sent.
+    pub udp_address: SocketAddr,
+}
+
+//Kernel objects shared with the producer are named after the namespace and the game's PID.
+//See ui::discovery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpcConfig {
+    pub namespace: String,
+    //Global\ objects instead of Local\ ones. Creating those needs SeCreateGlobalPrivilege.
+    pub global: bool,
+    //How to tell whether the producer is still running.
+    pub liveness: ProbeKind,
+    //Used by the heartbeat probe.
+    pub heartbeat_timeout_ms: u64,
+}
+
+//What to do when the producer is running but stopped ticking. See ui::stall.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StallConfig {
+    //0 disables the detection.
+    pub timeout_ms: u64,
+    //How long the overlay takes to fade out once stalled.
+    pub fade_ms: u64,
+    pub restart_blish: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggingConfig {
+    pub level: log::LevelFilter,
+    //Levels of specific modules, overriding level. See logging::filter.
+    pub modules: Vec<(String, log::LevelFilter)>,
+    //Logs older than this are deleted. 0 keeps them forever.
+    pub retention_hours: u64,
+    //Log files of each kind to keep. 0 keeps them all.
+    pub max_files: u64,
+    //A new file is started past this size. 0 never starts one.
+    pub max_file_kb: u64,
+    //Also write dll-<time>.jsonl, one JSON object per record.
+    pub json: bool,
+}
+
+//The debug panel toggled with a keybind. See debug::debug_overlay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugOverlayConfig {
+    pub corner: Corner,
+    //Distance to the edges of the window, in pixels.
+    pub margin: u32,
+    //TrueType font of the text. Empty uses the embedded one.
+    pub font: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub paths: PathsConfig,
+    pub input: InputConfig,
+    pub ipc: IpcConfig,
+    pub stall: StallConfig,
+    pub logging: LoggingConfig,
+    pub debug_overlay: DebugOverlayConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            paths: PathsConfig {
+                logs_dir: "addons/LOADER_public/logs".to_string(),
+                keybinds: "addons/LOADER_public/keybinds.conf".to_string(),
+                blish_exe: "addons/LOADER_public/Blish.HUD.1.2.0/Blish HUD.exe".to_string(),
+            },
+            input: InputConfig {
+                udp_address: SocketAddr::from(([127, 0, 0, 1], 49152)),
+            },
+            ipc: IpcConfig {
+                namespace: "BlishHUD".to_string(),
+                global: false,
+                liveness: ProbeKind::NamedMutex,
+                heartbeat_timeout_ms: 2000,
+            },
+            stall: StallConfig {
+                timeout_ms: 5000,
+                fade_ms: 1000,
+                restart_blish: false,
+            },
+            logging: LoggingConfig {
+                level: log::LevelFilter::Debug,
+                modules: Vec::new(),
+                retention_hours: 24,
+                max_files: 20,
+                max_file_kb: 10 * 1024,
+                json: false,
+            },
+            debug_overlay: DebugOverlayConfig {
+                corner: Corner::TopLeft,
+                margin: 0,
+                font: String::new(),
+            },
+        }
+    }
+}
+
+///The loaded configuration, or the defaults if load_config() wasn't called yet.
+pub fn config() -> &'static Config {
+    #[cfg(not(test))]
+    return CONFIG.get_or_init(Config::default);
+    #[cfg(test)]
+    CONFIG.get_or_init(test_config)
+}
+
+//The defaults, but with the files of the tests in a directory of their own instead of the
+//game's. One per test process, left in the temp directory.
+#[cfg(test)]
+fn test_config() -> Config {
+    let dir = std::env::temp_dir().join(format!("external-dx11-overlay-{}", std::process::id()));
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let mut config = Config::default();
+    config.paths.logs_dir = path("logs");
+    config.paths.keybinds = path("keybinds.conf");
+    config
+}
+
+///Reads CONFIG_PATH, writing the defaults first if it doesn't exist.
+///Must be called before anything uses config(). Logging isn't up yet at that point,
+///so problems are returned to be logged later.
+pub fn load_config() -> Vec<String> {
+    let mut problems = Vec::new();
+    if !Path::new(CONFIG_PATH).exists() {
+        if let Some(parent) = Path::new(CONFIG_PATH).parent() {
+            fs::create_dir_all(parent).ok();
+        }
+        if let Err(e) = fs::write(CONFIG_PATH, default_config_text()) {
+            problems.push(format!(
+                "Failed to write the default {}: {}",
+                CONFIG_PATH, e
+            ));
+        }
+    }
+    let config = match fs::read_to_string(CONFIG_PATH) {
+        Ok(text) => {
+            let (config, diagnostics) = parse_config(&text);
+            problems.extend(
+                diagnostics
+                    .iter()
+                    .map(|d| format!("{} {}, ignored.", CONFIG_PATH, d)),
+            );
+            config
+        }
+        Err(e) => {
+            problems.push(format!(
+                "Failed to read {}, using the defaults: {}",
+                CONFIG_PATH, e
+            ));
+            Config::default()
+        }
+    };
+    if CONFIG.set(config).is_err() {
+        problems.push("The configuration was used before being loaded.".to_string());
+    }
+    problems
+}
+
+///Builds the configuration from the content of the file. Never fails, anything invalid keeps
+///its default and ends up in diagnostics.
+pub fn parse_config(text: &str) -> (Config, Vec<Diagnostic>) {
+    let (entries, mut diagnostics) = self::toml::parse(text);
+    let mut config = Config::default();
+    for entry in &entries {
+        if let Err(message) = apply(&mut config, entry) {
+            diagnostics.push(Diagnostic {
+                line: entry.line,
+                message,
+            });
+        }
+    }
+    diagnostics.sort_by_key(|d| d.line);
+    (config, diagnostics)
+}
+
+fn apply(config: &mut Config, entry: &Entry) -> Result<(), String> {
+    let name = format!("{}.{}", entry.section, entry.key);
+    let value = &entry.value;
+    match (entry.section.as_str(), entry.key.as_str()) {
+        ("paths", "logs_dir") => config.paths.logs_dir = non_empty(&name, value)?,
+        ("paths", "keybinds") => config.paths.keybinds = non_empty(&name, value)?,
+        ("paths", "blish_exe") => config.paths.blish_exe = non_empty(&name, value)?,
+        ("input", "udp_address") => {
+            let address = string(&name, value)?;
+            config.input.udp_address = address
+                .parse()
+                .map_err(|_| format!("{} \"{}\" is not an ip:port address", name, address))?;
+        }
+        ("ipc", "namespace") => config.ipc.namespace = namespace(&name, value)?,
+        ("ipc", "global") => config.ipc.global = boolean(&name, value)?,
+        ("ipc", "liveness") => {
+            let kind = string(&name, value)?;
+            config.ipc.liveness = ProbeKind::from_name(&kind).ok_or_else(|| {
+                format!(
+                    "{} \"{}\" should be mutex, heartbeat or process",
+                    name, kind
+                )
+            })?;
+        }
+        ("ipc", "heartbeat_timeout_ms") => {
+            config.ipc.heartbeat_timeout_ms = positive(&name, value)?
+        }
+        ("stall", "timeout_ms") => config.stall.timeout_ms = positive(&name, value)?,
+        ("stall", "fade_ms") => config.stall.fade_ms = positive(&name, value)?,
+        ("stall", "restart_blish") => config.stall.restart_blish = boolean(&name, value)?,
+        ("logging", "level") => {
+            let level = string(&name, value)?;
+            config.logging.level = level.parse().map_err(|_| {
+                format!(
+                    "{} \"{}\" should be off, error, warn, info, debug or trace",
+                    name, level
+                )
+            })?;
+        }
+        ("logging", "modules") => {
+            config.logging.modules = parse_module_levels(&string(&name, value)?)
+                .map_err(|e| format!("{}: {}", name, e))?;
+        }
+        ("logging", "retention_hours") => config.logging.retention_hours = positive(&name, value)?,
+        ("logging", "max_files") => config.logging.max_files = positive(&name, value)?,
+        ("logging", "max_file_kb") => config.logging.max_file_kb = positive(&name, value)?,
+        ("logging", "json") => config.logging.json = boolean(&name, value)?,
+        ("debug_overlay", "corner") => {
+            let corner = string(&name, value)?;
+            config.debug_overlay.corner = Corner::from_name(&corner).ok_or_else(|| {
+                format!(
+                    "{} \"{}\" should be top_left, top_right, bottom_left or bottom_right",
+                    name, corner
+                )
+            })?;
+        }
+        ("debug_overlay", "margin") => {
+            config.debug_overlay.margin = positive(&name, value)?
+                .try_into()
+                .map_err(|_| format!("{} is too big", name))?
+        }
+        ("debug_overlay", "font") => config.debug_overlay.font = string(&name, value)?,
+        _ => return Err(format!("unknown setting {}", name)),
+    }
+    Ok(())
+}
+
+fn string(name: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        v => Err(format!(
+            "{} should be a string, not {}",
+            name,
+            v.type_name()
+        )),
+    }
+}
+
+fn boolean(name: &str, value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        v => Err(format!(
+            "{} should be a boolean, not {}",
+            name,
+            v.type_name()
+        )),
+    }
+}
+
+fn positive(name: &str, value: &Value) -> Result<u64, String> {
+    match value {
+        Value::Integer(i) if *i >= 0 => Ok(*i as u64),
+        _ => Err(format!("{} should be a positive integer", name)),
+    }
+}
+
+fn non_empty(name: &str, value: &Value) -> Result<String, String> {
+    let s = string(name, value)?;
+    if s.is_empty() {
+        return Err(format!("{} can't be empty", name));
+    }
+    Ok(s)
+}
+
+//Ends up in kernel object names, so no backslashes and nothing too long.
+fn namespace(name: &str, value: &Value) -> Result<String, String> {
+    let s = non_empty(name, value)?;
+    if s.len() > MAX_NAMESPACE_LEN
+        || !s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return Err(format!(
+            "{} \"{}\" should be at most {} letters, digits, '_', '-' or '.'",
+            name, s, MAX_NAMESPACE_LEN
+        ));
+    }
+    Ok(s)
+}
+
+///Content of the file written on the first run.
+pub fn default_config_text() -> String {
+    let d = Config::default();
+    format!(
+        "\
+# Configuration of the overlay DLL. Delete this file to get the defaults back.
+# Relative paths start from the game's folder.
+
+[paths]
+logs_dir = {logs_dir}
+keybinds = {keybinds}
+# Started by the restart_blish keybind.
+blish_exe = {blish_exe}
+
+[input]
+# Where mouse and keyboard events are sent.
+udp_address = {udp_address}
+
+[ipc]
+# Objects shared with Blish HUD are named after this and the game's PID, so several games
+# can each have their own overlay. Blish HUD must use the same namespace.
+namespace = {namespace}
+# Global objects are visible from other sessions, but creating them needs administrator rights.
+# Only set this if Blish HUD runs in another session than the game.
+global = {global}
+# How to tell whether Blish HUD is still running: mutex, heartbeat or process.
+liveness = {liveness}
+# With the heartbeat, Blish HUD is considered gone after this long without a tick.
+heartbeat_timeout_ms = {heartbeat_timeout_ms}
+
+[stall]
+# The overlay fades out when Blish HUD is running but hasn't ticked for this long. 0 disables it.
+# Only works with versions of Blish HUD sending a heartbeat.
+timeout_ms = {stall_timeout_ms}
+fade_ms = {fade_ms}
+# Also restart Blish HUD when it stalls.
+restart_blish = {restart_blish}
+
+[logging]
+# off, error, warn, info, debug or trace. level and modules are read again by the
+# reload_log_levels keybind, the rest needs a restart.
+level = {level}
+# Other levels for some modules, like \"ui::mmf=trace, udp=warn\".
+modules = {modules}
+# Logs older than this are deleted. 0 keeps them forever.
+retention_hours = {retention_hours}
+# Only the newest log files are kept. 0 keeps them all.
+max_files = {max_files}
+# A new log file is started once the current one reaches this size. 0 never starts one.
+max_file_kb = {max_file_kb}
+# Also write every record as a line of JSON, in dll-<time>.jsonl.
+json = {json}
+
+[debug_overlay]
+# Corner of the game's window the debug panel is drawn in: top_left, top_right, bottom_left
+# or bottom_right.
+corner = {corner}
+# Distance between the panel and the edges of the window, in pixels.
+margin = {margin}
+# TrueType font of the panel. Empty uses the one embedded in the DLL.
+font = {font}
+",
+        logs_dir = quote(&d.paths.logs_dir),
+        keybinds = quote(&d.paths.keybinds),
+        blish_exe = quote(&d.paths.blish_exe),
+        udp_address = quote(&d.input.udp_address.to_string()),
+        namespace = quote(&d.ipc.namespace),
+        global = d.ipc.global,
+        liveness = quote(d.ipc.liveness.name()),
+        heartbeat_timeout_ms = d.ipc.heartbeat_timeout_ms,
+        stall_timeout_ms = d.stall.timeout_ms,
+        fade_ms = d.stall.fade_ms,
+        restart_blish = d.stall.restart_blish,
+        level = quote(&d.logging.level.to_string().to_lowercase()),
+        modules = quote(&format_module_levels(&d.logging.modules)),
+        retention_hours = d.logging.retention_hours,
+        max_files = d.logging.max_files,
+        max_file_kb = d.logging.max_file_kb,
+        json = d.logging.json,
+        corner = quote(d.debug_overlay.corner.name()),
+        margin = d.debug_overlay.margin,
+        font = quote(&d.debug_overlay.font),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(text: &str) -> Vec<(usize, String)> {
+        parse_config(text)
+            .1
+            .into_iter()
+            .map(|d| (d.line, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn settings_are_applied() {
+        let (config, diagnostics) = parse_config(
+            "\
+[ipc]
+namespace = 'Blish.2'
+global = true
+liveness = \"heartbeat\"
+[stall]
+timeout_ms = 2_500
+[logging]
+level = \"debug\"
+modules = \"ui::mmf=trace\"
+[debug_overlay]
+corner = \"bottom_left\"
+",
+        );
+        assert_eq!(diagnostics, []);
+        assert_eq!(config.ipc.namespace, "Blish.2");
+        assert!(config.ipc.global);
+        assert_eq!(config.ipc.liveness, ProbeKind::Heartbeat);
+        assert_eq!(config.stall.timeout_ms, 2500);
+        assert_eq!(config.logging.level, log::LevelFilter::Debug);
+        assert_eq!(
+            config.logging.modules,
+            [("ui::mmf".to_string(), log::LevelFilter::Trace)]
+        );
+        assert_eq!(config.debug_overlay.corner, Corner::BottomLeft);
+        assert_eq!(config.stall.fade_ms, Config::default().stall.fade_ms);
+    }
+
+    #[test]
+    fn invalid_settings_keep_their_default() {
+        let text = "\
+[paths]
+unknown = 1
+keybinds = ''
+[input]
+udp_address = \"localhost\"
+[ipc]
+namespace = \"a\\\\b\"
+global = 1
+liveness = \"ping\"
+[stall]
+fade_ms = -1
+";
+        let (config, _) = parse_config(text);
+        assert_eq!(config, Config::default());
+        assert_eq!(
+            messages(text),
+            [
+                (2, "unknown setting paths.unknown".to_string()),
+                (3, "paths.keybinds can't be empty".to_string()),
+                (
+                    5,
+                    "input.udp_address \"localhost\" is not an ip:port address".to_string()
+                ),
+                (
+                    7,
+                    format!(
+                        "ipc.namespace \"a\\b\" should be at most {} letters, digits, '_', '-' or '.'",
+                        MAX_NAMESPACE_LEN
+                    )
+                ),
+                (
+                    8,
+                    "ipc.global should be a boolean, not an integer".to_string()
+                ),
+                (
+                    9,
+                    "ipc.liveness \"ping\" should be mutex, heartbeat or process".to_string()
+                ),
+                (11, "stall.fade_ms should be a positive integer".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn diagnostics_are_sorted_by_line() {
+        //Syntax errors come from the reader, the rest from apply, both end up in order.
+        let lines: Vec<usize> = messages("[ipc]\nglobal = 'yes'\nnamespace = \nliveness = 1\n")
+            .into_iter()
+            .map(|(line, _)| line)
+            .collect();
+        assert_eq!(lines, [2, 3, 4]);
+    }
+}
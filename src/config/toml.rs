@@ -0,0 +1,271 @@
+use ::toml::{
+    Spanned,
+    de::{DeString, DeTable, DeValue},
+};
+
+use super::Diagnostic;
+
+/*
+ *
+ * Reads the config file with the toml crate, and flattens it into the section.key entries the
+ * config is made of. Plain Rust, no windows.
+ *
+ * Any TOML is read, but the config only has [sections] of strings, integers and booleans:
+ * other values are kept as Value::Other so they can be reported by their type. Syntax errors
+ * are reported with their line number, and the parser recovers from them, so the rest of the
+ * file still applies.
+ *
+ * */
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    //Anything the config never uses, by the name of its type.
+    Other(&'static str),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Other(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    //Empty for keys before the first section.
+    pub section: String,
+    pub key: String,
+    pub value: Value,
+    pub line: usize,
+}
+
+///Reads every entry of the document. Syntax errors end up in diagnostics, and the entries
+///they are in are skipped.
+pub fn parse(text: &str) -> (Vec<Entry>, Vec<Diagnostic>) {
+    let (root, errors) = DeTable::parse_recoverable(text);
+    let line = |offset: usize| text[..offset.min(text.len())].matches('\n').count() + 1;
+
+    let mut diagnostics: Vec<_> = errors
+        .iter()
+        .map(|e| Diagnostic {
+            line: e.span().map_or(1, |span| line(span.start)),
+            //Some messages go on for several lines.
+            message: e.message().lines().collect::<Vec<_>>().join(", "),
+        })
+        .collect();
+    diagnostics.dedup();
+    //The parser recovers with whatever it could make of a bad line, it must not be used.
+    let broken = |key: &Spanned<DeString>, value: &Spanned<DeValue>| {
+        let lines = line(key.span().start)..=line(value.span().end.max(key.span().end));
+        diagnostics.iter().any(|d| lines.contains(&d.line))
+    };
+
+    let mut entries = Vec::new();
+    let mut push = |section: &str, key: &Spanned<DeString>, value: &Spanned<DeValue>| {
+        if !broken(key, value) {
+            entries.push(Entry {
+                section: section.to_string(),
+                key: key.get_ref().to_string(),
+                value: convert(value.get_ref()),
+                line: line(key.span().start),
+            });
+        }
+    };
+    for (key, value) in root.get_ref() {
+        match value.get_ref() {
+            DeValue::Table(table) => {
+                for (name, value) in table {
+                    push(key.get_ref(), name, value);
+                }
+            }
+            _ => push("", key, value),
+        }
+    }
+    entries.sort_by_key(|e| e.line);
+    (entries, diagnostics)
+}
+
+fn convert(value: &DeValue) -> Value {
+    match value {
+        DeValue::String(s) => Value::String(s.to_string()),
+        DeValue::Integer(i) => i64::from_str_radix(i.as_str(), i.radix())
+            .map_or(Value::Other("an integer out of range"), Value::Integer),
+        DeValue::Boolean(b) => Value::Boolean(*b),
+        DeValue::Float(_) => Value::Other("a float"),
+        DeValue::Datetime(_) => Value::Other("a date"),
+        DeValue::Array(_) => Value::Other("an array"),
+        DeValue::Table(_) => Value::Other("a table"),
+    }
+}
+
+///Quotes a string so parse() reads it back as is.
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            //TOML strings can't hold the other control characters as they are.
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{default_config_text, parse_config};
+
+    fn value(text: &str) -> Result<Value, usize> {
+        let (mut entries, diagnostics) = parse(&format!("[s]\nkey = {}", text));
+        match diagnostics.first() {
+            Some(d) => Err(d.line),
+            None => Ok(entries.remove(0).value),
+        }
+    }
+
+    fn string(s: &str) -> Result<Value, usize> {
+        Ok(Value::String(s.to_string()))
+    }
+
+    //Section, key and line of every entry.
+    fn keys(entries: &[Entry]) -> Vec<(&str, &str, usize)> {
+        entries
+            .iter()
+            .map(|e| (e.section.as_str(), e.key.as_str(), e.line))
+            .collect()
+    }
+
+    fn lines(diagnostics: &[Diagnostic]) -> Vec<usize> {
+        diagnostics.iter().map(|d| d.line).collect()
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(value(r#""C:\\path\t\"x\"""#), string("C:\\path\t\"x\""));
+        assert_eq!(value(r"'C:\path'"), string("C:\\path"));
+        assert_eq!(value("true # comment"), Ok(Value::Boolean(true)));
+        assert_eq!(value("-42"), Ok(Value::Integer(-42)));
+        assert_eq!(value("1_000"), Ok(Value::Integer(1000)));
+        assert_eq!(value("0x1F"), Ok(Value::Integer(31)));
+        assert_eq!(value("'#' # the first one isn't a comment"), string("#"));
+        assert_eq!(value("\"\"\"multi\nline\"\"\""), string("multi\nline"));
+    }
+
+    #[test]
+    fn values_the_config_never_uses_keep_their_type() {
+        assert_eq!(value("1.5"), Ok(Value::Other("a float")));
+        assert_eq!(value("[1, 2]"), Ok(Value::Other("an array")));
+        assert_eq!(value("{ a = 1 }"), Ok(Value::Other("a table")));
+        assert_eq!(value("1979-05-27"), Ok(Value::Other("a date")));
+        assert_eq!(
+            value("99999999999999999999"),
+            Ok(Value::Other("an integer out of range"))
+        );
+    }
+
+    #[test]
+    fn sections_and_dotted_keys() {
+        let text = "top = 1\n[a]\nx = 1\n\n[b]\ny = 'y'\n[c.d]\nz = 1\n\na.w = 2\n";
+        let (entries, diagnostics) = parse(text);
+        assert_eq!(diagnostics, []);
+        assert_eq!(
+            keys(&entries),
+            [
+                ("", "top", 1),
+                ("a", "x", 3),
+                ("b", "y", 6),
+                //Deeper tables are values of their section.
+                ("c", "d", 7),
+            ]
+        );
+        //a.w went into [c.d] above. Before any section, it is w of [a].
+        let (entries, _) = parse("a.w = 2\n");
+        assert_eq!(keys(&entries), [("a", "w", 1)]);
+    }
+
+    #[test]
+    fn bad_values_are_skipped_with_their_line() {
+        for bad in [
+            "\"abc",
+            "\"abc\\\"",
+            "'abc",
+            r#""C:\path""#,
+            "\"a\" b",
+            "12 34",
+            "true false",
+            "_1",
+            "1_",
+            "1__0",
+        ] {
+            let (entries, diagnostics) = parse(&format!("before = 1\nkey = {}\n", bad));
+            assert_eq!(keys(&entries), [("", "before", 1)], "{}", bad);
+            assert_eq!(lines(&diagnostics), [2], "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bad_lines_report_their_line() {
+        for (text, line) in [
+            ("ok = 1\n[s\n", 2),
+            ("ok = 1\n\nnothing\n", 3),
+            ("ok = 1\n[a b]\n", 2),
+            ("ok = 1\nbad key = 1\n", 2),
+        ] {
+            let (entries, diagnostics) = parse(text);
+            assert_eq!(keys(&entries), [("", "ok", 1)], "{}", text);
+            assert_eq!(lines(&diagnostics), [line], "{}", text);
+            assert!(!diagnostics[0].message.is_empty());
+        }
+    }
+
+    #[test]
+    fn duplicates_keep_the_first() {
+        let (entries, diagnostics) = parse("[s]\na = 1\na = 2\n");
+        assert_eq!(keys(&entries), [("s", "a", 2)]);
+        assert_eq!(entries[0].value, Value::Integer(1));
+        assert_eq!(lines(&diagnostics), [3]);
+
+        let (_, diagnostics) = parse("[s]\na = 1\n[t]\n[s]\nb = 2\n");
+        assert_eq!(lines(&diagnostics), [4]);
+    }
+
+    #[test]
+    fn quote_round_trip() {
+        for s in [
+            "",
+            "plain",
+            "C:\\path\\",
+            "\"quoted\"",
+            "tab\tnew\nline",
+            "bell\x07 and\r\n",
+            "# not a comment",
+            "é ✓",
+        ] {
+            assert_eq!(value(&quote(s)), string(s), "{}", s);
+        }
+    }
+
+    #[test]
+    fn default_config_parses_cleanly() {
+        let text = default_config_text();
+        let (_, diagnostics) = parse(&text);
+        assert_eq!(diagnostics, []);
+        let (config, diagnostics) = parse_config(&text);
+        assert_eq!(diagnostics, []);
+        assert_eq!(config, Default::default());
+    }
+}
@@ -1,41 +1,48 @@
 use std::{
-    net::UdpSocket,
-    slice::from_raw_parts,
-    sync::{
-        OnceLock,
-        atomic::{AtomicU8, Ordering},
-        mpsc::{Sender, channel},
-    },
+    sync::atomic::{AtomicU8, AtomicU16, AtomicU64, Ordering},
     time::{Duration, Instant},
 };
 
 use windows::Win32::{
-    Foundation::{HWND, LPARAM, LRESULT, RECT, WPARAM},
+    Foundation::{HWND, LPARAM, LRESULT, POINT, RECT, WPARAM},
+    Graphics::Gdi::ScreenToClient,
     UI::{
         Input::KeyboardAndMouse::{
-            GetKeyState, ReleaseCapture, SetCapture, SetFocus, VK_MENU, VK_NUMLOCK,
+            GetKeyState, ReleaseCapture, SetCapture, SetFocus, VK_ESCAPE, VK_MENU, VK_NUMLOCK,
         },
         WindowsAndMessaging::{
-            CallWindowProcW, DefWindowProcW, GetClientRect, SetForegroundWindow, SetWindowLongPtrW, GWLP_WNDPROC, WM_ACTIVATE, WM_ACTIVATEAPP, WM_DPICHANGED, WM_KEYDOWN, WM_KEYUP, WM_KILLFOCUS, WM_MOUSEMOVE, WM_SETFOCUS, WM_SIZE, WM_SYSKEYDOWN, WM_SYSKEYUP
+            CallWindowProcW, DefWindowProcW, GWLP_WNDPROC, SetForegroundWindow, SetWindowLongPtrW,
+            WM_ACTIVATE, WM_ACTIVATEAPP, WM_CHAR, WM_DPICHANGED, WM_KEYDOWN, WM_KEYUP,
+            WM_KILLFOCUS, WM_LBUTTONDOWN, WM_LBUTTONUP, WM_MBUTTONDOWN, WM_MBUTTONUP,
+            WM_MOUSEHWHEEL, WM_MOUSEMOVE, WM_MOUSEWHEEL, WM_RBUTTONDOWN, WM_RBUTTONUP, WM_SETFOCUS,
+            WM_SIZE, WM_SYSKEYDOWN, WM_SYSKEYUP, WM_XBUTTONDOWN, WM_XBUTTONUP, WNDPROC,
         },
     },
 };
 
 use crate::{
-    globals::{self, ORIGINAL_WNDPROC},
-    keybinds::{get_current_keybind, KEYBINDS}, ui::{mmf::set_mmf_dimensions, UPDATE_SCHEDULED},
+    globals::ORIGINAL_WNDPROC,
+    input::{InputEvent, MouseButton},
+    keybinds::{config::Action, get_current_action, keys::mouse, run_action},
+    shutdown::WNDPROC_CALLS,
+    udp::{release_capture, send_input, start_udp_threads},
+    ui::{KEYBOARD_CAPTURE, is_overlay_pixel, mmf::set_mmf_dimensions},
 };
 
 pub fn initialize_controls(hwnd: HWND) {
     unsafe {
-        let old_wndproc = SetWindowLongPtrW(hwnd, GWLP_WNDPROC, wnd_proc as _);
-        ORIGINAL_WNDPROC = Some(std::mem::transmute(old_wndproc)); 
+        let old_wndproc = SetWindowLongPtrW(hwnd, GWLP_WNDPROC, wnd_proc as *const () as isize);
+        ORIGINAL_WNDPROC = Some(std::mem::transmute::<isize, WNDPROC>(old_wndproc));
     }
 }
+///True from initialize_controls until restore_wnd_proc.
+pub fn is_wnd_proc_replaced() -> bool {
+    unsafe { ORIGINAL_WNDPROC }.is_some()
+}
 pub fn restore_wnd_proc(hwnd: HWND) {
     unsafe {
         if let Some(Some(orig)) = ORIGINAL_WNDPROC {
-            SetWindowLongPtrW(hwnd, GWLP_WNDPROC, orig as _);
+            SetWindowLongPtrW(hwnd, GWLP_WNDPROC, orig as usize as isize);
             ORIGINAL_WNDPROC = None;
         } else {
             log::error!("Could not get the value for ORIGINAL_WNDPROC to restore it.");
@@ -55,25 +62,101 @@ fn get_y_lparam(lparam: LPARAM) -> i32 {
     y as i32
 }
 
-#[repr(C, packed)]
-#[derive(Copy, Clone)]
-struct MouseInputPacket {
-    id: u8,
-    x: i32,
-    y: i32,
+fn mouse_button_vk(button: MouseButton) -> u32 {
+    match button {
+        MouseButton::Left => mouse::LEFT,
+        MouseButton::Right => mouse::RIGHT,
+        MouseButton::Middle => mouse::MIDDLE,
+        MouseButton::X1 => mouse::X1,
+        MouseButton::X2 => mouse::X2,
+    }
+}
+
+//Scan code of a key message, with 0x100 set for extended keys.
+fn get_scan_code(lparam: LPARAM) -> u32 {
+    let lparam_u32 = lparam.0 as u32;
+    let scan = (lparam_u32 >> 16) & 0xFF;
+    let extended = (lparam_u32 >> 24) & 1;
+    scan | (extended << 8)
+}
+
+//WM_CHAR sends UTF-16 code units, characters outside the BMP come as two messages.
+static HIGH_SURROGATE: AtomicU16 = AtomicU16::new(0);
+
+fn decode_char(unit: u16) -> Option<u32> {
+    match unit {
+        0xD800..=0xDBFF => {
+            HIGH_SURROGATE.store(unit, Ordering::Relaxed);
+            None
+        }
+        0xDC00..=0xDFFF => {
+            let high = HIGH_SURROGATE.swap(0, Ordering::Relaxed);
+            char::decode_utf16([high, unit])
+                .next()?
+                .ok()
+                .map(|c| c as u32)
+        }
+        _ => {
+            HIGH_SURROGATE.store(0, Ordering::Relaxed);
+            Some(unit as u32)
+        }
+    }
+}
+
+//Which button a mouse message is about, and whether it was pressed or released.
+fn get_mouse_button(msg: u32, wparam: WPARAM) -> Option<(MouseButton, bool)> {
+    match msg {
+        WM_LBUTTONDOWN => Some((MouseButton::Left, true)),
+        WM_LBUTTONUP => Some((MouseButton::Left, false)),
+        WM_RBUTTONDOWN => Some((MouseButton::Right, true)),
+        WM_RBUTTONUP => Some((MouseButton::Right, false)),
+        WM_MBUTTONDOWN => Some((MouseButton::Middle, true)),
+        WM_MBUTTONUP => Some((MouseButton::Middle, false)),
+        WM_XBUTTONDOWN | WM_XBUTTONUP => {
+            let button = if (wparam.0 >> 16) & 0xFFFF == 1 {
+                MouseButton::X1
+            } else {
+                MouseButton::X2
+            };
+            Some((button, msg == WM_XBUTTONDOWN))
+        }
+        _ => None,
+    }
 }
 
-//Unsafe way to send packets over to a thread.
-//It's 100% safe as long as:
-//- Thread is initialized before the first call
-//- Sender is only used in wnd_proc
-#[derive(Debug)]
-struct StaticSender {
-    sender: *const Sender<MouseInputPacket>,
+//Buttons whose press landed on the overlay, one bit per MouseButton.
+//Their release is swallowed too, wherever it happens, so the game never sees half a click.
+static SWALLOWED_BUTTONS: AtomicU8 = AtomicU8::new(0);
+
+//Returns true if the button message should not reach the game.
+fn swallow_button(button: MouseButton, pressed: bool, on_overlay: bool) -> bool {
+    let bit = 1 << button as u8;
+    if pressed {
+        if on_overlay {
+            SWALLOWED_BUTTONS.fetch_or(bit, Ordering::Relaxed);
+        } else {
+            SWALLOWED_BUTTONS.fetch_and(!bit, Ordering::Relaxed);
+        }
+        on_overlay
+    } else {
+        SWALLOWED_BUTTONS.fetch_and(!bit, Ordering::Relaxed) & bit != 0
+    }
+}
+
+//Keys the game saw go down and not up yet, one bit per virtual key.
+static GAME_KEYS_DOWN: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+//Returns whether the game had the key down before.
+fn set_game_key(vk: u32, down: bool) -> bool {
+    let vk = vk as usize & 0xFF;
+    let bit = 1 << (vk % 64);
+    let previous = if down {
+        GAME_KEYS_DOWN[vk / 64].fetch_or(bit, Ordering::Relaxed)
+    } else {
+        GAME_KEYS_DOWN[vk / 64].fetch_and(!bit, Ordering::Relaxed)
+    };
+    previous & bit != 0
 }
-unsafe impl Sync for StaticSender {}
-unsafe impl Send for StaticSender {}
-static MOUSE_SENDER: OnceLock<StaticSender> = OnceLock::new();
 
 //Keep track of the numlock state and ALT_UP
 //This is basically just a workaround for focus issues where windows
@@ -88,91 +171,140 @@ unsafe extern "system" fn wnd_proc(
     wparam: WPARAM,
     lparam: LPARAM,
 ) -> LRESULT {
-    'local_handling: {
-        match msg {
-            //Mouse
-            WM_MOUSEMOVE => {
-                let x = get_x_lparam(lparam);
-                let y = get_y_lparam(lparam);
-
-                //let is_overlay_pixel = ui::is_overlay_pixel(x as u32, y as u32);
-
-                //Mouse up/down are seemingly handled globally.
-                //So we only need to pass MOUSEMOVE.
-                let id = match msg {
-                    /*WM_LBUTTONDOWN => 0,
-                    WM_LBUTTONUP => 1,*/
-                    WM_MOUSEMOVE => 2,
-                    /*WM_RBUTTONDOWN => 3,
-                    WM_RBUTTONUP => 4,*/
-                    _ => {
-                        break 'local_handling;
-                    }
-                };
+    //Includes the original wnd_proc, we return into this function.
+    let _call = WNDPROC_CALLS.enter();
+    match msg {
+        //Mouse
+        WM_MOUSEMOVE | WM_LBUTTONDOWN | WM_LBUTTONUP | WM_RBUTTONDOWN | WM_RBUTTONUP
+        | WM_MBUTTONDOWN | WM_MBUTTONUP | WM_XBUTTONDOWN | WM_XBUTTONUP => {
+            let x = get_x_lparam(lparam);
+            let y = get_y_lparam(lparam);
 
-                //Send packet to listening thread.
-                let packet = MouseInputPacket { id, x, y };
-                let sender = unsafe { &*MOUSE_SENDER.get().unwrap().sender };
-                sender.send(packet).ok();
-                /*if is_overlay_pixel && msg == WM_LBUTTONDOWN && msg == WM_RBUTTONDOWN {
+            let event = match get_mouse_button(msg, wparam) {
+                Some((button, pressed)) => InputEvent::MouseButton {
+                    button,
+                    pressed,
+                    x,
+                    y,
+                },
+                None => InputEvent::MouseMove { x, y },
+            };
+            send_input(event);
+
+            //Moves always reach the game, otherwise it would miss the cursor leaving the overlay.
+            if let Some((button, pressed)) = get_mouse_button(msg, wparam) {
+                //Mouse buttons can be bound too. The release goes through, the game never saw the press.
+                if pressed && let Some(action) = get_current_action(mouse_button_vk(button)) {
+                    run_action(action);
+                    return LRESULT(0);
+                }
+                if swallow_button(button, pressed, is_overlay_pixel(x, y)) {
                     return LRESULT(0);
-                }*/
+                }
             }
-            WM_SYSKEYUP | WM_SYSKEYDOWN => {
-                if wparam.0 == 0x90 {
+        }
+        WM_MOUSEWHEEL | WM_MOUSEHWHEEL => {
+            //Wheel messages are in screen coordinates, unlike every other mouse message.
+            let mut point = POINT {
+                x: get_x_lparam(lparam),
+                y: get_y_lparam(lparam),
+            };
+            //Without client coordinates we can't tell where it landed, the game gets it.
+            if unsafe { ScreenToClient(hwnd, &mut point) }.as_bool() {
+                send_input(InputEvent::MouseWheel {
+                    delta: ((wparam.0 >> 16) & 0xFFFF) as u16 as i16,
+                    horizontal: msg == WM_MOUSEHWHEEL,
+                    x: point.x,
+                    y: point.y,
+                });
+                if is_overlay_pixel(point.x, point.y) {
                     return LRESULT(0);
                 }
             }
-            WM_KEYDOWN | WM_KEYUP => {
-                if wparam.0 as u16 == VK_MENU.0 {
-                    unsafe {
-                        LAST_ALT_UP = Some(Instant::now());
-                    }
+        }
+        WM_SYSKEYUP | WM_SYSKEYDOWN if wparam.0 == 0x90 => {
+            return LRESULT(0);
+        }
+        WM_KEYDOWN | WM_KEYUP => {
+            if wparam.0 as u16 == VK_MENU.0 {
+                unsafe {
+                    LAST_ALT_UP = Some(Instant::now());
                 }
-                //Numlock fix
-                if wparam.0 == 0x90 {
-                    if !synchronize_numlock() {
+            }
+            //Numlock fix
+            if wparam.0 == 0x90 && !synchronize_numlock() {
+                return LRESULT(0);
+            }
+
+            let vk = wparam.0 as u32;
+            let capturing = KEYBOARD_CAPTURE.load(Ordering::Relaxed);
+            if msg == WM_KEYDOWN {
+                //While capturing, keys are typed into the overlay. Only the way out still works.
+                match get_current_action(vk) {
+                    Some(action) if !capturing || action == Action::ReleaseKeyboardCapture => {
+                        run_action(action);
                         return LRESULT(0);
                     }
+                    _ => {}
                 }
-
-                if msg == WM_KEYDOWN {
-                    if let Some(map) = KEYBINDS.get() {
-                        let combo = get_current_keybind(wparam.0 as u32);
-                        if let Some(action) = map.get(&combo) {
-                            action();
-                            return LRESULT(0);
-                        }
-                    }
+                //Escape hatch, always works even if the producer never releases capture.
+                if vk as u16 == VK_ESCAPE.0 && release_capture() {
+                    return LRESULT(0);
                 }
             }
-            WM_SETFOCUS => grab_focus(hwnd),
-            WM_KILLFOCUS => release_focus(),
-            WM_ACTIVATEAPP | WM_ACTIVATE => {
-                if wparam.0 != 0 {
-                    grab_focus(hwnd);
-                } else {
-                    release_focus();
+
+            if capturing {
+                send_input(InputEvent::Key {
+                    vk,
+                    scan: get_scan_code(lparam),
+                    pressed: msg == WM_KEYDOWN,
+                    repeat: (lparam.0 >> 30) & 1 != 0,
+                });
+                //Except for keys held since before capture started, which would stay pressed.
+                if !(msg == WM_KEYUP && set_game_key(vk, false)) {
+                    return LRESULT(0);
                 }
+            } else {
+                set_game_key(vk, msg == WM_KEYDOWN);
             }
-            WM_SIZE => {
-                let lp = lparam.0 as u32;
-                let width  = (lp & 0xFFFF) as u32;
-                let height = (lp >> 16) as u32;
-                set_mmf_dimensions(width, height); 
+        }
+        WM_CHAR if KEYBOARD_CAPTURE.load(Ordering::Relaxed) => {
+            if let Some(ch) = decode_char(wparam.0 as u16) {
+                send_input(InputEvent::Char { ch });
             }
-            WM_DPICHANGED => {
-                unsafe {
-                    let rect = *(lparam.0 as *const RECT);
-
-                    let width  = rect.right - rect.left;
-                    let height = rect.bottom - rect.top;
-
-                    set_mmf_dimensions(width as u32, height as u32);
-                }
+            return LRESULT(0);
+        }
+        WM_SETFOCUS => {
+            send_input(InputEvent::Focus { focused: true });
+            grab_focus(hwnd);
+        }
+        WM_KILLFOCUS => {
+            send_input(InputEvent::Focus { focused: false });
+            release_focus();
+        }
+        WM_ACTIVATEAPP | WM_ACTIVATE => {
+            if wparam.0 != 0 {
+                grab_focus(hwnd);
+            } else {
+                release_focus();
             }
-            _ => {}
         }
+        WM_SIZE => {
+            let lp = lparam.0 as u32;
+            let width = lp & 0xFFFF;
+            let height = lp >> 16;
+            send_input(InputEvent::Resize { width, height });
+            set_mmf_dimensions(width, height);
+        }
+        WM_DPICHANGED => unsafe {
+            let rect = *(lparam.0 as *const RECT);
+
+            let width = rect.right - rect.left;
+            let height = rect.bottom - rect.top;
+
+            set_mmf_dimensions(width as u32, height as u32);
+        },
+        _ => {}
     }
     unsafe {
         if let Some(original) = ORIGINAL_WNDPROC {
@@ -186,17 +318,17 @@ unsafe extern "system" fn wnd_proc(
 //Returns true if the numlock press should count, false if we should swallow it
 fn synchronize_numlock() -> bool {
     //Swallow numlock if alt was pressed soon before
-    if let Some(instant) = unsafe { LAST_ALT_UP } {
-        if instant.elapsed() < Duration::from_millis(100) {
-            return false;
-        }
+    if let Some(instant) = unsafe { LAST_ALT_UP }
+        && instant.elapsed() < Duration::from_millis(100)
+    {
+        return false;
     }
     let numlock_state = unsafe { GetKeyState(VK_NUMLOCK.0 as i32) & 1 } as u8;
     if numlock_state != NUMLOCK_STATE.load(Ordering::Relaxed) {
         NUMLOCK_STATE.store(numlock_state, Ordering::Relaxed);
         return true;
     }
-    return false;
+    false
 }
 
 fn grab_focus(hwnd: HWND) {
@@ -212,26 +344,8 @@ fn release_focus() {
     }
 }
 
-pub fn start_mouse_input_thread() {
+//Starts the threads talking to the producer, see udp.rs.
+pub fn start_input_thread() -> std::io::Result<()> {
     synchronize_numlock();
-    let (tx, rx) = channel::<MouseInputPacket>();
-
-    MOUSE_SENDER
-        .set(StaticSender {
-            sender: Box::into_raw(Box::new(tx)),
-        })
-        .unwrap();
-
-    std::thread::spawn(move || {
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to bind socket");
-        for packet in rx {
-            let data = unsafe {
-                from_raw_parts(
-                    &packet as *const MouseInputPacket as *const u8,
-                    size_of::<MouseInputPacket>(),
-                )
-            };
-            socket.send_to(data, globals::UDPADDR).ok();
-        }
-    });
+    start_udp_threads()
 }
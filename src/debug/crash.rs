@@ -0,0 +1,261 @@
+use std::{
+    backtrace::Backtrace,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        TryLockError,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use chrono::{Local, SecondsFormat};
+
+use super::statistics::{debug_stat, statistics_snapshot};
+use crate::{
+    config::config,
+    keybinds::active_keybinds,
+    logging::{
+        current_thread_name,
+        json::{JsonObject, json_array, json_string},
+        ring::recent,
+        rotate::{Retention, new_file, prune_logs},
+    },
+    ui::{FRAME_NUMBER, MMF_DATA},
+};
+
+/*
+ *
+ * Crash reports, written by the panic hook next to the logs as crash-<local time>.json.
+ * A single file so users can attach it to bug reports as is.
+ *
+ * {
+ * "time", "version", "thread", "frame": when and where it happened,
+ * "panic": { "message", "location" },
+ * "backtrace": [ lines ],
+ * "logs": [ the last records of the log ring, oldest first ],
+ * "mmf": what was read from the producer, or "locked",
+ * "overlay": { "width", "height" } of the D3D state, null if there is none, or "locked",
+ * "keybinds": [ "<keybind> <action>" ],
+ * "statistics": { name: latest value },
+ * "config": [ lines ]
+ * }
+ *
+ * The panicking thread may hold any of the locks, everything is read with try_lock and a
+ * busy lock is reported as "locked" instead of waiting on it.
+ *
+ * */
+
+const CRASH_PREFIX: &str = "crash";
+const CRASH_EXTENSION: &str = "json";
+//Last records of the log ring in the report.
+const CRASH_LOG_LINES: usize = 200;
+
+//Only the first panic gets a report, the ones it causes would only bury it.
+static WRITTEN: AtomicBool = AtomicBool::new(false);
+
+///Writes the crash report of a panic. Ok(None) if one was already written.
+pub fn write_crash_bundle(message: &str, location: &str) -> io::Result<Option<PathBuf>> {
+    if WRITTEN.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+    let logging = &config().logging;
+    let retention = Retention {
+        hours: logging.retention_hours,
+        max_files: logging.max_files,
+    };
+    let bundle = crash_bundle(message, location);
+    write_bundle(Path::new(&config().paths.logs_dir), retention, &bundle).map(Some)
+}
+
+fn crash_bundle(message: &str, location: &str) -> String {
+    JsonObject::new()
+        .string(
+            "time",
+            &Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+        )
+        .string("version", env!("CARGO_PKG_VERSION"))
+        .string("thread", &current_thread_name())
+        .raw("frame", FRAME_NUMBER.load(Ordering::Relaxed))
+        .raw(
+            "panic",
+            JsonObject::new()
+                .string("message", message)
+                .string("location", location)
+                .finish(),
+        )
+        .raw("backtrace", lines(&Backtrace::force_capture().to_string()))
+        .raw(
+            "logs",
+            json_array(
+                recent(None, CRASH_LOG_LINES)
+                    .iter()
+                    .map(|entry| json_string(&entry.to_string())),
+            ),
+        )
+        .raw("mmf", mmf_json())
+        .raw("overlay", overlay_json())
+        .raw("keybinds", keybinds_json())
+        .raw("statistics", statistics_json())
+        .raw("config", lines(&format!("{:#?}", config())))
+        .finish()
+}
+
+fn write_bundle(dir: &Path, retention: Retention, bundle: &str) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    prune_logs(dir, CRASH_PREFIX, CRASH_EXTENSION, retention, 1);
+    let (mut file, path) = new_file(dir, CRASH_PREFIX, CRASH_EXTENSION)?;
+    file.write_all(bundle.as_bytes())?;
+    file.flush()?;
+    Ok(path)
+}
+
+//Multi-line text as an array of lines, easier to read than escaped newlines.
+fn lines(text: &str) -> String {
+    json_array(text.lines().map(json_string))
+}
+
+fn mmf_json() -> String {
+    let Some(data) = MMF_DATA.get() else {
+        return "null".to_string();
+    };
+    let data = match data.try_read() {
+        Ok(data) => data,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return json_string("locked"),
+    };
+    JsonObject::new()
+        .raw("is_blish_alive", data.is_blish_alive)
+        .string("negotiated", &format!("{:?}", data.negotiated))
+        .raw(
+            "layers",
+            json_array(
+                data.layers
+                    .iter()
+                    .map(|layer| json_string(&format!("{:?}", layer))),
+            ),
+        )
+        .string("dirty", &format!("{:?}", data.dirty))
+        .string("hit_test", &format!("{:?}", data.hit_test))
+        .string("fade", &format!("{:?}", data.fade))
+        .finish()
+}
+
+fn overlay_json() -> String {
+    #[cfg(windows)]
+    {
+        let Some(state) = crate::ui::OVERLAY_STATE.get() else {
+            return "null".to_string();
+        };
+        let state = match state.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => return json_string("locked"),
+        };
+        match state.as_ref() {
+            Some(state) => JsonObject::new()
+                .raw("width", state.width)
+                .raw("height", state.height)
+                .finish(),
+            None => "null".to_string(),
+        }
+    }
+    #[cfg(not(windows))]
+    "null".to_string()
+}
+
+fn keybinds_json() -> String {
+    match active_keybinds() {
+        Some(bindings) => json_array(
+            bindings
+                .iter()
+                .map(|(keybind, action)| json_string(&format!("{} {}", keybind, action.name()))),
+        ),
+        None => json_string("locked"),
+    }
+}
+
+fn statistics_json() -> String {
+    let Some(stats) = statistics_snapshot() else {
+        return json_string("locked");
+    };
+    stats
+        .iter()
+        .fold(
+            JsonObject::new(),
+            |object, (stat, value)| match debug_stat::name(*stat) {
+                Some(name) => object.raw(name, value),
+                None => object.raw(&stat.to_string(), value),
+            },
+        )
+        .finish()
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::logging::ring::{LogEntry, ring};
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    #[test]
+    fn bundles_are_json_with_every_part() {
+        ring().push(LogEntry {
+            seq: 0,
+            time: Local::now(),
+            level: log::Level::Error,
+            module: "external_dx11_overlay::ui".to_string(),
+            thread: "main".to_string(),
+            frame: 0,
+            message: "a \"quoted\" message\twith\x07controls".to_string(),
+        });
+        let dir = TempDir::new().unwrap();
+        let retention = Retention {
+            hours: 0,
+            max_files: 0,
+        };
+        let bundle = crash_bundle("it \"broke\"\n", "src/lib.rs:1");
+        let path = write_bundle(dir.path(), retention, &bundle).unwrap();
+        assert_eq!(path.parent(), Some(dir.path()));
+        let name = path.file_name().unwrap().to_string_lossy();
+        assert!(
+            name.starts_with("crash-") && name.ends_with(".json"),
+            "{}",
+            name
+        );
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let bundle: Value = serde_json::from_str(&text).unwrap();
+        for key in [
+            "time",
+            "version",
+            "thread",
+            "frame",
+            "panic",
+            "backtrace",
+            "logs",
+            "mmf",
+            "overlay",
+            "keybinds",
+            "statistics",
+            "config",
+        ] {
+            assert!(bundle.get(key).is_some(), "{} is missing", key);
+        }
+        assert_eq!(bundle["panic"]["message"], "it \"broke\"\n");
+        assert_eq!(bundle["panic"]["location"], "src/lib.rs:1");
+        assert!(bundle["backtrace"].is_array());
+        assert!(bundle["config"].is_array());
+        assert!(bundle["statistics"].is_object());
+        let logs = bundle["logs"].as_array().unwrap();
+        assert!(
+            logs.iter().any(|line| line
+                .as_str()
+                .unwrap()
+                .ends_with("a \"quoted\" message\twith\x07controls")),
+            "{:?}",
+            logs
+        );
+        //The game isn't running, there is no overlay.
+        assert!(bundle["overlay"].is_null());
+    }
+}
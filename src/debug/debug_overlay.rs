@@ -1,22 +1,46 @@
+use log::Level;
 use std::{
-    collections::{HashMap, VecDeque},
+    io,
+    path::Path,
     sync::{
-        Mutex, MutexGuard, OnceLock,
-        atomic::{AtomicU8, Ordering},
+        Mutex, TryLockError,
+        atomic::{AtomicU8, AtomicU64, Ordering},
     },
+    time::{Duration, Instant},
 };
 
-use fontdue::{Font, FontSettings};
-
-use super::{DEBUG_FEATURES, statistics::debug_stat};
+use super::{
+    DEBUG_FEATURES,
+    statistics::{debug_stat, statistics_snapshot},
+    text::{
+        Image, Rgba, TextRenderer,
+        layout::{Overflow, Span},
+    },
+};
+use crate::{
+    config::config,
+    logging::ring::recent,
+    shutdown::{spawn_worker, wait_for_shutdown},
+    ui::layers::{Placement, Rect, place},
+};
 
-//---------------------------------------- Debug Overlay ---------------------------------------
-//Is always drawn at 0,0 for minimal confusion and complexity
-//This overlay is very raw (compared to eg.imgui)
-//Lets us draw some debug information. Ideally toggled with a keybind.
-static OVERLAY: OnceLock<Box<[u8]>> = OnceLock::new();
-const OVERLAY_WIDTH: usize = 600;
-const OVERLAY_HEIGHT: usize = 180;
+/*
+ *
+ * Debug panel, toggled with a keybind. Plain Rust, no windows.
+ *
+ * The "debug-panel" thread draws the panel into an RGBA image whenever what it shows changes,
+ * and bumps its revision. The present hook uploads the image to a texture of its own when the
+ * revision differs from the one it has, then draws it on top of every layer, in the corner set
+ * in the config. Nothing is drawn or uploaded on the present thread otherwise.
+ *
+ * It shows either the last lines of the log ring, or the statistics, see overlay_mode.
+ * Text is drawn with debug::text.
+ *
+ * */
+
+//Very raw (compared to eg.imgui), lets us draw some debug information.
+pub const OVERLAY_WIDTH: usize = 600;
+pub const OVERLAY_HEIGHT: usize = 180;
 const MAX_X: f32 = OVERLAY_WIDTH as f32 - 5.0;
 
 //Current mode of the overlay.
@@ -26,235 +50,366 @@ pub mod overlay_mode {
     pub const STAT_MODE: u8 = 1;
 }
 
-//Font. Because I don't want users to have to install corefonts to their wine prefix.
-//Steam automatically links to fonts, but manually created prefixes do NOT.
-//So we link it statically for compatiblity purposes.
-//static FONT_DATA: &[u8] = include_bytes!("../ui/segoeui.ttf");
-static FONT: OnceLock<Font> = OnceLock::new();
 const FONT_SIZE: f32 = 12.0;
+//Between the border and the text.
+const PADDING: f32 = 3.0;
+const TEXT_COLOR: Rgba = [255, 255, 255, 255];
+const LABEL_COLOR: Rgba = [200, 200, 200, 255];
 
-//Log. Shows the same thing as what gets written to the log files.
-static LOG: OnceLock<Mutex<VecDeque<String>>> = OnceLock::new();
+//Log. The last records of the log ring, same as what gets written to the log files.
 const MAX_LOG_LINES: usize = 12;
 
+//How often the thread checks whether the content changed.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+//Statistics change every frame, they are only redrawn this often.
+const STAT_REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
 //Background color for the overlay.
 const DEBUG_OVERLAY_BG_R: u8 = 0;
 const DEBUG_OVERLAY_BG_G: u8 = 0;
 const DEBUG_OVERLAY_BG_B: u8 = 20;
 const DEBUG_OVERLAY_BG_A: u8 = 200;
+const BORDER: [u8; 4] = [200, 200, 200, 255];
+
+//The image uploaded by the present hook, OVERLAY_WIDTH * OVERLAY_HEIGHT RGBA pixels.
+static PANEL: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+//Bumped every time PANEL is redrawn. 0 until it is drawn the first time.
+static PANEL_REVISION: AtomicU64 = AtomicU64::new(0);
+
+//Corner of the game's window the panel is drawn in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
 
-fn get_overlay() -> &'static [u8] {
-    OVERLAY.get_or_init(|| {
-        let mut buf = vec![0u8; (OVERLAY_WIDTH * OVERLAY_HEIGHT * 4) as usize].into_boxed_slice();
-
-        //Border
-        let border_r = 200;
-        let border_g = 200;
-        let border_b = 200;
-        let border_alpha = 255;
-
-        for j in 0..OVERLAY_HEIGHT {
-            for i in 0..OVERLAY_WIDTH {
-                let idx = ((j * OVERLAY_WIDTH + i) * 4) as usize;
+impl Corner {
+    pub fn from_name(name: &str) -> Option<Corner> {
+        match name {
+            "top_left" => Some(Corner::TopLeft),
+            "top_right" => Some(Corner::TopRight),
+            "bottom_left" => Some(Corner::BottomLeft),
+            "bottom_right" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
 
-                //Border
-                if i == 0 || i == OVERLAY_WIDTH - 1 || j == 0 || j == OVERLAY_HEIGHT - 1 {
-                    buf[idx + 0] = border_r;
-                    buf[idx + 1] = border_g;
-                    buf[idx + 2] = border_b;
-                    buf[idx + 3] = border_alpha;
-                //Inside
-                } else {
-                    buf[idx + 0] = DEBUG_OVERLAY_BG_R;
-                    buf[idx + 1] = DEBUG_OVERLAY_BG_G;
-                    buf[idx + 2] = DEBUG_OVERLAY_BG_B;
-                    buf[idx + 3] = DEBUG_OVERLAY_BG_A;
-                }
-            }
+    pub fn name(self) -> &'static str {
+        match self {
+            Corner::TopLeft => "top_left",
+            Corner::TopRight => "top_right",
+            Corner::BottomLeft => "bottom_left",
+            Corner::BottomRight => "bottom_right",
         }
-        buf
-    })
+    }
 }
 
-fn get_log_lock() -> MutexGuard<'static, VecDeque<String>> {
-    LOG.get_or_init(|| Mutex::new(VecDeque::with_capacity(MAX_LOG_LINES)))
-        .lock()
-        .expect("Could not get log mutex")
+//Where the panel goes, what the frame planner needs to place it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PanelFacts {
+    pub width: u32,
+    pub height: u32,
+    pub corner: Corner,
+    //Distance to the edges of the window, in pixels.
+    pub margin: u32,
 }
 
-/*pub fn add_to_debug_log_overlay(str: String) {
-    let mut log = get_log_lock();
-
-    if log.len() == MAX_LOG_LINES {
-        log.pop_back();
+impl PanelFacts {
+    ///Clips the panel against a window of the given size.
+    pub fn place(&self, width: u32, height: u32) -> Option<Placement> {
+        let right = width as i64 - self.width as i64 - self.margin as i64;
+        let bottom = height as i64 - self.height as i64 - self.margin as i64;
+        let (x, y) = match self.corner {
+            Corner::TopLeft => (self.margin as i64, self.margin as i64),
+            Corner::TopRight => (right, self.margin as i64),
+            Corner::BottomLeft => (self.margin as i64, bottom),
+            Corner::BottomRight => (right, bottom),
+        };
+        place(
+            Rect::new(x as i32, y as i32, self.width, self.height),
+            width,
+            height,
+        )
     }
-    log.push_front(str);
-    if DEBUG_FEATURES.debug_overlay_enabled.load(Ordering::Relaxed) {
-        drop(log);
-        refresh_overlay_buffer(None);
-    }
-}*/
-
-/*pub fn refresh_overlay_buffer(stats: Option<&HashMap<u32, u32>>) {
-    let overlay = get_overlay();
-    let overlay_ptr = overlay.as_ptr() as *mut u8;
-    let log = get_log_lock();
+}
 
-    let mut y = 12.0;
+///The panel to draw this frame, None while it is toggled off or not drawn yet.
+pub fn panel_facts() -> Option<PanelFacts> {
+    if !DEBUG_FEATURES.debug_overlay_enabled.load(Ordering::Relaxed)
+        || PANEL_REVISION.load(Ordering::Relaxed) == 0
+    {
+        return None;
+    }
+    let panel = &config().debug_overlay;
+    Some(PanelFacts {
+        width: OVERLAY_WIDTH as u32,
+        height: OVERLAY_HEIGHT as u32,
+        corner: panel.corner,
+        margin: panel.margin,
+    })
+}
 
-    //Flush the previous buffer
-    clear_log_area(overlay_ptr);
+///Calls upload with the pixels and revision of the panel if it changed since uploaded.
+///Never waits, if the panel is being redrawn it is uploaded on a later frame.
+pub fn with_new_panel(uploaded: u64, upload: impl FnOnce(&[u8], u64)) {
+    if PANEL_REVISION.load(Ordering::Acquire) == uploaded {
+        return;
+    }
+    let pixels = match PANEL.try_lock() {
+        Ok(pixels) => pixels,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return,
+    };
+    //Read under the lock, it is only bumped with the pixels.
+    let revision = PANEL_REVISION.load(Ordering::Acquire);
+    if !pixels.is_empty() {
+        upload(&pixels, revision);
+    }
+}
 
-    unsafe {
-        match OVERLAY_MODE.load(Ordering::Relaxed) {
-            //Log
-            overlay_mode::LOG_MODE => {
-                for line in log.iter().rev() {
-                    let mut x = 2.0;
-                    for c in line.chars() {
-                        if x + FONT_SIZE >= MAX_X {
-                            //Overflow
-                            break;
-                        }
-                        x = draw_char(overlay_ptr, x, y, c);
+///Starts the thread keeping the panel up to date while it is toggled on.
+pub fn start_debug_panel() -> io::Result<()> {
+    spawn_worker("debug-panel", || {
+        let mut text = panel_text();
+        let mut shown: Option<(u8, Vec<Vec<Span>>)> = None;
+        let mut last_stats: Option<Instant> = None;
+        while !wait_for_shutdown(REFRESH_INTERVAL) {
+            if !DEBUG_FEATURES.debug_overlay_enabled.load(Ordering::Relaxed) {
+                continue;
+            }
+            let mode = OVERLAY_MODE.load(Ordering::Relaxed);
+            let lines = match mode {
+                overlay_mode::STAT_MODE => {
+                    let same_mode = shown.as_ref().is_some_and(|(m, _)| *m == mode);
+                    let fresh = last_stats.is_some_and(|t| t.elapsed() < STAT_REFRESH_INTERVAL);
+                    if same_mode && fresh {
+                        continue;
                     }
-                    y += FONT_SIZE + 2.0;
+                    last_stats = Some(Instant::now());
+                    stat_lines()
                 }
+                _ => log_lines(),
+            };
+            if shown
+                .as_ref()
+                .is_some_and(|(m, l)| *m == mode && *l == lines)
+            {
+                continue;
             }
-            //Statistics
-            overlay_mode::STAT_MODE => {
-                if let Some(stats) = stats {
-                    let mut x = 2.0;
-
-                    let frame_time_custom = stats.get(&debug_stat::FRAME_TIME_CUSTOM).unwrap();
-                    let frame_time_total = stats.get(&debug_stat::FRAME_TIME_TOTAL).unwrap();
-                    let frame_time_diff = stats.get(&debug_stat::FRAME_TIME_DIFF).unwrap();
+            store_panel(render_panel(&mut text, mode, &lines));
+            shown = Some((mode, lines));
+        }
+    })
+}
 
-                    x = draw_text_at(
-                        overlay_ptr,
-                        format!("Custom render: {}ns.  ", frame_time_custom),
-                        x,
-                        y,
-                    );
+///Lines of the log mode, oldest first.
+pub fn log_lines() -> Vec<Vec<Span>> {
+    recent(None, MAX_LOG_LINES)
+        .iter()
+        .map(|entry| {
+            vec![
+                Span::new(format!("[{}] ", entry.level), level_color(entry.level)),
+                Span::new(entry.message.clone(), TEXT_COLOR),
+            ]
+        })
+        .collect()
+}
 
-                    x = draw_text_at(
-                        overlay_ptr,
-                        format!("Total render: {}ns.  ", frame_time_total),
-                        x,
-                        y,
-                    );
+///Lines of the statistics mode.
+pub fn stat_lines() -> Vec<Vec<Span>> {
+    let Some(stats) = statistics_snapshot() else {
+        return Vec::new();
+    };
+    let get = |stat: u32| {
+        stats
+            .iter()
+            .find(|(s, _)| *s == stat)
+            .map_or(0, |(_, value)| *value)
+    };
+    let stat = |label: &str, value: String| {
+        vec![
+            Span::new(format!("{}: ", label), LABEL_COLOR),
+            Span::new(value, TEXT_COLOR),
+        ]
+    };
+    vec![
+        stat(
+            "Custom render",
+            format!("{}ns", get(debug_stat::FRAME_TIME_CUSTOM)),
+        ),
+        stat(
+            "Total render",
+            format!("{}ns", get(debug_stat::FRAME_TIME_TOTAL)),
+        ),
+        stat(
+            "Original",
+            format!("{}ns", get(debug_stat::FRAME_TIME_DIFF)),
+        ),
+        stat(
+            "Input round trip",
+            format!("{}us", get(debug_stat::INPUT_ROUND_TRIP)),
+        ),
+    ]
+}
 
-                    x = draw_text_at(
-                        overlay_ptr,
-                        format!("Original: {}ns.  ", frame_time_diff),
-                        x,
-                        y,
-                    );
-                }
-            }
-            _ => {}
-        }
+fn level_color(level: Level) -> Rgba {
+    match level {
+        Level::Error => [255, 96, 96, 255],
+        Level::Warn => [255, 200, 80, 255],
+        Level::Info => LABEL_COLOR,
+        Level::Debug | Level::Trace => [150, 150, 170, 255],
     }
-}*/
+}
 
-/*fn draw_text_at(buf: *mut u8, str: String, x: f32, y: f32) -> f32 {
-    let mut x = x;
-    for c in str.chars() {
-        if x + FONT_SIZE >= MAX_X {
-            //Overflow
-            break;
-        }
-        unsafe {
-            x = draw_char(buf, x, y, c);
-        }
+///Draws the panel of a mode. The log keeps one line per record, cut with an ellipsis, and
+///shows the newest that fit. Other modes wrap their lines.
+pub fn render_panel(text: &mut TextRenderer, mode: u8, lines: &[Vec<Span>]) -> Image {
+    let mut image = Image::new(OVERLAY_WIDTH, OVERLAY_HEIGHT, BORDER);
+    image.fill_rect(
+        1,
+        1,
+        OVERLAY_WIDTH - 2,
+        OVERLAY_HEIGHT - 2,
+        [
+            DEBUG_OVERLAY_BG_R,
+            DEBUG_OVERLAY_BG_G,
+            DEBUG_OVERLAY_BG_B,
+            DEBUG_OVERLAY_BG_A,
+        ],
+    );
+    let overflow = match mode {
+        overlay_mode::LOG_MODE => Overflow::Ellipsis,
+        _ => Overflow::Wrap,
+    };
+    let mut laid_out: Vec<_> = lines
+        .iter()
+        .flat_map(|line| text.layout(line, MAX_X - PADDING, overflow))
+        .collect();
+    let fitting = ((OVERLAY_HEIGHT as f32 - 2.0 * PADDING) / text.line_height()) as usize;
+    if overflow == Overflow::Ellipsis && laid_out.len() > fitting {
+        laid_out.drain(..laid_out.len() - fitting);
     }
-    x
-}*/
+    text.draw(&mut image, &laid_out, PADDING, PADDING);
+    image
+}
 
-fn clear_log_area(buf: *mut u8) {
-    for j in 0..OVERLAY_HEIGHT {
-        for i in 0..OVERLAY_WIDTH {
-            let idx = ((j * OVERLAY_WIDTH + i) * 4) as usize;
+//Replaces the image uploaded by the present hook.
+fn store_panel(image: Image) {
+    let mut panel = PANEL.lock().unwrap();
+    *panel = image.pixels;
+    PANEL_REVISION.fetch_add(1, Ordering::Release);
+}
 
-            //Border doesn't need to be cleared
-            if i == 0 || i == OVERLAY_WIDTH - 1 || j == 0 || j == OVERLAY_HEIGHT - 1 {
-            } else {
-                unsafe {
-                    *buf.add(idx + 0) = DEBUG_OVERLAY_BG_R;
-                    *buf.add(idx + 1) = DEBUG_OVERLAY_BG_G;
-                    *buf.add(idx + 2) = DEBUG_OVERLAY_BG_B;
-                    *buf.add(idx + 3) = DEBUG_OVERLAY_BG_A;
-                }
-            }
+//The font of the config, or the embedded one.
+fn panel_text() -> TextRenderer {
+    let font = &config().debug_overlay.font;
+    if font.is_empty() {
+        return TextRenderer::embedded(FONT_SIZE);
+    }
+    match TextRenderer::from_file(Path::new(font), FONT_SIZE) {
+        Ok(text) => text,
+        Err(e) => {
+            log::warn!(
+                "Could not load the debug overlay font {}, using the embedded one: {}",
+                font,
+                e
+            );
+            TextRenderer::embedded(FONT_SIZE)
         }
     }
 }
 
-//Draws a character. Very inefficient, but since it's only for debugging anyway we don't care
-/*unsafe fn draw_char(buf: *mut u8, x: f32, y: f32, ch: char) -> f32 {
-    let font = FONT.get_or_init(|| {
-        let font = Font::from_bytes(FONT_DATA, FontSettings::default()).unwrap();
-        font
-    });
-
-    let (metrics, bitmap) = font.rasterize(ch, FONT_SIZE);
-
-    let glyph_x = x as usize + metrics.xmin as usize;
-    let glyph_y = y as usize - metrics.height as usize - metrics.ymin as usize;
-
-    unsafe {
-        for row in 0..metrics.height {
-            for col in 0..metrics.width {
-                let src = bitmap[row * metrics.width + col];
-
-                if src == 0 {
-                    continue;
-                }
-                let dst_x = glyph_x + col;
-                let dst_y = glyph_y + row;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Rewritten with UPDATE_SNAPSHOTS=1 when the panel is meant to look different.
+    const PANEL_SNAPSHOT: &str = "src/debug/snapshots/panel.txt";
+    const BACKGROUND: Rgba = [
+        DEBUG_OVERLAY_BG_R,
+        DEBUG_OVERLAY_BG_G,
+        DEBUG_OVERLAY_BG_B,
+        DEBUG_OVERLAY_BG_A,
+    ];
+    const RED: Rgba = [255, 0, 0, 255];
+
+    //FNV-1a, of every byte of the image.
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
 
-                let dst_index = ((dst_y as usize) * OVERLAY_WIDTH + (dst_x as usize)) * 4;
-                *buf.add(dst_index) = src; // R
-                *buf.add(dst_index + 1) = src; // G
-                *buf.add(dst_index + 2) = src; // B
-                *buf.add(dst_index + 3) = 255; // A
-            }
+    //The size and hash of the image, then its top left corner as text: ' ' for the
+    //background, '#' for the border, and the ink by how much of it covers the pixel, with
+    //".:+@" when it's gray and ",;x%" when it's red.
+    fn snapshot(image: &Image, width: usize, height: usize) -> String {
+        let mut snapshot = format!(
+            "{}x{} {:016x}\n",
+            image.width,
+            image.height,
+            fnv1a(&image.pixels)
+        );
+        for y in 0..height {
+            let row: String = (0..width)
+                .map(|x| match image.pixel(x, y) {
+                    BACKGROUND => ' ',
+                    BORDER => '#',
+                    [r, g, _, _] => {
+                        let level = (r as usize * 4 / 256).min(3);
+                        if r > g.saturating_add(64) {
+                            b",;x%"[level] as char
+                        } else {
+                            b".:+@"[level] as char
+                        }
+                    }
+                })
+                .collect();
+            snapshot.push_str(row.trim_end());
+            snapshot.push('\n');
         }
+        snapshot
     }
-    x as f32 + metrics.advance_width
-}*/
-
-//Draws the debug overlay in the buffer into the pixels array (which will be copied to GPU)
-/*pub fn draw_debug_overlay(pixels: &mut [u8], width: u32) {
-    let overlay = get_overlay();
 
-    let frame_row_bytes = (width * 4) as usize;
-    let overlay_row_bytes = (OVERLAY_WIDTH * 4) as usize;
-
-    unsafe {
-        let dst_ptr = pixels.as_mut_ptr();
-        let src_ptr = overlay.as_ptr();
-
-        for j in 0..OVERLAY_HEIGHT {
-            let dst_row_ptr = dst_ptr.add(j * frame_row_bytes + 4);
-            let src_row_ptr = src_ptr.add(j * overlay_row_bytes);
-            std::ptr::copy_nonoverlapping(src_row_ptr, dst_row_ptr, OVERLAY_WIDTH * 4);
+    #[test]
+    fn panel_matches_its_snapshot() {
+        let lines = [
+            vec![
+                Span::new("frame_time_total: ", LABEL_COLOR),
+                Span::new("16", TEXT_COLOR),
+            ],
+            vec![Span::new("ERROR", RED), Span::new(" caf\u{e9}", TEXT_COLOR)],
+        ];
+        let mut text = TextRenderer::embedded(FONT_SIZE);
+        let image = render_panel(&mut text, overlay_mode::STAT_MODE, &lines);
+        let actual = snapshot(&image, 130, 2 * text.line_height() as usize + 6);
+
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            std::fs::write(PANEL_SNAPSHOT, &actual).unwrap();
         }
+        let expected = std::fs::read_to_string(PANEL_SNAPSHOT).unwrap();
+        assert!(
+            actual == expected,
+            "The panel changed, rerun with UPDATE_SNAPSHOTS=1 if it should have:\n{}",
+            actual
+        );
     }
-}*/
-
-//When overlay is toggled off, these pixels have to be cleared.
-/*pub fn clear_debug_overlay(pixels: &mut [u8], width: u32) {
-    let frame_row_bytes = (width * 4) as usize;
-
-    unsafe {
-        let dst_ptr = pixels.as_mut_ptr();
 
-        for j in 0..OVERLAY_HEIGHT {
-            let dst_row_ptr = dst_ptr.add(j * frame_row_bytes + 4);
-            for i in 0..OVERLAY_WIDTH {
-                *dst_row_ptr.add(i * 4 + 3) = 0;
-            }
-        }
+    #[test]
+    fn log_panel_keeps_the_newest_lines() {
+        let mut text = TextRenderer::embedded(FONT_SIZE);
+        let lines: Vec<_> = (0..40)
+            .map(|i| vec![Span::new(format!("line {}", i), TEXT_COLOR)])
+            .collect();
+        let fitting = ((OVERLAY_HEIGHT as f32 - 2.0 * PADDING) / text.line_height()) as usize;
+        let log = render_panel(&mut text, overlay_mode::LOG_MODE, &lines);
+        let newest = render_panel(
+            &mut text,
+            overlay_mode::LOG_MODE,
+            &lines[lines.len() - fitting..],
+        );
+        assert_eq!(log, newest);
     }
-}*/
+}
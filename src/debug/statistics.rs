@@ -1,83 +1,70 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
+    io,
     sync::{
-        OnceLock,
-        atomic::Ordering,
+        Mutex, TryLockError,
         mpsc::{Sender, channel},
     },
-    time::{Duration, Instant},
 };
 
-use super::{
-    DEBUG_FEATURES,
-    debug_overlay::{OVERLAY_MODE, overlay_mode},
-};
+use crate::shutdown::spawn_worker;
 
-//Sender
-static STATISTIC_SENDER: OnceLock<Sender<(u32, u32)>> = OnceLock::new();
+//Sender. Taken on shutdown, which ends the thread.
+static STATISTIC_SENDER: Mutex<Option<Sender<(u32, u32)>>> = Mutex::new(None);
+//Latest value of every statistic received, kept after the thread stops.
+static STATISTICS: Mutex<BTreeMap<u32, u32>> = Mutex::new(BTreeMap::new());
 
 //Stores the stats that will be rendered on the overlay
 pub mod debug_stat {
     pub const FRAME_TIME_CUSTOM: u32 = 0;
     pub const FRAME_TIME_TOTAL: u32 = 1;
     pub const FRAME_TIME_DIFF: u32 = 2;
+    //Microseconds between an input event and the producer's ack.
+    pub const INPUT_ROUND_TRIP: u32 = 3;
+
+    pub fn name(stat: u32) -> Option<&'static str> {
+        match stat {
+            FRAME_TIME_CUSTOM => Some("frame_time_custom"),
+            FRAME_TIME_TOTAL => Some("frame_time_total"),
+            FRAME_TIME_DIFF => Some("frame_time_diff"),
+            INPUT_ROUND_TRIP => Some("input_round_trip"),
+            _ => None,
+        }
+    }
 }
 
 //Small thread that listens to and counts certain statistics for debugging purposes.
-//They are displayed on statistic mode of the debug overlay.
-pub fn start_statistics_server() {
+//They are displayed on statistic mode of the debug overlay, which reads them every so often.
+pub fn start_statistics_server() -> io::Result<()> {
     let (tx, rx) = channel::<(u32, u32)>();
-    STATISTIC_SENDER.set(tx).ok();
-
-    std::thread::spawn(move || {
-        let mut stats = get_stats_map();
-
-        let mut last_refresh = Instant::now();
-        let refresh_interval = Duration::from_millis(500);
+    *STATISTIC_SENDER.lock().unwrap() = Some(tx);
 
+    spawn_worker("statistics", move || {
         while let Ok(msg) = rx.recv() {
-            match msg.0 {
-                _ => {
-                    stats.insert(msg.0, msg.1);
-                    /*log::debug!(
-                        "Custom time: {}",
-                        stats.get(&debug_stat::FRAME_TIME_CUSTOM).unwrap()
-                    );
-                    log::debug!(
-                        "Total time: {}",
-                        stats.get(&debug_stat::FRAME_TIME_TOTAL).unwrap()
-                    );
-                    log::debug!(
-                        "Diff time: {}",
-                        stats.get(&debug_stat::FRAME_TIME_DIFF).unwrap()
-                    );*/
-                }
-            }
-            if last_refresh.elapsed() >= refresh_interval
-                && DEBUG_FEATURES.debug_overlay_enabled.load(Ordering::Relaxed)
-                && OVERLAY_MODE.load(Ordering::Relaxed) == overlay_mode::STAT_MODE
-            {
-                //refresh_overlay_buffer(Some(&stats));
-                last_refresh = Instant::now();
-            }
+            STATISTICS.lock().unwrap().insert(msg.0, msg.1);
         }
-    });
+    })
 }
 
-//Just to make sure the stats are indeed valid.
-fn get_stats_map() -> HashMap<u32, u32> {
-    let mut stats = HashMap::with_capacity(100);
-    for i in 0..100 {
-        stats.insert(i, 0);
-    }
-    return stats;
+///Latest value of every statistic, by id. None if the map is locked, which only happens
+///when called while a value is being stored.
+pub fn statistics_snapshot() -> Option<Vec<(u32, u32)>> {
+    let stats = match STATISTICS.try_lock() {
+        Ok(stats) => stats,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return None,
+    };
+    Some(stats.iter().map(|(stat, value)| (*stat, *value)).collect())
 }
 
-//Sends a simple statistic to the listener.
+///Ends the thread once it handled what's queued.
+pub fn stop_statistics_server() {
+    STATISTIC_SENDER.lock().unwrap().take();
+}
+
+//Sends a simple statistic to the listener. Dropped if it isn't running.
 pub fn send_statistic(key: u32, value: u32) {
-    STATISTIC_SENDER
-        .get()
-        .expect("Could not get the statistic sender")
-        .send((key, value))
-        .ok();
+    if let Some(sender) = STATISTIC_SENDER.lock().unwrap().as_ref() {
+        sender.send((key, value)).ok();
+    }
 }
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use fontdue::{Font, LineMetrics};
+
+/*
+ *
+ * Glyph atlas. Plain Rust, no windows.
+ *
+ * Every character is rasterized once, the first time it is drawn, and its coverage copied into
+ * a single 8 bit bitmap. Glyphs are packed in rows (shelves) left to right; when the atlas is
+ * full it doubles in height, glyphs already in it keep their place.
+ *
+ * Characters the font doesn't have are drawn as REPLACEMENT, or '?' if it lacks that too.
+ *
+ * */
+
+const ATLAS_WIDTH: usize = 256;
+const INITIAL_HEIGHT: usize = 64;
+//Between glyphs, so sampling one never bleeds into its neighbours.
+const PADDING: usize = 1;
+pub const REPLACEMENT: char = '\u{FFFD}';
+
+//A glyph in the atlas, at the size of the atlas.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Glyph {
+    //Top left corner of the coverage in the atlas.
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    //Offset of the bitmap's bottom left corner from the pen position, y going up.
+    pub xmin: i32,
+    pub ymin: i32,
+    pub advance: f32,
+}
+
+pub struct GlyphAtlas {
+    font: Font,
+    size: f32,
+    line: LineMetrics,
+    coverage: Vec<u8>,
+    height: usize,
+    glyphs: HashMap<char, Glyph>,
+    //Where the next glyph goes, and the height of the current row.
+    cursor_x: usize,
+    cursor_y: usize,
+    row_height: usize,
+}
+
+impl GlyphAtlas {
+    pub fn new(font: Font, size: f32) -> GlyphAtlas {
+        //Every font fontdue can read has them, this is only for fonts without horizontal metrics.
+        let line = font.horizontal_line_metrics(size).unwrap_or(LineMetrics {
+            ascent: size * 0.8,
+            descent: -size * 0.2,
+            line_gap: 0.0,
+            new_line_size: size,
+        });
+        GlyphAtlas {
+            font,
+            size,
+            line,
+            coverage: vec![0; ATLAS_WIDTH * INITIAL_HEIGHT],
+            height: INITIAL_HEIGHT,
+            glyphs: HashMap::new(),
+            cursor_x: 0,
+            cursor_y: 0,
+            row_height: 0,
+        }
+    }
+
+    pub fn size(&self) -> f32 {
+        self.size
+    }
+
+    ///Distance from the top of a line to its baseline.
+    pub fn ascent(&self) -> f32 {
+        self.line.ascent
+    }
+
+    ///Distance between the baselines of two lines.
+    pub fn line_height(&self) -> f32 {
+        self.line.new_line_size.ceil()
+    }
+
+    pub fn has_glyph(&self, c: char) -> bool {
+        self.font.lookup_glyph_index(c) != 0
+    }
+
+    ///The character actually drawn for c.
+    pub fn drawn_char(&self, c: char) -> char {
+        if self.has_glyph(c) {
+            c
+        } else if self.has_glyph(REPLACEMENT) {
+            REPLACEMENT
+        } else {
+            '?'
+        }
+    }
+
+    pub fn advance(&mut self, c: char) -> f32 {
+        self.glyph(c).advance
+    }
+
+    ///The glyph of c, rasterized into the atlas the first time.
+    pub fn glyph(&mut self, c: char) -> Glyph {
+        let c = self.drawn_char(c);
+        if let Some(glyph) = self.glyphs.get(&c) {
+            return *glyph;
+        }
+        let (metrics, bitmap) = self.font.rasterize(c, self.size);
+        //Nothing is wider than the atlas at the sizes we draw, wider glyphs are cut.
+        let width = metrics.width.min(ATLAS_WIDTH);
+        let (x, y) = self.allocate(width, metrics.height);
+        for row in 0..metrics.height {
+            let src = &bitmap[row * metrics.width..row * metrics.width + width];
+            let dst = (y + row) * ATLAS_WIDTH + x;
+            self.coverage[dst..dst + width].copy_from_slice(src);
+        }
+        let glyph = Glyph {
+            x,
+            y,
+            width,
+            height: metrics.height,
+            xmin: metrics.xmin,
+            ymin: metrics.ymin,
+            advance: metrics.advance_width,
+        };
+        self.glyphs.insert(c, glyph);
+        glyph
+    }
+
+    ///Coverage of a pixel of a glyph, 0 to 255.
+    pub fn coverage(&self, glyph: &Glyph, col: usize, row: usize) -> u8 {
+        self.coverage[(glyph.y + row) * ATLAS_WIDTH + glyph.x + col]
+    }
+
+    ///Glyphs rasterized so far.
+    pub fn len(&self) -> usize {
+        self.glyphs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.glyphs.is_empty()
+    }
+
+    //Room for a width x height bitmap, width being at most ATLAS_WIDTH.
+    fn allocate(&mut self, width: usize, height: usize) -> (usize, usize) {
+        if self.cursor_x + width > ATLAS_WIDTH {
+            self.cursor_x = 0;
+            self.cursor_y += self.row_height + PADDING;
+            self.row_height = 0;
+        }
+        while self.cursor_y + height > self.height {
+            self.height *= 2;
+            self.coverage.resize(ATLAS_WIDTH * self.height, 0);
+        }
+        let position = (self.cursor_x, self.cursor_y);
+        self.cursor_x += width + PADDING;
+        self.row_height = self.row_height.max(height);
+        position
+    }
+}
@@ -0,0 +1,291 @@
+use super::Rgba;
+
+/*
+ *
+ * Text layout. Plain Rust, no windows, and no font either: widths come from the advance
+ * function, so it lays out the same for any font.
+ *
+ * Text is a list of colored spans, laid out as one flow of characters:
+ *  - '\n' starts a new line, '\t' is a space, other control characters are skipped,
+ *  - Overflow::Wrap breaks lines at the last space that fits, or anywhere in words longer
+ *    than a line. Spaces around a break are dropped,
+ *  - Overflow::Ellipsis keeps each line on one line, cutting what doesn't fit and ending it
+ *    with ELLIPSIS.
+ *
+ * Everything works on chars, a multi-byte character is never split.
+ *
+ * */
+
+pub const ELLIPSIS: char = '\u{2026}';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub color: Rgba,
+}
+
+impl Span {
+    pub fn new(text: impl Into<String>, color: Rgba) -> Span {
+        Span {
+            text: text.into(),
+            color,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Ellipsis,
+}
+
+//A character and where its pen position is on the line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlacedChar {
+    pub c: char,
+    pub x: f32,
+    pub color: Rgba,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Line {
+    pub chars: Vec<PlacedChar>,
+    pub width: f32,
+}
+
+impl Line {
+    pub fn text(&self) -> String {
+        self.chars.iter().map(|placed| placed.c).collect()
+    }
+}
+
+//A character not placed yet, with its advance.
+type Pending = (char, Rgba, f32);
+
+///Lays the spans out in lines no wider than max_width, except for single characters wider
+///than that.
+pub fn layout(
+    spans: &[Span],
+    max_width: f32,
+    overflow: Overflow,
+    mut advance: impl FnMut(char) -> f32,
+) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut pending: Vec<Pending> = Vec::new();
+    //Set once the rest of the line is cut by the ellipsis.
+    let mut cut = false;
+    //The current line comes from a wrap, its leading spaces are dropped.
+    let mut wrapped = false;
+
+    for span in spans {
+        for c in span.text.chars() {
+            let c = match c {
+                '\n' => {
+                    lines.push(place(&pending));
+                    pending.clear();
+                    (cut, wrapped) = (false, false);
+                    continue;
+                }
+                '\t' => ' ',
+                c if c.is_control() => continue,
+                c => c,
+            };
+            if cut || (wrapped && c == ' ' && pending.is_empty()) {
+                continue;
+            }
+            let width = advance(c);
+            loop {
+                if pending.is_empty() || line_width(&pending) + width <= max_width {
+                    pending.push((c, span.color, width));
+                    break;
+                }
+                match overflow {
+                    //Breaks at the space itself, dropped like the ones before it.
+                    Overflow::Wrap if c == ' ' => {
+                        trim_end(&mut pending);
+                        lines.push(place(&pending));
+                        pending.clear();
+                        wrapped = true;
+                        break;
+                    }
+                    Overflow::Wrap => {
+                        let rest = match pending.iter().rposition(|(p, _, _)| *p == ' ') {
+                            //The space it breaks at is dropped with the others before it.
+                            Some(space) => pending.split_off(space + 1),
+                            //A word longer than the line, broken where it is.
+                            None => Vec::new(),
+                        };
+                        trim_end(&mut pending);
+                        lines.push(place(&pending));
+                        pending = rest;
+                        wrapped = true;
+                        //The new line may still be too long with c, checked again.
+                    }
+                    Overflow::Ellipsis => {
+                        let ellipsis = advance(ELLIPSIS);
+                        let fits =
+                            |pending: &[Pending]| line_width(pending) + ellipsis <= max_width;
+                        while !pending.is_empty() && !fits(&pending) {
+                            pending.pop();
+                        }
+                        trim_end(&mut pending);
+                        pending.push((ELLIPSIS, span.color, ellipsis));
+                        cut = true;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    lines.push(place(&pending));
+    lines
+}
+
+fn line_width(pending: &[Pending]) -> f32 {
+    pending.iter().map(|(_, _, width)| width).sum()
+}
+
+fn trim_end(pending: &mut Vec<Pending>) {
+    while pending.last().is_some_and(|(c, _, _)| *c == ' ') {
+        pending.pop();
+    }
+}
+
+fn place(pending: &[Pending]) -> Line {
+    let mut x = 0.0;
+    let chars = pending
+        .iter()
+        .map(|&(c, color, width)| {
+            let placed = PlacedChar { c, x, color };
+            x += width;
+            placed
+        })
+        .collect();
+    Line { chars, width: x }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgba = [255, 255, 255, 255];
+    const RED: Rgba = [255, 0, 0, 255];
+    const GREEN: Rgba = [0, 255, 0, 255];
+
+    //Like a monospace font with double width characters: ASCII is 1 wide, the rest 2.
+    fn advance(c: char) -> f32 {
+        if c.is_ascii() { 1.0 } else { 2.0 }
+    }
+
+    fn lay(text: &str, max_width: f32, overflow: Overflow) -> Vec<Line> {
+        layout(&[Span::new(text, WHITE)], max_width, overflow, advance)
+    }
+
+    //One line per line laid out, with its width.
+    fn snapshot(lines: &[Line]) -> String {
+        lines
+            .iter()
+            .map(|line| format!("{}|{}", line.text(), line.width))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    //The color of every character, as a letter.
+    fn colors(line: &Line) -> String {
+        line.chars
+            .iter()
+            .map(|placed| match placed.color {
+                WHITE => 'w',
+                RED => 'r',
+                GREEN => 'g',
+                _ => '?',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wraps_at_spaces() {
+        let lines = lay("the quick brown fox jumps over", 10.0, Overflow::Wrap);
+        assert_eq!(
+            snapshot(&lines),
+            "the quick|9\n\
+             brown fox|9\n\
+             jumps over|10"
+        );
+        //Spaces around a break are dropped, the others kept.
+        let lines = lay("a  b   c    d", 4.0, Overflow::Wrap);
+        assert_eq!(snapshot(&lines), "a  b|4\nc|1\nd|1");
+    }
+
+    #[test]
+    fn breaks_words_longer_than_a_line() {
+        let lines = lay("abcdefghijkl mn", 5.0, Overflow::Wrap);
+        assert_eq!(snapshot(&lines), "abcde|5\nfghij|5\nkl mn|5");
+        let lines = lay("ab abcdefgh", 4.0, Overflow::Wrap);
+        assert_eq!(snapshot(&lines), "ab|2\nabcd|4\nefgh|4");
+    }
+
+    #[test]
+    fn newlines_tabs_and_control_characters() {
+        let lines = lay("a\tb\x07c\n\nd\r", 10.0, Overflow::Wrap);
+        assert_eq!(snapshot(&lines), "a bc|4\n|0\nd|1");
+        assert_eq!(snapshot(&lay("", 10.0, Overflow::Wrap)), "|0");
+    }
+
+    #[test]
+    fn clips_with_an_ellipsis() {
+        let lines = lay("hello world\nshort\nexactly8", 8.0, Overflow::Ellipsis);
+        assert_eq!(snapshot(&lines), "hello\u{2026}|7\nshort|5\nexactly8|8");
+        //The ellipsis never follows a space.
+        let lines = lay("ab    cdefgh", 6.0, Overflow::Ellipsis);
+        assert_eq!(snapshot(&lines), "ab\u{2026}|4");
+        //Nothing fits but the ellipsis.
+        let lines = lay("abc", 1.0, Overflow::Ellipsis);
+        assert_eq!(snapshot(&lines), "\u{2026}|2");
+    }
+
+    #[test]
+    fn never_splits_multi_byte_characters() {
+        let lines = lay("h\u{e9}llo w\u{f6}rld", 6.0, Overflow::Wrap);
+        assert_eq!(snapshot(&lines), "h\u{e9}llo|6\nw\u{f6}rld|6");
+        let lines = lay("日本語テキスト", 5.0, Overflow::Wrap);
+        assert_eq!(snapshot(&lines), "日本|4\n語テ|4\nキス|4\nト|2");
+        let lines = lay("日本語テキスト", 7.0, Overflow::Ellipsis);
+        assert_eq!(snapshot(&lines), "日本\u{2026}|6");
+        //Wider than the line on its own, it gets a line anyway.
+        let lines = lay("日a", 1.0, Overflow::Wrap);
+        assert_eq!(snapshot(&lines), "日|2\na|1");
+    }
+
+    #[test]
+    fn keeps_the_colors_of_spans() {
+        let spans = [
+            Span::new("red ", RED),
+            Span::new("green", GREEN),
+            Span::new(" white", WHITE),
+        ];
+        let lines = layout(&spans, 7.0, Overflow::Wrap, advance);
+        let snapshot: Vec<_> = lines.iter().map(|l| (l.text(), colors(l))).collect();
+        assert_eq!(
+            snapshot,
+            [
+                ("red".to_string(), "rrr".to_string()),
+                ("green".to_string(), "ggggg".to_string()),
+                ("white".to_string(), "wwwww".to_string()),
+            ]
+        );
+
+        //The ellipsis takes the color of the span that overflowed.
+        let lines = layout(&spans, 8.0, Overflow::Ellipsis, advance);
+        assert_eq!(lines[0].text(), "red gr\u{2026}");
+        assert_eq!(colors(&lines[0]), "rrrrggg");
+    }
+
+    #[test]
+    fn places_characters_at_their_pen_position() {
+        let lines = lay("a日b", 10.0, Overflow::Wrap);
+        let xs: Vec<_> = lines[0].chars.iter().map(|placed| placed.x).collect();
+        assert_eq!(xs, [0.0, 1.0, 3.0]);
+        assert_eq!(lines[0].width, 4.0);
+    }
+}
@@ -0,0 +1,147 @@
+use std::{fs, path::Path};
+
+use fontdue::{Font, FontSettings};
+
+use atlas::GlyphAtlas;
+use layout::{Line, Overflow, Span, layout};
+
+pub mod atlas;
+pub mod layout;
+
+/*
+ *
+ * Text drawing of the debug panel. Plain Rust, no windows: it draws into an RGBA Image that
+ * the present hook only uploads, so the same pixels come out on any platform.
+ *
+ * The font is embedded, wine prefixes don't always have the usual fonts. It is DejaVu Sans
+ * Mono, see DejaVuSansMono.LICENSE next to it.
+ *
+ * */
+
+static FONT_DATA: &[u8] = include_bytes!("DejaVuSansMono.ttf");
+
+//Straight (not premultiplied) alpha, like the textures of the overlay.
+pub type Rgba = [u8; 4];
+
+//RGBA pixels, row after row without padding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, color: Rgba) -> Image {
+        Image {
+            width,
+            height,
+            pixels: color.repeat(width * height),
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Rgba {
+        let i = (y * self.width + x) * 4;
+        [
+            self.pixels[i],
+            self.pixels[i + 1],
+            self.pixels[i + 2],
+            self.pixels[i + 3],
+        ]
+    }
+
+    ///Draws color over the pixel with the given coverage. Pixels outside are ignored.
+    pub fn blend(&mut self, x: i64, y: i64, color: Rgba, coverage: u8) {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return;
+        }
+        let alpha = color[3] as u32 * coverage as u32 / 255;
+        if alpha == 0 {
+            return;
+        }
+        let i = (y as usize * self.width + x as usize) * 4;
+        let dst = &mut self.pixels[i..i + 4];
+        let dst_alpha = dst[3] as u32 * (255 - alpha) / 255;
+        let out_alpha = alpha + dst_alpha;
+        for channel in 0..3 {
+            dst[channel] = ((color[channel] as u32 * alpha + dst[channel] as u32 * dst_alpha)
+                / out_alpha) as u8;
+        }
+        dst[3] = out_alpha as u8;
+    }
+
+    ///Replaces the pixels of a rectangle, clipped to the image.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgba) {
+        for row in y..(y + height).min(self.height) {
+            for col in x..(x + width).min(self.width) {
+                let i = (row * self.width + col) * 4;
+                self.pixels[i..i + 4].copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+pub struct TextRenderer {
+    atlas: GlyphAtlas,
+}
+
+impl TextRenderer {
+    ///With the embedded font.
+    pub fn embedded(size: f32) -> TextRenderer {
+        let font = Font::from_bytes(FONT_DATA, FontSettings::default())
+            .expect("the embedded font is valid");
+        TextRenderer {
+            atlas: GlyphAtlas::new(font, size),
+        }
+    }
+
+    ///With a TrueType or OpenType font file.
+    pub fn from_file(path: &Path, size: f32) -> Result<TextRenderer, String> {
+        let data = fs::read(path).map_err(|e| e.to_string())?;
+        let font = Font::from_bytes(data, FontSettings::default()).map_err(|e| e.to_string())?;
+        Ok(TextRenderer {
+            atlas: GlyphAtlas::new(font, size),
+        })
+    }
+
+    pub fn line_height(&self) -> f32 {
+        self.atlas.line_height()
+    }
+
+    pub fn layout(&mut self, spans: &[Span], max_width: f32, overflow: Overflow) -> Vec<Line> {
+        layout(spans, max_width, overflow, |c| self.atlas.advance(c))
+    }
+
+    ///Draws lines laid out by layout(), the first one with its top at y. Lines below the
+    ///image are skipped. Returns the top of the line after the last one.
+    pub fn draw(&mut self, image: &mut Image, lines: &[Line], x: f32, y: f32) -> f32 {
+        let mut top = y;
+        for line in lines {
+            if top >= image.height as f32 {
+                break;
+            }
+            let baseline = (top + self.atlas.ascent()).round() as i64;
+            for placed in &line.chars {
+                let glyph = self.atlas.glyph(placed.c);
+                //Glyphs start on whole pixels, they are rasterized for that.
+                let left = (x + placed.x).round() as i64 + glyph.xmin as i64;
+                let glyph_top = baseline - glyph.height as i64 - glyph.ymin as i64;
+                for row in 0..glyph.height {
+                    for col in 0..glyph.width {
+                        let coverage = self.atlas.coverage(&glyph, col, row);
+                        if coverage != 0 {
+                            image.blend(
+                                left + col as i64,
+                                glyph_top + row as i64,
+                                placed.color,
+                                coverage,
+                            );
+                        }
+                    }
+                }
+            }
+            top += self.line_height();
+        }
+        top
+    }
+}
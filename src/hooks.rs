@@ -1,4 +1,8 @@
 use windows::{Win32::Graphics::Dxgi::IDXGISwapChain, core::HRESULT};
+
+///Signature of IDXGISwapChain::Present, as found in the vtable.
+pub type PresentFn = unsafe extern "system" fn(IDXGISwapChain, u32, u32) -> HRESULT;
+
 retour::static_detour! {
     pub static present_hook: unsafe extern "system" fn(IDXGISwapChain, u32, u32) -> HRESULT;
 }
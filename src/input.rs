@@ -0,0 +1,568 @@
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/*
+ *
+ * Input protocol spoken over UDP with the producer. Plain Rust, no windows.
+ *
+ * Every datagram is one packet (little endian):
+ *
+ *   0  magic        u16   INPUT_MAGIC
+ *   2  version      u8    INPUT_VERSION
+ *   3  kind         u8    See event_kind
+ *   4  seq          u32   Incremented for every packet sent, per direction
+ *   8  timestamp    u64   Microseconds since the UNIX epoch, when the event happened
+ *  16  payload_len  u16
+ *  18  reserved     u16
+ *  20  payload
+ *
+ * Payloads only ever grow: a decoder ignores bytes past the ones it knows about.
+ * The producer answers with Ack packets, echoing the timestamp so we can measure the round trip.
+ *
+ * */
+
+pub const INPUT_MAGIC: u16 = u16::from_le_bytes(*b"BI");
+pub const INPUT_VERSION: u8 = 1;
+pub const PACKET_HEADER_LEN: usize = 20;
+//Biggest packet we can receive. Way more than what any event needs.
+pub const MAX_PACKET_LEN: usize = 512;
+
+pub mod event_kind {
+    pub const MOUSE_MOVE: u8 = 1;
+    pub const MOUSE_BUTTON: u8 = 2;
+    pub const MOUSE_WHEEL: u8 = 3;
+    pub const KEY: u8 = 4;
+    pub const CHAR: u8 = 5;
+    pub const FOCUS: u8 = 6;
+    pub const RESIZE: u8 = 7;
+    pub const ACK: u8 = 8;
+    pub const CAPTURE_RELEASED: u8 = 9;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    X1,
+    X2,
+}
+
+impl MouseButton {
+    fn to_u8(self) -> u8 {
+        match self {
+            MouseButton::Left => 0,
+            MouseButton::Right => 1,
+            MouseButton::Middle => 2,
+            MouseButton::X1 => 3,
+            MouseButton::X2 => 4,
+        }
+    }
+    fn from_u8(v: u8) -> Option<MouseButton> {
+        match v {
+            0 => Some(MouseButton::Left),
+            1 => Some(MouseButton::Right),
+            2 => Some(MouseButton::Middle),
+            3 => Some(MouseButton::X1),
+            4 => Some(MouseButton::X2),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    //Coordinates are in the client area of the game's window.
+    MouseMove {
+        x: i32,
+        y: i32,
+    },
+    MouseButton {
+        button: MouseButton,
+        pressed: bool,
+        x: i32,
+        y: i32,
+    },
+    //delta is in the same unit as windows, 120 per notch.
+    MouseWheel {
+        delta: i16,
+        horizontal: bool,
+        x: i32,
+        y: i32,
+    },
+    Key {
+        vk: u32,
+        scan: u32,
+        pressed: bool,
+        repeat: bool,
+    },
+    //A character produced by the keyboard, as a unicode scalar value.
+    Char {
+        ch: u32,
+    },
+    Focus {
+        focused: bool,
+    },
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    //Sent by the producer when it received a packet.
+    Ack {
+        seq: u32,
+        timestamp: u64,
+    },
+    //The user left keyboard capture with the escape hatch, the producer should drop its focus.
+    CaptureReleased,
+}
+
+impl InputEvent {
+    pub fn kind(&self) -> u8 {
+        match self {
+            InputEvent::MouseMove { .. } => event_kind::MOUSE_MOVE,
+            InputEvent::MouseButton { .. } => event_kind::MOUSE_BUTTON,
+            InputEvent::MouseWheel { .. } => event_kind::MOUSE_WHEEL,
+            InputEvent::Key { .. } => event_kind::KEY,
+            InputEvent::Char { .. } => event_kind::CHAR,
+            InputEvent::Focus { .. } => event_kind::FOCUS,
+            InputEvent::Resize { .. } => event_kind::RESIZE,
+            InputEvent::Ack { .. } => event_kind::ACK,
+            InputEvent::CaptureReleased => event_kind::CAPTURE_RELEASED,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputPacket {
+    pub seq: u32,
+    pub timestamp: u64,
+    pub event: InputEvent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputError {
+    TooShort(usize),
+    BadMagic(u16),
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    BadPayload(u8),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InputError::TooShort(len) => write!(f, "packet too short ({} bytes)", len),
+            InputError::BadMagic(magic) => write!(f, "bad magic 0x{:04x}", magic),
+            InputError::UnsupportedVersion(v) => write!(f, "unsupported input version {}", v),
+            InputError::UnknownKind(k) => write!(f, "unknown event kind {}", k),
+            InputError::BadPayload(k) => write!(f, "malformed payload for event kind {}", k),
+        }
+    }
+}
+
+impl InputPacket {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(16);
+        match self.event {
+            InputEvent::MouseMove { x, y } => {
+                payload.extend_from_slice(&x.to_le_bytes());
+                payload.extend_from_slice(&y.to_le_bytes());
+            }
+            InputEvent::MouseButton {
+                button,
+                pressed,
+                x,
+                y,
+            } => {
+                payload.extend_from_slice(&[button.to_u8(), pressed as u8, 0, 0]);
+                payload.extend_from_slice(&x.to_le_bytes());
+                payload.extend_from_slice(&y.to_le_bytes());
+            }
+            InputEvent::MouseWheel {
+                delta,
+                horizontal,
+                x,
+                y,
+            } => {
+                payload.extend_from_slice(&delta.to_le_bytes());
+                payload.extend_from_slice(&[horizontal as u8, 0]);
+                payload.extend_from_slice(&x.to_le_bytes());
+                payload.extend_from_slice(&y.to_le_bytes());
+            }
+            InputEvent::Key {
+                vk,
+                scan,
+                pressed,
+                repeat,
+            } => {
+                payload.extend_from_slice(&vk.to_le_bytes());
+                payload.extend_from_slice(&scan.to_le_bytes());
+                payload.extend_from_slice(&[pressed as u8, repeat as u8, 0, 0]);
+            }
+            InputEvent::Char { ch } => {
+                payload.extend_from_slice(&ch.to_le_bytes());
+            }
+            InputEvent::Focus { focused } => {
+                payload.extend_from_slice(&[focused as u8, 0, 0, 0]);
+            }
+            InputEvent::Resize { width, height } => {
+                payload.extend_from_slice(&width.to_le_bytes());
+                payload.extend_from_slice(&height.to_le_bytes());
+            }
+            InputEvent::Ack { seq, timestamp } => {
+                payload.extend_from_slice(&seq.to_le_bytes());
+                payload.extend_from_slice(&0u32.to_le_bytes());
+                payload.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            InputEvent::CaptureReleased => {}
+        }
+
+        let mut out = Vec::with_capacity(PACKET_HEADER_LEN + payload.len());
+        out.extend_from_slice(&INPUT_MAGIC.to_le_bytes());
+        out.push(INPUT_VERSION);
+        out.push(self.event.kind());
+        out.extend_from_slice(&self.seq.to_le_bytes());
+        out.extend_from_slice(&self.timestamp.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(&payload);
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<InputPacket, InputError> {
+        if data.len() < PACKET_HEADER_LEN {
+            return Err(InputError::TooShort(data.len()));
+        }
+        let magic = u16::from_le_bytes([data[0], data[1]]);
+        if magic != INPUT_MAGIC {
+            return Err(InputError::BadMagic(magic));
+        }
+        //Newer versions only append, so anything from version 1 on can be read.
+        let version = data[2];
+        if version == 0 {
+            return Err(InputError::UnsupportedVersion(version));
+        }
+        let kind = data[3];
+        let seq = u32_at(data, 4);
+        let timestamp = u64_at(data, 8);
+        let payload_len = u16::from_le_bytes([data[16], data[17]]) as usize;
+        if data.len() < PACKET_HEADER_LEN + payload_len {
+            return Err(InputError::TooShort(data.len()));
+        }
+        let p = &data[PACKET_HEADER_LEN..PACKET_HEADER_LEN + payload_len];
+
+        let need = |len: usize| {
+            if p.len() < len {
+                Err(InputError::BadPayload(kind))
+            } else {
+                Ok(())
+            }
+        };
+        let event = match kind {
+            event_kind::MOUSE_MOVE => {
+                need(8)?;
+                InputEvent::MouseMove {
+                    x: u32_at(p, 0) as i32,
+                    y: u32_at(p, 4) as i32,
+                }
+            }
+            event_kind::MOUSE_BUTTON => {
+                need(12)?;
+                InputEvent::MouseButton {
+                    button: MouseButton::from_u8(p[0]).ok_or(InputError::BadPayload(kind))?,
+                    pressed: p[1] != 0,
+                    x: u32_at(p, 4) as i32,
+                    y: u32_at(p, 8) as i32,
+                }
+            }
+            event_kind::MOUSE_WHEEL => {
+                need(12)?;
+                InputEvent::MouseWheel {
+                    delta: i16::from_le_bytes([p[0], p[1]]),
+                    horizontal: p[2] != 0,
+                    x: u32_at(p, 4) as i32,
+                    y: u32_at(p, 8) as i32,
+                }
+            }
+            event_kind::KEY => {
+                need(12)?;
+                InputEvent::Key {
+                    vk: u32_at(p, 0),
+                    scan: u32_at(p, 4),
+                    pressed: p[8] != 0,
+                    repeat: p[9] != 0,
+                }
+            }
+            event_kind::CHAR => {
+                need(4)?;
+                InputEvent::Char { ch: u32_at(p, 0) }
+            }
+            event_kind::FOCUS => {
+                need(4)?;
+                InputEvent::Focus { focused: p[0] != 0 }
+            }
+            event_kind::RESIZE => {
+                need(8)?;
+                InputEvent::Resize {
+                    width: u32_at(p, 0),
+                    height: u32_at(p, 4),
+                }
+            }
+            event_kind::ACK => {
+                need(16)?;
+                InputEvent::Ack {
+                    seq: u32_at(p, 0),
+                    timestamp: u64_at(p, 8),
+                }
+            }
+            event_kind::CAPTURE_RELEASED => InputEvent::CaptureReleased,
+            _ => return Err(InputError::UnknownKind(kind)),
+        };
+        Ok(InputPacket {
+            seq,
+            timestamp,
+            event,
+        })
+    }
+}
+
+//Stamps outgoing events with a sequence number.
+#[derive(Debug, Default)]
+pub struct Sequencer {
+    next: u32,
+}
+
+impl Sequencer {
+    pub fn stamp(&mut self, event: InputEvent, timestamp: u64) -> InputPacket {
+        let packet = InputPacket {
+            seq: self.next,
+            timestamp,
+            event,
+        };
+        self.next = self.next.wrapping_add(1);
+        packet
+    }
+}
+
+///Timestamp used in packets.
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr, UdpSocket},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::platform::{DatagramSocket, Platform, mock::MockPlatform};
+
+    fn every_event() -> Vec<InputEvent> {
+        vec![
+            InputEvent::MouseMove { x: -5, y: 1080 },
+            InputEvent::MouseButton {
+                button: MouseButton::X2,
+                pressed: true,
+                x: 10,
+                y: -20,
+            },
+            InputEvent::MouseWheel {
+                delta: -240,
+                horizontal: true,
+                x: 3,
+                y: 4,
+            },
+            InputEvent::Key {
+                vk: 0x41,
+                scan: 0x1e,
+                pressed: false,
+                repeat: true,
+            },
+            InputEvent::Char { ch: '√' as u32 },
+            InputEvent::Focus { focused: true },
+            InputEvent::Resize {
+                width: 2560,
+                height: 1440,
+            },
+            InputEvent::Ack {
+                seq: u32::MAX,
+                timestamp: 1_700_000_000_000_000,
+            },
+            InputEvent::CaptureReleased,
+        ]
+    }
+
+    fn packet(event: InputEvent) -> InputPacket {
+        InputPacket {
+            seq: 7,
+            timestamp: 123_456,
+            event,
+        }
+    }
+
+    #[test]
+    fn every_event_round_trips() {
+        for event in every_event() {
+            let encoded = packet(event).encode();
+            assert_eq!(encoded[3], event.kind());
+            assert_eq!(InputPacket::decode(&encoded), Ok(packet(event)));
+        }
+    }
+
+    #[test]
+    fn every_button_round_trips() {
+        for button in [
+            MouseButton::Left,
+            MouseButton::Right,
+            MouseButton::Middle,
+            MouseButton::X1,
+            MouseButton::X2,
+        ] {
+            assert_eq!(MouseButton::from_u8(button.to_u8()), Some(button));
+        }
+        assert_eq!(MouseButton::from_u8(5), None);
+    }
+
+    #[test]
+    fn truncated_packets() {
+        for event in every_event() {
+            let encoded = packet(event).encode();
+            for len in 0..PACKET_HEADER_LEN {
+                assert_eq!(
+                    InputPacket::decode(&encoded[..len]),
+                    Err(InputError::TooShort(len))
+                );
+            }
+            //The header announces more than what arrived.
+            if encoded.len() > PACKET_HEADER_LEN {
+                let len = encoded.len() - 1;
+                assert_eq!(
+                    InputPacket::decode(&encoded[..len]),
+                    Err(InputError::TooShort(len))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn short_payloads() {
+        let mut encoded = packet(InputEvent::MouseMove { x: 1, y: 2 }).encode();
+        //Claims a 4 byte payload, a mouse move needs 8.
+        encoded[16] = 4;
+        assert_eq!(
+            InputPacket::decode(&encoded),
+            Err(InputError::BadPayload(event_kind::MOUSE_MOVE))
+        );
+
+        let mut encoded = packet(InputEvent::MouseButton {
+            button: MouseButton::Left,
+            pressed: true,
+            x: 0,
+            y: 0,
+        })
+        .encode();
+        encoded[PACKET_HEADER_LEN] = 9;
+        assert_eq!(
+            InputPacket::decode(&encoded),
+            Err(InputError::BadPayload(event_kind::MOUSE_BUTTON))
+        );
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut encoded = packet(InputEvent::Focus { focused: true }).encode();
+        encoded[0] = b'X';
+        assert_eq!(
+            InputPacket::decode(&encoded),
+            Err(InputError::BadMagic(u16::from_le_bytes([b'X', b'I'])))
+        );
+
+        let mut encoded = packet(InputEvent::Focus { focused: true }).encode();
+        encoded[2] = 0;
+        assert_eq!(
+            InputPacket::decode(&encoded),
+            Err(InputError::UnsupportedVersion(0))
+        );
+
+        let mut encoded = packet(InputEvent::Focus { focused: true }).encode();
+        encoded[3] = 200;
+        assert_eq!(
+            InputPacket::decode(&encoded),
+            Err(InputError::UnknownKind(200))
+        );
+    }
+
+    #[test]
+    fn newer_versions_are_read() {
+        //A later version with a longer payload.
+        let mut encoded = packet(InputEvent::Char { ch: 'a' as u32 }).encode();
+        encoded[2] = INPUT_VERSION + 1;
+        encoded[16] = 8;
+        encoded.extend_from_slice(&[0xff; 4]);
+        assert_eq!(
+            InputPacket::decode(&encoded),
+            Ok(packet(InputEvent::Char { ch: 'a' as u32 }))
+        );
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut sequencer = Sequencer { next: u32::MAX };
+        let event = InputEvent::CaptureReleased;
+        assert_eq!(sequencer.stamp(event, 1).seq, u32::MAX);
+        assert_eq!(sequencer.stamp(event, 2).seq, 0);
+    }
+
+    //Sends every event from one socket to the other and decodes what arrived.
+    fn loopback(sender: &dyn DatagramSocket, receiver: &dyn DatagramSocket, to: SocketAddr) {
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut sequencer = Sequencer::default();
+        let mut buf = [0; MAX_PACKET_LEN];
+        for event in every_event() {
+            let sent = sequencer.stamp(event, now_micros());
+            sender.send_to(&sent.encode(), to).unwrap();
+            let (len, _) = receiver.recv_from(&mut buf).unwrap();
+            assert_eq!(InputPacket::decode(&buf[..len]), Ok(sent));
+        }
+    }
+
+    #[test]
+    fn loopback_through_the_mock_platform() {
+        let game = MockPlatform::new();
+        let producer = game.spawn_process("Blish HUD.exe");
+        let listening = producer
+            .bind_udp(SocketAddr::from((Ipv4Addr::LOCALHOST, 49152)))
+            .unwrap();
+        let socket = game
+            .bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+            .unwrap();
+        loopback(
+            socket.as_ref(),
+            listening.as_ref(),
+            SocketAddr::from((Ipv4Addr::LOCALHOST, 49152)),
+        );
+    }
+
+    #[test]
+    fn loopback_through_real_sockets() {
+        let listening = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        loopback(&socket, &listening, listening.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,306 @@
+use std::{collections::HashMap, fmt};
+
+use super::keys::{ComboError, KeyBind, parse_combo_located};
+
+/*
+ *
+ * Parser for keybinds.conf. Plain Rust, no windows.
+ *
+ * One binding per line: a key combination (see keys), whitespace, then an action name.
+ * Empty lines and lines starting with '#' are ignored.
+ *
+ * Bad lines never stop the parsing, they are reported as diagnostics and skipped,
+ * so a typo only costs that one binding.
+ *
+ * */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    DumpDebugData,
+    RestartBlish,
+    ToggleRendering,
+    ToggleProcessing,
+    ToggleDebugOverlay,
+    DebugOverlayLogMode,
+    DebugOverlayStatisticsMode,
+    ReleaseKeyboardCapture,
+    ReloadKeybinds,
+    ReloadLogLevels,
+    Unload,
+}
+
+//Names used in the file.
+const ACTION_NAMES: &[(&str, Action)] = &[
+    ("dump_debug_data", Action::DumpDebugData),
+    ("restart_blish", Action::RestartBlish),
+    ("toggle_rendering", Action::ToggleRendering),
+    ("toggle_processing", Action::ToggleProcessing),
+    ("toggle_debug_overlay", Action::ToggleDebugOverlay),
+    ("debug_overlay_log_mode", Action::DebugOverlayLogMode),
+    (
+        "debug_overlay_statistics_mode",
+        Action::DebugOverlayStatisticsMode,
+    ),
+    ("release_keyboard_capture", Action::ReleaseKeyboardCapture),
+    ("reload_keybinds", Action::ReloadKeybinds),
+    ("reload_log_levels", Action::ReloadLogLevels),
+    ("unload", Action::Unload),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTION_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, a)| *a)
+    }
+    pub fn name(self) -> &'static str {
+        ACTION_NAMES
+            .iter()
+            .find(|(_, a)| *a == self)
+            .map(|(n, _)| *n)
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    //The line has a combination but nothing after it.
+    MissingAction,
+    UnknownAction(String),
+    //Anything wrong with the combination itself: unknown key, conflicting modifiers, ...
+    BadCombo(ComboError),
+    //The same combination was already bound. The first binding wins.
+    DuplicateBinding { first_line: usize },
+    //Something after the action name.
+    UnexpectedText(String),
+}
+
+//A problem found on a line. Lines and columns start at 1, columns count characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub kind: DiagnosticKind,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        match &self.kind {
+            DiagnosticKind::MissingAction => write!(f, "missing action name"),
+            DiagnosticKind::UnknownAction(a) => write!(f, "unknown action \"{}\"", a),
+            DiagnosticKind::BadCombo(e) => write!(f, "{}", e),
+            DiagnosticKind::DuplicateBinding { first_line } => {
+                write!(f, "combination already bound on line {}", first_line)
+            }
+            DiagnosticKind::UnexpectedText(t) => write!(f, "unexpected \"{}\"", t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ParsedKeybinds {
+    //In file order.
+    pub bindings: Vec<(KeyBind, Action)>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+///Parses the whole content of keybinds.conf. Never fails, bad lines end up in diagnostics.
+pub fn parse_keybinds(text: &str) -> ParsedKeybinds {
+    let mut parsed = ParsedKeybinds::default();
+    //Line each combination was first bound on.
+    let mut seen: HashMap<KeyBind, usize> = HashMap::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let diagnostic = |at: usize, kind: DiagnosticKind| Diagnostic {
+            line: line_no,
+            column: line[..at].chars().count() + 1,
+            kind,
+        };
+
+        let mut tokens = tokens(line);
+        let Some((combo_at, combo)) = tokens.next() else {
+            continue;
+        };
+        if combo.starts_with('#') {
+            continue;
+        }
+
+        let keybind = match parse_combo_located(combo) {
+            Ok(keybind) => keybind,
+            Err((offset, e)) => {
+                parsed
+                    .diagnostics
+                    .push(diagnostic(combo_at + offset, DiagnosticKind::BadCombo(e)));
+                continue;
+            }
+        };
+
+        let Some((action_at, action_name)) = tokens.next() else {
+            parsed
+                .diagnostics
+                .push(diagnostic(line.len(), DiagnosticKind::MissingAction));
+            continue;
+        };
+        let Some(action) = Action::from_name(action_name) else {
+            parsed.diagnostics.push(diagnostic(
+                action_at,
+                DiagnosticKind::UnknownAction(action_name.to_string()),
+            ));
+            continue;
+        };
+
+        if let Some((extra_at, extra)) = tokens.next() {
+            //Trailing comments are fine.
+            if !extra.starts_with('#') {
+                parsed.diagnostics.push(diagnostic(
+                    extra_at,
+                    DiagnosticKind::UnexpectedText(extra.to_string()),
+                ));
+                continue;
+            }
+        }
+
+        if let Some(first_line) = seen.get(&keybind) {
+            parsed.diagnostics.push(diagnostic(
+                combo_at,
+                DiagnosticKind::DuplicateBinding {
+                    first_line: *first_line,
+                },
+            ));
+            continue;
+        }
+        seen.insert(keybind, line_no);
+        parsed.bindings.push((keybind, action));
+    }
+    parsed
+}
+
+///Formats bindings the way parse_keybinds reads them.
+pub fn format_keybinds(bindings: &[(KeyBind, Action)]) -> String {
+    bindings
+        .iter()
+        .map(|(keybind, action)| format!("{} {}\n", keybind, action.name()))
+        .collect()
+}
+
+//Whitespace separated tokens, with their byte offset in the line.
+fn tokens(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    line.split_whitespace()
+        .map(move |token| (token.as_ptr() as usize - line.as_ptr() as usize, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keybinds::default_keybinds;
+
+    fn diagnostics(text: &str) -> Vec<(usize, usize, DiagnosticKind)> {
+        parse_keybinds(text)
+            .diagnostics
+            .into_iter()
+            .map(|d| (d.line, d.column, d.kind))
+            .collect()
+    }
+
+    #[test]
+    fn unknown_action() {
+        assert_eq!(
+            diagnostics("\n  Ctrl+P  dump_everything\n"),
+            [(
+                2,
+                11,
+                DiagnosticKind::UnknownAction("dump_everything".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn bad_combo_points_at_the_bad_part() {
+        assert_eq!(
+            diagnostics("Ctrl+Alt+Nope unload\n\tCtrl+Hyper+P unload"),
+            [
+                (
+                    1,
+                    10,
+                    DiagnosticKind::BadCombo(ComboError::UnknownKey("Nope".to_string()))
+                ),
+                (
+                    2,
+                    7,
+                    DiagnosticKind::BadCombo(ComboError::UnknownModifier("Hyper".to_string()))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        //"é" is two bytes, the column is still one past it.
+        assert_eq!(
+            diagnostics("Ctrl+é unload"),
+            [(
+                1,
+                6,
+                DiagnosticKind::BadCombo(ComboError::UnknownKey("é".to_string()))
+            )]
+        );
+        assert_eq!(
+            diagnostics("F5 unload é"),
+            [(1, 11, DiagnosticKind::UnexpectedText("é".to_string()))]
+        );
+    }
+
+    #[test]
+    fn duplicate_binding_keeps_the_first() {
+        let parsed = parse_keybinds("Ctrl+P unload\n# comment\n  ctrl+p reload_keybinds\n");
+        assert_eq!(
+            parsed.bindings,
+            [(parse_combo_located("Ctrl+P").unwrap(), Action::Unload)]
+        );
+        assert_eq!(
+            parsed.diagnostics,
+            [Diagnostic {
+                line: 3,
+                column: 3,
+                kind: DiagnosticKind::DuplicateBinding { first_line: 1 },
+            }]
+        );
+    }
+
+    #[test]
+    fn unexpected_text() {
+        assert_eq!(
+            diagnostics("F5 unload now\nF6 unload # trailing comments are fine"),
+            [(1, 11, DiagnosticKind::UnexpectedText("now".to_string()))]
+        );
+    }
+
+    #[test]
+    fn missing_action() {
+        //Points right after the end of the line.
+        assert_eq!(
+            diagnostics("F5\nF6   "),
+            [
+                (1, 3, DiagnosticKind::MissingAction),
+                (2, 6, DiagnosticKind::MissingAction),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_lines_are_skipped() {
+        let parsed = parse_keybinds("F5 unload\nF6 nothing\nF7 reload_keybinds");
+        assert_eq!(parsed.bindings.len(), 2);
+        assert_eq!(parsed.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn defaults_round_trip() {
+        let parsed = parse_keybinds(&format_keybinds(&default_keybinds()));
+        assert_eq!(parsed.diagnostics, []);
+        assert_eq!(parsed.bindings, default_keybinds());
+    }
+}
@@ -0,0 +1,489 @@
+use std::fmt;
+
+/*
+ *
+ * Key names used in keybinds.conf. Plain Rust, no windows, so virtual-key codes are spelled out.
+ *
+ * A combo is any number of modifiers followed by a single key, separated by '+': "Ctrl+Alt+F5".
+ * Names are case insensitive. Modifiers can be side specific: "LCtrl", "RAlt", ...
+ * Plain "Ctrl" matches either side.
+ *
+ * Formatting always uses the first name of a key in KEY_NAMES, so a formatted combo parses
+ * back to the same KeyBind. Keys without a name are written as their hex code: "Ctrl+0xE8".
+ *
+ * */
+
+//Which physical modifier keys a binding requires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Side {
+    //Must not be held.
+    #[default]
+    None,
+    Either,
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Modifiers {
+    pub ctrl: Side,
+    pub alt: Side,
+    pub shift: Side,
+    pub win: Side,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyBind {
+    //Windows virtual-key code
+    pub key: u32,
+    pub modifiers: Modifiers,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComboError {
+    Empty,
+    UnknownKey(String),
+    UnknownModifier(String),
+    //A modifier given as the key, like "Ctrl+Shift".
+    MissingKey,
+    //Two keys in the same combo, like "A+B".
+    NotAModifier(String),
+    //The same modifier given twice, like "Ctrl+LCtrl".
+    ConflictingModifier(String),
+}
+
+impl fmt::Display for ComboError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComboError::Empty => write!(f, "empty key combination"),
+            ComboError::UnknownKey(k) => write!(f, "unknown key \"{}\"", k),
+            ComboError::UnknownModifier(m) => write!(f, "unknown modifier \"{}\"", m),
+            ComboError::MissingKey => write!(f, "only modifiers, a key is missing"),
+            ComboError::NotAModifier(k) => {
+                write!(
+                    f,
+                    "\"{}\" is a key, only one key is allowed per combination",
+                    k
+                )
+            }
+            ComboError::ConflictingModifier(m) => write!(f, "modifier \"{}\" given twice", m),
+        }
+    }
+}
+
+//Virtual-key codes of the mouse buttons, they can be bound like any other key.
+pub mod mouse {
+    pub const LEFT: u32 = 0x01;
+    pub const RIGHT: u32 = 0x02;
+    pub const MIDDLE: u32 = 0x04;
+    pub const X1: u32 = 0x05;
+    pub const X2: u32 = 0x06;
+}
+
+//Virtual-key codes of the side specific modifier keys, to check which ones are held.
+pub mod modifier {
+    pub const LSHIFT: u32 = 0xA0;
+    pub const RSHIFT: u32 = 0xA1;
+    pub const LCONTROL: u32 = 0xA2;
+    pub const RCONTROL: u32 = 0xA3;
+    pub const LMENU: u32 = 0xA4;
+    pub const RMENU: u32 = 0xA5;
+    pub const LWIN: u32 = 0x5B;
+    pub const RWIN: u32 = 0x5C;
+}
+
+//Letters and digits are handled separately, their virtual-key code is their ASCII value.
+//The first name of every key is the one used for formatting.
+pub const KEY_NAMES: &[(&str, u32)] = &[
+    ("MouseLeft", mouse::LEFT),
+    ("MouseRight", mouse::RIGHT),
+    ("MouseMiddle", mouse::MIDDLE),
+    ("Mouse4", mouse::X1),
+    ("XButton1", mouse::X1),
+    ("Mouse5", mouse::X2),
+    ("XButton2", mouse::X2),
+    ("Backspace", 0x08),
+    ("Tab", 0x09),
+    ("Enter", 0x0D),
+    ("Return", 0x0D),
+    ("Pause", 0x13),
+    ("CapsLock", 0x14),
+    ("Escape", 0x1B),
+    ("Esc", 0x1B),
+    ("Space", 0x20),
+    ("PageUp", 0x21),
+    ("PgUp", 0x21),
+    ("PageDown", 0x22),
+    ("PgDn", 0x22),
+    ("End", 0x23),
+    ("Home", 0x24),
+    ("Left", 0x25),
+    ("Up", 0x26),
+    ("Right", 0x27),
+    ("Down", 0x28),
+    ("PrintScreen", 0x2C),
+    ("PrtSc", 0x2C),
+    ("Insert", 0x2D),
+    ("Ins", 0x2D),
+    ("Delete", 0x2E),
+    ("Del", 0x2E),
+    ("Apps", 0x5D),
+    ("Menu", 0x5D),
+    ("Numpad0", 0x60),
+    ("Numpad1", 0x61),
+    ("Numpad2", 0x62),
+    ("Numpad3", 0x63),
+    ("Numpad4", 0x64),
+    ("Numpad5", 0x65),
+    ("Numpad6", 0x66),
+    ("Numpad7", 0x67),
+    ("Numpad8", 0x68),
+    ("Numpad9", 0x69),
+    ("NumpadMultiply", 0x6A),
+    ("NumpadAdd", 0x6B),
+    ("NumpadSeparator", 0x6C),
+    ("NumpadSubtract", 0x6D),
+    ("NumpadDecimal", 0x6E),
+    ("NumpadDivide", 0x6F),
+    ("F1", 0x70),
+    ("F2", 0x71),
+    ("F3", 0x72),
+    ("F4", 0x73),
+    ("F5", 0x74),
+    ("F6", 0x75),
+    ("F7", 0x76),
+    ("F8", 0x77),
+    ("F9", 0x78),
+    ("F10", 0x79),
+    ("F11", 0x7A),
+    ("F12", 0x7B),
+    ("F13", 0x7C),
+    ("F14", 0x7D),
+    ("F15", 0x7E),
+    ("F16", 0x7F),
+    ("F17", 0x80),
+    ("F18", 0x81),
+    ("F19", 0x82),
+    ("F20", 0x83),
+    ("F21", 0x84),
+    ("F22", 0x85),
+    ("F23", 0x86),
+    ("F24", 0x87),
+    ("NumLock", 0x90),
+    ("ScrollLock", 0x91),
+    //Punctuation, named after the US layout.
+    ("Semicolon", 0xBA),
+    (";", 0xBA),
+    ("Equals", 0xBB),
+    ("=", 0xBB),
+    ("Comma", 0xBC),
+    (",", 0xBC),
+    ("Minus", 0xBD),
+    ("-", 0xBD),
+    ("Period", 0xBE),
+    (".", 0xBE),
+    ("Slash", 0xBF),
+    ("/", 0xBF),
+    ("Backquote", 0xC0),
+    ("`", 0xC0),
+    ("LeftBracket", 0xDB),
+    ("[", 0xDB),
+    ("Backslash", 0xDC),
+    ("\\", 0xDC),
+    ("RightBracket", 0xDD),
+    ("]", 0xDD),
+    ("Quote", 0xDE),
+    ("'", 0xDE),
+    ("IntlBackslash", 0xE2),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModifierKind {
+    Ctrl,
+    Alt,
+    Shift,
+    Win,
+}
+
+//Formatting uses the first name for a given kind and side.
+const MODIFIER_NAMES: &[(&str, ModifierKind, Side)] = &[
+    ("Ctrl", ModifierKind::Ctrl, Side::Either),
+    ("Control", ModifierKind::Ctrl, Side::Either),
+    ("LCtrl", ModifierKind::Ctrl, Side::Left),
+    ("LeftCtrl", ModifierKind::Ctrl, Side::Left),
+    ("RCtrl", ModifierKind::Ctrl, Side::Right),
+    ("RightCtrl", ModifierKind::Ctrl, Side::Right),
+    ("Alt", ModifierKind::Alt, Side::Either),
+    ("LAlt", ModifierKind::Alt, Side::Left),
+    ("LeftAlt", ModifierKind::Alt, Side::Left),
+    ("RAlt", ModifierKind::Alt, Side::Right),
+    ("RightAlt", ModifierKind::Alt, Side::Right),
+    ("AltGr", ModifierKind::Alt, Side::Right),
+    ("Shift", ModifierKind::Shift, Side::Either),
+    ("LShift", ModifierKind::Shift, Side::Left),
+    ("LeftShift", ModifierKind::Shift, Side::Left),
+    ("RShift", ModifierKind::Shift, Side::Right),
+    ("RightShift", ModifierKind::Shift, Side::Right),
+    ("Win", ModifierKind::Win, Side::Either),
+    ("LWin", ModifierKind::Win, Side::Left),
+    ("LeftWin", ModifierKind::Win, Side::Left),
+    ("RWin", ModifierKind::Win, Side::Right),
+    ("RightWin", ModifierKind::Win, Side::Right),
+];
+
+impl Modifiers {
+    fn get_mut(&mut self, kind: ModifierKind) -> &mut Side {
+        match kind {
+            ModifierKind::Ctrl => &mut self.ctrl,
+            ModifierKind::Alt => &mut self.alt,
+            ModifierKind::Shift => &mut self.shift,
+            ModifierKind::Win => &mut self.win,
+        }
+    }
+}
+
+///Virtual-key code of a key name, case insensitive.
+pub fn key_from_name(name: &str) -> Option<u32> {
+    match name.as_bytes() {
+        [c] if c.is_ascii_alphanumeric() => Some(c.to_ascii_uppercase() as u32),
+        //Keys without a name, as formatted by KeyBind's Display.
+        [b'0', b'x' | b'X', digits @ ..]
+            if (1..=2).contains(&digits.len()) && digits.iter().all(u8::is_ascii_hexdigit) =>
+        {
+            u32::from_str_radix(&name[2..], 16)
+                .ok()
+                .filter(|vk| (1..0xFF).contains(vk))
+        }
+        _ => KEY_NAMES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, vk)| *vk),
+    }
+}
+
+///Name of a virtual-key code, None if it has none.
+pub fn key_name(vk: u32) -> Option<String> {
+    match char::from_u32(vk) {
+        Some(c) if c.is_ascii_uppercase() || c.is_ascii_digit() => Some(c.to_string()),
+        _ => KEY_NAMES
+            .iter()
+            .find(|(_, v)| *v == vk)
+            .map(|(n, _)| n.to_string()),
+    }
+}
+
+fn modifier_from_name(name: &str) -> Option<(ModifierKind, Side)> {
+    MODIFIER_NAMES
+        .iter()
+        .find(|(n, _, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, kind, side)| (*kind, *side))
+}
+
+fn modifier_name(kind: ModifierKind, side: Side) -> Option<&'static str> {
+    MODIFIER_NAMES
+        .iter()
+        .find(|(_, k, s)| *k == kind && *s == side)
+        .map(|(n, _, _)| *n)
+}
+
+///Parses a combination like "Ctrl+Alt+F5".
+pub fn parse_combo(combo: &str) -> Result<KeyBind, ComboError> {
+    parse_combo_located(combo).map_err(|(_, e)| e)
+}
+
+///Same as parse_combo, but errors also carry the byte offset of the offending part.
+pub fn parse_combo_located(combo: &str) -> Result<KeyBind, (usize, ComboError)> {
+    if combo.is_empty() {
+        return Err((0, ComboError::Empty));
+    }
+    let mut parts = Vec::new();
+    let mut at = 0;
+    for part in combo.split('+') {
+        parts.push((at, part));
+        at += part.len() + 1;
+    }
+    let (&(key_at, key), modifier_parts) = parts.split_last().ok_or((0, ComboError::Empty))?;
+
+    let mut modifiers = Modifiers::default();
+    for &(at, part) in modifier_parts {
+        let Some((kind, side)) = modifier_from_name(part) else {
+            if key_from_name(part).is_some() {
+                return Err((at, ComboError::NotAModifier(part.to_string())));
+            }
+            return Err((at, ComboError::UnknownModifier(part.to_string())));
+        };
+        let current = modifiers.get_mut(kind);
+        if *current != Side::None {
+            return Err((at, ComboError::ConflictingModifier(part.to_string())));
+        }
+        *current = side;
+    }
+
+    if modifier_from_name(key).is_some() {
+        return Err((key_at, ComboError::MissingKey));
+    }
+    let key = key_from_name(key).ok_or_else(|| {
+        if key.is_empty() {
+            (key_at, ComboError::Empty)
+        } else {
+            (key_at, ComboError::UnknownKey(key.to_string()))
+        }
+    })?;
+    Ok(KeyBind { key, modifiers })
+}
+
+impl fmt::Display for KeyBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = self.modifiers;
+        let modifiers = [
+            (ModifierKind::Ctrl, m.ctrl),
+            (ModifierKind::Alt, m.alt),
+            (ModifierKind::Shift, m.shift),
+            (ModifierKind::Win, m.win),
+        ];
+        for (kind, side) in modifiers {
+            if let Some(name) = modifier_name(kind, side) {
+                write!(f, "{}+", name)?;
+            }
+        }
+        match key_name(self.key) {
+            Some(name) => write!(f, "{}", name),
+            //key_from_name reads this back.
+            None => write!(f, "0x{:02X}", self.key),
+        }
+    }
+}
+
+//State of both keys of a modifier.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Held {
+    pub left: bool,
+    pub right: bool,
+}
+
+impl Held {
+    //Every requirement satisfied by this state, side specific ones first.
+    fn matching(self) -> Vec<Side> {
+        let mut sides = Vec::with_capacity(3);
+        if self.left {
+            sides.push(Side::Left);
+        }
+        if self.right {
+            sides.push(Side::Right);
+        }
+        if sides.is_empty() {
+            sides.push(Side::None);
+        } else {
+            sides.push(Side::Either);
+        }
+        sides
+    }
+}
+
+///Every binding that matches a key press with the given modifiers held,
+///most specific first. Used to look bindings up in a map.
+pub fn candidates(key: u32, ctrl: Held, alt: Held, shift: Held, win: Held) -> Vec<KeyBind> {
+    let mut out = Vec::new();
+    for ctrl in ctrl.matching() {
+        for alt in alt.matching() {
+            for shift in shift.matching() {
+                for win in win.matching() {
+                    out.push(KeyBind {
+                        key,
+                        modifiers: Modifiers {
+                            ctrl,
+                            alt,
+                            shift,
+                            win,
+                        },
+                    });
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(keybind: KeyBind) {
+        assert_eq!(
+            parse_combo(&keybind.to_string()),
+            Ok(keybind),
+            "{}",
+            keybind
+        );
+    }
+
+    #[test]
+    fn every_key_name_round_trips() {
+        for &(name, vk) in KEY_NAMES {
+            let keybind = parse_combo(name).unwrap();
+            assert_eq!(keybind.key, vk, "{}", name);
+            round_trip(keybind);
+        }
+        for c in ('A'..='Z').chain('0'..='9') {
+            assert_eq!(
+                parse_combo(&c.to_ascii_lowercase().to_string())
+                    .unwrap()
+                    .key,
+                c as u32
+            );
+        }
+    }
+
+    #[test]
+    fn every_modifier_name_round_trips() {
+        for &(name, kind, side) in MODIFIER_NAMES {
+            let keybind = parse_combo(&format!("{}+F5", name)).unwrap();
+            let mut modifiers = Modifiers::default();
+            *modifiers.get_mut(kind) = side;
+            assert_eq!(keybind.modifiers, modifiers, "{}", name);
+            round_trip(keybind);
+        }
+        let all = parse_combo("RCtrl+LAlt+Shift+RWin+Delete").unwrap();
+        assert_eq!(all.to_string(), "RCtrl+LAlt+Shift+RWin+Delete");
+        round_trip(all);
+    }
+
+    #[test]
+    fn unnamed_keys_round_trip() {
+        for vk in 1..0xFF {
+            round_trip(KeyBind {
+                key: vk,
+                modifiers: Modifiers::default(),
+            });
+        }
+        assert_eq!(parse_combo("Ctrl+0xe8").unwrap().to_string(), "Ctrl+0xE8");
+        for bad in ["0x", "0x0", "0xFF", "0x100", "0xG1"] {
+            assert_eq!(
+                parse_combo(bad),
+                Err(ComboError::UnknownKey(bad.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn errors_carry_the_offset() {
+        assert_eq!(
+            parse_combo_located("Ctrl+Foo+A"),
+            Err((5, ComboError::UnknownModifier("Foo".to_string())))
+        );
+        assert_eq!(
+            parse_combo_located("Ctrl+A+B"),
+            Err((5, ComboError::NotAModifier("A".to_string())))
+        );
+        assert_eq!(
+            parse_combo_located("Ctrl+LCtrl+A"),
+            Err((5, ComboError::ConflictingModifier("LCtrl".to_string())))
+        );
+        assert_eq!(
+            parse_combo_located("Ctrl+Shift"),
+            Err((5, ComboError::MissingKey))
+        );
+        assert_eq!(parse_combo_located("Ctrl+"), Err((5, ComboError::Empty)));
+        assert_eq!(parse_combo_located(""), Err((0, ComboError::Empty)));
+    }
+}
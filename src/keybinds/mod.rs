@@ -0,0 +1,430 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    sync::{OnceLock, RwLock, atomic::Ordering},
+    time::Duration,
+};
+
+use config::{Action, format_keybinds, parse_keybinds};
+use keys::{Held, KeyBind, Modifiers, Side, candidates, modifier};
+
+use crate::{
+    config::config,
+    debug::{
+        DEBUG_FEATURES,
+        debug_overlay::{OVERLAY_MODE, overlay_mode},
+        dump_debug_data, restart_blish,
+    },
+    logging::reload_log_levels,
+    platform::platform,
+    shutdown::{spawn_worker, wait_for_shutdown},
+    udp::release_capture,
+};
+
+pub mod config;
+pub mod keys;
+
+//How often the file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+fn keybinds_path() -> &'static str {
+    &config().paths.keybinds
+}
+
+//Handle keybinds and custom keybinds. Swapped as a whole when the file is reloaded.
+pub static KEYBINDS: OnceLock<RwLock<HashMap<KeyBind, Action>>> = OnceLock::new();
+
+pub fn init_keybinds() {
+    if !std::path::Path::new(keybinds_path()).exists() {
+        dump_default_keybinds(keybinds_path());
+    } else {
+        add_missing_default_keybinds(keybinds_path());
+    }
+
+    if KEYBINDS
+        .set(RwLock::new(load_keybinds(keybinds_path())))
+        .is_err()
+    {
+        log::error!("Keybinds are already initialized.");
+        return;
+    }
+    if let Err(e) = start_keybinds_watcher() {
+        log::error!(
+            "Could not watch {}, keybinds won't be reloaded: {}",
+            keybinds_path(),
+            e
+        );
+    }
+}
+
+///Reloads keybinds.conf. If anything in it is wrong, the current bindings are kept.
+pub fn reload_keybinds() {
+    let Some(keybinds) = KEYBINDS.get() else {
+        return;
+    };
+    let text = match fs::read_to_string(keybinds_path()) {
+        Ok(text) => text,
+        Err(e) => {
+            log::error!(
+                "Failed to read {}, keeping the current keybinds: {}",
+                keybinds_path(),
+                e
+            );
+            return;
+        }
+    };
+    let parsed = parse_keybinds(&text);
+    if !parsed.diagnostics.is_empty() {
+        for diagnostic in &parsed.diagnostics {
+            log::error!("Invalid keybind in {}: {}", keybinds_path(), diagnostic);
+        }
+        log::error!(
+            "Keeping the current keybinds until {} is fixed.",
+            keybinds_path()
+        );
+        return;
+    }
+    let map: HashMap<_, _> = parsed.bindings.into_iter().collect();
+    let count = map.len();
+    *keybinds.write().unwrap() = map;
+    log::info!("Reloaded {} keybinds.", count);
+}
+
+//Polls the modification time of the file, reloading it when it changes.
+fn start_keybinds_watcher() -> io::Result<()> {
+    spawn_worker("keybinds-watcher", || {
+        let modified = || {
+            fs::metadata(keybinds_path())
+                .and_then(|m| m.modified())
+                .ok()
+        };
+        let mut last = modified();
+        while !wait_for_shutdown(WATCH_INTERVAL) {
+            let current = modified();
+            if current != last {
+                last = current;
+                //Deleted, or in the middle of being saved. The next change will be picked up.
+                if current.is_some() {
+                    reload_keybinds();
+                }
+            }
+        }
+    })
+}
+
+pub fn default_keybinds() -> Vec<(KeyBind, Action)> {
+    let ctrl_alt = Modifiers {
+        ctrl: Side::Either,
+        alt: Side::Either,
+        ..Default::default()
+    };
+    let ctrl_alt_shift = Modifiers {
+        shift: Side::Either,
+        ..ctrl_alt
+    };
+    let bind = |key: char, modifiers: Modifiers| KeyBind {
+        key: key as u32,
+        modifiers,
+    };
+    vec![
+        (bind('P', ctrl_alt), Action::DumpDebugData),
+        (bind('O', ctrl_alt), Action::RestartBlish),
+        (bind('B', ctrl_alt), Action::ToggleRendering),
+        (bind('N', ctrl_alt), Action::ToggleProcessing),
+        (bind('D', ctrl_alt), Action::ToggleDebugOverlay),
+        (bind('1', ctrl_alt_shift), Action::DebugOverlayLogMode),
+        (
+            bind('2', ctrl_alt_shift),
+            Action::DebugOverlayStatisticsMode,
+        ),
+        (bind('K', ctrl_alt), Action::ReleaseKeyboardCapture),
+        (bind('R', ctrl_alt), Action::ReloadKeybinds),
+        (bind('L', ctrl_alt), Action::ReloadLogLevels),
+        (bind('U', ctrl_alt), Action::Unload),
+    ]
+}
+
+fn dump_default_keybinds(path: &str) {
+    //Written with the same names the parser reads.
+    if let Err(e) = fs::write(path, format_keybinds(&default_keybinds())) {
+        log::error!("Failed to create the keybinds file {}: {}", path, e);
+    }
+}
+
+//Files written by older versions lack the actions added since. Default bindings of actions
+//the file never mentions are appended, unless their combination is taken. Any mention counts,
+//comments included, so commenting a binding out disables it for good.
+fn add_missing_default_keybinds(path: &str) {
+    let Ok(mut text) = fs::read_to_string(path) else {
+        return;
+    };
+    let mentioned: HashSet<&str> = text
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .collect();
+    let taken: HashSet<KeyBind> = parse_keybinds(&text)
+        .bindings
+        .iter()
+        .map(|(keybind, _)| *keybind)
+        .collect();
+    let missing: Vec<_> = default_keybinds()
+        .into_iter()
+        .filter(|(keybind, action)| !mentioned.contains(action.name()) && !taken.contains(keybind))
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(&format_keybinds(&missing));
+    if let Err(e) = fs::write(path, text) {
+        log::error!("Failed to add the new default keybinds to {}: {}", path, e);
+        return;
+    }
+    for (keybind, action) in &missing {
+        log::info!(
+            "Added the default keybind {} {} to {}.",
+            keybind,
+            action.name(),
+            path
+        );
+    }
+}
+
+//Loads keybinds from the config file. Bad lines are logged and skipped.
+fn load_keybinds(path: &str) -> HashMap<KeyBind, Action> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            log::error!("Failed to read {}, using the defaults: {}", path, e);
+            format_keybinds(&default_keybinds())
+        }
+    };
+    let parsed = parse_keybinds(&text);
+    for diagnostic in &parsed.diagnostics {
+        log::error!("Skipping keybind in {}: {}", path, diagnostic);
+    }
+    parsed.bindings.into_iter().collect()
+}
+
+///Bindings currently in use, sorted by action. None while they are being swapped.
+pub fn active_keybinds() -> Option<Vec<(KeyBind, Action)>> {
+    let map = KEYBINDS.get()?.try_read().ok()?;
+    let mut bindings: Vec<_> = map.iter().map(|(k, a)| (*k, *a)).collect();
+    bindings.sort_by_key(|(_, action)| action.name());
+    Some(bindings)
+}
+
+fn action_fn(action: Action) -> fn() {
+    match action {
+        Action::DumpDebugData => dump_debug_data as fn(),
+        Action::RestartBlish => restart_blish as fn(),
+        Action::ToggleRendering => toggle_rendering_action as fn(),
+        Action::ToggleProcessing => toggle_processing_action as fn(),
+        Action::ToggleDebugOverlay => toggle_debug_overlay as fn(),
+        Action::DebugOverlayLogMode => change_overlay_mode_to_log as fn(),
+        Action::DebugOverlayStatisticsMode => change_overlay_mode_to_statistics as fn(),
+        Action::ReleaseKeyboardCapture => release_keyboard_capture_action as fn(),
+        Action::ReloadKeybinds => reload_keybinds as fn(),
+        Action::ReloadLogLevels => reload_log_levels as fn(),
+        Action::Unload => unload_action as fn(),
+    }
+}
+
+//Finds the action bound to a key (or mouse button) press, given the modifiers currently held.
+pub fn get_current_action(vk: u32) -> Option<Action> {
+    let map = KEYBINDS.get()?.read().unwrap();
+    let held = |left: u32, right: u32| Held {
+        left: platform().is_key_down(left),
+        right: platform().is_key_down(right),
+    };
+    candidates(
+        vk,
+        held(modifier::LCONTROL, modifier::RCONTROL),
+        held(modifier::LMENU, modifier::RMENU),
+        held(modifier::LSHIFT, modifier::RSHIFT),
+        held(modifier::LWIN, modifier::RWIN),
+    )
+    .iter()
+    .find_map(|keybind| map.get(keybind).copied())
+}
+
+pub fn run_action(action: Action) {
+    action_fn(action)();
+}
+
+fn toggle_rendering_action() {
+    log::info!("Rendering toggled.");
+    DEBUG_FEATURES.rendering_enabled.store(
+        !DEBUG_FEATURES.rendering_enabled.load(Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+}
+fn toggle_processing_action() {
+    log::info!("Processing toggled.");
+    DEBUG_FEATURES.processing_enabled.store(
+        !DEBUG_FEATURES.processing_enabled.load(Ordering::Relaxed),
+        Ordering::Relaxed,
+    );
+}
+fn toggle_debug_overlay() {
+    let old = DEBUG_FEATURES.debug_overlay_enabled.load(Ordering::Relaxed);
+    //The present hook stops drawing the panel right away, there is nothing to clear.
+    DEBUG_FEATURES
+        .debug_overlay_enabled
+        .store(!old, Ordering::Relaxed);
+    log::info!("Debug overlay toggled.");
+}
+
+fn release_keyboard_capture_action() {
+    release_capture();
+}
+
+fn unload_action() {
+    #[cfg(windows)]
+    crate::unload();
+    #[cfg(not(windows))]
+    log::info!("Nothing to unload outside of the game.");
+}
+
+fn change_overlay_mode_to_log() {
+    OVERLAY_MODE.store(overlay_mode::LOG_MODE, Ordering::Relaxed);
+}
+fn change_overlay_mode_to_statistics() {
+    OVERLAY_MODE.store(overlay_mode::STAT_MODE, Ordering::Relaxed);
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use std::{path::Path, sync::Mutex};
+
+    use crate::platform::default_mock;
+
+    //KEYBINDS is shared by every test using it.
+    static KEYBINDS_LOCK: Mutex<()> = Mutex::new(());
+
+    //Installs the bindings of text as the active ones.
+    fn set_keybinds(text: &str) -> Vec<(KeyBind, Action)> {
+        let parsed = parse_keybinds(text);
+        assert_eq!(parsed.diagnostics, []);
+        *KEYBINDS.get_or_init(Default::default).write().unwrap() =
+            parsed.bindings.iter().copied().collect();
+        active_keybinds().unwrap()
+    }
+
+    fn write_keybinds_file(text: &str) {
+        let path = Path::new(keybinds_path());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, text).unwrap();
+    }
+
+    #[test]
+    fn reload_keeps_the_table_if_the_file_is_invalid() {
+        let _lock = KEYBINDS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let before = set_keybinds("Ctrl+Alt+P dump_debug_data\nF5 reload_keybinds\n");
+
+        write_keybinds_file("Ctrl+Alt+U unload\nF6 no_such_action\n");
+        reload_keybinds();
+        assert_eq!(active_keybinds().unwrap(), before);
+
+        write_keybinds_file("Ctrl+Alt+Nope unload\n");
+        reload_keybinds();
+        assert_eq!(active_keybinds().unwrap(), before);
+
+        fs::remove_file(keybinds_path()).unwrap();
+        reload_keybinds();
+        assert_eq!(active_keybinds().unwrap(), before);
+    }
+
+    #[test]
+    fn reload_replaces_the_table_with_a_valid_file() {
+        let _lock = KEYBINDS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_keybinds("Ctrl+Alt+P dump_debug_data\nF5 reload_keybinds\n");
+
+        let text = "Ctrl+Alt+U unload\n# Comment\n\nF6 reload_keybinds\n";
+        write_keybinds_file(text);
+        reload_keybinds();
+        let mut expected = parse_keybinds(text).bindings;
+        expected.sort_by_key(|(_, action)| action.name());
+        assert_eq!(active_keybinds().unwrap(), expected);
+    }
+
+    #[test]
+    fn old_files_get_the_missing_default_actions() {
+        let path = Path::new(keybinds_path()).with_file_name("old-keybinds.conf");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let path = path.to_str().unwrap();
+        //Ctrl+Alt+U is taken, and reload_keybinds was commented out.
+        let old =
+            "Ctrl+Alt+P dump_debug_data\nCtrl+Alt+U toggle_rendering\n# Ctrl+Alt+R reload_keybinds";
+        fs::write(path, old).unwrap();
+
+        add_missing_default_keybinds(path);
+        let text = fs::read_to_string(path).unwrap();
+        let (kept, added) = text.split_at(old.len() + 1);
+        assert_eq!(kept, format!("{}\n", old));
+        let added = parse_keybinds(added);
+        assert_eq!(added.diagnostics, []);
+        let expected: Vec<_> = default_keybinds()
+            .into_iter()
+            .filter(|(_, action)| {
+                ![
+                    Action::DumpDebugData,
+                    Action::ToggleRendering,
+                    Action::ReloadKeybinds,
+                    Action::Unload,
+                ]
+                .contains(action)
+            })
+            .collect();
+        assert_eq!(added.bindings, expected);
+        assert!(
+            added
+                .bindings
+                .iter()
+                .any(|(_, action)| *action == Action::ReleaseKeyboardCapture)
+        );
+
+        //Nothing more the second time.
+        add_missing_default_keybinds(path);
+        assert_eq!(fs::read_to_string(path).unwrap(), text);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn current_action_follows_held_modifiers() {
+        let _lock = KEYBINDS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let text = "Ctrl+Alt+P dump_debug_data\nLCtrl+Alt+P unload\nF5 reload_keybinds\n";
+        let parsed = parse_keybinds(text);
+        assert_eq!(parsed.diagnostics, []);
+        *KEYBINDS.get_or_init(Default::default).write().unwrap() =
+            parsed.bindings.into_iter().collect();
+
+        let mock = default_mock();
+        let held = |keys: &[u32]| {
+            for vk in [
+                modifier::LCONTROL,
+                modifier::RCONTROL,
+                modifier::LMENU,
+                modifier::RMENU,
+            ] {
+                mock.set_key_down(vk, keys.contains(&vk));
+            }
+        };
+        let p = 'P' as u32;
+
+        held(&[]);
+        assert_eq!(get_current_action(p), None);
+        assert_eq!(get_current_action(0x74), Some(Action::ReloadKeybinds));
+        held(&[modifier::RCONTROL, modifier::LMENU]);
+        assert_eq!(get_current_action(p), Some(Action::DumpDebugData));
+        //The side specific binding wins.
+        held(&[modifier::LCONTROL, modifier::RMENU]);
+        assert_eq!(get_current_action(p), Some(Action::Unload));
+        //Modifiers that aren't part of a binding must not be held.
+        assert_eq!(get_current_action(0x74), None);
+        held(&[]);
+    }
+}
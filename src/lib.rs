@@ -1,18 +1,40 @@
+#[cfg(windows)]
 use address_finder::AddressFinder;
-use chrono::{Local, NaiveDateTime};
-use controls::{initialize_controls, restore_wnd_proc, start_mouse_input_thread};
-use debug::statistics::start_statistics_server;
-use fern::Dispatch;
-use hooks::present_hook;
+#[cfg(windows)]
+use attach::{AttachError, retry};
+#[cfg(windows)]
+use config::load_config;
+#[cfg(windows)]
+use controls::{initialize_controls, is_wnd_proc_replaced, restore_wnd_proc, start_input_thread};
+#[cfg(windows)]
+use debug::{
+    debug_overlay::start_debug_panel,
+    statistics::{start_statistics_server, stop_statistics_server},
+};
+#[cfg(windows)]
+use hooks::{PresentFn, present_hook};
+#[cfg(windows)]
 use keybinds::init_keybinds;
+#[cfg(windows)]
+use logging::enable_logging;
+#[cfg(windows)]
+use shutdown::{PRESENT_CALLS, WNDPROC_CALLS, join_workers, request_shutdown};
+#[cfg(windows)]
 use std::{
-    fs::{OpenOptions, create_dir_all, read_dir, remove_file},
     mem,
-    path::{Path, PathBuf},
-    time::SystemTime,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+#[cfg(windows)]
+use udp::stop_udp_threads;
+#[cfg(windows)]
+use ui::{
+    mmf::{start_mmf_thread, withdraw_discovery_record},
+    release_overlay_state,
 };
-use ui::mmf::start_mmf_thread;
+#[cfg(windows)]
 use utils::{get_base_addr_and_size, get_mainwindow_hwnd};
+#[cfg(windows)]
 use windows::Win32::{
     Foundation::HINSTANCE,
     System::{
@@ -21,32 +43,63 @@ use windows::Win32::{
     },
 };
 
+#[cfg(windows)]
 pub mod address_finder;
+pub mod attach;
+pub mod config;
+#[cfg(windows)]
 pub mod controls;
 pub mod debug;
+#[cfg(windows)]
 pub mod globals;
+#[cfg(windows)]
 pub mod hooks;
+pub mod input;
 pub mod keybinds;
+pub mod logging;
+pub mod platform;
+pub mod shutdown;
+pub mod simulator;
+pub mod udp;
 pub mod ui;
+#[cfg(windows)]
 pub mod utils;
 
+#[cfg(windows)]
 static mut HANDLE_NO: u64 = 0;
+//Set by the first unload() or detatch(), what attach() did is only undone once.
+#[cfg(windows)]
+static TEARING_DOWN: AtomicBool = AtomicBool::new(false);
+//How long the teardown waits for each of: wnd_proc calls, present calls, worker threads.
+#[cfg(windows)]
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+//How long attach() waits for the game to create its window.
+#[cfg(windows)]
+const WINDOW_TIMEOUT: Duration = Duration::from_secs(60);
+#[cfg(windows)]
+const WINDOW_RETRY_INTERVAL: Duration = Duration::from_millis(500);
 
 /*
  *
  * This assumes that the DLL is loaded in a general way, such as LoadLibraryW. If other loading
  * methods need to be supported, simply call attach() and detatch() where appropriate.
  *
- * TODO: detatch() is poorly tested. It also definitely lacks some unloading stuff, like wnd_proc
+ * There are two ways out:
+ *  - unload() (the unload keybind) undoes everything attach() did, in reverse order, waits
+ *    for every thread and hooked call to be done, then frees the library itself.
+ *  - detatch() runs under the loader lock when someone else frees the library. Waiting on
+ *    another thread there deadlocks, so it only unhooks and withdraws the discovery record.
+ *    Nothing is done when the process is exiting, every other thread is already gone.
  *
  * */
-#[cfg(not(feature = "for_nexus"))]
+#[cfg(all(windows, not(feature = "for_nexus")))]
 #[unsafe(no_mangle)]
 #[allow(unused_variables)]
-extern "system" fn DllMain(dll_module: HINSTANCE, call_reason: u32, _: *mut ()) -> bool {
+extern "system" fn DllMain(dll_module: HINSTANCE, call_reason: u32, reserved: *mut ()) -> bool {
     match call_reason {
         DLL_PROCESS_ATTACH => attach(dll_module),
-        DLL_PROCESS_DETACH => detatch(),
+        //Reserved is non null when the process is exiting.
+        DLL_PROCESS_DETACH if reserved.is_null() => detatch(),
 
         _ => (),
     }
@@ -54,167 +107,155 @@ extern "system" fn DllMain(dll_module: HINSTANCE, call_reason: u32, _: *mut ())
 }
 ///THE MAIN FUNCTION. It initializes everything needed.
 ///Ideally, all hooks are created here.
+#[cfg(windows)]
 pub fn attach(handle: HINSTANCE) {
     std::thread::spawn(move || {
         log::info!("Attaching to process");
-        enable_logging();
-
-        //Do this early
-        start_mmf_thread();
-
-        let (base, size) = get_base_addr_and_size();
-        let mainwindow_hwnd = get_mainwindow_hwnd().expect("Could not get the game's window.");
+        //Needed to unload ourselves if anything below fails.
+        unsafe { HANDLE_NO = handle.0 as u64 };
+        //Logging depends on the config, so its problems can only be logged afterwards.
+        let config_problems = load_config();
+        if let Err(e) = enable_logging() {
+            log::error!("{}", e);
+        }
+        for problem in config_problems {
+            log::error!("{}", problem);
+        }
 
-        if base == 0 || size == 0 {
-            log::error!(
-                "Could not get the module base/size. Base: {} Size: {}",
-                base,
-                size
-            );
-            unsafe { FreeLibraryAndExitThread(HINSTANCE { 0: handle.0 }, 0) };
+        if let Err(e) = initialize() {
+            log::error!("Could not attach, unloading: {}", e);
+            if !TEARING_DOWN.swap(true, Ordering::SeqCst) {
+                unload_now();
+            }
         }
+    });
+}
 
-        let address_finder = AddressFinder {
-            base_addr: base,
-            module_size: size,
-        };
+#[cfg(windows)]
+fn initialize() -> Result<(), AttachError> {
+    //Do this early
+    start_mmf_thread().map_err(AttachError::SharedObjects)?;
 
-        let present_addr = address_finder.find_addr_present();
+    let (base, size) = get_base_addr_and_size();
+    if base == 0 || size == 0 {
+        return Err(AttachError::ModuleInfo { base, size });
+    }
+    //The DLL can be injected before the game created its window.
+    let mainwindow_hwnd = retry(WINDOW_TIMEOUT, WINDOW_RETRY_INTERVAL, || {
+        get_mainwindow_hwnd().ok_or(AttachError::NoWindow)
+    })?;
 
-        if present_addr == 0 {
-            log::error!("Could not find the address of DirectX11 Present.");
-            unsafe { FreeLibraryAndExitThread(HINSTANCE { 0: handle.0 }, 0) };
-        }
+    let address_finder = AddressFinder {
+        base_addr: base,
+        module_size: size,
+    };
 
-        unsafe {
-            present_hook
-                .initialize(
-                    mem::transmute(present_addr as *const ()),
-                    ui::get_detoured_present(),
-                )
-                .unwrap()
-                .enable()
-                .unwrap();
-        }
+    let present_addr = address_finder.find_addr_present();
 
-        unsafe { HANDLE_NO = handle.0 as u64 };
+    if present_addr == 0 {
+        return Err(AttachError::PresentNotFound);
+    }
 
-        start_statistics_server();
-        init_keybinds();
+    unsafe {
+        present_hook
+            .initialize(
+                mem::transmute::<*const (), PresentFn>(present_addr as *const ()),
+                ui::get_detoured_present(),
+            )
+            .and_then(|hook| hook.enable())
+            .map_err(|e| AttachError::Hook(e.to_string()))?;
+    }
 
-        //MUST BE CALLED IN THIS ORDER
-        start_mouse_input_thread();
-        initialize_controls(mainwindow_hwnd);
-    });
+    //Debugging aids, the overlay works without them.
+    if let Err(e) = start_statistics_server() {
+        log::error!("Could not start the statistics server: {}", e);
+    }
+    if let Err(e) = start_debug_panel() {
+        log::error!("Could not start the debug panel: {}", e);
+    }
+    init_keybinds();
+
+    //MUST BE CALLED IN THIS ORDER
+    start_input_thread().map_err(AttachError::Socket)?;
+    initialize_controls(mainwindow_hwnd);
+    Ok(())
 }
 
+///Safe under the loader lock: never waits on other threads. Threads still running our code
+///crash once the DLL is unmapped, prefer unload().
+#[cfg(windows)]
 pub fn detatch() {
+    if TEARING_DOWN.swap(true, Ordering::SeqCst) {
+        return;
+    }
     log::info!("Detatching from process");
-    unsafe {
-        present_hook.disable().unwrap();
+    request_shutdown();
+    withdraw_discovery_record();
+    if present_hook.is_enabled() {
+        unsafe { present_hook.disable() }.ok();
+    }
+    //If the window has been closed, we don't care about restoring wnd proc.
+    if let Some(hwnd) = get_mainwindow_hwnd().filter(|_| is_wnd_proc_replaced()) {
+        restore_wnd_proc(hwnd);
+    }
+}
 
-        if let Some(hwnd) = get_mainwindow_hwnd() {
-            restore_wnd_proc(hwnd);
-        } else {
-            //The window has been closed, we don't care about restoring wnd proc in that case.
-        }
+///Undoes everything attach() did, then frees the library. Returns right away: the teardown
+///waits for wnd_proc and present to return, so it can't run on the thread calling them.
+#[cfg(windows)]
+pub fn unload() {
+    if TEARING_DOWN.swap(true, Ordering::SeqCst) {
+        return;
     }
+    std::thread::spawn(unload_now);
 }
-pub fn enable_logging() {
-    let file = {
-        let logs_dir = PathBuf::from("addons/LOADER_public/logs");
-        cleanup_old_logs(&logs_dir);
-        create_dir_all(&logs_dir).expect("Failed to create logs directory");
-
-        let filename = format!("dll-{}.log", Local::now().format("%Y-%m-%d_%H-%M-%S"));
-        let filepath = logs_dir.join(filename);
-
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(filepath)
-            .expect("Failed to open log file")
-    };
 
-    //Init Fern
-    Dispatch::new()
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
-        .chain(file)
-        .format(|out, message, record| {
-            let now = Local::now();
-            let format = if record.level() == log::Level::Error {
-                format_args!(
-                    "[{}] [external-dx11-overlay] [{}] [{}:{}] {}",
-                    now.format("%Y-%m-%d %H:%M:%S"),
-                    record.level(),
-                    record.file().unwrap_or("<unknown>"),
-                    record.line().unwrap_or(0),
-                    message
-                )
-            } else {
-                format_args!(
-                    "[{}] [external-dx11-overlay] [{}] {}",
-                    now.format("%Y-%m-%d %H:%M:%S"),
-                    record.level(),
-                    message
-                )
-            };
-            //add_to_debug_log_overlay(format.to_string());
-            out.finish(format);
-        })
-        .apply()
-        .ok();
-
-    //Panic hook
-    std::panic::set_hook(Box::new(|panic_info| {
-        let payload = panic_info
-            .payload()
-            .downcast_ref::<&str>()
-            .copied()
-            .or_else(|| {
-                panic_info
-                    .payload()
-                    .downcast_ref::<String>()
-                    .map(|s| s.as_str())
-            })
-            .unwrap_or("Unknown panic");
-
-        let location = panic_info
-            .location()
-            .map(|l| format!("{}:{}", l.file(), l.line()))
-            .unwrap_or_else(|| "unknown location".to_string());
-        log::error!("PANIC at {}: {}", location, payload);
-    }));
-
-    log::info!(
-        "---------------------------------------- New Session ----------------------------------------------"
-    );
+//Never returns if the library could be freed.
+#[cfg(windows)]
+fn unload_now() {
+    if !teardown() {
+        log::error!("Keeping the DLL loaded, some of its code may still be running.");
+        return;
+    }
+    log::info!("Unloading.");
+    unsafe { FreeLibraryAndExitThread(HINSTANCE(HANDLE_NO as isize), 0) };
 }
 
-///Cleans up logs older than 24h
-pub fn cleanup_old_logs(dir: &Path) {
-    let cutoff = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64
-        - 24 * 3600; //-24h
-
-    if let Ok(readdir) = read_dir(dir) {
-        for entry in readdir.flatten() {
-            let name = entry.file_name();
-            let name = name.to_string_lossy();
-
-            if let Some((_pfx, date_str)) = name.split_once('-') {
-                if date_str.ends_with(".log") {
-                    let date_str = &date_str[..date_str.len() - 4];
-                    if let Ok(dt) = NaiveDateTime::parse_from_str(date_str, "%Y-%m-%d_%H-%M-%S") {
-                        if dt.and_utc().timestamp() < cutoff {
-                            remove_file(entry.path()).ok();
-                        }
-                    }
-                }
-            }
-        }
+//Reverse order of attach(). False if some of our code may still run afterwards.
+#[cfg(windows)]
+fn teardown() -> bool {
+    log::info!("Tearing down");
+    //Every worker starts winding down on its own.
+    request_shutdown();
+    let mut clean = true;
+
+    //Not replaced yet if attaching failed.
+    if let Some(hwnd) = get_mainwindow_hwnd().filter(|_| is_wnd_proc_replaced()) {
+        restore_wnd_proc(hwnd);
     }
+    if !WNDPROC_CALLS.wait_idle(TEARDOWN_TIMEOUT) {
+        log::error!("wnd_proc did not return in time.");
+        clean = false;
+    }
+    stop_udp_threads();
+    stop_statistics_server();
+
+    let disabled = if present_hook.is_enabled() {
+        unsafe { present_hook.disable() }
+    } else {
+        Ok(())
+    };
+    if let Err(e) = disabled {
+        log::error!("Could not disable the present hook: {}", e);
+        clean = false;
+    }
+    if !PRESENT_CALLS.wait_idle(TEARDOWN_TIMEOUT) {
+        log::error!("present did not return in time.");
+        clean = false;
+    }
+    release_overlay_state();
+
+    //The MMF thread withdraws our discovery record and closes the shared objects on its way out.
+    clean &= join_workers(TEARDOWN_TIMEOUT);
+    clean
 }
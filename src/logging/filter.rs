@@ -0,0 +1,245 @@
+use log::LevelFilter;
+
+/*
+ *
+ * Level filter per module. Plain Rust, no windows.
+ *
+ * Modules are written like in the code, without the crate name: "ui::mmf" covers ui::mmf and
+ * everything under it. The most specific module wins, anything else gets the default level.
+ * Records from other crates are matched with their full target.
+ *
+ * In the config: modules = "ui::mmf=trace, udp=warn"
+ *
+ * */
+
+const CRATE_PREFIX: &str = "external_dx11_overlay";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleLevels {
+    pub default: LevelFilter,
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl ModuleLevels {
+    pub const fn new(default: LevelFilter) -> ModuleLevels {
+        ModuleLevels {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    ///Level of the most specific module covering target, a module path like log's targets.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let target = strip_crate(target);
+        self.modules
+            .iter()
+            .filter(|(module, _)| covers(module, target))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    pub fn enabled(&self, target: &str, level: log::Level) -> bool {
+        level <= self.level_for(target)
+    }
+
+    ///The most verbose level of all, for log::set_max_level.
+    pub fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+
+    ///Overrides the level of a module, None gives it the default back.
+    pub fn set(&mut self, module: &str, level: Option<LevelFilter>) {
+        let module = strip_crate(module);
+        self.modules.retain(|(m, _)| m != module);
+        if let Some(level) = level {
+            self.modules.push((module.to_string(), level));
+        }
+    }
+}
+
+//"external_dx11_overlay::ui::mmf" -> "ui::mmf", the crate itself -> "".
+fn strip_crate(target: &str) -> &str {
+    match target.strip_prefix(CRATE_PREFIX) {
+        Some("") => "",
+        Some(rest) => rest.strip_prefix("::").unwrap_or(target),
+        None => target,
+    }
+}
+
+//Only at "::" boundaries, "ui" doesn't cover "udp" nor "uix".
+fn covers(module: &str, target: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => module.is_empty() || rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+///Whether module is target or one of its parents. Both may have the crate name or not.
+pub fn module_covers(module: &str, target: &str) -> bool {
+    covers(strip_crate(module), strip_crate(target))
+}
+
+///Reads "module=level" pairs separated by commas.
+pub fn parse_module_levels(text: &str) -> Result<Vec<(String, LevelFilter)>, String> {
+    let mut modules = Vec::new();
+    for pair in text.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let Some((module, level)) = pair.split_once('=') else {
+            return Err(format!("\"{}\" should be module=level", pair));
+        };
+        let (module, level) = (strip_crate(module.trim()), level.trim());
+        if module.is_empty()
+            || !module.split("::").all(|part| {
+                !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            })
+        {
+            return Err(format!("invalid module \"{}\"", module));
+        }
+        let level = level.parse().map_err(|_| {
+            format!(
+                "level \"{}\" of {} should be off, error, warn, info, debug or trace",
+                level, module
+            )
+        })?;
+        modules.push((module.to_string(), level));
+    }
+    Ok(modules)
+}
+
+///Inverse of parse_module_levels.
+pub fn format_module_levels(modules: &[(String, LevelFilter)]) -> String {
+    modules
+        .iter()
+        .map(|(module, level)| format!("{}={}", module, level.to_string().to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(modules: &[(&str, LevelFilter)]) -> ModuleLevels {
+        ModuleLevels {
+            default: LevelFilter::Info,
+            modules: modules.iter().map(|(m, l)| (m.to_string(), *l)).collect(),
+        }
+    }
+
+    #[test]
+    fn modules_cover_their_children_only() {
+        assert!(covers("ui", "ui"));
+        assert!(covers("ui", "ui::mmf"));
+        assert!(covers("ui", "ui::mmf::header"));
+        assert!(!covers("ui", "udp"));
+        assert!(!covers("ui", "uix"));
+        assert!(!covers("ui::mmf", "ui"));
+        assert!(!covers("ui", "controls::ui"));
+        //The crate itself covers everything of it.
+        assert!(covers("", "ui::mmf"));
+    }
+
+    #[test]
+    fn the_crate_name_is_stripped() {
+        assert_eq!(strip_crate("external_dx11_overlay::ui::mmf"), "ui::mmf");
+        assert_eq!(strip_crate("external_dx11_overlay"), "");
+        assert_eq!(
+            strip_crate("external_dx11_overlayx::ui"),
+            "external_dx11_overlayx::ui"
+        );
+        assert_eq!(strip_crate("fern::meta"), "fern::meta");
+        assert!(module_covers("external_dx11_overlay::ui", "ui::mmf"));
+        assert!(module_covers("ui", "external_dx11_overlay::ui::mmf"));
+        assert!(!module_covers("ui", "external_dx11_overlay::udp"));
+    }
+
+    #[test]
+    fn the_most_specific_module_wins() {
+        let levels = levels(&[
+            ("ui", LevelFilter::Debug),
+            ("ui::mmf", LevelFilter::Trace),
+            ("udp", LevelFilter::Warn),
+            ("fern", LevelFilter::Off),
+        ]);
+        let level = |target| levels.level_for(target);
+        assert_eq!(level("external_dx11_overlay::ui"), LevelFilter::Debug);
+        assert_eq!(
+            level("external_dx11_overlay::ui::rendering"),
+            LevelFilter::Debug
+        );
+        assert_eq!(level("external_dx11_overlay::ui::mmf"), LevelFilter::Trace);
+        assert_eq!(
+            level("external_dx11_overlay::ui::mmf::x"),
+            LevelFilter::Trace
+        );
+        assert_eq!(level("external_dx11_overlay::udp"), LevelFilter::Warn);
+        assert_eq!(level("external_dx11_overlay::uix"), LevelFilter::Info);
+        assert_eq!(level("external_dx11_overlay"), LevelFilter::Info);
+        //Other crates by their full target.
+        assert_eq!(level("fern::meta"), LevelFilter::Off);
+        assert_eq!(level("chrono"), LevelFilter::Info);
+
+        assert!(levels.enabled("external_dx11_overlay::ui::mmf", log::Level::Trace));
+        assert!(!levels.enabled("external_dx11_overlay::udp", log::Level::Info));
+        assert!(levels.enabled("external_dx11_overlay::udp", log::Level::Warn));
+    }
+
+    #[test]
+    fn max_is_the_most_verbose() {
+        assert_eq!(levels(&[]).max(), LevelFilter::Info);
+        assert_eq!(
+            levels(&[("udp", LevelFilter::Off)]).max(),
+            LevelFilter::Info
+        );
+        assert_eq!(
+            levels(&[("udp", LevelFilter::Off), ("ui", LevelFilter::Trace)]).max(),
+            LevelFilter::Trace
+        );
+    }
+
+    #[test]
+    fn set_replaces_and_removes() {
+        let mut levels = levels(&[("ui", LevelFilter::Debug)]);
+        levels.set("external_dx11_overlay::ui", Some(LevelFilter::Trace));
+        assert_eq!(levels.modules, [("ui".to_string(), LevelFilter::Trace)]);
+        levels.set("udp", None);
+        assert_eq!(levels.modules.len(), 1);
+        levels.set("ui", None);
+        assert_eq!(levels.modules, []);
+    }
+
+    #[test]
+    fn module_levels_round_trip() {
+        let modules =
+            parse_module_levels(" ui::mmf=trace,external_dx11_overlay::udp = WARN, ,").unwrap();
+        assert_eq!(
+            modules,
+            [
+                ("ui::mmf".to_string(), LevelFilter::Trace),
+                ("udp".to_string(), LevelFilter::Warn),
+            ]
+        );
+        assert_eq!(format_module_levels(&modules), "ui::mmf=trace, udp=warn");
+        assert_eq!(
+            parse_module_levels(&format_module_levels(&modules)),
+            Ok(modules)
+        );
+        assert_eq!(parse_module_levels(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn bad_module_levels() {
+        for bad in [
+            "ui",
+            "=trace",
+            "ui=loud",
+            "ui::=trace",
+            "u i=trace",
+            "ui-x=trace",
+        ] {
+            assert!(parse_module_levels(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt::{self, Write};
+
+//Writing JSON by hand, it's only ever small objects of strings, numbers and arrays.
+
+///Quoted and escaped JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(out, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+///Array of values that are already JSON.
+pub fn json_array(values: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+///Object built field by field, in order.
+#[derive(Default)]
+pub struct JsonObject {
+    fields: Vec<String>,
+}
+
+impl JsonObject {
+    pub fn new() -> JsonObject {
+        JsonObject::default()
+    }
+
+    ///Field whose value is already JSON.
+    pub fn raw(mut self, key: &str, value: impl fmt::Display) -> JsonObject {
+        self.fields.push(format!("{}:{}", json_string(key), value));
+        self
+    }
+
+    pub fn string(self, key: &str, value: &str) -> JsonObject {
+        self.raw(key, json_string(value))
+    }
+
+    ///One field per line, for files meant to be read by people.
+    pub fn finish(self) -> String {
+        format!("{{\n{}\n}}", self.fields.join(",\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("a\"b\\c"), r#""a\"b\\c""#);
+        assert_eq!(json_string("\n\r\t"), r#""\n\r\t""#);
+        assert_eq!(json_string("\x00\x1f\x7f"), "\"\\u0000\\u001f\x7f\"");
+        assert_eq!(json_string("é ✓"), "\"é ✓\"");
+    }
+
+    #[test]
+    fn strings_round_trip_through_serde_json() {
+        let all_controls: String = (0..0x20).map(|c| char::from_u32(c).unwrap()).collect();
+        for s in [
+            "",
+            "\"quoted\" and \\back\\slashed\\",
+            "C:\\path\\to\\file.log",
+            "line\nbreak\r\n\ttab",
+            "\x00\x01\x08\x0b\x0c\x1b[31m\x7f",
+            "é ✓ 😀",
+            &all_controls,
+        ] {
+            let parsed: String = serde_json::from_str(&json_string(s)).unwrap();
+            assert_eq!(parsed, s);
+        }
+    }
+
+    #[test]
+    fn objects_and_arrays_parse() {
+        let text = JsonObject::new()
+            .string("message", "a \"b\"\n")
+            .raw("count", 3)
+            .raw("list", json_array(["1".to_string(), json_string("x")]))
+            .raw("empty", json_array([]))
+            .raw("nested", JsonObject::new().raw("ok", true).finish())
+            .finish();
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(
+            parsed,
+            json!({
+                "message": "a \"b\"\n",
+                "count": 3,
+                "list": [1, "x"],
+                "empty": [],
+                "nested": { "ok": true },
+            })
+        );
+        assert_eq!(JsonObject::new().finish(), "{\n\n}");
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    fmt, fs,
+    path::PathBuf,
+    sync::{RwLock, atomic::Ordering},
+};
+
+use chrono::{Local, SecondsFormat};
+use fern::{Dispatch, FormatCallback, Output};
+use log::{LevelFilter, Metadata, Record};
+
+use filter::ModuleLevels;
+use json::json_string;
+use rotate::{Retention, RotatingFile};
+
+use crate::{
+    attach::AttachError,
+    config::{CONFIG_PATH, LoggingConfig, config, parse_config},
+    debug::crash::write_crash_bundle,
+    ui::FRAME_NUMBER,
+};
+
+pub mod filter;
+pub mod json;
+pub mod ring;
+pub mod rotate;
+
+/*
+ *
+ * Logging of the DLL, set up from the [logging] section of the config.
+ *
+ * Records go through the level filter of their module (see filter), then to:
+ *  - stdout and dll-<time>.log, as text,
+ *  - dll-<time>.jsonl if enabled, one JSON object per record with its module, thread and the
+ *    number of the frame being presented,
+ *  - the ring of the last records, see ring.
+ *
+ * Both files rotate by size and are pruned by count and age, see rotate.
+ * The levels can be changed at runtime with set_level and set_module_level. The reload_log_levels
+ * keybind action applies the levels of the config file again, the other settings need a restart.
+ *
+ * */
+
+const LOG_PREFIX: &str = "dll";
+
+//Until enable_logging, everything at Debug like before the config existed.
+static LEVELS: RwLock<ModuleLevels> = RwLock::new(ModuleLevels::new(LevelFilter::Debug));
+
+///Logs to stdout and new files in the logs directory. If a file can't be opened, logging
+///still goes to the other outputs and the error is returned once the logger works.
+pub fn enable_logging() -> Result<(), AttachError> {
+    let logging = &config().logging;
+    set_levels(ModuleLevels {
+        default: logging.level,
+        modules: logging.modules.clone(),
+    });
+
+    let dir = PathBuf::from(&config().paths.logs_dir);
+    let retention = Retention {
+        hours: logging.retention_hours,
+        max_files: logging.max_files,
+    };
+    let max_bytes = logging.max_file_kb * 1024;
+    let mut problem = None;
+    let mut open = |extension: &'static str| match RotatingFile::create(
+        &dir, LOG_PREFIX, extension, max_bytes, retention,
+    ) {
+        Ok(file) => Some(Output::writer(Box::new(file), "\n")),
+        Err(e) => {
+            problem.get_or_insert(e);
+            None
+        }
+    };
+
+    let mut text = Dispatch::new().format(text_record).chain(std::io::stdout());
+    if let Some(file) = open("log") {
+        text = text.chain(file);
+    }
+    //Levels are only checked here, so changing them applies to every output.
+    let mut root = Dispatch::new()
+        .level(LevelFilter::Trace)
+        .filter(enabled)
+        .chain(Output::call(ring::push_record))
+        .chain(text);
+    let json = if logging.json { open("jsonl") } else { None };
+    if let Some(file) = json {
+        root = root.chain(Dispatch::new().format(json_record).chain(file));
+    }
+    root.apply().ok();
+    //apply() lets everything through, only ask for what some module wants.
+    log::set_max_level(LEVELS.read().unwrap().max());
+
+    //Panic hook, also writes a crash report. See debug::crash.
+    std::panic::set_hook(Box::new(|panic_info| {
+        let payload = panic_info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| {
+                panic_info
+                    .payload()
+                    .downcast_ref::<String>()
+                    .map(|s| s.as_str())
+            })
+            .unwrap_or("Unknown panic");
+
+        let location = panic_info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_else(|| "unknown location".to_string());
+        log::error!("PANIC at {}: {}", location, payload);
+        match write_crash_bundle(payload, &location) {
+            Ok(Some(path)) => log::error!("Crash report written to {}", path.display()),
+            Ok(None) => {}
+            Err(e) => log::error!("Could not write the crash report: {}", e),
+        }
+    }));
+
+    log::info!(
+        "---------------------------------------- New Session ----------------------------------------------"
+    );
+    problem.map_or(Ok(()), |e| Err(AttachError::Logging(e)))
+}
+
+fn enabled(metadata: &Metadata) -> bool {
+    LEVELS
+        .read()
+        .unwrap()
+        .enabled(metadata.target(), metadata.level())
+}
+
+///Current levels.
+pub fn levels() -> ModuleLevels {
+    LEVELS.read().unwrap().clone()
+}
+
+///Replaces every level, takes effect right away.
+pub fn set_levels(levels: ModuleLevels) {
+    let mut current = LEVELS.write().unwrap();
+    *current = levels;
+    log::set_max_level(current.max());
+}
+
+///Changes the level of modules without their own.
+pub fn set_level(level: LevelFilter) {
+    let mut levels = levels();
+    levels.default = level;
+    set_levels(levels);
+}
+
+///Changes the level of a module and everything under it, None gives it the default back.
+pub fn set_module_level(module: &str, level: Option<LevelFilter>) {
+    let mut levels = levels();
+    levels.set(module, level);
+    set_levels(levels);
+}
+
+///Reads the config file again and applies the levels of its [logging] section.
+pub fn reload_log_levels() {
+    let text = match fs::read_to_string(CONFIG_PATH) {
+        Ok(text) => text,
+        Err(e) => {
+            log::error!(
+                "Failed to read {}, keeping the log levels: {}",
+                CONFIG_PATH,
+                e
+            );
+            return;
+        }
+    };
+    let (config, diagnostics) = parse_config(&text);
+    for diagnostic in &diagnostics {
+        log::warn!("{} {}, ignored.", CONFIG_PATH, diagnostic);
+    }
+    apply_log_levels(&config.logging);
+}
+
+//Only changes what differs, and logs each change.
+fn apply_log_levels(logging: &LoggingConfig) {
+    let current = levels();
+    if current.default != logging.level {
+        set_level(logging.level);
+        log::info!("Log level set to {}.", logging.level);
+    }
+    for (module, _) in &current.modules {
+        if !logging.modules.iter().any(|(m, _)| m == module) {
+            set_module_level(module, None);
+            log::info!("Log level of {} set back to the default.", module);
+        }
+    }
+    for (module, level) in &logging.modules {
+        if !current.modules.contains(&(module.clone(), *level)) {
+            set_module_level(module, Some(*level));
+            log::info!("Log level of {} set to {}.", module, level);
+        }
+    }
+}
+
+fn text_record(out: FormatCallback, message: &fmt::Arguments, record: &Record) {
+    let now = Local::now();
+    let format = if record.level() == log::Level::Error {
+        format_args!(
+            "[{}] [external-dx11-overlay] [{}] [{}:{}] {}",
+            now.format("%Y-%m-%d %H:%M:%S"),
+            record.level(),
+            record.file().unwrap_or("<unknown>"),
+            record.line().unwrap_or(0),
+            message
+        )
+    } else {
+        format_args!(
+            "[{}] [external-dx11-overlay] [{}] {}",
+            now.format("%Y-%m-%d %H:%M:%S"),
+            record.level(),
+            message
+        )
+    };
+    out.finish(format);
+}
+
+///Name of the current thread, its id if it has none.
+pub fn current_thread_name() -> String {
+    let thread = std::thread::current();
+    match thread.name() {
+        Some(name) => name.to_string(),
+        None => format!("{:?}", thread.id()),
+    }
+}
+
+fn json_record(out: FormatCallback, message: &fmt::Arguments, record: &Record) {
+    let line = record
+        .line()
+        .map_or("null".to_string(), |line| line.to_string());
+    out.finish(format_args!(
+        "{{\"time\":{},\"level\":{},\"module\":{},\"thread\":{},\"frame\":{},\"file\":{},\"line\":{},\"message\":{}}}",
+        json_string(&Local::now().to_rfc3339_opts(SecondsFormat::Millis, false)),
+        json_string(record.level().as_str()),
+        json_string(record.target()),
+        json_string(&current_thread_name()),
+        FRAME_NUMBER.load(Ordering::Relaxed),
+        json_string(record.file().unwrap_or("<unknown>")),
+        line,
+        json_string(&message.to_string()),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, MetadataBuilder};
+    use std::sync::{Mutex, PoisonError};
+
+    //The levels are global, one test at a time, and put them back after.
+    static LEVELS_LOCK: Mutex<()> = Mutex::new(());
+
+    fn with_levels(levels: ModuleLevels, test: impl FnOnce()) {
+        let _lock = LEVELS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = self::levels();
+        set_levels(levels);
+        test();
+        set_levels(before);
+    }
+
+    fn passes(target: &str, level: Level) -> bool {
+        enabled(&MetadataBuilder::new().target(target).level(level).build())
+    }
+
+    #[test]
+    fn module_levels_change_the_max_level_and_filtering() {
+        with_levels(ModuleLevels::new(LevelFilter::Info), || {
+            assert_eq!(log::max_level(), LevelFilter::Info);
+            assert!(!passes("external_dx11_overlay::ui::mmf", Level::Debug));
+
+            set_module_level("ui", Some(LevelFilter::Trace));
+            assert_eq!(log::max_level(), LevelFilter::Trace);
+            assert!(passes("external_dx11_overlay::ui::mmf", Level::Trace));
+            assert!(!passes("external_dx11_overlay::udp", Level::Debug));
+
+            set_module_level("external_dx11_overlay::ui", None);
+            assert_eq!(log::max_level(), LevelFilter::Info);
+            assert!(!passes("external_dx11_overlay::ui::mmf", Level::Debug));
+
+            set_level(LevelFilter::Warn);
+            assert_eq!(log::max_level(), LevelFilter::Warn);
+            assert!(!passes("external_dx11_overlay::udp", Level::Info));
+        });
+    }
+
+    #[test]
+    fn reloading_applies_the_config_levels() {
+        let current = ModuleLevels {
+            default: LevelFilter::Info,
+            modules: vec![
+                ("ui".to_string(), LevelFilter::Trace),
+                ("udp".to_string(), LevelFilter::Warn),
+            ],
+        };
+        with_levels(current, || {
+            let logging = LoggingConfig {
+                level: LevelFilter::Debug,
+                modules: vec![
+                    ("udp".to_string(), LevelFilter::Warn),
+                    ("controls".to_string(), LevelFilter::Error),
+                ],
+                ..config().logging.clone()
+            };
+            apply_log_levels(&logging);
+            assert_eq!(
+                levels(),
+                ModuleLevels {
+                    default: LevelFilter::Debug,
+                    modules: logging.modules.clone(),
+                }
+            );
+            assert_eq!(log::max_level(), LevelFilter::Debug);
+        });
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    fmt,
+    sync::{
+        Arc, Mutex, PoisonError, TryLockError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use chrono::{DateTime, Local};
+use log::{Level, Record};
+
+use super::{current_thread_name, filter::module_covers};
+use crate::ui::FRAME_NUMBER;
+
+/*
+ *
+ * The last records logged, kept in memory for the debug overlay, crash reports and
+ * diagnostics. Plain Rust, no windows.
+ *
+ * Fed by its own sink of the root dispatch, so it holds what passed the level filter, before
+ * any formatting.
+ *
+ * Lock-light: every record gets a sequence number from an atomic counter, which also picks
+ * its slot. A writer only locks that one slot, long enough to swap an Arc in, so writers on
+ * different threads almost never meet. Readers try_lock the slots and skip the busy ones
+ * instead of waiting, which makes reading safe from the panic hook too.
+ *
+ * */
+
+pub const RING_CAPACITY: usize = 512;
+
+static RING: LogRing = LogRing::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    //Increases by one per record, never reused. Set by the ring.
+    pub seq: u64,
+    pub time: DateTime<Local>,
+    pub level: Level,
+    //Target of the record, the module path for the crate's own records.
+    pub module: String,
+    pub thread: String,
+    //Frame being presented when it was logged.
+    pub frame: u64,
+    pub message: String,
+}
+
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] [{}] [{}] [{}] {}",
+            self.time.format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.level,
+            self.module,
+            self.thread,
+            self.message
+        )
+    }
+}
+
+//What to read from the ring. The default returns everything.
+#[derive(Debug, Clone, Default)]
+pub struct RingQuery {
+    //Least severe level returned, None for all.
+    pub level: Option<Level>,
+    //Only this module and the ones under it, written like in the config.
+    pub module: Option<String>,
+    //Only records newer than this sequence number, to poll for new ones.
+    pub after: Option<u64>,
+    //Newest records kept once the others are filtered out. 0 keeps them all.
+    pub limit: usize,
+}
+
+impl RingQuery {
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        self.level.is_none_or(|level| entry.level <= level)
+            && self
+                .module
+                .as_ref()
+                .is_none_or(|module| module_covers(module, &entry.module))
+            && self.after.is_none_or(|after| entry.seq > after)
+    }
+}
+
+pub struct LogRing {
+    next: AtomicU64,
+    slots: [Mutex<Option<Arc<LogEntry>>>; RING_CAPACITY],
+}
+
+impl LogRing {
+    pub const fn new() -> LogRing {
+        LogRing {
+            next: AtomicU64::new(0),
+            slots: [const { Mutex::new(None) }; RING_CAPACITY],
+        }
+    }
+
+    ///Stores a record, overwriting the oldest once full. Returns its sequence number.
+    pub fn push(&self, mut entry: LogEntry) -> u64 {
+        let seq = self.next.fetch_add(1, Ordering::Relaxed);
+        entry.seq = seq;
+        let entry = Arc::new(entry);
+        let mut slot = self.slots[seq as usize % RING_CAPACITY]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        //A slow writer of an older record must not overwrite a newer one.
+        if slot.as_ref().is_none_or(|current| current.seq < seq) {
+            *slot = Some(entry);
+        }
+        seq
+    }
+
+    ///Matching records, oldest first. Records being written while this runs may be missing.
+    pub fn query(&self, query: &RingQuery) -> Vec<Arc<LogEntry>> {
+        let mut entries: Vec<Arc<LogEntry>> = self
+            .slots
+            .iter()
+            .filter_map(|slot| match slot.try_lock() {
+                Ok(slot) => slot.clone(),
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner().clone(),
+                Err(TryLockError::WouldBlock) => None,
+            })
+            .filter(|entry| query.matches(entry))
+            .collect();
+        entries.sort_by_key(|entry| entry.seq);
+        if query.limit != 0 && entries.len() > query.limit {
+            entries.drain(..entries.len() - query.limit);
+        }
+        entries
+    }
+
+    ///Sequence number the next record will get.
+    pub fn next_seq(&self) -> u64 {
+        self.next.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for LogRing {
+    fn default() -> Self {
+        LogRing::new()
+    }
+}
+
+///The ring fed by the logger.
+pub fn ring() -> &'static LogRing {
+    &RING
+}
+
+///Sink of the root dispatch.
+pub fn push_record(record: &Record) {
+    RING.push(LogEntry {
+        seq: 0,
+        time: Local::now(),
+        level: record.level(),
+        module: record.target().to_string(),
+        thread: current_thread_name(),
+        frame: FRAME_NUMBER.load(Ordering::Relaxed),
+        message: record.args().to_string(),
+    });
+}
+
+///The last records of the logger, up to limit, of this level or more severe.
+pub fn recent(level: Option<Level>, limit: usize) -> Vec<Arc<LogEntry>> {
+    RING.query(&RingQuery {
+        level,
+        limit,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(level: Level, module: &str, message: &str) -> LogEntry {
+        LogEntry {
+            seq: 0,
+            time: Local::now(),
+            level,
+            module: module.to_string(),
+            thread: "main".to_string(),
+            frame: 0,
+            message: message.to_string(),
+        }
+    }
+
+    fn messages(entries: &[Arc<LogEntry>]) -> Vec<&str> {
+        entries.iter().map(|e| e.message.as_str()).collect()
+    }
+
+    fn seqs(entries: &[Arc<LogEntry>]) -> Vec<u64> {
+        entries.iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn the_oldest_records_are_overwritten() {
+        let ring = LogRing::new();
+        let total = RING_CAPACITY as u64 * 2 + 10;
+        for i in 0..total {
+            assert_eq!(ring.push(entry(Level::Info, "ui", &i.to_string())), i);
+        }
+        assert_eq!(ring.next_seq(), total);
+
+        let entries = ring.query(&RingQuery::default());
+        assert_eq!(entries.len(), RING_CAPACITY);
+        //The newest ones, oldest first, without gaps.
+        let first = total - RING_CAPACITY as u64;
+        assert_eq!(seqs(&entries), (first..total).collect::<Vec<_>>());
+        assert_eq!(entries[0].message, first.to_string());
+    }
+
+    #[test]
+    fn queries_filter() {
+        let ring = LogRing::new();
+        ring.push(entry(Level::Error, "external_dx11_overlay::ui", "ui error"));
+        ring.push(entry(
+            Level::Debug,
+            "external_dx11_overlay::ui::mmf",
+            "mmf debug",
+        ));
+        ring.push(entry(Level::Warn, "external_dx11_overlay::udp", "udp warn"));
+        ring.push(entry(Level::Info, "external_dx11_overlay::uix", "uix info"));
+        ring.push(entry(Level::Trace, "fern", "fern trace"));
+        let query = |query: RingQuery| messages(&ring.query(&query)).join(", ");
+
+        assert_eq!(
+            query(RingQuery {
+                level: Some(Level::Warn),
+                ..Default::default()
+            }),
+            "ui error, udp warn"
+        );
+        //Only at module boundaries.
+        assert_eq!(
+            query(RingQuery {
+                module: Some("ui".to_string()),
+                ..Default::default()
+            }),
+            "ui error, mmf debug"
+        );
+        assert_eq!(
+            query(RingQuery {
+                module: Some("external_dx11_overlay::udp".to_string()),
+                ..Default::default()
+            }),
+            "udp warn"
+        );
+        assert_eq!(
+            query(RingQuery {
+                after: Some(2),
+                ..Default::default()
+            }),
+            "uix info, fern trace"
+        );
+        assert_eq!(
+            query(RingQuery {
+                level: Some(Level::Debug),
+                module: Some("ui".to_string()),
+                after: Some(0),
+                limit: 5,
+            }),
+            "mmf debug"
+        );
+        assert_eq!(
+            query(RingQuery {
+                after: Some(4),
+                ..Default::default()
+            }),
+            ""
+        );
+    }
+
+    #[test]
+    fn limits_keep_the_newest_oldest_first() {
+        let ring = LogRing::new();
+        for i in 0..10 {
+            let level = if i % 2 == 0 { Level::Warn } else { Level::Info };
+            ring.push(entry(level, "ui", &i.to_string()));
+        }
+        let entries = ring.query(&RingQuery {
+            limit: 3,
+            ..Default::default()
+        });
+        assert_eq!(messages(&entries), ["7", "8", "9"]);
+        //Filtered before the limit.
+        let entries = ring.query(&RingQuery {
+            level: Some(Level::Warn),
+            limit: 3,
+            ..Default::default()
+        });
+        assert_eq!(messages(&entries), ["4", "6", "8"]);
+        let entries = ring.query(&RingQuery {
+            limit: 20,
+            ..Default::default()
+        });
+        assert_eq!(entries.len(), 10);
+    }
+
+    #[test]
+    fn concurrent_writers_lose_nothing() {
+        let ring = LogRing::new();
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let ring = &ring;
+                scope.spawn(move || {
+                    for i in 0..100 {
+                        ring.push(entry(Level::Info, "ui", &format!("{}-{}", thread, i)));
+                    }
+                });
+            }
+        });
+        let entries = ring.query(&RingQuery::default());
+        assert_eq!(seqs(&entries), (0..400).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn entries_display() {
+        let mut entry = entry(Level::Warn, "external_dx11_overlay::ui", "hello");
+        entry.time = DateTime::parse_from_rfc3339("2024-05-01T18:30:00.250Z")
+            .unwrap()
+            .with_timezone(&Local);
+        let text = entry.to_string();
+        assert!(
+            text.ends_with("[WARN] [external_dx11_overlay::ui] [main] hello"),
+            "{}",
+            text
+        );
+        assert!(text.contains(":00.250]"), "{}", text);
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    fs::{File, OpenOptions, create_dir_all, read_dir, remove_file},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{Local, NaiveDateTime};
+
+/*
+ *
+ * Log files. Plain Rust, no windows.
+ *
+ * Files are named <prefix>-<local time>[-<n>].<extension>, like dll-2024-05-01_18-30-00.log.
+ * The counter only shows up when several files are started in the same second.
+ *
+ * A RotatingFile starts a new file once the current one reaches max_bytes, then prunes the
+ * files of its kind: the oldest go beyond max_files, and any older than retention_hours.
+ * A record is whatever is written between two flushes, like fern does: it is kept until the
+ * flush, so it never gets split between two files.
+ *
+ * */
+
+const TIME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+//Length of a formatted TIME_FORMAT.
+const TIME_LEN: usize = 19;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    //0 keeps them forever.
+    pub hours: u64,
+    //Files of the same kind to keep, the current one included. 0 keeps them all.
+    pub max_files: u64,
+}
+
+pub struct RotatingFile {
+    dir: PathBuf,
+    prefix: &'static str,
+    extension: &'static str,
+    //0 never rotates.
+    max_bytes: u64,
+    retention: Retention,
+    file: File,
+    written: u64,
+    //The record being written, until the next flush.
+    record: Vec<u8>,
+}
+
+impl RotatingFile {
+    ///Prunes the old files of this kind and starts a new one.
+    pub fn create(
+        dir: &Path,
+        prefix: &'static str,
+        extension: &'static str,
+        max_bytes: u64,
+        retention: Retention,
+    ) -> io::Result<RotatingFile> {
+        create_dir_all(dir)?;
+        //Room for the one about to be created.
+        prune_logs(dir, prefix, extension, retention, 1);
+        Ok(RotatingFile {
+            dir: dir.to_path_buf(),
+            prefix,
+            extension,
+            max_bytes,
+            retention,
+            file: new_file(dir, prefix, extension)?.0,
+            written: 0,
+            record: Vec::new(),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        prune_logs(&self.dir, self.prefix, self.extension, self.retention, 1);
+        self.file = new_file(&self.dir, self.prefix, self.extension)?.0;
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write_record(&mut self) -> io::Result<()> {
+        let len = self.record.len() as u64;
+        //A record never causes an empty file, even if it's bigger than max_bytes.
+        if len != 0
+            && self.max_bytes != 0
+            && self.written != 0
+            && self.written + len > self.max_bytes
+        {
+            self.rotate()?;
+        }
+        self.file.write_all(&self.record)?;
+        self.written += len;
+        self.file.flush()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.write_record();
+        //Dropped even if it failed, it would only fail again with the next ones.
+        self.record.clear();
+        result
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+///Creates a file named after the current time, which no other file has. Counters only go up
+///within a second, even if the first files of that second were pruned already.
+pub fn new_file(dir: &Path, prefix: &str, extension: &str) -> io::Result<(File, PathBuf)> {
+    let now = Local::now();
+    let time = now.format(TIME_FORMAT).to_string();
+    let first = read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| parse_log_name(&entry.file_name().to_string_lossy(), prefix, extension))
+        .filter(|(file_time, _)| file_time.format(TIME_FORMAT).to_string() == time)
+        .map(|(_, counter)| counter + 1)
+        .max()
+        .unwrap_or(0);
+    for n in first.. {
+        let name = match n {
+            0 => format!("{}-{}.{}", prefix, time, extension),
+            n => format!("{}-{}-{}.{}", prefix, time, n, extension),
+        };
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            result => return result.map(|file| (file, path)),
+        }
+    }
+    unreachable!()
+}
+
+///Time and counter of a log file of this kind, from its name. None for any other file.
+pub fn parse_log_name(name: &str, prefix: &str, extension: &str) -> Option<(NaiveDateTime, u32)> {
+    let rest = name
+        .strip_prefix(prefix)?
+        .strip_prefix('-')?
+        .strip_suffix(extension)?
+        .strip_suffix('.')?;
+    let time = NaiveDateTime::parse_from_str(rest.get(..TIME_LEN)?, TIME_FORMAT).ok()?;
+    let counter = match &rest[TIME_LEN..] {
+        "" => 0,
+        counter => counter.strip_prefix('-')?.parse().ok()?,
+    };
+    Some((time, counter))
+}
+
+///Deletes the files of this kind older than the retention, and the oldest ones until only
+///max_files - room are left.
+pub fn prune_logs(dir: &Path, prefix: &str, extension: &str, retention: Retention, room: u64) {
+    let Ok(readdir) = read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<_> = readdir
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let key = parse_log_name(&name.to_string_lossy(), prefix, extension)?;
+            Some((key, entry.path()))
+        })
+        .collect();
+    //Oldest first.
+    files.sort();
+
+    let now = Local::now().naive_local();
+    let too_many = match retention.max_files {
+        0 => 0,
+        max => (files.len() as u64 + room).saturating_sub(max) as usize,
+    };
+    for (i, ((time, _), path)) in files.iter().enumerate() {
+        let expired =
+            retention.hours != 0 && (now - *time).num_seconds() > retention.hours as i64 * 3600;
+        if i < too_many || expired {
+            remove_file(path).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use std::fs::{read_to_string, write};
+    use tempfile::TempDir;
+
+    const KEEP_ALL: Retention = Retention {
+        hours: 0,
+        max_files: 0,
+    };
+
+    fn name(time: NaiveDateTime, counter: u32) -> String {
+        match counter {
+            0 => format!("dll-{}.log", time.format(TIME_FORMAT)),
+            n => format!("dll-{}-{}.log", time.format(TIME_FORMAT), n),
+        }
+    }
+
+    fn at(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, TIME_FORMAT).unwrap()
+    }
+
+    //Names in the directory, sorted.
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    //Content of the log files, oldest first.
+    fn logs(dir: &Path) -> Vec<String> {
+        let mut files: Vec<_> = names(dir)
+            .into_iter()
+            .filter_map(|name| Some((parse_log_name(&name, "dll", "log")?, name)))
+            .collect();
+        files.sort();
+        files
+            .iter()
+            .map(|(_, name)| read_to_string(dir.join(name)).unwrap())
+            .collect()
+    }
+
+    //Runs test in a new directory until it fits in one second, the names depend on the clock.
+    fn within_a_second(test: impl Fn(&Path)) {
+        loop {
+            let dir = TempDir::new().unwrap();
+            let before = Local::now().format(TIME_FORMAT).to_string();
+            test(dir.path());
+            if Local::now().format(TIME_FORMAT).to_string() == before {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn log_names() {
+        let time = at("2024-05-01_18-30-00");
+        assert_eq!(
+            parse_log_name("dll-2024-05-01_18-30-00.log", "dll", "log"),
+            Some((time, 0))
+        );
+        assert_eq!(
+            parse_log_name("dll-2024-05-01_18-30-00-12.log", "dll", "log"),
+            Some((time, 12))
+        );
+        for other in [
+            "dll-2024-05-01_18-30-00.jsonl",
+            "crash-2024-05-01_18-30-00.log",
+            "dll-2024-05-01_18-30-00-.log",
+            "dll-2024-05-01_18-30-00-x.log",
+            "dll-2024-05-01_18-30-00_1.log",
+            "dll-2024-05-01.log",
+            "dll-2024-13-01_18-30-00.log",
+            "dll.log",
+            "dll-.log",
+            "notes.txt",
+        ] {
+            assert_eq!(parse_log_name(other, "dll", "log"), None, "{}", other);
+        }
+        assert_eq!(
+            parse_log_name(&name(time, 3), "dll", "log"),
+            Some((time, 3))
+        );
+    }
+
+    #[test]
+    fn names_in_the_same_second_get_counters() {
+        within_a_second(|dir| {
+            let paths: Vec<_> = (0..3)
+                .map(|_| new_file(dir, "dll", "log").unwrap().1)
+                .collect();
+            let counters: Vec<_> = paths
+                .iter()
+                .map(|path| {
+                    let name = path.file_name().unwrap().to_string_lossy();
+                    parse_log_name(&name, "dll", "log").unwrap().1
+                })
+                .collect();
+            assert_eq!(counters, [0, 1, 2]);
+
+            //Even once the first ones are gone.
+            remove_file(&paths[0]).unwrap();
+            remove_file(&paths[1]).unwrap();
+            let (_, path) = new_file(dir, "dll", "log").unwrap();
+            let name = path.file_name().unwrap().to_string_lossy();
+            assert_eq!(parse_log_name(&name, "dll", "log").unwrap().1, 3);
+            //Other kinds have their own.
+            let (_, path) = new_file(dir, "dll", "jsonl").unwrap();
+            let name = path.file_name().unwrap().to_string_lossy();
+            assert_eq!(parse_log_name(&name, "dll", "jsonl").unwrap().1, 0);
+        });
+    }
+
+    #[test]
+    fn prune_keeps_the_newest_with_room() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path();
+        let time = at("2024-05-01_18-30-00");
+        let old: Vec<_> = (0..5)
+            .map(|i| name(time + TimeDelta::seconds(i), 0))
+            .chain([name(time + TimeDelta::seconds(4), 1)])
+            .collect();
+        let others = [
+            "crash-2024-05-01_18-30-00.json",
+            "dll-2024-05-01_18-30-00.jsonl",
+            "notes.txt",
+        ];
+        for name in old.iter().map(String::as_str).chain(others) {
+            write(dir.join(name), "").unwrap();
+        }
+
+        let retention = Retention {
+            hours: 0,
+            max_files: 4,
+        };
+        //Room for one more, three are left.
+        prune_logs(dir, "dll", "log", retention, 1);
+        let mut expected: Vec<_> = old[3..].iter().map(String::as_str).chain(others).collect();
+        expected.sort();
+        assert_eq!(names(dir), expected);
+
+        //No room needed, nothing to do.
+        prune_logs(dir, "dll", "log", retention, 0);
+        assert_eq!(names(dir), expected);
+
+        prune_logs(dir, "dll", "log", KEEP_ALL, 1);
+        assert_eq!(names(dir), expected);
+    }
+
+    #[test]
+    fn prune_deletes_expired_files() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path();
+        let now = Local::now().naive_local();
+        let expired = name(now - TimeDelta::minutes(90), 0);
+        let recent = name(now - TimeDelta::minutes(30), 0);
+        let other = format!(
+            "crash-{}.json",
+            (now - TimeDelta::days(30)).format(TIME_FORMAT)
+        );
+        for name in [&expired, &recent, &other] {
+            write(dir.join(name), "").unwrap();
+        }
+
+        prune_logs(dir, "dll", "log", KEEP_ALL, 1);
+        assert_eq!(names(dir).len(), 3);
+        let retention = Retention {
+            hours: 1,
+            max_files: 0,
+        };
+        prune_logs(dir, "dll", "log", retention, 1);
+        let mut expected = vec![recent, other];
+        expected.sort();
+        assert_eq!(names(dir), expected);
+    }
+
+    #[test]
+    fn records_are_never_split() {
+        within_a_second(|dir| {
+            let mut file = RotatingFile::create(dir, "dll", "log", 10, KEEP_ALL).unwrap();
+            //Written in pieces like fern does, one flush per record.
+            let line_sep = "\n";
+            let mut record = |text: &str| {
+                write!(file, "{}{}", text, line_sep).unwrap();
+                file.flush().unwrap();
+            };
+            record("1234");
+            record("5678");
+            //Would go past 10 bytes.
+            record("abc");
+            //Bigger than a whole file, alone in its own.
+            record("a long record");
+            record("x");
+            assert_eq!(
+                logs(dir),
+                ["1234\n5678\n", "abc\n", "a long record\n", "x\n"]
+            );
+        });
+    }
+
+    #[test]
+    fn unflushed_records_are_written_on_drop() {
+        let dir = TempDir::new().unwrap();
+        let mut file = RotatingFile::create(dir.path(), "dll", "log", 0, KEEP_ALL).unwrap();
+        write!(file, "last words").unwrap();
+        assert_eq!(logs(dir.path()), [""]);
+        drop(file);
+        assert_eq!(logs(dir.path()), ["last words"]);
+    }
+
+    #[test]
+    fn rotation_prunes_to_max_files() {
+        within_a_second(|dir| {
+            let retention = Retention {
+                hours: 0,
+                max_files: 2,
+            };
+            let mut file = RotatingFile::create(dir, "dll", "log", 4, retention).unwrap();
+            for text in ["one\n", "two\n", "three\n", "four\n"] {
+                write!(file, "{}", text).unwrap();
+                file.flush().unwrap();
+            }
+            //The current file included.
+            assert_eq!(logs(dir), ["three\n", "four\n"]);
+        });
+    }
+
+    #[test]
+    fn creating_prunes_expired_files() {
+        let dir = TempDir::new().unwrap();
+        let expired = name(Local::now().naive_local() - TimeDelta::hours(3), 0);
+        write(dir.path().join(&expired), "old").unwrap();
+        let retention = Retention {
+            hours: 2,
+            max_files: 0,
+        };
+        let file = RotatingFile::create(dir.path(), "dll", "log", 0, retention).unwrap();
+        drop(file);
+        assert_eq!(logs(dir.path()), [""]);
+    }
+}
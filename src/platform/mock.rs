@@ -0,0 +1,508 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak, atomic::AtomicU64},
+    time::Duration,
+};
+
+use super::{DatagramSocket, Event, NamedMutex, Platform, SharedMemory};
+
+/*
+ *
+ * In-memory platform. Every clone of a MockPlatform lives in the same "machine", so both sides
+ * of the protocol can run in one process: as_process() gives a view of the machine from
+ * another pid.
+ *
+ * Named objects behave like on Windows: they exist as long as someone holds them, and the
+ * same name gives the same object. UDP datagrams go straight to the socket bound to the
+ * destination address, or are dropped if there is none.
+ *
+ * */
+
+//First pid handed out. Real pids are multiples of 4, so are these.
+const FIRST_PID: u32 = 1000;
+const FIRST_PORT: u16 = 50000;
+
+#[derive(Clone)]
+pub struct MockPlatform {
+    machine: Arc<Mutex<Machine>>,
+    pid: u32,
+}
+
+#[derive(Default)]
+struct Machine {
+    memory: HashMap<String, Weak<MockMemory>>,
+    events: HashMap<String, Weak<MockEvent>>,
+    mutexes: HashMap<String, Weak<MockMutex>>,
+    //Running processes, by pid, with their executable name.
+    processes: HashMap<u32, String>,
+    next_pid: u32,
+    spawned: Vec<PathBuf>,
+    window_size: Option<(u32, u32)>,
+    keys_down: HashSet<u32>,
+    sockets: HashMap<SocketAddr, Weak<MockSocket>>,
+    next_port: u16,
+}
+
+impl Machine {
+    fn add_process(&mut self, exe_name: &str) -> u32 {
+        let pid = self.next_pid;
+        self.next_pid += 4;
+        self.processes.insert(pid, exe_name.to_string());
+        pid
+    }
+}
+
+impl MockPlatform {
+    ///A new machine, with a running process for us.
+    pub fn new() -> MockPlatform {
+        let mut machine = Machine {
+            next_pid: FIRST_PID,
+            next_port: FIRST_PORT,
+            ..Default::default()
+        };
+        let pid = machine.add_process("Gw2-64.exe");
+        MockPlatform {
+            machine: Arc::new(Mutex::new(machine)),
+            pid,
+        }
+    }
+
+    ///Starts another process on the same machine, and returns its view of it.
+    pub fn spawn_process(&self, exe_name: &str) -> MockPlatform {
+        let pid = self.machine().add_process(exe_name);
+        self.as_process(pid)
+    }
+
+    ///The machine as seen from another process.
+    pub fn as_process(&self, pid: u32) -> MockPlatform {
+        MockPlatform {
+            machine: self.machine.clone(),
+            pid,
+        }
+    }
+
+    ///Ends a process. Its objects live on until they are dropped.
+    pub fn exit_process(&self, pid: u32) {
+        self.machine().processes.remove(&pid);
+    }
+
+    ///Programs started with spawn_detached, in order.
+    pub fn spawned(&self) -> Vec<PathBuf> {
+        self.machine().spawned.clone()
+    }
+
+    pub fn set_window_size(&self, size: Option<(u32, u32)>) {
+        self.machine().window_size = size;
+    }
+
+    pub fn set_key_down(&self, vk: u32, down: bool) {
+        let mut machine = self.machine();
+        if down {
+            machine.keys_down.insert(vk);
+        } else {
+            machine.keys_down.remove(&vk);
+        }
+    }
+
+    fn machine(&self) -> MutexGuard<'_, Machine> {
+        self.machine.lock().unwrap()
+    }
+}
+
+impl Default for MockPlatform {
+    fn default() -> Self {
+        MockPlatform::new()
+    }
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no object named {}", name))
+}
+
+struct MockMemory {
+    //u64s so the memory is aligned like a real view.
+    words: Box<[AtomicU64]>,
+}
+
+impl SharedMemory for MockMemory {
+    fn as_ptr(&self) -> *mut u8 {
+        self.words.as_ptr() as *mut u8
+    }
+    fn size(&self) -> usize {
+        self.words.len() * 8
+    }
+}
+
+#[derive(Default)]
+struct MockEvent {
+    manual_reset: bool,
+    set: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Event for MockEvent {
+    fn set(&self) {
+        *self.set.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+    fn reset(&self) {
+        *self.set.lock().unwrap() = false;
+    }
+    fn wait(&self, timeout: Duration) -> bool {
+        let set = self.set.lock().unwrap();
+        let (mut set, _) = self
+            .condvar
+            .wait_timeout_while(set, timeout, |set| !*set)
+            .unwrap();
+        let woken = *set;
+        if woken && !self.manual_reset {
+            *set = false;
+        }
+        woken
+    }
+}
+
+struct MockMutex;
+
+struct MockSocket {
+    machine: Weak<Mutex<Machine>>,
+    address: SocketAddr,
+    inbox: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+    condvar: Condvar,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl DatagramSocket for MockSocket {
+    fn send_to(&self, data: &[u8], to: SocketAddr) -> io::Result<usize> {
+        let destination = self
+            .machine
+            .upgrade()
+            .and_then(|machine| machine.lock().unwrap().sockets.get(&to)?.upgrade());
+        if let Some(destination) = destination {
+            destination
+                .inbox
+                .lock()
+                .unwrap()
+                .push_back((data.to_vec(), self.address));
+            destination.condvar.notify_all();
+        }
+        Ok(data.len())
+    }
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let inbox = self.inbox.lock().unwrap();
+        let mut inbox = match timeout {
+            Some(timeout) => {
+                self.condvar
+                    .wait_timeout_while(inbox, timeout, |inbox| inbox.is_empty())
+                    .unwrap()
+                    .0
+            }
+            None => self
+                .condvar
+                .wait_while(inbox, |inbox| inbox.is_empty())
+                .unwrap(),
+        };
+        //Same error as a real socket on unix.
+        let Some((data, from)) = inbox.pop_front() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+        //Like a real socket, whatever doesn't fit is lost.
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+}
+
+impl Platform for MockPlatform {
+    fn create_shared_memory(&self, name: &str, size: usize) -> io::Result<Arc<dyn SharedMemory>> {
+        let mut machine = self.machine();
+        if let Some(memory) = machine.memory.get(name).and_then(Weak::upgrade) {
+            return Ok(memory);
+        }
+        let memory = Arc::new(MockMemory {
+            words: (0..size.div_ceil(8)).map(|_| AtomicU64::new(0)).collect(),
+        });
+        machine
+            .memory
+            .insert(name.to_string(), Arc::downgrade(&memory));
+        Ok(memory)
+    }
+
+    fn open_shared_memory(&self, name: &str) -> io::Result<Arc<dyn SharedMemory>> {
+        match self.machine().memory.get(name).and_then(Weak::upgrade) {
+            Some(memory) => Ok(memory),
+            None => Err(not_found(name)),
+        }
+    }
+
+    fn create_event(&self, name: &str, manual_reset: bool) -> io::Result<Arc<dyn Event>> {
+        let mut machine = self.machine();
+        if let Some(event) = machine.events.get(name).and_then(Weak::upgrade) {
+            return Ok(event);
+        }
+        let event = Arc::new(MockEvent {
+            manual_reset,
+            ..Default::default()
+        });
+        machine
+            .events
+            .insert(name.to_string(), Arc::downgrade(&event));
+        Ok(event)
+    }
+
+    fn open_event(&self, name: &str) -> io::Result<Arc<dyn Event>> {
+        match self.machine().events.get(name).and_then(Weak::upgrade) {
+            Some(event) => Ok(event),
+            None => Err(not_found(name)),
+        }
+    }
+
+    fn create_mutex(&self, name: &str) -> io::Result<Box<dyn NamedMutex>> {
+        let mut machine = self.machine();
+        let mutex = machine
+            .mutexes
+            .get(name)
+            .and_then(Weak::upgrade)
+            .unwrap_or_else(|| Arc::new(MockMutex));
+        machine
+            .mutexes
+            .insert(name.to_string(), Arc::downgrade(&mutex));
+        Ok(Box::new(HeldMutex { _mutex: mutex }))
+    }
+
+    fn mutex_exists(&self, name: &str) -> bool {
+        self.machine()
+            .mutexes
+            .get(name)
+            .is_some_and(|mutex| mutex.strong_count() > 0)
+    }
+
+    fn current_pid(&self) -> u32 {
+        self.pid
+    }
+
+    fn is_process_alive(&self, pid: u32) -> bool {
+        self.machine().processes.contains_key(&pid)
+    }
+
+    fn kill_processes_named(&self, exe_name: &str) {
+        self.machine()
+            .processes
+            .retain(|_, name| !name.eq_ignore_ascii_case(exe_name));
+    }
+
+    fn spawn_detached(&self, path: &Path) -> io::Result<()> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a program"))?
+            .to_string_lossy();
+        let mut machine = self.machine();
+        machine.add_process(&name);
+        machine.spawned.push(path.to_path_buf());
+        Ok(())
+    }
+
+    fn window_size(&self) -> Option<(u32, u32)> {
+        self.machine().window_size
+    }
+
+    fn is_key_down(&self, vk: u32) -> bool {
+        self.machine().keys_down.contains(&vk)
+    }
+
+    fn bind_udp(&self, address: SocketAddr) -> io::Result<Arc<dyn DatagramSocket>> {
+        let mut machine = self.machine();
+        let mut address = address;
+        //Replies to an unspecified address have to land somewhere.
+        if address.ip().is_unspecified() {
+            address.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        if address.port() == 0 {
+            address.set_port(machine.next_port);
+            machine.next_port += 1;
+        }
+        if machine
+            .sockets
+            .get(&address)
+            .is_some_and(|socket| socket.strong_count() > 0)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already bound", address),
+            ));
+        }
+        let socket = Arc::new(MockSocket {
+            machine: Arc::downgrade(&self.machine),
+            address,
+            inbox: Mutex::new(VecDeque::new()),
+            condvar: Condvar::new(),
+            read_timeout: Mutex::new(None),
+        });
+        machine.sockets.insert(address, Arc::downgrade(&socket));
+        Ok(socket)
+    }
+}
+
+//Mutexes can be created several times, the object lives until the last one is dropped.
+struct HeldMutex {
+    _mutex: Arc<MockMutex>,
+}
+
+impl NamedMutex for HeldMutex {}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, thread};
+
+    use super::*;
+
+    #[test]
+    fn shared_memory_is_shared_by_name() {
+        let game = MockPlatform::new();
+        let producer = game.spawn_process("Blish HUD.exe");
+        assert!(producer.open_shared_memory("Local\\Header").is_err());
+
+        let created = producer.create_shared_memory("Local\\Header", 100).unwrap();
+        //Rounded up, like a real view.
+        assert_eq!(created.size(), 104);
+        unsafe { created.bytes_mut()[3] = 42 };
+        let opened = game.open_shared_memory("Local\\Header").unwrap();
+        assert_eq!(opened.bytes()[3], 42);
+        //Creating it again opens it, whatever the size.
+        let again = game.create_shared_memory("Local\\Header", 8).unwrap();
+        assert_eq!(again.size(), 104);
+        opened.words()[0].fetch_add(1, Ordering::Relaxed);
+        assert_eq!(created.bytes()[0], 1);
+
+        drop((created, opened, again));
+        assert!(game.open_shared_memory("Local\\Header").is_err());
+    }
+
+    #[test]
+    fn events() {
+        let game = MockPlatform::new();
+        let auto = game.create_event("Local\\Auto", false).unwrap();
+        let manual = game.create_event("Local\\Manual", true).unwrap();
+        assert!(!auto.wait(Duration::ZERO));
+
+        game.open_event("Local\\Auto").unwrap().set();
+        assert!(auto.wait(Duration::ZERO));
+        //Reset by the wait that saw it.
+        assert!(!auto.wait(Duration::ZERO));
+
+        manual.set();
+        assert!(manual.wait(Duration::ZERO));
+        assert!(manual.wait(Duration::ZERO));
+        manual.reset();
+        assert!(!manual.wait(Duration::ZERO));
+
+        //Wakes up a waiting thread.
+        let producer = game.spawn_process("Blish HUD.exe");
+        let waiter = thread::spawn(move || auto.wait(Duration::from_secs(5)));
+        producer.open_event("Local\\Auto").unwrap().set();
+        assert!(waiter.join().unwrap());
+
+        drop(manual);
+        assert!(game.open_event("Local\\Manual").is_err());
+    }
+
+    #[test]
+    fn mutexes_exist_while_held() {
+        let game = MockPlatform::new();
+        let producer = game.spawn_process("Blish HUD.exe");
+        assert!(!game.mutex_exists("Local\\Alive"));
+        let first = producer.create_mutex("Local\\Alive").unwrap();
+        let second = producer.create_mutex("Local\\Alive").unwrap();
+        assert!(game.mutex_exists("Local\\Alive"));
+        drop(first);
+        assert!(game.mutex_exists("Local\\Alive"));
+        drop(second);
+        assert!(!game.mutex_exists("Local\\Alive"));
+    }
+
+    #[test]
+    fn processes() {
+        let game = MockPlatform::new();
+        assert_eq!(game.current_pid(), FIRST_PID);
+        assert!(game.is_process_alive(game.current_pid()));
+
+        let producer = game.spawn_process("Blish HUD.exe");
+        assert_eq!(producer.current_pid(), FIRST_PID + 4);
+        assert!(game.is_process_alive(producer.current_pid()));
+        game.exit_process(producer.current_pid());
+        assert!(!game.is_process_alive(producer.current_pid()));
+
+        let path = Path::new("addons/Blish HUD.exe");
+        game.spawn_detached(path).unwrap();
+        assert_eq!(game.spawned(), [path]);
+        let spawned = FIRST_PID + 8;
+        assert!(game.is_process_alive(spawned));
+        game.kill_processes_named("blish hud.exe");
+        assert!(!game.is_process_alive(spawned));
+        assert!(game.is_process_alive(game.current_pid()));
+        assert!(game.spawn_detached(Path::new("")).is_err());
+    }
+
+    #[test]
+    fn window_and_keys() {
+        let game = MockPlatform::new();
+        assert_eq!(game.window_size(), None);
+        game.set_window_size(Some((800, 600)));
+        //Every process sees the same machine.
+        assert_eq!(game.as_process(1).window_size(), Some((800, 600)));
+
+        game.set_key_down(0x41, true);
+        assert!(game.is_key_down(0x41));
+        assert!(!game.is_key_down(0x42));
+        game.set_key_down(0x41, false);
+        assert!(!game.is_key_down(0x41));
+    }
+
+    #[test]
+    fn sockets() {
+        let game = MockPlatform::new();
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 49152));
+        let listening = game.bind_udp(address).unwrap();
+        assert_eq!(
+            game.bind_udp(address).err().map(|e| e.kind()),
+            Some(io::ErrorKind::AddrInUse)
+        );
+
+        let socket = game
+            .bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+            .unwrap();
+        socket.send_to(b"hello", address).unwrap();
+        //Nobody there, dropped.
+        socket
+            .send_to(b"lost", SocketAddr::from((Ipv4Addr::LOCALHOST, 1)))
+            .unwrap();
+        let mut buf = [0; 3];
+        let (len, from) = listening.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            (&buf[..len], from),
+            (
+                &b"hel"[..],
+                SocketAddr::from((Ipv4Addr::LOCALHOST, FIRST_PORT))
+            )
+        );
+
+        listening
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        assert_eq!(
+            listening.recv_from(&mut buf).err().map(|e| e.kind()),
+            Some(io::ErrorKind::WouldBlock)
+        );
+
+        drop(listening);
+        assert!(game.bind_udp(address).is_ok());
+    }
+}
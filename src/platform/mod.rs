@@ -0,0 +1,141 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    path::Path,
+    slice,
+    sync::{Arc, OnceLock, atomic::AtomicU32},
+    time::Duration,
+};
+
+pub mod mock;
+#[cfg(windows)]
+pub mod win32;
+
+/*
+ *
+ * Everything the DLL asks from the OS, apart from rendering and the window procedure which
+ * only make sense inside the game.
+ *
+ * platform() is the Windows implementation on Windows and the in-memory mock everywhere
+ * else, unless something else was installed with set_platform() first (tests, simulator).
+ *
+ * Named objects use the Windows names, Global\ or Local\ prefix included.
+ *
+ * */
+
+static PLATFORM: OnceLock<Box<dyn Platform>> = OnceLock::new();
+
+///A view of memory shared with other processes. Unmapped when dropped.
+pub trait SharedMemory: Send + Sync {
+    fn as_ptr(&self) -> *mut u8;
+    ///Size of the view, can be bigger than what was asked for.
+    fn size(&self) -> usize;
+}
+
+impl dyn SharedMemory + '_ {
+    ///The other side writes whenever it wants, anything read must be validated.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size()) }
+    }
+
+    ///# Safety
+    ///No other reference to this view may be alive in this process while the slice is.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn bytes_mut(&self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.size()) }
+    }
+
+    ///The view as u32 words, for tables shared through atomics. Views are always page aligned.
+    pub fn words(&self) -> &[AtomicU32] {
+        unsafe { slice::from_raw_parts(self.as_ptr() as *const AtomicU32, self.size() / 4) }
+    }
+}
+
+///A named event shared with other processes. Closed when dropped.
+pub trait Event: Send + Sync {
+    fn set(&self);
+    ///Only needed for manual reset events.
+    fn reset(&self);
+    ///False if timeout elapsed before the event was set.
+    fn wait(&self, timeout: Duration) -> bool;
+}
+
+///A named mutex held for as long as it lives.
+pub trait NamedMutex: Send + Sync {}
+
+pub trait DatagramSocket: Send + Sync {
+    fn send_to(&self, data: &[u8], to: SocketAddr) -> io::Result<usize>;
+    ///Blocks until a datagram arrives, or the read timeout elapses (WouldBlock or TimedOut).
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    ///None blocks forever, which is the default.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+pub trait Platform: Send + Sync {
+    ///Creates the memory, or opens it if it already exists.
+    fn create_shared_memory(&self, name: &str, size: usize) -> io::Result<Arc<dyn SharedMemory>>;
+    ///Maps the whole memory, whatever its size.
+    fn open_shared_memory(&self, name: &str) -> io::Result<Arc<dyn SharedMemory>>;
+
+    ///Creates the event, or opens it if it already exists. Auto reset events are reset
+    ///by the first wait they wake up.
+    fn create_event(&self, name: &str, manual_reset: bool) -> io::Result<Arc<dyn Event>>;
+    fn open_event(&self, name: &str) -> io::Result<Arc<dyn Event>>;
+
+    fn create_mutex(&self, name: &str) -> io::Result<Box<dyn NamedMutex>>;
+    ///True if some process created the mutex, even if we can't open it.
+    fn mutex_exists(&self, name: &str) -> bool;
+
+    fn current_pid(&self) -> u32;
+    fn is_process_alive(&self, pid: u32) -> bool;
+    ///Terminates every process whose executable has this file name.
+    fn kill_processes_named(&self, exe_name: &str);
+    ///Starts a program without a console, and doesn't wait for it.
+    fn spawn_detached(&self, path: &Path) -> io::Result<()>;
+
+    ///Size of the client area of the game's window.
+    fn window_size(&self) -> Option<(u32, u32)>;
+    fn is_key_down(&self, vk: u32) -> bool;
+
+    fn bind_udp(&self, address: SocketAddr) -> io::Result<Arc<dyn DatagramSocket>>;
+}
+
+///The platform everything goes through.
+pub fn platform() -> &'static dyn Platform {
+    PLATFORM.get_or_init(default_platform).as_ref()
+}
+
+///Replaces the default platform. Fails once platform() has been called, giving it back.
+pub fn set_platform(platform: Box<dyn Platform>) -> Result<(), Box<dyn Platform>> {
+    PLATFORM.set(platform)
+}
+
+#[cfg(windows)]
+fn default_platform() -> Box<dyn Platform> {
+    Box::new(win32::WindowsPlatform)
+}
+
+#[cfg(not(windows))]
+fn default_platform() -> Box<dyn Platform> {
+    Box::new(default_mock().clone())
+}
+
+///The machine platform() runs on outside of windows, unless set_platform() replaced it.
+///Tests use it to play the other processes and the user.
+#[cfg(not(windows))]
+pub fn default_mock() -> &'static mock::MockPlatform {
+    static MOCK: OnceLock<mock::MockPlatform> = OnceLock::new();
+    MOCK.get_or_init(mock::MockPlatform::new)
+}
+
+impl DatagramSocket for UdpSocket {
+    fn send_to(&self, data: &[u8], to: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, data, to)
+    }
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
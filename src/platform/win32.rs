@@ -0,0 +1,300 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    os::windows::process::CommandExt,
+    path::Path,
+    process::{Command, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+
+use windows::{
+    Win32::{
+        Foundation::{
+            BOOL, CloseHandle, ERROR_ACCESS_DENIED, HANDLE, INVALID_HANDLE_VALUE, RECT,
+            WAIT_OBJECT_0,
+        },
+        System::{
+            Diagnostics::ToolHelp::{
+                CreateToolhelp32Snapshot, PROCESSENTRY32W, Process32FirstW, Process32NextW,
+                TH32CS_SNAPPROCESS,
+            },
+            Memory::{
+                CreateFileMappingW, FILE_MAP_ALL_ACCESS, MEMORY_BASIC_INFORMATION,
+                MEMORY_MAPPED_VIEW_ADDRESS, MapViewOfFile, OpenFileMappingW, PAGE_READWRITE,
+                UnmapViewOfFile, VirtualQuery,
+            },
+            Threading::{
+                CreateEventW, CreateMutexW, GetCurrentProcessId, GetExitCodeProcess, OpenEventW,
+                OpenMutexW, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_TERMINATE,
+                ReleaseMutex, ResetEvent, SYNCHRONIZATION_ACCESS_RIGHTS, SetEvent,
+                TerminateProcess, WaitForSingleObject,
+            },
+        },
+        UI::{Input::KeyboardAndMouse::GetKeyState, WindowsAndMessaging::GetClientRect},
+    },
+    core::PCWSTR,
+};
+
+use super::{DatagramSocket, Event, NamedMutex, Platform, SharedMemory};
+use crate::utils::{get_mainwindow_hwnd, to_wide};
+
+//Access rights, see the docs of OpenEventW/OpenMutexW.
+const SYNCHRONIZE: u32 = 0x00100000;
+const EVENT_MODIFY_STATE: u32 = 0x0002;
+//Hides the console of spawned programs.
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+pub struct WindowsPlatform;
+
+struct Mapping {
+    view: MEMORY_MAPPED_VIEW_ADDRESS,
+    mapping: HANDLE,
+    size: usize,
+}
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl SharedMemory for Mapping {
+    fn as_ptr(&self) -> *mut u8 {
+        self.view.Value as *mut u8
+    }
+    fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            UnmapViewOfFile(self.view).ok();
+            CloseHandle(self.mapping).ok();
+        }
+    }
+}
+
+//Maps the whole mapping. The size is queried since it may be bigger than what we know about.
+fn map_view(mapping: HANDLE, size: usize) -> io::Result<Arc<dyn SharedMemory>> {
+    unsafe {
+        let view = MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, size);
+        if view.Value.is_null() {
+            let e = io::Error::last_os_error();
+            CloseHandle(mapping).ok();
+            return Err(e);
+        }
+        let mut info = MEMORY_BASIC_INFORMATION::default();
+        let written = VirtualQuery(
+            Some(view.Value),
+            &mut info,
+            size_of::<MEMORY_BASIC_INFORMATION>(),
+        );
+        if written == 0 {
+            let e = io::Error::last_os_error();
+            UnmapViewOfFile(view).ok();
+            CloseHandle(mapping).ok();
+            return Err(e);
+        }
+        Ok(Arc::new(Mapping {
+            view,
+            mapping,
+            size: info.RegionSize,
+        }))
+    }
+}
+
+struct WindowsEvent(HANDLE);
+unsafe impl Send for WindowsEvent {}
+unsafe impl Sync for WindowsEvent {}
+
+impl Event for WindowsEvent {
+    //Only fails on a bad handle. The other side then misses a wake up, it times out instead.
+    fn set(&self) {
+        if let Err(e) = unsafe { SetEvent(self.0) } {
+            log::error!("Could not set an event: {}", e);
+        }
+    }
+    fn reset(&self) {
+        if let Err(e) = unsafe { ResetEvent(self.0) } {
+            log::error!("Could not reset an event: {}", e);
+        }
+    }
+    fn wait(&self, timeout: Duration) -> bool {
+        unsafe { WaitForSingleObject(self.0, timeout.as_millis() as u32) == WAIT_OBJECT_0 }
+    }
+}
+
+impl Drop for WindowsEvent {
+    fn drop(&mut self) {
+        unsafe {
+            CloseHandle(self.0).ok();
+        }
+    }
+}
+
+struct WindowsMutex(HANDLE);
+unsafe impl Send for WindowsMutex {}
+unsafe impl Sync for WindowsMutex {}
+
+impl NamedMutex for WindowsMutex {}
+
+impl Drop for WindowsMutex {
+    fn drop(&mut self) {
+        unsafe {
+            ReleaseMutex(self.0).ok();
+            CloseHandle(self.0).ok();
+        }
+    }
+}
+
+impl Platform for WindowsPlatform {
+    fn create_shared_memory(&self, name: &str, size: usize) -> io::Result<Arc<dyn SharedMemory>> {
+        let name = to_wide(name);
+        let mapping = unsafe {
+            CreateFileMappingW(
+                INVALID_HANDLE_VALUE,
+                None,
+                PAGE_READWRITE,
+                0,
+                size as u32,
+                PCWSTR(name.as_ptr()),
+            )?
+        };
+        map_view(mapping, size)
+    }
+
+    fn open_shared_memory(&self, name: &str) -> io::Result<Arc<dyn SharedMemory>> {
+        let name = to_wide(name);
+        let mapping =
+            unsafe { OpenFileMappingW(FILE_MAP_ALL_ACCESS.0, BOOL(0), PCWSTR(name.as_ptr()))? };
+        map_view(mapping, 0)
+    }
+
+    fn create_event(&self, name: &str, manual_reset: bool) -> io::Result<Arc<dyn Event>> {
+        let name = to_wide(name);
+        let event = unsafe { CreateEventW(None, manual_reset, false, PCWSTR(name.as_ptr()))? };
+        Ok(Arc::new(WindowsEvent(event)))
+    }
+
+    fn open_event(&self, name: &str) -> io::Result<Arc<dyn Event>> {
+        let name = to_wide(name);
+        let event = unsafe {
+            OpenEventW(
+                SYNCHRONIZATION_ACCESS_RIGHTS(SYNCHRONIZE | EVENT_MODIFY_STATE),
+                false,
+                PCWSTR(name.as_ptr()),
+            )?
+        };
+        Ok(Arc::new(WindowsEvent(event)))
+    }
+
+    fn create_mutex(&self, name: &str) -> io::Result<Box<dyn NamedMutex>> {
+        let name = to_wide(name);
+        let mutex = unsafe { CreateMutexW(None, true, PCWSTR(name.as_ptr()))? };
+        Ok(Box::new(WindowsMutex(mutex)))
+    }
+
+    fn mutex_exists(&self, name: &str) -> bool {
+        let name = to_wide(name);
+        unsafe {
+            match OpenMutexW(
+                SYNCHRONIZATION_ACCESS_RIGHTS(SYNCHRONIZE),
+                false,
+                PCWSTR(name.as_ptr()),
+            ) {
+                Ok(handle) => {
+                    CloseHandle(handle).ok();
+                    true
+                }
+                //It exists, we just can't open it.
+                Err(e) => e.code() == ERROR_ACCESS_DENIED.to_hresult(),
+            }
+        }
+    }
+
+    fn current_pid(&self) -> u32 {
+        unsafe { GetCurrentProcessId() }
+    }
+
+    fn is_process_alive(&self, pid: u32) -> bool {
+        //Exit code reported while a process is still running.
+        const STILL_ACTIVE: u32 = 259;
+        unsafe {
+            match OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) {
+                Ok(handle) => {
+                    let mut code = 0;
+                    let alive =
+                        GetExitCodeProcess(handle, &mut code).is_ok() && code == STILL_ACTIVE;
+                    CloseHandle(handle).ok();
+                    alive
+                }
+                //Access denied means it exists.
+                Err(e) => e.code() == ERROR_ACCESS_DENIED.to_hresult(),
+            }
+        }
+    }
+
+    fn kill_processes_named(&self, exe_name: &str) {
+        unsafe {
+            let Ok(snapshot) = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) else {
+                return;
+            };
+            let mut entry = PROCESSENTRY32W {
+                dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+                ..Default::default()
+            };
+
+            if Process32FirstW(snapshot, &mut entry).is_ok() {
+                loop {
+                    let name = String::from_utf16_lossy(
+                        &entry.szExeFile
+                            [..entry.szExeFile.iter().position(|&c| c == 0).unwrap_or(0)],
+                    );
+
+                    if name.eq_ignore_ascii_case(exe_name) {
+                        match OpenProcess(PROCESS_TERMINATE, false, entry.th32ProcessID) {
+                            Ok(handle) => {
+                                TerminateProcess(handle, 1).ok();
+                                CloseHandle(handle).ok();
+                                log::info!("Terminated {}", name);
+                            }
+                            Err(e) => log::error!("Failed to open {}: {}", name, e),
+                        }
+                    }
+
+                    if Process32NextW(snapshot, &mut entry).is_err() {
+                        break;
+                    }
+                }
+            }
+
+            CloseHandle(snapshot).ok();
+        }
+    }
+
+    fn spawn_detached(&self, path: &Path) -> io::Result<()> {
+        Command::new(path)
+            .creation_flags(CREATE_NO_WINDOW)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map(|_| ())
+    }
+
+    fn window_size(&self) -> Option<(u32, u32)> {
+        let hwnd = get_mainwindow_hwnd()?;
+        let mut rect = RECT::default();
+        unsafe { GetClientRect(hwnd, &mut rect).ok()? };
+        Some((
+            (rect.right - rect.left) as u32,
+            (rect.bottom - rect.top) as u32,
+        ))
+    }
+
+    fn is_key_down(&self, vk: u32) -> bool {
+        (unsafe { GetKeyState(vk as i32) } as u16 & 0x8000) != 0
+    }
+
+    fn bind_udp(&self, address: SocketAddr) -> io::Result<Arc<dyn DatagramSocket>> {
+        Ok(Arc::new(UdpSocket::bind(address)?))
+    }
+}
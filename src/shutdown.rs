@@ -0,0 +1,143 @@
+use std::{
+    io,
+    sync::{
+        Condvar, Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/*
+ *
+ * Coordinated shutdown of the DLL. Plain Rust, no windows.
+ *
+ * Every thread that lives as long as the DLL is started with spawn_worker, and returns on its
+ * own once request_shutdown() has been called: loops check is_shutting_down(), sleeps go
+ * through wait_for_shutdown() so they end early. join_workers() then waits for all of them.
+ *
+ * Code called by the game (present, wnd_proc) can't be joined. It counts its calls with an
+ * InFlight instead, and the teardown waits for the count to drop to 0 once the hook is gone.
+ *
+ * */
+
+//Set once, never cleared. A DLL that was shut down is unloaded, not restarted.
+//Read on every present and wnd_proc call, so it's an atomic, not behind a lock.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+//Only for wait_for_shutdown, nothing is kept under it.
+static SHUTDOWN_LOCK: Mutex<()> = Mutex::new(());
+static SHUTDOWN_CONDVAR: Condvar = Condvar::new();
+static WORKERS: Mutex<Vec<(&'static str, JoinHandle<()>)>> = Mutex::new(Vec::new());
+
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN.load(Ordering::Acquire)
+}
+
+///Tells every worker to stop. Doesn't wait for them, see join_workers.
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::Release);
+    //Taken so a waiter can't miss the notification between checking the flag and waiting.
+    drop(SHUTDOWN_LOCK.lock().unwrap_or_else(PoisonError::into_inner));
+    SHUTDOWN_CONDVAR.notify_all();
+}
+
+///Sleeps for timeout, or less if a shutdown is requested. True if shutting down.
+pub fn wait_for_shutdown(timeout: Duration) -> bool {
+    let lock = SHUTDOWN_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    drop(
+        SHUTDOWN_CONDVAR
+            .wait_timeout_while(lock, timeout, |_| !is_shutting_down())
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+    is_shutting_down()
+}
+
+///Starts a thread that must be done before the DLL can be unloaded.
+///Fails if the OS couldn't start it, nothing is left to join then.
+pub fn spawn_worker(name: &'static str, f: impl FnOnce() + Send + 'static) -> io::Result<()> {
+    let handle = std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)?;
+    WORKERS.lock().unwrap().push((name, handle));
+    Ok(())
+}
+
+///Joins every worker, giving them timeout in total. False if some are still running,
+///they are logged and left alone.
+pub fn join_workers(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut workers = std::mem::take(&mut *WORKERS.lock().unwrap());
+    loop {
+        let (finished, running): (Vec<_>, Vec<_>) = workers
+            .into_iter()
+            .partition(|(_, handle)| handle.is_finished());
+        for (name, handle) in finished {
+            if handle.join().is_err() {
+                log::error!("Worker {} panicked.", name);
+            }
+        }
+        workers = running;
+        if workers.is_empty() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            for (name, _) in &workers {
+                log::error!("Worker {} did not stop in time.", name);
+            }
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+///Counts the calls currently running through a hook.
+pub struct InFlight {
+    calls: AtomicUsize,
+}
+
+///One call, until dropped.
+pub struct InFlightCall<'a> {
+    in_flight: &'a InFlight,
+}
+
+impl InFlight {
+    pub const fn new() -> InFlight {
+        InFlight {
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn enter(&self) -> InFlightCall<'_> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        InFlightCall { in_flight: self }
+    }
+
+    ///Waits for every call to return. Only makes sense once no new call can come in.
+    ///False if some were still running after timeout.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.calls.load(Ordering::SeqCst) != 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight::new()
+    }
+}
+
+impl Drop for InFlightCall<'_> {
+    fn drop(&mut self) {
+        self.in_flight.calls.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+//Calls of the present detour and of our wnd_proc.
+pub static PRESENT_CALLS: InFlight = InFlight::new();
+pub static WNDPROC_CALLS: InFlight = InFlight::new();
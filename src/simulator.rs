@@ -0,0 +1,369 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        mpsc::{Receiver, channel},
+    },
+    time::Duration,
+};
+
+use crate::{
+    config::Config,
+    input::{InputEvent, InputPacket, MAX_PACKET_LEN, Sequencer, now_micros},
+    platform::{Event, NamedMutex, Platform, SharedMemory},
+    ui::{
+        discovery::{DISCOVERY_RECORDS, DiscoveryTable, ObjectNames, record_state},
+        header::{
+            self, HEADER_MAGIC, MMFHeader, PROTOCOL_VERSION, feature, flag, section, slots_end,
+        },
+    },
+};
+
+/*
+ *
+ * Plays the producer side of the protocol, like a patched BlishHUD would, but without drawing
+ * anything. Everything goes through a Platform, so it runs against the real OS (src/bin) or
+ * against a MockPlatform shared with the DLL's code in the same process.
+ *
+ * The simulator pairs with a waiting game through the discovery table, then:
+ *  - creates the header MMF and holds the liveness mutex for as long as it lives,
+ *  - publishes synthetic frames: the shown slot flips and the heartbeat ticks on every tick(),
+ *  - answers the consumer's resizes with new texture handles, like recreated textures,
+ *  - acks input packets received over UDP.
+ *
+ * What the consumer did is returned by poll() and logged.
+ *
+ * */
+
+//Size of the header MMF. Way more than what the header and its sections need.
+pub const HEADER_MMF_LEN: usize = 4096;
+//Synthetic texture handles start here, so they are easy to spot in logs.
+const FIRST_HANDLE: u64 = 0x1000;
+
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    pub namespace: String,
+    pub global: bool,
+    //Game to pair with. Any waiting game if None.
+    pub game_pid: Option<u32>,
+    pub slot_count: usize,
+    //Ask the consumer for keyboard capture.
+    pub keyboard_capture: bool,
+    //Where input events are received. Nothing is received if None.
+    pub input_address: Option<SocketAddr>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        let config = Config::default();
+        SimulatorConfig {
+            namespace: config.ipc.namespace,
+            global: config.ipc.global,
+            game_pid: None,
+            slot_count: 2,
+            keyboard_capture: false,
+            input_address: Some(config.input.udp_address),
+        }
+    }
+}
+
+//Something the consumer did, as seen from the producer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsumerAction {
+    //The consumer accepted our header and wrote its version.
+    Handshake { version: u16 },
+    //The consumer signaled the resize event.
+    Resized { width: u32, height: u32 },
+    //An input event arrived over UDP.
+    Input(InputEvent),
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    //No discovery table, or no game waiting in it. Try again later.
+    NoGame,
+    BadSlotCount(usize),
+    //Creating one of the producer's objects failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::NoGame => write!(f, "no game is waiting for a producer"),
+            ConnectError::BadSlotCount(count) => write!(
+                f,
+                "invalid slot count {} (1 to {} supported)",
+                count,
+                header::MAX_SLOTS
+            ),
+            ConnectError::Io(e) => write!(f, "could not create the producer's objects: {}", e),
+        }
+    }
+}
+
+pub struct Simulator<'a> {
+    platform: &'a dyn Platform,
+    names: ObjectNames,
+    discovery: Arc<dyn SharedMemory>,
+    record: usize,
+    header: Arc<dyn SharedMemory>,
+    wake_event: Arc<dyn Event>,
+    resize_event: Arc<dyn Event>,
+    //Held until the simulator is dropped, which is how the named mutex probe sees us leave.
+    _alive: Box<dyn NamedMutex>,
+    input: Option<Receiver<InputEvent>>,
+    slots: Vec<u64>,
+    next_handle: u64,
+    index: u32,
+    heartbeat: u64,
+    keyboard_capture: bool,
+    consumer_version: u16,
+}
+
+impl<'a> Simulator<'a> {
+    ///Pairs with a waiting game and creates everything a producer shares with it.
+    pub fn connect(
+        platform: &'a dyn Platform,
+        config: &SimulatorConfig,
+    ) -> Result<Self, ConnectError> {
+        if config.slot_count == 0 || config.slot_count > header::MAX_SLOTS {
+            return Err(ConnectError::BadSlotCount(config.slot_count));
+        }
+        let discovery_name = ObjectNames::new(&config.namespace, config.global, 0).discovery;
+        let discovery = platform
+            .open_shared_memory(&discovery_name)
+            .map_err(|_| ConnectError::NoGame)?;
+        let (record, game_pid) = pair(platform, discovery.as_ref(), config)?;
+        let names = ObjectNames::new(&config.namespace, config.global, game_pid);
+        log::info!("Paired with game {} (record {}).", game_pid, record);
+
+        //The game can take the next producer if anything goes wrong from here.
+        let unpair = |e: io::Error| {
+            if let Some(table) = DiscoveryTable::new(discovery.words()) {
+                table.unpair(record, platform.current_pid());
+            }
+            ConnectError::Io(e)
+        };
+        let header = platform
+            .create_shared_memory(&names.header, HEADER_MMF_LEN)
+            .map_err(unpair)?;
+        let wake_event = platform
+            .create_event(&names.wake_event, false)
+            .map_err(unpair)?;
+        let resize_event = platform
+            .create_event(&names.resize_event, true)
+            .map_err(unpair)?;
+        let alive = platform.create_mutex(&names.alive_mutex).map_err(unpair)?;
+        let input = match config.input_address {
+            Some(address) => Some(start_input_thread(platform, address).map_err(unpair)?),
+            None => None,
+        };
+
+        let mut simulator = Simulator {
+            platform,
+            names,
+            discovery,
+            record,
+            header,
+            wake_event,
+            resize_event,
+            _alive: alive,
+            input,
+            slots: Vec::new(),
+            next_handle: FIRST_HANDLE,
+            index: 0,
+            heartbeat: 0,
+            keyboard_capture: config.keyboard_capture,
+            consumer_version: 0,
+        };
+        simulator.slots = simulator.new_handles(config.slot_count);
+        simulator.tick();
+        Ok(simulator)
+    }
+
+    pub fn names(&self) -> &ObjectNames {
+        &self.names
+    }
+
+    ///Texture handles currently announced in the header.
+    pub fn slots(&self) -> &[u64] {
+        &self.slots
+    }
+
+    pub fn set_keyboard_capture(&mut self, capture: bool) {
+        self.keyboard_capture = capture;
+    }
+
+    ///Publishes the next frame and wakes the consumer up.
+    pub fn tick(&mut self) {
+        self.index = (self.index + 1) % self.slots.len() as u32;
+        self.heartbeat += 1;
+        self.publish();
+    }
+
+    ///Collects what the consumer did since the last call, answering resizes on the way.
+    pub fn poll(&mut self) -> Vec<ConsumerAction> {
+        let mut actions = Vec::new();
+        let current = MMFHeader::decode(self.header.bytes()).ok();
+
+        if let Some(current) = current {
+            if current.consumer_version != self.consumer_version {
+                self.consumer_version = current.consumer_version;
+                actions.push(ConsumerAction::Handshake {
+                    version: current.consumer_version,
+                });
+            }
+            if self.resize_event.wait(Duration::ZERO) {
+                self.resize_event.reset();
+                actions.push(ConsumerAction::Resized {
+                    width: current.width,
+                    height: current.height,
+                });
+                //Textures of the new size.
+                self.slots = self.new_handles(self.slots.len());
+                self.publish();
+            }
+        }
+        if let Some(input) = &self.input {
+            actions.extend(input.try_iter().map(ConsumerAction::Input));
+        }
+
+        for action in &actions {
+            log::info!("Consumer: {:?}", action);
+        }
+        actions
+    }
+
+    ///Ticks every interval, forever or for the given number of frames.
+    pub fn run(&mut self, interval: Duration, frames: Option<u64>) {
+        let mut published = 0;
+        while frames.is_none_or(|frames| published < frames) {
+            std::thread::sleep(interval);
+            self.poll();
+            self.tick();
+            published += 1;
+        }
+    }
+
+    fn new_handles(&mut self, count: usize) -> Vec<u64> {
+        let handles = (self.next_handle..self.next_handle + count as u64).collect();
+        self.next_handle += count as u64;
+        handles
+    }
+
+    //Writes the whole header, keeping the fields owned by the consumer.
+    fn publish(&mut self) {
+        //Only the simulator writes into its header, apart from the consumer fields kept below.
+        let data = unsafe { self.header.bytes_mut() };
+        let current = MMFHeader::decode(data).unwrap_or_default();
+        let mut header = MMFHeader {
+            magic: HEADER_MAGIC,
+            version: PROTOCOL_VERSION,
+            features: feature::RESIZE_EVENT | feature::FLAGS | feature::HEARTBEAT,
+            consumer_version: current.consumer_version,
+            width: current.width,
+            height: current.height,
+            index: self.index,
+            ..Default::default()
+        };
+        header.set_slots(&self.slots).unwrap();
+        header.sections_offset = slots_end(self.slots.len()) as u16;
+
+        let flags = if self.keyboard_capture {
+            flag::KEYBOARD_CAPTURE
+        } else {
+            0
+        };
+        let mut heartbeat = self.heartbeat.to_le_bytes().to_vec();
+        heartbeat.extend_from_slice(&now_micros().to_le_bytes());
+        //The sections always fit, the MMF is much bigger than them.
+        let at = header::write_section(
+            data,
+            header.sections_offset as usize,
+            section::FLAGS,
+            &flags.to_le_bytes(),
+        )
+        .unwrap();
+        let at = header::write_section(data, at, section::HEARTBEAT, &heartbeat).unwrap();
+        let end = header::write_section(data, at, section::END, &[]).unwrap();
+        header.header_len = end as u16;
+        header.encode(data).unwrap();
+
+        self.wake_event.set();
+    }
+}
+
+impl Drop for Simulator<'_> {
+    fn drop(&mut self) {
+        if let Some(table) = DiscoveryTable::new(self.discovery.words()) {
+            table.unpair(self.record, self.platform.current_pid());
+        }
+        log::info!("Left game {}.", self.names.prefix);
+    }
+}
+
+//Finds a waiting game in the table and pairs with it.
+fn pair(
+    platform: &dyn Platform,
+    discovery: &dyn SharedMemory,
+    config: &SimulatorConfig,
+) -> Result<(usize, u32), ConnectError> {
+    //Too small to be a table.
+    let table = DiscoveryTable::new(discovery.words()).ok_or(ConnectError::NoGame)?;
+    for index in 0..DISCOVERY_RECORDS {
+        let Some(record) = table.record(index) else {
+            continue;
+        };
+        if record.state != record_state::WAITING
+            || config.game_pid.is_some_and(|pid| pid != record.pid)
+            || !platform.is_process_alive(record.pid)
+        {
+            continue;
+        }
+        if table.pair(index, platform.current_pid()) {
+            return Ok((index, record.pid));
+        }
+    }
+    Err(ConnectError::NoGame)
+}
+
+//Receives the consumer's input events and acks them, like the producer does.
+//The thread ends with the first packet received once the simulator is gone.
+fn start_input_thread(
+    platform: &dyn Platform,
+    address: SocketAddr,
+) -> io::Result<Receiver<InputEvent>> {
+    let socket = platform.bind_udp(address)?;
+    let (tx, rx) = channel();
+    std::thread::spawn(move || {
+        let mut sequencer = Sequencer::default();
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        loop {
+            let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            };
+            let packet = match InputPacket::decode(&buf[..len]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log::warn!("Invalid input packet from {}: {}", from, e);
+                    continue;
+                }
+            };
+            let ack = InputEvent::Ack {
+                seq: packet.seq,
+                timestamp: packet.timestamp,
+            };
+            socket
+                .send_to(&sequencer.stamp(ack, now_micros()).encode(), from)
+                .ok();
+            //The simulator is gone.
+            if tx.send(packet.event).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(rx)
+}
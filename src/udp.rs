@@ -0,0 +1,91 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        Mutex,
+        mpsc::{Sender, channel},
+    },
+    time::Duration,
+};
+
+use crate::{
+    config::config,
+    debug::statistics::{debug_stat, send_statistic},
+    input::{InputEvent, InputPacket, MAX_PACKET_LEN, Sequencer, now_micros},
+    platform::platform,
+    shutdown::{is_shutting_down, spawn_worker},
+    ui::release_keyboard_capture,
+};
+
+//Taken on shutdown, which ends the sender thread.
+static INPUT_SENDER: Mutex<Option<Sender<(InputEvent, u64)>>> = Mutex::new(None);
+//How often the incoming thread checks for a shutdown.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(200);
+
+//Sends an event to the input thread, which forwards it to the producer.
+pub fn send_input(event: InputEvent) {
+    if let Some(sender) = INPUT_SENDER.lock().unwrap().as_ref() {
+        sender.send((event, now_micros())).ok();
+    }
+}
+
+///Gives the keyboard back to the game and tells the producer about it.
+///Returns false if capture wasn't active.
+pub fn release_capture() -> bool {
+    if !release_keyboard_capture() {
+        return false;
+    }
+    send_input(InputEvent::CaptureReleased);
+    true
+}
+
+//Starts the threads talking to the producer over UDP.
+//One sends the events coming from wnd_proc, the other one handles what the producer sends back.
+pub fn start_udp_threads() -> io::Result<()> {
+    let socket = platform().bind_udp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))?;
+    socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+    let (tx, rx) = channel::<(InputEvent, u64)>();
+    *INPUT_SENDER.lock().unwrap() = Some(tx);
+    let incoming = socket.clone();
+    //Any overlay that cares about input listens there.
+    let address = config().input.udp_address;
+
+    spawn_worker("udp-send", move || {
+        let mut sequencer = Sequencer::default();
+        for (event, timestamp) in rx {
+            let packet = sequencer.stamp(event, timestamp);
+            socket.send_to(&packet.encode(), address).ok();
+        }
+    })?;
+
+    spawn_worker("udp-receive", move || {
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        while !is_shutting_down() {
+            match incoming.recv_from(&mut buf) {
+                Ok((len, _)) => handle_incoming_packet(&buf[..len]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                //Windows reports ICMP port unreachable (nobody listening yet) as a receive error.
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    })
+}
+
+///Ends the sender thread once it sent what's queued. The other one ends with the shutdown.
+pub fn stop_udp_threads() {
+    INPUT_SENDER.lock().unwrap().take();
+}
+
+fn handle_incoming_packet(data: &[u8]) {
+    match InputPacket::decode(data) {
+        Ok(packet) => match packet.event {
+            InputEvent::Ack { timestamp, .. } => {
+                let round_trip = now_micros().saturating_sub(timestamp);
+                send_statistic(debug_stat::INPUT_ROUND_TRIP, round_trip as u32);
+            }
+            event => log::debug!("Ignoring input event from the producer: {:?}", event),
+        },
+        Err(e) => log::debug!("Invalid input packet from the producer: {}", e),
+    }
+}
@@ -0,0 +1,352 @@
+use super::{
+    header::{HeaderError, read_u32, read_u64},
+    layers::Rect,
+};
+
+/*
+ *
+ * Dirty rectangles. Producers that negotiated feature::DIRTY_RECTS tell us which parts of a slot
+ * changed, so only those get copied into a persistent composition texture instead of sampling
+ * the whole shared texture every frame.
+ *
+ * Every slot entry carries two frame numbers: rects describe what changed between base_frame
+ * and frame. If the composition texture does not hold base_frame (we missed a frame, or it was
+ * just created), the whole texture is copied instead.
+ *
+ * Payload of a section::DIRTY_RECTS section (little endian):
+ *
+ *   0  entry_count  u32
+ *   4  reserved     u32
+ *   8  entries      One per slot
+ *
+ * Entry:
+ *
+ *   0  slot        u32
+ *   4  rect_count  u32
+ *   8  base_frame  u64
+ *  16  frame       u64
+ *  24  rects       (x u32, y u32, width u32, height u32) * rect_count
+ *
+ * */
+
+pub const DIRTY_ENTRY_LEN: usize = 24;
+pub const DIRTY_RECT_LEN: usize = 16;
+//Producers sending more than this should just send a full update.
+pub const MAX_DIRTY_RECTS: usize = 64;
+//After merging, more copies than this cost more than a single full copy.
+pub const MAX_COPIES: usize = 16;
+//Rectangles closer than this are merged, copying a few extra pixels is cheaper than another call.
+pub const MERGE_DISTANCE: u32 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotDirty {
+    pub slot: u32,
+    pub base_frame: u64,
+    pub frame: u64,
+    pub rects: Vec<Rect>,
+}
+
+//What has to be copied into the composition texture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopyPlan {
+    //The composition texture is already up to date.
+    Nothing,
+    Full,
+    Regions(Vec<Rect>),
+}
+
+///Decides what to copy, given the frame currently held by the composition texture.
+pub fn plan_copy(
+    composition_frame: Option<u64>,
+    dirty: &SlotDirty,
+    width: u32,
+    height: u32,
+) -> CopyPlan {
+    match composition_frame {
+        Some(frame) if frame == dirty.frame => CopyPlan::Nothing,
+        Some(frame) if frame == dirty.base_frame => {
+            let rects = merge(&clip(&dirty.rects, width, height));
+            if rects.is_empty() {
+                CopyPlan::Nothing
+            } else if rects.len() > MAX_COPIES || covers_most(&rects, width, height) {
+                CopyPlan::Full
+            } else {
+                CopyPlan::Regions(rects)
+            }
+        }
+        _ => CopyPlan::Full,
+    }
+}
+
+///Clips every rectangle to the texture, dropping the ones that end up empty.
+pub fn clip(rects: &[Rect], width: u32, height: u32) -> Vec<Rect> {
+    let bounds = Rect::new(0, 0, width, height);
+    rects.iter().filter_map(|r| r.intersect(&bounds)).collect()
+}
+
+///Merges rectangles that overlap or are within MERGE_DISTANCE of each other.
+///The result covers at least everything the input did.
+pub fn merge(rects: &[Rect]) -> Vec<Rect> {
+    let mut merged: Vec<Rect> = rects.iter().filter(|r| !r.is_empty()).copied().collect();
+    loop {
+        let mut changed = false;
+        let mut i = 0;
+        while i < merged.len() {
+            let mut j = i + 1;
+            while j < merged.len() {
+                if are_close(&merged[i], &merged[j]) {
+                    merged[i] = union(&merged[i], &merged[j]);
+                    merged.swap_remove(j);
+                    changed = true;
+                } else {
+                    j += 1;
+                }
+            }
+            i += 1;
+        }
+        if !changed {
+            break;
+        }
+    }
+    merged
+}
+
+///Smallest rectangle containing both.
+pub fn union(a: &Rect, b: &Rect) -> Rect {
+    let left = a.x.min(b.x);
+    let top = a.y.min(b.y);
+    let right = a.right().max(b.right());
+    let bottom = a.bottom().max(b.bottom());
+    Rect::new(
+        left,
+        top,
+        (right - left as i64) as u32,
+        (bottom - top as i64) as u32,
+    )
+}
+
+fn are_close(a: &Rect, b: &Rect) -> bool {
+    let d = MERGE_DISTANCE as i64;
+    (a.x as i64) <= b.right() + d
+        && (b.x as i64) <= a.right() + d
+        && (a.y as i64) <= b.bottom() + d
+        && (b.y as i64) <= a.bottom() + d
+}
+
+//Once merged, rectangles don't overlap much so the sum of their areas is close enough.
+fn covers_most(rects: &[Rect], width: u32, height: u32) -> bool {
+    let area: u64 = rects.iter().map(|r| r.width as u64 * r.height as u64).sum();
+    area * 4 >= width as u64 * height as u64 * 3
+}
+
+///Decodes the payload of a section::DIRTY_RECTS section.
+pub fn decode_dirty(payload: &[u8]) -> Result<Vec<SlotDirty>, HeaderError> {
+    let bad = |offset: usize| HeaderError::BadSection { offset };
+    if payload.len() < 8 {
+        return Err(bad(0));
+    }
+    let count = read_u32(payload, 0) as usize;
+
+    let mut entries = Vec::with_capacity(count.min(8));
+    let mut at = 8;
+    for _ in 0..count {
+        if at + DIRTY_ENTRY_LEN > payload.len() {
+            return Err(bad(at));
+        }
+        let rect_count = read_u32(payload, at + 4) as usize;
+        if rect_count > MAX_DIRTY_RECTS {
+            return Err(bad(at));
+        }
+        let end = at + DIRTY_ENTRY_LEN + rect_count * DIRTY_RECT_LEN;
+        if end > payload.len() {
+            return Err(bad(at));
+        }
+        let rects = (0..rect_count)
+            .map(|i| {
+                let r = at + DIRTY_ENTRY_LEN + i * DIRTY_RECT_LEN;
+                Rect::new(
+                    read_u32(payload, r).min(i32::MAX as u32) as i32,
+                    read_u32(payload, r + 4).min(i32::MAX as u32) as i32,
+                    read_u32(payload, r + 8),
+                    read_u32(payload, r + 12),
+                )
+            })
+            .collect();
+        entries.push(SlotDirty {
+            slot: read_u32(payload, at),
+            base_frame: read_u64(payload, at + 8),
+            frame: read_u64(payload, at + 16),
+            rects,
+        });
+        at = end;
+    }
+    Ok(entries)
+}
+
+///Encodes the payload of a section::DIRTY_RECTS section. Used by producers and tests.
+pub fn encode_dirty(entries: &[SlotDirty]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    for entry in entries {
+        out.extend_from_slice(&entry.slot.to_le_bytes());
+        out.extend_from_slice(&(entry.rects.len() as u32).to_le_bytes());
+        out.extend_from_slice(&entry.base_frame.to_le_bytes());
+        out.extend_from_slice(&entry.frame.to_le_bytes());
+        for rect in &entry.rects {
+            out.extend_from_slice(&(rect.x.max(0) as u32).to_le_bytes());
+            out.extend_from_slice(&(rect.y.max(0) as u32).to_le_bytes());
+            out.extend_from_slice(&rect.width.to_le_bytes());
+            out.extend_from_slice(&rect.height.to_le_bytes());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirty(base_frame: u64, frame: u64, rects: Vec<Rect>) -> SlotDirty {
+        SlotDirty {
+            slot: 0,
+            base_frame,
+            frame,
+            rects,
+        }
+    }
+
+    #[test]
+    fn merges_within_merge_distance() {
+        let a = Rect::new(0, 0, 10, 10);
+        //Starts MERGE_DISTANCE pixels after a ends.
+        let close = Rect::new(10 + MERGE_DISTANCE as i32, 0, 10, 10);
+        let far = Rect::new(11 + MERGE_DISTANCE as i32, 0, 10, 10);
+        assert_eq!(merge(&[a, close]), [Rect::new(0, 0, 28, 10)]);
+        assert_eq!(merge(&[a, far]), [a, far]);
+
+        let below = Rect::new(0, 10 + MERGE_DISTANCE as i32, 10, 10);
+        assert_eq!(merge(&[a, below]), [Rect::new(0, 0, 10, 28)]);
+    }
+
+    #[test]
+    fn merging_is_transitive() {
+        //c only gets close to a once b grew it.
+        let a = Rect::new(0, 0, 10, 10);
+        let c = Rect::new(36, 0, 10, 10);
+        let b = Rect::new(18, 0, 10, 10);
+        assert_eq!(merge(&[a, c, b]), [Rect::new(0, 0, 46, 10)]);
+    }
+
+    #[test]
+    fn merge_drops_empty_rects() {
+        let a = Rect::new(0, 0, 10, 10);
+        assert_eq!(merge(&[Rect::new(5, 5, 0, 10), a]), [a]);
+    }
+
+    #[test]
+    fn clip_to_the_texture() {
+        let rects = [
+            Rect::new(-5, -5, 10, 10),
+            Rect::new(95, 50, 10, 10),
+            Rect::new(100, 0, 10, 10),
+            Rect::new(0, 200, 10, 10),
+        ];
+        assert_eq!(
+            clip(&rects, 100, 100),
+            [Rect::new(0, 0, 5, 5), Rect::new(95, 50, 5, 10)]
+        );
+    }
+
+    #[test]
+    fn copies_regions_on_top_of_base_frame() {
+        let rects = vec![Rect::new(0, 0, 10, 10), Rect::new(50, 50, 10, 10)];
+        assert_eq!(
+            plan_copy(Some(4), &dirty(4, 5, rects.clone()), 100, 100),
+            CopyPlan::Regions(rects)
+        );
+        assert_eq!(
+            plan_copy(Some(5), &dirty(4, 5, vec![]), 100, 100),
+            CopyPlan::Nothing
+        );
+        //Everything fell off the texture.
+        assert_eq!(
+            plan_copy(
+                Some(4),
+                &dirty(4, 5, vec![Rect::new(200, 0, 5, 5)]),
+                100,
+                100
+            ),
+            CopyPlan::Nothing
+        );
+    }
+
+    #[test]
+    fn full_copy_without_base_frame() {
+        let rects = vec![Rect::new(0, 0, 10, 10)];
+        assert_eq!(
+            plan_copy(Some(3), &dirty(4, 5, rects.clone()), 100, 100),
+            CopyPlan::Full
+        );
+        assert_eq!(
+            plan_copy(None, &dirty(4, 5, rects), 100, 100),
+            CopyPlan::Full
+        );
+    }
+
+    #[test]
+    fn full_copy_when_cheaper() {
+        //Far enough apart to never merge.
+        let many: Vec<Rect> = (0..=MAX_COPIES as i32)
+            .map(|i| Rect::new(i * 20, 0, 1, 1))
+            .collect();
+        assert_eq!(
+            plan_copy(
+                Some(4),
+                &dirty(4, 5, many[..MAX_COPIES].to_vec()),
+                1000,
+                1000
+            ),
+            CopyPlan::Regions(many[..MAX_COPIES].to_vec())
+        );
+        assert_eq!(
+            plan_copy(Some(4), &dirty(4, 5, many), 1000, 1000),
+            CopyPlan::Full
+        );
+
+        //Three quarters of the texture.
+        let most = vec![Rect::new(0, 0, 100, 75)];
+        assert_eq!(
+            plan_copy(Some(4), &dirty(4, 5, most), 100, 100),
+            CopyPlan::Full
+        );
+        let less = vec![Rect::new(0, 0, 100, 74)];
+        assert_eq!(
+            plan_copy(Some(4), &dirty(4, 5, less.clone()), 100, 100),
+            CopyPlan::Regions(less)
+        );
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let entries = vec![
+            dirty(1, 2, vec![Rect::new(1, 2, 3, 4)]),
+            SlotDirty {
+                slot: 1,
+                ..dirty(u64::MAX - 1, u64::MAX, vec![])
+            },
+        ];
+        assert_eq!(decode_dirty(&encode_dirty(&entries)), Ok(entries));
+
+        let payload = encode_dirty(&[dirty(1, 2, vec![Rect::new(1, 2, 3, 4)])]);
+        assert_eq!(
+            decode_dirty(&payload[..payload.len() - 1]),
+            Err(HeaderError::BadSection { offset: 8 })
+        );
+        let too_many = dirty(1, 2, vec![Rect::new(0, 0, 1, 1); MAX_DIRTY_RECTS + 1]);
+        assert_eq!(
+            decode_dirty(&encode_dirty(&[too_many])),
+            Err(HeaderError::BadSection { offset: 8 })
+        );
+    }
+}
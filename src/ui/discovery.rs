@@ -0,0 +1,399 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/*
+ *
+ * Discovery of game instances. Plain Rust, no windows.
+ *
+ * Every kernel object shared with a producer is named after a prefix made of the configured
+ * namespace and the game's PID, so several games (and their overlays) can run side by side.
+ * To find those names, producers read a table of records in a well-known MMF
+ * (ObjectNames::discovery), shared by every game running with the same namespace.
+ *
+ * The table is accessed as u32 words, little endian, with atomic operations since several
+ * processes use it at the same time:
+ *
+ *   0  magic         DISCOVERY_MAGIC, set by the first game creating the table
+ *   1  version       DISCOVERY_VERSION
+ *   2  record_count  DISCOVERY_RECORDS
+ *   3  reserved
+ *   4  records       RECORD_WORDS each
+ *
+ * Record:
+ *
+ *   0  pid           PID of the game owning the record, 0 when free
+ *   1  state         See record_state
+ *   2  producer_pid  Set by the producer when it pairs with the game
+ *   3  reserved
+ *   4  prefix        PREFIX_LEN bytes, UTF-8 padded with zeroes
+ *
+ * A game claims a record by swapping its pid in, fills the prefix, then sets the state to
+ * WAITING. Producers look for a WAITING record, swap the state to PAIRED and write their pid.
+ * A producer leaving on its own puts the record back to WAITING.
+ * Records of games that died without cleaning up are reused.
+ *
+ * */
+
+pub const DISCOVERY_MAGIC: u32 = u32::from_le_bytes(*b"BHDS");
+pub const DISCOVERY_VERSION: u32 = 1;
+pub const DISCOVERY_RECORDS: usize = 16;
+pub const PREFIX_LEN: usize = 48;
+pub const TABLE_HEADER_WORDS: usize = 4;
+pub const RECORD_WORDS: usize = 4 + PREFIX_LEN / 4;
+pub const DISCOVERY_WORDS: usize = TABLE_HEADER_WORDS + DISCOVERY_RECORDS * RECORD_WORDS;
+//Size of the MMF holding the table.
+pub const DISCOVERY_LEN: usize = DISCOVERY_WORDS * 4;
+//Namespaces longer than this wouldn't leave room for the PID in the prefix.
+pub const MAX_NAMESPACE_LEN: usize = 32;
+
+pub mod record_state {
+    pub const FREE: u32 = 0;
+    //The game is ready, no producer yet.
+    pub const WAITING: u32 = 1;
+    pub const PAIRED: u32 = 2;
+}
+
+//Names of every kernel object shared with the producer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectNames {
+    //Prefix of every per-game object, published in the discovery record.
+    pub prefix: String,
+    pub header: String,
+    pub wake_event: String,
+    pub resize_event: String,
+    pub alive_mutex: String,
+    //Shared by every game using the same namespace.
+    pub discovery: String,
+}
+
+impl ObjectNames {
+    ///Global objects are visible across sessions, which needs more privileges than local ones.
+    pub fn new(namespace: &str, global: bool, pid: u32) -> ObjectNames {
+        let scope = if global { "Global\\" } else { "Local\\" };
+        let prefix = format!("{}_{}", namespace, pid);
+        ObjectNames {
+            header: format!("{}{}_Header", scope, prefix),
+            wake_event: format!("{}{}_WakeEvent", scope, prefix),
+            resize_event: format!("{}{}_ResizeEvent", scope, prefix),
+            alive_mutex: format!("{}{}_Alive", scope, prefix),
+            discovery: format!("{}{}_Discovery", scope, namespace),
+            prefix,
+        }
+    }
+}
+
+//A published record, as read back from the table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub pid: u32,
+    pub state: u32,
+    pub producer_pid: u32,
+    pub prefix: String,
+}
+
+//View over the table, wherever it is mapped.
+pub struct DiscoveryTable<'a> {
+    words: &'a [AtomicU32],
+}
+
+impl<'a> DiscoveryTable<'a> {
+    ///None if there aren't DISCOVERY_WORDS words.
+    pub fn new(words: &'a [AtomicU32]) -> Option<DiscoveryTable<'a>> {
+        if words.len() < DISCOVERY_WORDS {
+            return None;
+        }
+        Some(DiscoveryTable { words })
+    }
+
+    ///Sets up a freshly created (zeroed) table. Returns false if another version
+    ///of the table is already there.
+    pub fn init(&self) -> bool {
+        match self.words[0].compare_exchange(
+            0,
+            DISCOVERY_MAGIC,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                self.words[1].store(DISCOVERY_VERSION, Ordering::Release);
+                self.words[2].store(DISCOVERY_RECORDS as u32, Ordering::Release);
+                true
+            }
+            //Whoever created it may still be writing the version, it can only be ours or newer.
+            Err(DISCOVERY_MAGIC) => {
+                let version = self.words[1].load(Ordering::Acquire);
+                version == 0 || version == DISCOVERY_VERSION
+            }
+            Err(_) => false,
+        }
+    }
+
+    ///Publishes a record for this game. Records owned by processes that are gone
+    ///(according to is_alive) are taken over. Returns the index of the record.
+    pub fn claim(&self, pid: u32, prefix: &str, is_alive: impl Fn(u32) -> bool) -> Option<usize> {
+        if pid == 0 || prefix.is_empty() || prefix.len() > PREFIX_LEN {
+            return None;
+        }
+        let index = (0..DISCOVERY_RECORDS)
+            .find(|i| self.record_words(*i)[0].load(Ordering::Acquire) == pid)
+            .or_else(|| (0..DISCOVERY_RECORDS).find(|i| self.try_take(*i, pid, &is_alive)))?;
+
+        let words = self.record_words(index);
+        words[1].store(record_state::FREE, Ordering::Release);
+        words[2].store(0, Ordering::Release);
+        let mut bytes = [0u8; PREFIX_LEN];
+        bytes[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        for (word, chunk) in words[4..].iter().zip(bytes.chunks(4)) {
+            word.store(
+                u32::from_le_bytes(chunk.try_into().unwrap()),
+                Ordering::Release,
+            );
+        }
+        words[1].store(record_state::WAITING, Ordering::Release);
+        Some(index)
+    }
+
+    ///Frees the record, if it still belongs to pid.
+    pub fn release(&self, index: usize, pid: u32) {
+        if index >= DISCOVERY_RECORDS {
+            return;
+        }
+        let words = self.record_words(index);
+        if words[0].load(Ordering::Acquire) != pid {
+            return;
+        }
+        words[1].store(record_state::FREE, Ordering::Release);
+        words[2].store(0, Ordering::Release);
+        words[0]
+            .compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Acquire)
+            .ok();
+    }
+
+    ///Producer side: takes a WAITING record. False if someone else got it first.
+    pub fn pair(&self, index: usize, producer_pid: u32) -> bool {
+        if index >= DISCOVERY_RECORDS || producer_pid == 0 {
+            return false;
+        }
+        let words = self.record_words(index);
+        if words[1]
+            .compare_exchange(
+                record_state::WAITING,
+                record_state::PAIRED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            return false;
+        }
+        words[2].store(producer_pid, Ordering::Release);
+        true
+    }
+
+    ///Producer side: gives the record back to the game, if it is still paired with producer_pid.
+    pub fn unpair(&self, index: usize, producer_pid: u32) {
+        if index >= DISCOVERY_RECORDS {
+            return;
+        }
+        let words = self.record_words(index);
+        if words[1].load(Ordering::Acquire) != record_state::PAIRED
+            || words[2].load(Ordering::Acquire) != producer_pid
+        {
+            return;
+        }
+        words[2].store(0, Ordering::Release);
+        words[1]
+            .compare_exchange(
+                record_state::PAIRED,
+                record_state::WAITING,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .ok();
+    }
+
+    pub fn record(&self, index: usize) -> Option<Record> {
+        if index >= DISCOVERY_RECORDS {
+            return None;
+        }
+        let words = self.record_words(index);
+        let mut bytes = Vec::with_capacity(PREFIX_LEN);
+        for word in &words[4..] {
+            bytes.extend_from_slice(&word.load(Ordering::Acquire).to_le_bytes());
+        }
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(PREFIX_LEN);
+        Some(Record {
+            pid: words[0].load(Ordering::Acquire),
+            state: words[1].load(Ordering::Acquire),
+            producer_pid: words[2].load(Ordering::Acquire),
+            prefix: String::from_utf8_lossy(&bytes[..len]).into_owned(),
+        })
+    }
+
+    fn try_take(&self, index: usize, pid: u32, is_alive: &impl Fn(u32) -> bool) -> bool {
+        let owner = &self.record_words(index)[0];
+        let current = owner.load(Ordering::Acquire);
+        if current != 0 && is_alive(current) {
+            return false;
+        }
+        owner
+            .compare_exchange(current, pid, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    fn record_words(&self, index: usize) -> &[AtomicU32] {
+        let start = TABLE_HEADER_WORDS + index * RECORD_WORDS;
+        &self.words[start..start + RECORD_WORDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn words() -> Vec<AtomicU32> {
+        (0..DISCOVERY_WORDS).map(|_| AtomicU32::new(0)).collect()
+    }
+
+    fn alive(pid: u32) -> bool {
+        pid != 666
+    }
+
+    #[test]
+    fn object_names_are_local_by_default() {
+        let ipc = Config::default().ipc;
+        let names = ObjectNames::new(&ipc.namespace, ipc.global, 1234);
+        assert_eq!(names.prefix, "BlishHUD_1234");
+        assert_eq!(names.header, "Local\\BlishHUD_1234_Header");
+        assert_eq!(names.discovery, "Local\\BlishHUD_Discovery");
+        assert_eq!(
+            ObjectNames::new("ns", true, 1).wake_event,
+            "Global\\ns_1_WakeEvent"
+        );
+    }
+
+    #[test]
+    fn init() {
+        let words = words();
+        assert!(DiscoveryTable::new(&words[1..]).is_none());
+        let table = DiscoveryTable::new(&words).unwrap();
+        assert!(table.init());
+        assert_eq!(words[0].load(Ordering::Relaxed), DISCOVERY_MAGIC);
+        assert_eq!(words[1].load(Ordering::Relaxed), DISCOVERY_VERSION);
+        assert_eq!(words[2].load(Ordering::Relaxed), DISCOVERY_RECORDS as u32);
+        //Opened by another game.
+        assert!(table.init());
+
+        words[1].store(DISCOVERY_VERSION + 1, Ordering::Relaxed);
+        assert!(!table.init());
+        let other = self::words();
+        other[0].store(1, Ordering::Relaxed);
+        assert!(!DiscoveryTable::new(&other).unwrap().init());
+    }
+
+    #[test]
+    fn claim_and_release() {
+        let words = words();
+        let table = DiscoveryTable::new(&words).unwrap();
+        table.init();
+        assert_eq!(table.claim(10, "ns_10", alive), Some(0));
+        assert_eq!(table.claim(11, "ns_11", alive), Some(1));
+        assert_eq!(
+            table.record(1),
+            Some(Record {
+                pid: 11,
+                state: record_state::WAITING,
+                producer_pid: 0,
+                prefix: "ns_11".to_string(),
+            })
+        );
+        //Claiming again reuses the same record.
+        assert_eq!(table.claim(10, "ns_10", alive), Some(0));
+
+        //Only the owner can release it.
+        table.release(0, 11);
+        assert_eq!(table.record(0).unwrap().pid, 10);
+        table.release(0, 10);
+        assert_eq!(table.record(0).unwrap().pid, 0);
+        assert_eq!(table.record(0).unwrap().state, record_state::FREE);
+        assert_eq!(table.claim(12, "ns_12", alive), Some(0));
+
+        assert_eq!(table.claim(0, "ns_0", alive), None);
+        assert_eq!(table.claim(13, "", alive), None);
+        assert_eq!(table.claim(13, &"x".repeat(PREFIX_LEN + 1), alive), None);
+        let longest = "x".repeat(PREFIX_LEN);
+        let index = table.claim(13, &longest, alive).unwrap();
+        assert_eq!(table.record(index).unwrap().prefix, longest);
+        assert_eq!(table.record(DISCOVERY_RECORDS), None);
+    }
+
+    #[test]
+    fn full_table() {
+        let words = words();
+        let table = DiscoveryTable::new(&words).unwrap();
+        table.init();
+        for pid in 1..=DISCOVERY_RECORDS as u32 {
+            assert!(table.claim(pid, "ns", alive).is_some());
+        }
+        assert_eq!(table.claim(100, "ns", alive), None);
+    }
+
+    #[test]
+    fn takes_over_records_of_dead_games() {
+        let words = words();
+        let table = DiscoveryTable::new(&words).unwrap();
+        table.init();
+        for pid in 1..DISCOVERY_RECORDS as u32 {
+            table.claim(pid, "ns", alive);
+        }
+        let dead = table.claim(666, "ns_666", alive).unwrap();
+        assert!(table.pair(dead, 50));
+
+        let index = table.claim(20, "ns_20", alive).unwrap();
+        assert_eq!(index, dead);
+        //Nothing of the dead game's pairing is left.
+        assert_eq!(
+            table.record(index),
+            Some(Record {
+                pid: 20,
+                state: record_state::WAITING,
+                producer_pid: 0,
+                prefix: "ns_20".to_string(),
+            })
+        );
+        //The dead game can't free it anymore.
+        table.release(index, 666);
+        assert_eq!(table.record(index).unwrap().pid, 20);
+    }
+
+    #[test]
+    fn pair_and_unpair() {
+        let words = words();
+        let table = DiscoveryTable::new(&words).unwrap();
+        table.init();
+        assert!(!table.pair(0, 50));
+        let index = table.claim(10, "ns_10", alive).unwrap();
+        assert!(!table.pair(index, 0));
+        assert!(table.pair(index, 50));
+        //Someone else got it first.
+        assert!(!table.pair(index, 51));
+        let record = table.record(index).unwrap();
+        assert_eq!(record.state, record_state::PAIRED);
+        assert_eq!(record.producer_pid, 50);
+
+        //Only the paired producer can give it back.
+        table.unpair(index, 51);
+        assert_eq!(table.record(index).unwrap().state, record_state::PAIRED);
+        table.unpair(index, 50);
+        let record = table.record(index).unwrap();
+        assert_eq!(record.state, record_state::WAITING);
+        assert_eq!(record.producer_pid, 0);
+        assert!(table.pair(index, 51));
+
+        //Released while paired.
+        table.release(index, 10);
+        assert_eq!(table.record(index).unwrap().state, record_state::FREE);
+        assert!(!table.pair(index, 52));
+        assert!(!table.pair(DISCOVERY_RECORDS, 52));
+    }
+}
@@ -0,0 +1,492 @@
+use super::{
+    dirty::SlotDirty,
+    layers::{LayerList, MAIN_LAYER, Placement},
+};
+use crate::debug::debug_overlay::PanelFacts;
+
+/*
+ *
+ * Decisions of the present hook. Plain Rust, no windows: the hook gathers what it knows about
+ * the swapchain and its D3D state, takes a snapshot of the MMF data, and FramePlanner tells it
+ * what to do with this frame. The hook only carries the plan out.
+ *
+ * In order, a frame:
+ *  - is left alone if rendering is disabled,
+ *  - recreates the overlay state if there is none, or it lost its size or its device,
+ *  - is left alone if there is no producer, or it faded out,
+ *  - resizes the state to the back buffer if the producer's textures changed,
+ *  - forgets and (re)opens the textures of layers whose slots changed,
+ *  - updates the composition texture of the main layer when the producer sends dirty rects,
+ *  - draws every visible layer, back to front.
+ *
+ * The debug panel is drawn on top whenever rendering is enabled, with or without a producer.
+ *
+ * */
+
+//What the hook knows about the swapchain and the state it keeps across frames.
+#[derive(Debug, Clone, Default)]
+pub struct SwapchainFacts<'a> {
+    pub rendering_enabled: bool,
+    //None when there is no overlay state yet.
+    pub state: Option<StateFacts<'a>>,
+    //Size of the back buffer, which the state takes when created or resized.
+    pub back_buffer: (u32, u32),
+    //None while the debug panel is toggled off.
+    pub panel: Option<PanelFacts>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StateFacts<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub device_removed: bool,
+    //Slots the textures of each layer were opened from, even if opening them failed.
+    pub textures: Vec<(&'a str, &'a [u64])>,
+}
+
+//The part of MMFData the hook needs, read under its lock.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSnapshot<'a> {
+    pub is_blish_alive: bool,
+    pub layers: &'a LayerList,
+    pub dirty: Option<&'a SlotDirty>,
+    //Opacity of the fade out, at the time of the frame.
+    pub fade: f32,
+    //UPDATE_SCHEDULED: the producer's textures changed.
+    pub update_scheduled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FramePlan {
+    //Create the overlay state again before anything else. It starts without textures.
+    pub recreate_state: bool,
+    //New value of OVERLAY_HIDDEN, None leaves it as it is.
+    pub hidden: Option<bool>,
+    pub action: FrameAction,
+    //Where to draw the debug panel, after the layers if there are any.
+    pub panel: Option<Placement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameAction {
+    //Only call the original present.
+    Skip(SkipReason),
+    Draw(DrawPlan),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    RenderingDisabled,
+    //The producer is gone, or sent nothing to draw.
+    NoProducer,
+    FadedOut,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DrawPlan {
+    //Resize the state to the back buffer, and clear UPDATE_SCHEDULED.
+    pub resize: bool,
+    //Layers whose textures must be dropped, they are gone or announce null handles.
+    pub forget: Vec<String>,
+    //Layers whose textures must be opened, in drawing order. See FramePlanner::open_failed.
+    pub open: Vec<String>,
+    //Slot of the main layer to bring the composition texture up to date with.
+    pub compose: Option<usize>,
+    pub draws: Vec<LayerDraw>,
+}
+
+//One layer to draw. Skipped by the hook if it has no view for the slot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerDraw {
+    pub name: String,
+    //Comes from the producer, the hook must check it against the textures it has.
+    pub index: usize,
+    pub placement: Placement,
+    //Opacity of the layer, fade included.
+    pub opacity: f32,
+    //Sample the composition texture if it holds this slot, only set for the main layer.
+    pub dirty: bool,
+}
+
+//What to do when the textures of a layer could not be opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenFailure {
+    //The main layer is the producer itself, nothing can be drawn without it. Shut the overlay
+    //down and skip the frame.
+    Shutdown,
+    //Remember the failure so it isn't retried every frame, and keep drawing the others.
+    Remember,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FramePlanner;
+
+impl FramePlanner {
+    pub fn plan(&self, facts: &SwapchainFacts, snapshot: &FrameSnapshot) -> FramePlan {
+        if !facts.rendering_enabled {
+            return FramePlan {
+                recreate_state: false,
+                hidden: None,
+                action: FrameAction::Skip(SkipReason::RenderingDisabled),
+                panel: None,
+            };
+        }
+        let recreate_state = facts
+            .state
+            .as_ref()
+            .is_none_or(|state| state.width == 0 || state.height == 0 || state.device_removed);
+        let panel =
+            |(width, height): (u32, u32)| facts.panel.and_then(|panel| panel.place(width, height));
+        //A new state has the size of the back buffer.
+        let size = match (&facts.state, recreate_state) {
+            (Some(state), false) => (state.width, state.height),
+            _ => facts.back_buffer,
+        };
+        let skip = |hidden: Option<bool>, reason: SkipReason| FramePlan {
+            recreate_state,
+            hidden,
+            action: FrameAction::Skip(reason),
+            panel: panel(size),
+        };
+
+        if !snapshot.is_blish_alive || snapshot.layers.is_empty() {
+            return skip(None, SkipReason::NoProducer);
+        }
+        //A frozen producer fades out instead of showing its last frame forever.
+        let hidden = snapshot.fade == 0.0;
+        if hidden {
+            return skip(Some(hidden), SkipReason::FadedOut);
+        }
+
+        //A new state has no textures.
+        let textures = match (&facts.state, recreate_state) {
+            (Some(state), false) => state.textures.as_slice(),
+            _ => [].as_slice(),
+        };
+        let (mut width, mut height) = size;
+        let mut plan = DrawPlan {
+            resize: snapshot.update_scheduled,
+            ..Default::default()
+        };
+        let mut textures: Vec<(&str, &[u64])> = textures.to_vec();
+        if plan.resize {
+            (width, height) = facts.back_buffer;
+            for (name, _) in &textures {
+                if snapshot.layers.get(name).is_none() {
+                    plan.forget.push(name.to_string());
+                }
+            }
+            textures.retain(|(name, _)| snapshot.layers.get(name).is_some());
+        }
+
+        for layer in snapshot.layers.iter() {
+            let opened = textures.iter().find(|(name, _)| *name == layer.name);
+            if opened.is_some_and(|(_, slots)| *slots == layer.slots.as_slice()) {
+                continue;
+            }
+            if layer.slots.contains(&0) {
+                if opened.is_some() {
+                    plan.forget.push(layer.name.clone());
+                }
+                continue;
+            }
+            plan.open.push(layer.name.clone());
+        }
+
+        plan.compose = snapshot
+            .dirty
+            .and(snapshot.layers.get(MAIN_LAYER))
+            .map(|main| main.index as usize);
+
+        plan.draws = snapshot
+            .layers
+            .iter()
+            .filter_map(|layer| {
+                Some(LayerDraw {
+                    name: layer.name.clone(),
+                    index: layer.index as usize,
+                    placement: layer.place(width, height)?,
+                    opacity: layer.opacity * snapshot.fade,
+                    dirty: snapshot.dirty.is_some() && layer.name == MAIN_LAYER,
+                })
+            })
+            .collect();
+
+        FramePlan {
+            recreate_state,
+            hidden: Some(hidden),
+            action: FrameAction::Draw(plan),
+            panel: panel((width, height)),
+        }
+    }
+
+    ///Called by the hook for every layer of DrawPlan::open it couldn't open.
+    pub fn open_failed(&self, layer: &str) -> OpenFailure {
+        if layer == MAIN_LAYER {
+            OpenFailure::Shutdown
+        } else {
+            OpenFailure::Remember
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        debug::debug_overlay::Corner,
+        ui::layers::{Layer, Rect},
+    };
+
+    const BACK_BUFFER: (u32, u32) = (1920, 1080);
+
+    fn layer(name: &str, z: i32, slots: &[u64]) -> Layer {
+        Layer {
+            name: name.to_string(),
+            z,
+            ..Layer::main(slots, 1)
+        }
+    }
+
+    //A state of the back buffer's size, with the given textures opened.
+    fn facts<'a>(textures: &[(&'a str, &'a [u64])]) -> SwapchainFacts<'a> {
+        SwapchainFacts {
+            rendering_enabled: true,
+            state: Some(StateFacts {
+                width: BACK_BUFFER.0,
+                height: BACK_BUFFER.1,
+                device_removed: false,
+                textures: textures.to_vec(),
+            }),
+            back_buffer: BACK_BUFFER,
+            panel: None,
+        }
+    }
+
+    fn snapshot(layers: &LayerList) -> FrameSnapshot<'_> {
+        FrameSnapshot {
+            is_blish_alive: true,
+            layers,
+            dirty: None,
+            fade: 1.0,
+            update_scheduled: false,
+        }
+    }
+
+    fn draw_plan(plan: FramePlan) -> DrawPlan {
+        match plan.action {
+            FrameAction::Draw(plan) => plan,
+            FrameAction::Skip(reason) => panic!("skipped: {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn nothing_happens_while_rendering_is_disabled() {
+        let layers = LayerList::new(vec![Layer::main(&[1, 2], 0)]);
+        let facts = SwapchainFacts {
+            rendering_enabled: false,
+            ..Default::default()
+        };
+        let plan = FramePlanner.plan(&facts, &snapshot(&layers));
+        assert!(!plan.recreate_state);
+        assert_eq!(plan.hidden, None);
+        assert_eq!(
+            plan.action,
+            FrameAction::Skip(SkipReason::RenderingDisabled)
+        );
+    }
+
+    #[test]
+    fn recreates_missing_empty_or_removed_states() {
+        let layers = LayerList::new(vec![Layer::main(&[1, 2], 0)]);
+        let snapshot = snapshot(&layers);
+        let opened: &[(&str, &[u64])] = &[(MAIN_LAYER, &[1, 2])];
+
+        let plan = FramePlanner.plan(&facts(opened), &snapshot);
+        assert!(!plan.recreate_state);
+        assert_eq!(draw_plan(plan).open, Vec::<String>::new());
+
+        let broken = [
+            SwapchainFacts {
+                state: None,
+                ..facts(opened)
+            },
+            SwapchainFacts {
+                state: Some(StateFacts {
+                    width: 0,
+                    ..facts(opened).state.unwrap()
+                }),
+                ..facts(opened)
+            },
+            SwapchainFacts {
+                state: Some(StateFacts {
+                    device_removed: true,
+                    ..facts(opened).state.unwrap()
+                }),
+                ..facts(opened)
+            },
+        ];
+        for facts in broken {
+            let plan = FramePlanner.plan(&facts, &snapshot);
+            assert!(plan.recreate_state, "{:?}", facts.state);
+            //A new state has no textures, they are opened again.
+            assert_eq!(draw_plan(plan).open, [MAIN_LAYER]);
+        }
+    }
+
+    #[test]
+    fn skips_without_producer_but_keeps_the_panel() {
+        let empty = LayerList::default();
+        let facts = SwapchainFacts {
+            panel: Some(PanelFacts {
+                width: 200,
+                height: 100,
+                corner: Corner::BottomRight,
+                margin: 10,
+            }),
+            ..facts(&[])
+        };
+        let plan = FramePlanner.plan(&facts, &snapshot(&empty));
+        assert_eq!(plan.action, FrameAction::Skip(SkipReason::NoProducer));
+        assert_eq!(plan.hidden, None);
+        assert_eq!(plan.panel.unwrap().visible, Rect::new(1710, 970, 200, 100));
+
+        let layers = LayerList::new(vec![Layer::main(&[1, 2], 0)]);
+        let dead = FrameSnapshot {
+            is_blish_alive: false,
+            ..snapshot(&layers)
+        };
+        let plan = FramePlanner.plan(&facts, &dead);
+        assert_eq!(plan.action, FrameAction::Skip(SkipReason::NoProducer));
+    }
+
+    #[test]
+    fn hides_the_overlay_once_faded_out() {
+        let layers = LayerList::new(vec![Layer::main(&[1, 2], 0)]);
+        let faded = FrameSnapshot {
+            fade: 0.0,
+            ..snapshot(&layers)
+        };
+        let plan = FramePlanner.plan(&facts(&[]), &faded);
+        assert_eq!(plan.hidden, Some(true));
+        assert_eq!(plan.action, FrameAction::Skip(SkipReason::FadedOut));
+
+        let fading = FrameSnapshot {
+            fade: 0.5,
+            ..snapshot(&layers)
+        };
+        let plan = FramePlanner.plan(&facts(&[(MAIN_LAYER, &[1, 2])]), &fading);
+        assert_eq!(plan.hidden, Some(false));
+        let plan = draw_plan(plan);
+        assert_eq!(plan.draws.len(), 1);
+        assert_eq!(plan.draws[0].opacity, 0.5);
+    }
+
+    #[test]
+    fn opens_and_forgets_layers_whose_slots_changed() {
+        let layers = LayerList::new(vec![
+            Layer::main(&[1, 2], 1),
+            layer("map", 5, &[3, 4]),
+            layer("new", 10, &[5, 6]),
+            layer("closing", -1, &[0, 0]),
+        ]);
+        let facts = facts(&[
+            (MAIN_LAYER, &[1, 2]),
+            ("map", &[3, 7]),
+            ("closing", &[8, 9]),
+        ]);
+        let plan = draw_plan(FramePlanner.plan(&facts, &snapshot(&layers)));
+        assert!(!plan.resize);
+        //Null handles are never opened.
+        assert_eq!(plan.forget, ["closing"]);
+        assert_eq!(plan.open, ["map", "new"]);
+        //Layers without textures are drawn anyway, the hook skips them.
+        let draws: Vec<_> = plan.draws.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(draws, ["closing", MAIN_LAYER, "map", "new"]);
+        assert!(plan.draws.iter().all(|d| d.index == 1));
+    }
+
+    #[test]
+    fn never_opens_layers_announcing_null_handles() {
+        let layers = LayerList::new(vec![Layer::main(&[0, 2], 0)]);
+        let plan = draw_plan(FramePlanner.plan(&facts(&[]), &snapshot(&layers)));
+        assert!(plan.open.is_empty());
+        assert!(plan.forget.is_empty());
+    }
+
+    #[test]
+    fn resizes_and_forgets_vanished_layers() {
+        let layers = LayerList::new(vec![Layer::main(&[1, 2], 0)]);
+        let opened: &[(&str, &[u64])] = &[(MAIN_LAYER, &[1, 2]), ("gone", &[3, 4])];
+        let facts = SwapchainFacts {
+            state: Some(StateFacts {
+                width: 800,
+                height: 600,
+                ..facts(opened).state.unwrap()
+            }),
+            ..facts(opened)
+        };
+        let scheduled = FrameSnapshot {
+            update_scheduled: true,
+            ..snapshot(&layers)
+        };
+        let plan = draw_plan(FramePlanner.plan(&facts, &scheduled));
+        assert!(plan.resize);
+        assert_eq!(plan.forget, ["gone"]);
+        assert!(plan.open.is_empty());
+        //Drawn at the size of the back buffer, not the old one.
+        assert_eq!(
+            plan.draws[0].placement.visible,
+            Rect::new(0, 0, BACK_BUFFER.0, BACK_BUFFER.1)
+        );
+
+        let plan = draw_plan(FramePlanner.plan(&facts, &snapshot(&layers)));
+        assert!(!plan.resize);
+        assert_eq!(plan.draws[0].placement.visible, Rect::new(0, 0, 800, 600));
+    }
+
+    #[test]
+    fn composes_the_main_layer_when_dirty() {
+        let layers = LayerList::new(vec![Layer::main(&[1, 2], 1), layer("map", 5, &[3, 4])]);
+        let dirty = SlotDirty {
+            slot: 1,
+            base_frame: 1,
+            frame: 2,
+            rects: vec![Rect::new(0, 0, 10, 10)],
+        };
+        let facts = facts(&[(MAIN_LAYER, &[1, 2]), ("map", &[3, 4])]);
+        let with_dirty = FrameSnapshot {
+            dirty: Some(&dirty),
+            ..snapshot(&layers)
+        };
+        let plan = draw_plan(FramePlanner.plan(&facts, &with_dirty));
+        assert_eq!(plan.compose, Some(1));
+        let flags: Vec<_> = plan
+            .draws
+            .iter()
+            .map(|d| (d.name.as_str(), d.dirty))
+            .collect();
+        assert_eq!(flags, [(MAIN_LAYER, true), ("map", false)]);
+
+        let plan = draw_plan(FramePlanner.plan(&facts, &snapshot(&layers)));
+        assert_eq!(plan.compose, None);
+        assert!(plan.draws.iter().all(|d| !d.dirty));
+
+        //Nothing to compose without a main layer.
+        let no_main = LayerList::new(vec![layer("map", 5, &[3, 4])]);
+        let plan = draw_plan(FramePlanner.plan(
+            &facts,
+            &FrameSnapshot {
+                dirty: Some(&dirty),
+                ..snapshot(&no_main)
+            },
+        ));
+        assert_eq!(plan.compose, None);
+    }
+
+    #[test]
+    fn only_the_main_layer_failing_shuts_down() {
+        assert_eq!(FramePlanner.open_failed(MAIN_LAYER), OpenFailure::Shutdown);
+        assert_eq!(FramePlanner.open_failed("map"), OpenFailure::Remember);
+    }
+}
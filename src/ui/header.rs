@@ -0,0 +1,709 @@
+use std::fmt;
+
+/*
+ *
+ * Typed codec for the header shared with the producer (BlishHUD) through the MMF.
+ * Everything here is plain Rust working on byte slices so it does not depend on windows.
+ *
+ * Layout (little endian). Offsets are fixed, new fields are only ever appended,
+ * and header_len tells the other side how much of it is actually valid.
+ *
+ *   0  magic             u32   Written by the producer, must be HEADER_MAGIC
+ *   4  version           u16   Protocol version of the producer
+ *   6  header_len        u16   Size of the header written by the producer
+ *   8  features          u32   Feature bitflags supported by the producer
+ *  12  consumer_version  u16   Protocol version of the DLL, written by us
+ *  14  sections_offset   u16   Where the section directory starts, 0 if there is none (version 3+)
+ *  16  width             u32   Written by us
+ *  20  height            u32   Written by us
+ *  24  index             u32   Slot the producer wants us to draw
+ *  28  slot_count        u32   Number of shared textures (version 2+, always 2 before that)
+ *  32  slots             u64 * slot_count   Shared texture handles
+ *
+ * Optional data lives in sections, somewhere after the slots but within header_len.
+ * Each section is a tag u32, a payload length u32, then the payload, padded to 8 bytes.
+ * The directory ends on a END tag or at header_len, whichever comes first.
+ *
+ * */
+
+pub const HEADER_MAGIC: u32 = u32::from_le_bytes(*b"BHUD");
+pub const PROTOCOL_VERSION: u16 = 3;
+//Oldest producer we still know how to talk to.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+//Size of the fixed part of the header. The slots follow it.
+pub const HEADER_LEN: usize = 32;
+
+//Version 1 producers always double buffered and had no slot_count field.
+pub const LEGACY_SLOT_COUNT: u32 = 2;
+//More than enough for triple buffering, and keeps the header within a few cache lines.
+pub const MAX_SLOTS: usize = 8;
+
+//Offsets of every field. See the layout above.
+pub mod offset {
+    pub const MAGIC: usize = 0;
+    pub const VERSION: usize = 4;
+    pub const HEADER_LEN: usize = 6;
+    pub const FEATURES: usize = 8;
+    pub const CONSUMER_VERSION: usize = 12;
+    pub const SECTIONS_OFFSET: usize = 14;
+    pub const WIDTH: usize = 16;
+    pub const HEIGHT: usize = 20;
+    pub const INDEX: usize = 24;
+    pub const SLOT_COUNT: usize = 28;
+    pub const SLOTS: usize = 32;
+}
+
+//Bitflags stored in the features field. Unknown bits are ignored.
+pub mod feature {
+    //The producer waits for the resize event before allocating new textures.
+    pub const RESIZE_EVENT: u32 = 1 << 0;
+
+    //Extra layers are described in a section::LAYERS section.
+    pub const LAYERS: u32 = 1 << 1;
+    //Changed regions of the slots are described in a section::DIRTY_RECTS section.
+    pub const DIRTY_RECTS: u32 = 1 << 2;
+    //Interactive parts of the overlay are described in a section::HIT_TEST section.
+    pub const HIT_TEST: u32 = 1 << 3;
+    //The producer sends state flags (see flag) in a section::FLAGS section.
+    pub const FLAGS: u32 = 1 << 4;
+    //The producer keeps a section::HEARTBEAT section ticking while it runs.
+    pub const HEARTBEAT: u32 = 1 << 5;
+
+    //Every feature this version of the DLL understands.
+    pub const SUPPORTED: u32 = RESIZE_EVENT | LAYERS | DIRTY_RECTS | HIT_TEST | FLAGS | HEARTBEAT;
+}
+
+//Bitflags describing the current state of the producer, unlike features they can change any frame.
+pub mod flag {
+    //A text field of the overlay has focus, keyboard input should only go to the producer.
+    pub const KEYBOARD_CAPTURE: u32 = 1 << 0;
+}
+
+//Tags of the sections found in the section directory. Unknown tags are skipped.
+pub mod section {
+    pub const END: u32 = 0;
+    //See ui::layers for the payload.
+    pub const LAYERS: u32 = 1;
+    //See ui::dirty for the payload.
+    pub const DIRTY_RECTS: u32 = 2;
+    //See ui::hit_test for the payload.
+    pub const HIT_TEST: u32 = 3;
+    //A single u32 holding flag bits.
+    pub const FLAGS: u32 = 4;
+    //counter u64, incremented on every tick, then timestamp u64 (microseconds since the UNIX
+    //epoch) of the last tick. A counter that stops changing means the producer froze.
+    pub const HEARTBEAT: u32 = 5;
+}
+
+//Tag and payload length that precede every section.
+pub const SECTION_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MMFHeader {
+    pub magic: u32,
+    pub version: u16,
+    pub header_len: u16,
+    pub features: u32,
+    pub consumer_version: u16,
+    pub sections_offset: u16,
+    pub width: u32,
+    pub height: u32,
+    pub index: u32,
+    pub slot_count: u32,
+    pub slots: [u64; MAX_SLOTS],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    //Less bytes available than what the header needs
+    TooShort { len: usize, required: usize },
+    //The producer has not written the header yet (all zeroes)
+    Uninitialized,
+    //Something else, most likely an old producer using the legacy layout
+    BadMagic(u32),
+    UnsupportedVersion(u16),
+    BadHeaderLen(u16),
+    BadSlotCount(u32),
+    BadSection { offset: usize },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::TooShort { len, required } => {
+                write!(
+                    f,
+                    "header is {} bytes, at least {} are required",
+                    len, required
+                )
+            }
+            HeaderError::Uninitialized => write!(f, "header has not been written yet"),
+            HeaderError::BadMagic(magic) => write!(
+                f,
+                "bad magic 0x{:08x} (expected 0x{:08x}), the producer is probably outdated",
+                magic, HEADER_MAGIC
+            ),
+            HeaderError::UnsupportedVersion(v) => write!(
+                f,
+                "producer speaks protocol version {}, this DLL supports {} to {}",
+                v, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HeaderError::BadHeaderLen(l) => write!(f, "invalid header length {}", l),
+            HeaderError::BadSlotCount(c) => {
+                write!(f, "invalid slot count {} (1 to {} supported)", c, MAX_SLOTS)
+            }
+            HeaderError::BadSection { offset } => {
+                write!(f, "malformed section at offset {}", offset)
+            }
+        }
+    }
+}
+
+//What both sides agreed on after reading the producer's header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u16,
+    pub features: u32,
+    pub header_len: usize,
+}
+
+impl MMFHeader {
+    ///Shared texture handles announced by the producer.
+    pub fn slots(&self) -> &[u64] {
+        &self.slots[..(self.slot_count as usize).min(MAX_SLOTS)]
+    }
+
+    ///Replaces the shared texture handles. Extra handles past MAX_SLOTS are an error.
+    pub fn set_slots(&mut self, slots: &[u64]) -> Result<(), HeaderError> {
+        if slots.len() > MAX_SLOTS {
+            return Err(HeaderError::BadSlotCount(slots.len() as u32));
+        }
+        self.slots = [0; MAX_SLOTS];
+        self.slots[..slots.len()].copy_from_slice(slots);
+        self.slot_count = slots.len() as u32;
+        Ok(())
+    }
+
+    ///Decodes a header from the raw bytes of the MMF.
+    ///Only checks that the bytes are there, use negotiate() to validate the content.
+    pub fn decode(data: &[u8]) -> Result<MMFHeader, HeaderError> {
+        if data.len() < HEADER_LEN {
+            return Err(HeaderError::TooShort {
+                len: data.len(),
+                required: HEADER_LEN,
+            });
+        }
+        let version = read_u16(data, offset::VERSION);
+        let slot_count = if version < 2 {
+            LEGACY_SLOT_COUNT
+        } else {
+            read_u32(data, offset::SLOT_COUNT)
+        };
+        if slot_count as usize > MAX_SLOTS {
+            return Err(HeaderError::BadSlotCount(slot_count));
+        }
+        let required = slots_end(slot_count as usize);
+        if data.len() < required {
+            return Err(HeaderError::TooShort {
+                len: data.len(),
+                required,
+            });
+        }
+
+        let mut slots = [0; MAX_SLOTS];
+        for (i, slot) in slots.iter_mut().take(slot_count as usize).enumerate() {
+            *slot = read_u64(data, offset::SLOTS + i * 8);
+        }
+
+        Ok(MMFHeader {
+            magic: read_u32(data, offset::MAGIC),
+            version,
+            header_len: read_u16(data, offset::HEADER_LEN),
+            features: read_u32(data, offset::FEATURES),
+            consumer_version: read_u16(data, offset::CONSUMER_VERSION),
+            sections_offset: read_u16(data, offset::SECTIONS_OFFSET),
+            width: read_u32(data, offset::WIDTH),
+            height: read_u32(data, offset::HEIGHT),
+            index: read_u32(data, offset::INDEX),
+            slot_count,
+            slots,
+        })
+    }
+
+    ///Encodes the whole header. Mostly useful for the producer side and for tests,
+    ///the DLL itself only ever writes the fields it owns.
+    pub fn encode(&self, data: &mut [u8]) -> Result<(), HeaderError> {
+        if self.slot_count as usize > MAX_SLOTS {
+            return Err(HeaderError::BadSlotCount(self.slot_count));
+        }
+        let required = slots_end(self.slot_count as usize);
+        if data.len() < required {
+            return Err(HeaderError::TooShort {
+                len: data.len(),
+                required,
+            });
+        }
+        data[..required].fill(0);
+        write_u32(data, offset::MAGIC, self.magic);
+        write_u16(data, offset::VERSION, self.version);
+        write_u16(data, offset::HEADER_LEN, self.header_len);
+        write_u32(data, offset::FEATURES, self.features);
+        write_u16(data, offset::CONSUMER_VERSION, self.consumer_version);
+        write_u16(data, offset::SECTIONS_OFFSET, self.sections_offset);
+        write_u32(data, offset::WIDTH, self.width);
+        write_u32(data, offset::HEIGHT, self.height);
+        write_u32(data, offset::INDEX, self.index);
+        if self.version >= 2 {
+            write_u32(data, offset::SLOT_COUNT, self.slot_count);
+        }
+        for (i, slot) in self.slots().iter().enumerate() {
+            write_u64(data, offset::SLOTS + i * 8, *slot);
+        }
+        Ok(())
+    }
+
+    ///Checks that we can talk to whoever wrote this header.
+    ///A newer producer is accepted as long as its header contains everything we know about,
+    ///features we do not understand are simply masked out.
+    pub fn negotiate(&self, available: usize) -> Result<Negotiated, HeaderError> {
+        //The consumer only writes after a successful negotiation, so a header full of zeroes
+        //means the producer has not gotten to it yet. Anything else without our magic is a
+        //producer we don't understand, like one still using the legacy 28 byte layout.
+        if self.is_blank() {
+            return Err(HeaderError::Uninitialized);
+        }
+        if self.magic != HEADER_MAGIC {
+            return Err(HeaderError::BadMagic(self.magic));
+        }
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(HeaderError::UnsupportedVersion(self.version));
+        }
+        if self.slot_count == 0 || self.slot_count as usize > MAX_SLOTS {
+            return Err(HeaderError::BadSlotCount(self.slot_count));
+        }
+        let header_len = self.header_len as usize;
+        if header_len < slots_end(self.slot_count as usize) || header_len > available {
+            return Err(HeaderError::BadHeaderLen(self.header_len));
+        }
+        Ok(Negotiated {
+            version: self.version.min(PROTOCOL_VERSION),
+            features: self.features & feature::SUPPORTED,
+            header_len,
+        })
+    }
+
+    fn is_blank(&self) -> bool {
+        self.magic == 0
+            && self.version == 0
+            && self.header_len == 0
+            && self.features == 0
+            && self.consumer_version == 0
+            && self.sections_offset == 0
+            && self.width == 0
+            && self.height == 0
+            && self.index == 0
+            && self.slots.iter().all(|s| *s == 0)
+    }
+}
+
+///Finds the payload of the first section with the given tag.
+///data must be the header as validated by negotiate(), only header_len bytes of it are looked at.
+pub fn find_section<'a>(
+    data: &'a [u8],
+    header: &MMFHeader,
+    negotiated: &Negotiated,
+    tag: u32,
+) -> Result<Option<&'a [u8]>, HeaderError> {
+    Ok(locate_section(data, header, negotiated, tag)?.map(|(_, payload)| payload))
+}
+
+//Same as find_section, along with the offset of the section, for errors about its payload.
+fn locate_section<'a>(
+    data: &'a [u8],
+    header: &MMFHeader,
+    negotiated: &Negotiated,
+    tag: u32,
+) -> Result<Option<(usize, &'a [u8])>, HeaderError> {
+    let end = negotiated.header_len.min(data.len());
+    let mut at = header.sections_offset as usize;
+    if at == 0 {
+        return Ok(None);
+    }
+    //The directory can't overlap the fixed part or the slots.
+    if at < slots_end(header.slot_count as usize) {
+        return Err(HeaderError::BadSection { offset: at });
+    }
+    while at + SECTION_HEADER_LEN <= end {
+        let current = read_u32(data, at);
+        let len = read_u32(data, at + 4) as usize;
+        if current == section::END {
+            break;
+        }
+        let payload = at + SECTION_HEADER_LEN;
+        if payload + len > end {
+            return Err(HeaderError::BadSection { offset: at });
+        }
+        if current == tag {
+            return Ok(Some((at, &data[payload..payload + len])));
+        }
+        at = align8(payload + len);
+    }
+    Ok(None)
+}
+
+///State flags sent by the producer, 0 if it doesn't send any.
+pub fn read_flags(
+    data: &[u8],
+    header: &MMFHeader,
+    negotiated: &Negotiated,
+) -> Result<u32, HeaderError> {
+    if negotiated.features & feature::FLAGS == 0 {
+        return Ok(0);
+    }
+    match locate_section(data, header, negotiated, section::FLAGS)? {
+        Some((_, payload)) if payload.len() >= 4 => Ok(read_u32(payload, 0)),
+        Some((offset, _)) => Err(HeaderError::BadSection { offset }),
+        None => Ok(0),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    //Incremented by the producer on every tick, even when no new frame is published.
+    pub counter: u64,
+    pub timestamp: u64,
+}
+
+///Heartbeat sent by the producer, None if it doesn't send one.
+pub fn read_heartbeat(
+    data: &[u8],
+    header: &MMFHeader,
+    negotiated: &Negotiated,
+) -> Result<Option<Heartbeat>, HeaderError> {
+    if negotiated.features & feature::HEARTBEAT == 0 {
+        return Ok(None);
+    }
+    match locate_section(data, header, negotiated, section::HEARTBEAT)? {
+        Some((_, payload)) if payload.len() >= 16 => Ok(Some(Heartbeat {
+            counter: read_u64(payload, 0),
+            timestamp: read_u64(payload, 8),
+        })),
+        Some((offset, _)) => Err(HeaderError::BadSection { offset }),
+        None => Ok(None),
+    }
+}
+
+///Appends a section at the given offset and returns where the next one goes.
+///The caller is responsible for the END tag (or zeroed memory) after the last one.
+pub fn write_section(
+    data: &mut [u8],
+    at: usize,
+    tag: u32,
+    payload: &[u8],
+) -> Result<usize, HeaderError> {
+    let next = align8(at + SECTION_HEADER_LEN + payload.len());
+    if next > data.len() {
+        return Err(HeaderError::BadSection { offset: at });
+    }
+    write_u32(data, at, tag);
+    write_u32(data, at + 4, payload.len() as u32);
+    data[at + SECTION_HEADER_LEN..at + SECTION_HEADER_LEN + payload.len()].copy_from_slice(payload);
+    data[at + SECTION_HEADER_LEN + payload.len()..next].fill(0);
+    Ok(next)
+}
+
+fn align8(v: usize) -> usize {
+    (v + 7) & !7
+}
+
+///Writes the fields owned by the consumer. Everything else belongs to the producer.
+pub fn write_dimensions(data: &mut [u8], width: u32, height: u32) {
+    write_u32(data, offset::WIDTH, width);
+    write_u32(data, offset::HEIGHT, height);
+}
+
+///Lets the producer know which version of the protocol the DLL speaks.
+pub fn write_consumer_version(data: &mut [u8]) {
+    write_u16(data, offset::CONSUMER_VERSION, PROTOCOL_VERSION);
+}
+
+///Zeroes the fields owned by the consumer, telling the producer we are gone.
+pub fn clear_consumer_fields(data: &mut [u8]) {
+    write_dimensions(data, 0, 0);
+    write_u16(data, offset::CONSUMER_VERSION, 0);
+}
+
+//End of the slot array, which is also the minimum header_len for that many slots.
+pub fn slots_end(slot_count: usize) -> usize {
+    offset::SLOTS + slot_count * 8
+}
+
+pub(crate) fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+}
+pub(crate) fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+pub(crate) fn read_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+pub(crate) fn write_u16(data: &mut [u8], at: usize, v: u16) {
+    data[at..at + 2].copy_from_slice(&v.to_le_bytes());
+}
+pub(crate) fn write_u32(data: &mut [u8], at: usize, v: u32) {
+    data[at..at + 4].copy_from_slice(&v.to_le_bytes());
+}
+pub(crate) fn write_u64(data: &mut [u8], at: usize, v: u64) {
+    data[at..at + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn producer_header(slots: &[u64]) -> MMFHeader {
+        let mut header = MMFHeader {
+            magic: HEADER_MAGIC,
+            version: PROTOCOL_VERSION,
+            header_len: 128,
+            features: feature::SUPPORTED,
+            index: 1,
+            ..Default::default()
+        };
+        header.set_slots(slots).unwrap();
+        header
+    }
+
+    fn negotiated(header: &MMFHeader) -> Negotiated {
+        header.negotiate(header.header_len as usize).unwrap()
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let mut header = producer_header(&[0x1000, 0x2000, 0x3000]);
+        header.consumer_version = PROTOCOL_VERSION;
+        header.sections_offset = 56;
+        header.width = 1920;
+        header.height = 1080;
+        let mut data = [0xAA; 128];
+        header.encode(&mut data).unwrap();
+        assert_eq!(MMFHeader::decode(&data), Ok(header));
+        //Bytes past the slots are left alone.
+        assert_eq!(data[slots_end(3)..], [0xAA; 128 - 56]);
+    }
+
+    #[test]
+    fn legacy_header_has_two_slots() {
+        let mut header = producer_header(&[7, 8]);
+        header.version = 1;
+        let mut data = [0; 64];
+        header.encode(&mut data).unwrap();
+        assert_eq!(read_u32(&data, offset::SLOT_COUNT), 0);
+        let decoded = MMFHeader::decode(&data).unwrap();
+        assert_eq!(decoded.slots(), &[7, 8]);
+    }
+
+    #[test]
+    fn decode_needs_the_slots() {
+        let header = producer_header(&[1, 2, 3]);
+        let mut data = [0; 64];
+        header.encode(&mut data).unwrap();
+        assert_eq!(
+            MMFHeader::decode(&data[..40]),
+            Err(HeaderError::TooShort {
+                len: 40,
+                required: 56
+            })
+        );
+        assert_eq!(
+            header.encode(&mut [0; 40]),
+            Err(HeaderError::TooShort {
+                len: 40,
+                required: 56
+            })
+        );
+    }
+
+    #[test]
+    fn negotiate_accepts_a_newer_producer() {
+        let mut header = producer_header(&[1, 2]);
+        header.version = PROTOCOL_VERSION + 1;
+        header.features = u32::MAX;
+        assert_eq!(
+            header.negotiate(128),
+            Ok(Negotiated {
+                version: PROTOCOL_VERSION,
+                features: feature::SUPPORTED,
+                header_len: 128,
+            })
+        );
+    }
+
+    #[test]
+    fn negotiate_errors() {
+        assert_eq!(
+            MMFHeader::default().negotiate(128),
+            Err(HeaderError::Uninitialized)
+        );
+
+        //What a legacy producer writes: width, height, index and two handles.
+        let mut legacy = [0; 28];
+        write_u32(&mut legacy, 0, 1920);
+        write_u32(&mut legacy, 4, 1080);
+        write_u64(&mut legacy, 12, 0x1000);
+        let mut data = [0; 64];
+        data[..28].copy_from_slice(&legacy);
+        assert_eq!(
+            MMFHeader::decode(&data).unwrap().negotiate(64),
+            Err(HeaderError::BadMagic(1920))
+        );
+
+        let mut header = producer_header(&[1, 2]);
+        header.version = 0;
+        assert_eq!(
+            header.negotiate(128),
+            Err(HeaderError::UnsupportedVersion(0))
+        );
+
+        let mut header = producer_header(&[1, 2]);
+        header.header_len = (slots_end(2) - 1) as u16;
+        assert_eq!(header.negotiate(128), Err(HeaderError::BadHeaderLen(47)));
+        header.header_len = 256;
+        assert_eq!(header.negotiate(128), Err(HeaderError::BadHeaderLen(256)));
+
+        let header = producer_header(&[]);
+        assert_eq!(header.negotiate(128), Err(HeaderError::BadSlotCount(0)));
+        let mut header = producer_header(&[1, 2]);
+        header.slot_count = MAX_SLOTS as u32 + 1;
+        assert_eq!(header.negotiate(128), Err(HeaderError::BadSlotCount(9)));
+        assert_eq!(
+            producer_header(&[]).set_slots(&[0; MAX_SLOTS + 1]),
+            Err(HeaderError::BadSlotCount(9))
+        );
+    }
+
+    #[test]
+    fn sections_are_found_within_header_len() {
+        let mut header = producer_header(&[1, 2]);
+        header.sections_offset = slots_end(2) as u16;
+        let mut data = [0; 128];
+        header.encode(&mut data).unwrap();
+        let at = write_section(&mut data, slots_end(2), 99, &[1, 2, 3]).unwrap();
+        assert_eq!(at, slots_end(2) + 16);
+        write_section(&mut data, at, section::FLAGS, &7u32.to_le_bytes()).unwrap();
+        let negotiated = negotiated(&header);
+
+        assert_eq!(
+            find_section(&data, &header, &negotiated, 99),
+            Ok(Some(&[1, 2, 3][..]))
+        );
+        assert_eq!(read_flags(&data, &header, &negotiated), Ok(7));
+        assert_eq!(
+            find_section(&data, &header, &negotiated, section::LAYERS),
+            Ok(None)
+        );
+        //The FLAGS payload (72 to 76) crosses header_len.
+        let short = Negotiated {
+            header_len: 74,
+            ..negotiated
+        };
+        assert_eq!(
+            find_section(&data, &header, &short, section::FLAGS),
+            Err(HeaderError::BadSection { offset: 64 })
+        );
+        //Ends before it, the directory just stops there.
+        let shorter = Negotiated {
+            header_len: 64,
+            ..negotiated
+        };
+        assert_eq!(read_flags(&data, &header, &shorter), Ok(0));
+    }
+
+    #[test]
+    fn sections_must_fit() {
+        let mut header = producer_header(&[1, 2]);
+        header.sections_offset = 40;
+        let data = [0; 128];
+        let negotiated = negotiated(&header);
+        //Overlaps the slots.
+        assert_eq!(
+            find_section(&data, &header, &negotiated, section::FLAGS),
+            Err(HeaderError::BadSection { offset: 40 })
+        );
+
+        header.sections_offset = slots_end(2) as u16;
+        let mut data = [0; 128];
+        write_u32(&mut data, 48, section::LAYERS);
+        write_u32(&mut data, 52, 1000);
+        assert_eq!(
+            find_section(&data, &header, &negotiated, section::FLAGS),
+            Err(HeaderError::BadSection { offset: 48 })
+        );
+        assert_eq!(
+            write_section(&mut [0; 64], 48, section::FLAGS, &[0; 16]),
+            Err(HeaderError::BadSection { offset: 48 })
+        );
+    }
+
+    #[test]
+    fn short_payloads_report_their_own_section() {
+        let mut header = producer_header(&[1, 2]);
+        header.sections_offset = slots_end(2) as u16;
+        let mut data = [0; 128];
+        let at = write_section(&mut data, slots_end(2), 99, &[0; 8]).unwrap();
+        let heartbeat = write_section(&mut data, at, section::HEARTBEAT, &[0; 8]).unwrap();
+        write_section(&mut data, heartbeat, section::FLAGS, &[0; 2]).unwrap();
+        let negotiated = negotiated(&header);
+        assert_eq!(
+            read_heartbeat(&data, &header, &negotiated),
+            Err(HeaderError::BadSection { offset: at })
+        );
+        assert_eq!(
+            read_flags(&data, &header, &negotiated),
+            Err(HeaderError::BadSection { offset: heartbeat })
+        );
+    }
+
+    #[test]
+    fn heartbeat_is_read() {
+        let mut header = producer_header(&[1, 2]);
+        header.sections_offset = slots_end(2) as u16;
+        let mut data = [0; 128];
+        let mut payload = [0; 16];
+        payload[..8].copy_from_slice(&5u64.to_le_bytes());
+        payload[8..].copy_from_slice(&6u64.to_le_bytes());
+        write_section(&mut data, slots_end(2), section::HEARTBEAT, &payload).unwrap();
+        let negotiated = negotiated(&header);
+        assert_eq!(
+            read_heartbeat(&data, &header, &negotiated),
+            Ok(Some(Heartbeat {
+                counter: 5,
+                timestamp: 6
+            }))
+        );
+        let without = Negotiated {
+            features: feature::SUPPORTED & !feature::HEARTBEAT,
+            ..negotiated
+        };
+        assert_eq!(read_heartbeat(&data, &header, &without), Ok(None));
+    }
+
+    #[test]
+    fn clearing_keeps_the_producer_fields() {
+        let mut header = producer_header(&[1, 2]);
+        let mut data = [0; 128];
+        header.encode(&mut data).unwrap();
+        write_consumer_version(&mut data);
+        write_dimensions(&mut data, 800, 600);
+        let written = MMFHeader::decode(&data).unwrap();
+        assert_eq!(
+            (written.consumer_version, written.width, written.height),
+            (PROTOCOL_VERSION, 800, 600)
+        );
+
+        clear_consumer_fields(&mut data);
+        header.consumer_version = 0;
+        assert_eq!(MMFHeader::decode(&data), Ok(header));
+    }
+}
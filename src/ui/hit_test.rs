@@ -0,0 +1,296 @@
+use super::{
+    header::{HeaderError, read_u32},
+    layers::Rect,
+};
+
+/*
+ *
+ * Hit-testing. Producers that negotiated feature::HIT_TEST tell us which parts of the window
+ * are interactive, so clicks landing there are only sent to the overlay and not to the game.
+ *
+ * It comes either as a low resolution mask, or as a list of rectangles when there are only a
+ * few windows open. Coordinates are in pixels of the game's client area.
+ *
+ * Payload of a section::HIT_TEST section (little endian):
+ *
+ *   0  kind       u32   HIT_TEST_MASK or HIT_TEST_RECTS
+ *
+ * Mask:
+ *
+ *   4  cell_size  u32   Pixels covered by a cell, in both directions
+ *   8  columns    u32
+ *  12  rows       u32
+ *  16  bits       One bit per cell, row major, least significant bit first
+ *
+ * Rectangles:
+ *
+ *   4  count      u32
+ *   8  rects      (x i32, y i32, width u32, height u32) * count
+ *
+ * */
+
+pub const HIT_TEST_MASK: u32 = 0;
+pub const HIT_TEST_RECTS: u32 = 1;
+//A 4k window with 1 pixel cells. Anything bigger is a broken producer.
+pub const MAX_MASK_CELLS: usize = 3840 * 2160;
+pub const MAX_HIT_RECTS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HitTest {
+    Mask {
+        cell_size: u32,
+        columns: u32,
+        rows: u32,
+        bits: Vec<u8>,
+    },
+    Rects(Vec<Rect>),
+}
+
+impl HitTest {
+    ///True if the pixel belongs to something interactive on the overlay.
+    pub fn hit(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 {
+            return false;
+        }
+        match self {
+            HitTest::Mask {
+                cell_size,
+                columns,
+                rows,
+                bits,
+            } => {
+                let col = x as u32 / cell_size;
+                let row = y as u32 / cell_size;
+                if col >= *columns || row >= *rows {
+                    return false;
+                }
+                let bit = (row * columns + col) as usize;
+                bits.get(bit / 8).is_some_and(|b| b & (1 << (bit % 8)) != 0)
+            }
+            HitTest::Rects(rects) => rects
+                .iter()
+                .any(|r| x >= r.x && (x as i64) < r.right() && y >= r.y && (y as i64) < r.bottom()),
+        }
+    }
+}
+
+///Decodes the payload of a section::HIT_TEST section.
+pub fn decode_hit_test(payload: &[u8]) -> Result<HitTest, HeaderError> {
+    let bad = |offset: usize| HeaderError::BadSection { offset };
+    if payload.len() < 8 {
+        return Err(bad(0));
+    }
+    match read_u32(payload, 0) {
+        HIT_TEST_MASK => {
+            if payload.len() < 16 {
+                return Err(bad(0));
+            }
+            let cell_size = read_u32(payload, 4);
+            let columns = read_u32(payload, 8);
+            let rows = read_u32(payload, 12);
+            let cells = columns as usize * rows as usize;
+            if cell_size == 0 || cells > MAX_MASK_CELLS {
+                return Err(bad(4));
+            }
+            let len = cells.div_ceil(8);
+            if payload.len() < 16 + len {
+                return Err(bad(16));
+            }
+            Ok(HitTest::Mask {
+                cell_size,
+                columns,
+                rows,
+                bits: payload[16..16 + len].to_vec(),
+            })
+        }
+        HIT_TEST_RECTS => {
+            let count = read_u32(payload, 4) as usize;
+            if count > MAX_HIT_RECTS || payload.len() < 8 + count * 16 {
+                return Err(bad(4));
+            }
+            Ok(HitTest::Rects(
+                (0..count)
+                    .map(|i| {
+                        let at = 8 + i * 16;
+                        Rect::new(
+                            read_u32(payload, at) as i32,
+                            read_u32(payload, at + 4) as i32,
+                            read_u32(payload, at + 8),
+                            read_u32(payload, at + 12),
+                        )
+                    })
+                    .collect(),
+            ))
+        }
+        _ => Err(bad(0)),
+    }
+}
+
+///Encodes the payload of a section::HIT_TEST section. Used by producers and tests.
+pub fn encode_hit_test(hit_test: &HitTest) -> Vec<u8> {
+    let mut out = Vec::new();
+    match hit_test {
+        HitTest::Mask {
+            cell_size,
+            columns,
+            rows,
+            bits,
+        } => {
+            out.extend_from_slice(&HIT_TEST_MASK.to_le_bytes());
+            out.extend_from_slice(&cell_size.to_le_bytes());
+            out.extend_from_slice(&columns.to_le_bytes());
+            out.extend_from_slice(&rows.to_le_bytes());
+            out.extend_from_slice(bits);
+        }
+        HitTest::Rects(rects) => {
+            out.extend_from_slice(&HIT_TEST_RECTS.to_le_bytes());
+            out.extend_from_slice(&(rects.len() as u32).to_le_bytes());
+            for r in rects {
+                out.extend_from_slice(&r.x.to_le_bytes());
+                out.extend_from_slice(&r.y.to_le_bytes());
+                out.extend_from_slice(&r.width.to_le_bytes());
+                out.extend_from_slice(&r.height.to_le_bytes());
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A 3x2 mask of 10 pixel cells, with the middle column and the bottom right cell set:
+    // .#.
+    // .##
+    fn mask() -> HitTest {
+        HitTest::Mask {
+            cell_size: 10,
+            columns: 3,
+            rows: 2,
+            bits: vec![0b0011_0010],
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        for hit_test in [
+            mask(),
+            HitTest::Rects(vec![Rect::new(-5, 10, 20, 30), Rect::new(100, 0, 1, 1)]),
+            HitTest::Rects(Vec::new()),
+        ] {
+            assert_eq!(decode_hit_test(&encode_hit_test(&hit_test)), Ok(hit_test));
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        for payload in [mask(), HitTest::Rects(vec![Rect::new(0, 0, 1, 1)])]
+            .iter()
+            .map(encode_hit_test)
+        {
+            for len in 0..payload.len() {
+                assert!(decode_hit_test(&payload[..len]).is_err(), "{} bytes", len);
+            }
+        }
+        let mut unknown = encode_hit_test(&mask());
+        unknown[0] = 2;
+        assert_eq!(
+            decode_hit_test(&unknown),
+            Err(HeaderError::BadSection { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_masks_and_lists() {
+        let huge = HitTest::Mask {
+            cell_size: 1,
+            columns: 3841,
+            rows: 2160,
+            bits: Vec::new(),
+        };
+        assert_eq!(
+            decode_hit_test(&encode_hit_test(&huge)),
+            Err(HeaderError::BadSection { offset: 4 })
+        );
+        //Would overflow 32 bits if multiplied as such.
+        let overflowing = HitTest::Mask {
+            cell_size: 1,
+            columns: u32::MAX,
+            rows: u32::MAX,
+            bits: Vec::new(),
+        };
+        assert!(decode_hit_test(&encode_hit_test(&overflowing)).is_err());
+        let too_many = HitTest::Rects(vec![Rect::new(0, 0, 1, 1); MAX_HIT_RECTS + 1]);
+        assert_eq!(
+            decode_hit_test(&encode_hit_test(&too_many)),
+            Err(HeaderError::BadSection { offset: 4 })
+        );
+        let zero_cells = HitTest::Mask {
+            cell_size: 0,
+            columns: 3,
+            rows: 2,
+            bits: vec![0xFF],
+        };
+        assert!(decode_hit_test(&encode_hit_test(&zero_cells)).is_err());
+    }
+
+    #[test]
+    fn ignores_bytes_past_the_payload() {
+        let mut payload = encode_hit_test(&mask());
+        payload.extend_from_slice(&[0xFF; 32]);
+        assert_eq!(decode_hit_test(&payload), Ok(mask()));
+    }
+
+    #[test]
+    fn zero_sized_masks_hit_nothing() {
+        let empty = HitTest::Mask {
+            cell_size: 10,
+            columns: 0,
+            rows: 0,
+            bits: Vec::new(),
+        };
+        assert_eq!(decode_hit_test(&encode_hit_test(&empty)), Ok(empty.clone()));
+        assert!(!empty.hit(0, 0));
+    }
+
+    #[test]
+    fn mask_cells_cover_cell_size_pixels() {
+        let mask = mask();
+        //First and last pixels of the set cells, and their neighbours.
+        assert!(mask.hit(10, 0));
+        assert!(mask.hit(19, 9));
+        assert!(!mask.hit(9, 0));
+        assert!(!mask.hit(20, 9));
+        assert!(mask.hit(10, 10));
+        assert!(mask.hit(29, 19));
+        assert!(!mask.hit(0, 19));
+        //Past the mask.
+        assert!(!mask.hit(30, 10));
+        assert!(!mask.hit(10, 20));
+        assert!(!mask.hit(i32::MAX, i32::MAX));
+    }
+
+    #[test]
+    fn negative_coordinates_hit_nothing() {
+        let covering = HitTest::Rects(vec![Rect::new(-100, -100, 200, 200)]);
+        for hit_test in [mask(), covering] {
+            assert!(!hit_test.hit(-1, 10));
+            assert!(!hit_test.hit(10, -1));
+            assert!(!hit_test.hit(i32::MIN, i32::MIN));
+        }
+    }
+
+    #[test]
+    fn rects_are_half_open() {
+        let rects = HitTest::Rects(vec![Rect::new(10, 20, 5, 5), Rect::new(100, 100, 0, 10)]);
+        assert!(rects.hit(10, 20));
+        assert!(rects.hit(14, 24));
+        assert!(!rects.hit(15, 24));
+        assert!(!rects.hit(14, 25));
+        assert!(!rects.hit(9, 20));
+        //Empty rectangles cover nothing.
+        assert!(!rects.hit(100, 105));
+        assert!(!HitTest::Rects(Vec::new()).hit(0, 0));
+    }
+}
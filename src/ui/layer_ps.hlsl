@@ -0,0 +1,20 @@
+//Pixel shader used for every layer. Compiled to layer_ps.cso with fxc /T ps_5_0 /E main layer_ps.hlsl
+//The vertex shader outputs a full-screen triangle with uv going from 0 to 1 over the viewport,
+//so only the visible part of the layer's texture has to be remapped here.
+Texture2D overlay_texture : register(t0);
+SamplerState overlay_sampler : register(s0);
+
+cbuffer LayerParams : register(b0)
+{
+    //xy: offset, zw: scale. See ui::layers::UvRect.
+    float4 uv_rect;
+    float opacity;
+    float3 padding;
+};
+
+float4 main(float4 position : SV_Position, float2 uv : TEXCOORD0) : SV_Target
+{
+    float4 color = overlay_texture.Sample(overlay_sampler, uv_rect.xy + uv * uv_rect.zw);
+    color.a *= opacity;
+    return color;
+}
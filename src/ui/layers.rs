@@ -0,0 +1,436 @@
+use super::header::{HeaderError, MAX_SLOTS, read_u32, read_u64};
+
+/*
+ *
+ * Overlay layers. The base slots of the header are always the "main" layer, drawn full screen.
+ * Producers that negotiated feature::LAYERS can describe more of them in a section::LAYERS
+ * section, each with its own textures, placement, opacity and z-order.
+ *
+ * Payload of the section (little endian):
+ *
+ *   0  layer_count  u32
+ *   4  reserved     u32
+ *   8  entries      One per layer, each LAYER_ENTRY_LEN bytes followed by its slots
+ *
+ * Entry:
+ *
+ *   0  name        [u8; 32]   UTF-8, padded with zeroes
+ *  32  z           i32        Higher is drawn on top
+ *  36  opacity     f32        0.0 to 1.0
+ *  40  x           i32        Destination rectangle, in pixels of the game's window.
+ *  44  y           i32        Can be partially (or fully) off screen.
+ *  48  width       u32        0 means the whole window.
+ *  52  height      u32
+ *  56  index       u32        Slot to draw
+ *  60  slot_count  u32
+ *  64  slots       u64 * slot_count
+ *
+ * */
+
+pub const MAIN_LAYER: &str = "main";
+pub const MAX_LAYERS: usize = 16;
+pub const LAYER_NAME_LEN: usize = 32;
+pub const LAYER_ENTRY_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+    pub fn right(&self) -> i64 {
+        self.x as i64 + self.width as i64
+    }
+    pub fn bottom(&self) -> i64 {
+        self.y as i64 + self.height as i64
+    }
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    ///Part of both rectangles, None if they don't overlap.
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let left = self.x.max(other.x) as i64;
+        let top = self.y.max(other.y) as i64;
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= left || bottom <= top {
+            return None;
+        }
+        Some(Rect::new(
+            left as i32,
+            top as i32,
+            (right - left) as u32,
+            (bottom - top) as u32,
+        ))
+    }
+}
+
+//Sub rectangle of a texture, in normalized coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UvRect {
+    pub u: f32,
+    pub v: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+//Where a layer ends up on screen once clipped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    //Visible part of the destination, this is what the viewport gets set to.
+    pub visible: Rect,
+    //Part of the texture that maps to the visible rectangle.
+    pub uv: UvRect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub name: String,
+    pub z: i32,
+    pub opacity: f32,
+    //None means the whole window.
+    pub dest: Option<Rect>,
+    pub index: u32,
+    pub slots: Vec<u64>,
+}
+
+impl Layer {
+    ///The layer made from the base slots of the header.
+    pub fn main(slots: &[u64], index: u32) -> Layer {
+        Layer {
+            name: MAIN_LAYER.to_string(),
+            z: 0,
+            opacity: 1.0,
+            dest: None,
+            index,
+            slots: slots.to_vec(),
+        }
+    }
+
+    ///Handle of the slot to draw, None if the producer sent an index out of bounds.
+    pub fn current_slot(&self) -> Option<u64> {
+        self.slots.get(self.index as usize).copied()
+    }
+
+    ///Clips the layer against a window of the given size.
+    pub fn place(&self, width: u32, height: u32) -> Option<Placement> {
+        let dest = self.dest.unwrap_or(Rect::new(0, 0, width, height));
+        place(dest, width, height)
+    }
+}
+
+///Clips dest against the window and computes which part of the texture remains visible.
+///Returns None when nothing of it is visible.
+pub fn place(dest: Rect, width: u32, height: u32) -> Option<Placement> {
+    if dest.is_empty() {
+        return None;
+    }
+    let visible = dest.intersect(&Rect::new(0, 0, width, height))?;
+
+    let dest_w = dest.width as f32;
+    let dest_h = dest.height as f32;
+    Some(Placement {
+        visible,
+        uv: UvRect {
+            u: (visible.x as i64 - dest.x as i64) as f32 / dest_w,
+            v: (visible.y as i64 - dest.y as i64) as f32 / dest_h,
+            width: visible.width as f32 / dest_w,
+            height: visible.height as f32 / dest_h,
+        },
+    })
+}
+
+//Layers sorted in drawing order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LayerList {
+    layers: Vec<Layer>,
+}
+
+impl LayerList {
+    ///Sorts by z, layers with the same z keep the order they were given in.
+    ///Names are unique, if one shows up twice only the first is kept.
+    pub fn new(layers: Vec<Layer>) -> LayerList {
+        let mut unique: Vec<Layer> = Vec::with_capacity(layers.len());
+        for layer in layers {
+            if !unique.iter().any(|l| l.name == layer.name) {
+                unique.push(layer);
+            }
+        }
+        unique.sort_by_key(|l| l.z);
+        LayerList { layers: unique }
+    }
+
+    ///Back to front.
+    pub fn iter(&self) -> impl Iterator<Item = &Layer> {
+        self.layers.iter()
+    }
+    pub fn get(&self, name: &str) -> Option<&Layer> {
+        self.layers.iter().find(|l| l.name == name)
+    }
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+    pub fn clear(&mut self) {
+        self.layers.clear();
+    }
+}
+
+///Decodes the payload of a section::LAYERS section.
+pub fn decode_layers(payload: &[u8]) -> Result<Vec<Layer>, HeaderError> {
+    let bad = |offset: usize| HeaderError::BadSection { offset };
+    if payload.len() < 8 {
+        return Err(bad(0));
+    }
+    let count = read_u32(payload, 0) as usize;
+    if count > MAX_LAYERS {
+        return Err(bad(0));
+    }
+
+    let mut layers = Vec::with_capacity(count);
+    let mut at = 8;
+    for _ in 0..count {
+        if at + LAYER_ENTRY_LEN > payload.len() {
+            return Err(bad(at));
+        }
+        let slot_count = read_u32(payload, at + 60) as usize;
+        if slot_count == 0 || slot_count > MAX_SLOTS {
+            return Err(HeaderError::BadSlotCount(slot_count as u32));
+        }
+        let end = at + LAYER_ENTRY_LEN + slot_count * 8;
+        if end > payload.len() {
+            return Err(bad(at));
+        }
+
+        let name = &payload[at..at + LAYER_NAME_LEN];
+        let name_len = name.iter().position(|c| *c == 0).unwrap_or(LAYER_NAME_LEN);
+        let name = std::str::from_utf8(&name[..name_len]).map_err(|_| bad(at))?;
+        if name.is_empty() {
+            return Err(bad(at));
+        }
+
+        let width = read_u32(payload, at + 48);
+        let height = read_u32(payload, at + 52);
+        let opacity = f32::from_bits(read_u32(payload, at + 36));
+
+        layers.push(Layer {
+            name: name.to_string(),
+            z: read_u32(payload, at + 32) as i32,
+            opacity: if opacity.is_nan() {
+                1.0
+            } else {
+                opacity.clamp(0.0, 1.0)
+            },
+            dest: if width == 0 || height == 0 {
+                None
+            } else {
+                Some(Rect::new(
+                    read_u32(payload, at + 40) as i32,
+                    read_u32(payload, at + 44) as i32,
+                    width,
+                    height,
+                ))
+            },
+            index: read_u32(payload, at + 56),
+            slots: (0..slot_count)
+                .map(|i| read_u64(payload, at + LAYER_ENTRY_LEN + i * 8))
+                .collect(),
+        });
+        at = end;
+    }
+    Ok(layers)
+}
+
+///Encodes layers into the payload of a section::LAYERS section. Used by producers and tests.
+pub fn encode_layers(layers: &[Layer]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(layers.len() as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    for layer in layers {
+        let mut name = [0u8; LAYER_NAME_LEN];
+        let bytes = layer.name.as_bytes();
+        let len = bytes.len().min(LAYER_NAME_LEN);
+        name[..len].copy_from_slice(&bytes[..len]);
+        let dest = layer.dest.unwrap_or_default();
+
+        out.extend_from_slice(&name);
+        out.extend_from_slice(&layer.z.to_le_bytes());
+        out.extend_from_slice(&layer.opacity.to_bits().to_le_bytes());
+        out.extend_from_slice(&dest.x.to_le_bytes());
+        out.extend_from_slice(&dest.y.to_le_bytes());
+        out.extend_from_slice(&dest.width.to_le_bytes());
+        out.extend_from_slice(&dest.height.to_le_bytes());
+        out.extend_from_slice(&layer.index.to_le_bytes());
+        out.extend_from_slice(&(layer.slots.len() as u32).to_le_bytes());
+        for slot in &layer.slots {
+            out.extend_from_slice(&slot.to_le_bytes());
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(name: &str, z: i32) -> Layer {
+        Layer {
+            name: name.to_string(),
+            z,
+            ..Layer::main(&[1, 2], 0)
+        }
+    }
+
+    fn names(list: &LayerList) -> Vec<(&str, i32)> {
+        list.iter().map(|l| (l.name.as_str(), l.z)).collect()
+    }
+
+    #[test]
+    fn layers_are_sorted_by_z() {
+        let list = LayerList::new(vec![
+            layer("top", 10),
+            Layer::main(&[1, 2], 0),
+            layer("below", -1),
+            layer("tie", 0),
+        ]);
+        assert_eq!(
+            names(&list),
+            [("below", -1), ("main", 0), ("tie", 0), ("top", 10)]
+        );
+    }
+
+    #[test]
+    fn first_layer_of_a_name_wins() {
+        let list = LayerList::new(vec![layer("map", 5), layer("main", 0), layer("map", -5)]);
+        assert_eq!(names(&list), [("main", 0), ("map", 5)]);
+        assert_eq!(list.get("map").unwrap().z, 5);
+        assert_eq!(list.len(), 2);
+    }
+
+    #[test]
+    fn intersect() {
+        let a = Rect::new(0, 0, 100, 100);
+        assert_eq!(
+            a.intersect(&Rect::new(50, -20, 100, 40)),
+            Some(Rect::new(50, 0, 50, 20))
+        );
+        assert_eq!(a.intersect(&Rect::new(100, 0, 10, 10)), None);
+        assert_eq!(a.intersect(&Rect::new(-10, -10, 10, 200)), None);
+        //No overflow near the edges of i32.
+        let far = Rect::new(i32::MAX - 5, 0, u32::MAX, 10);
+        assert_eq!(far.intersect(&a), None);
+    }
+
+    #[test]
+    fn place_clips_and_maps_uv() {
+        //Whole window.
+        let main = Layer::main(&[1], 0);
+        let placement = main.place(800, 600).unwrap();
+        assert_eq!(placement.visible, Rect::new(0, 0, 800, 600));
+        assert_eq!(
+            placement.uv,
+            UvRect {
+                u: 0.0,
+                v: 0.0,
+                width: 1.0,
+                height: 1.0
+            }
+        );
+
+        //Half off the left edge, a quarter off the bottom.
+        let placement = place(Rect::new(-50, 500, 100, 200), 800, 600).unwrap();
+        assert_eq!(placement.visible, Rect::new(0, 500, 50, 100));
+        assert_eq!(
+            placement.uv,
+            UvRect {
+                u: 0.5,
+                v: 0.0,
+                width: 0.5,
+                height: 0.5
+            }
+        );
+
+        assert_eq!(place(Rect::new(800, 0, 100, 100), 800, 600), None);
+        assert_eq!(place(Rect::new(0, 0, 0, 100), 800, 600), None);
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let layers = vec![
+            Layer::main(&[0x10, 0x20], 1),
+            Layer {
+                name: "minimap".to_string(),
+                z: -3,
+                opacity: 0.5,
+                dest: Some(Rect::new(-10, 20, 300, 200)),
+                index: 2,
+                slots: vec![1, 2, 3],
+            },
+        ];
+        assert_eq!(decode_layers(&encode_layers(&layers)), Ok(layers));
+        assert_eq!(decode_layers(&encode_layers(&[])), Ok(vec![]));
+    }
+
+    #[test]
+    fn decode_sanitizes_opacity() {
+        let mut layers = vec![layer("a", 0), layer("b", 0), layer("c", 0)];
+        layers[0].opacity = 2.0;
+        layers[1].opacity = -1.0;
+        layers[2].opacity = f32::NAN;
+        let opacities: Vec<f32> = decode_layers(&encode_layers(&layers))
+            .unwrap()
+            .iter()
+            .map(|l| l.opacity)
+            .collect();
+        assert_eq!(opacities, [1.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn decode_rejects_malformed_payloads() {
+        let bad = |offset| Err(HeaderError::BadSection { offset });
+        assert_eq!(decode_layers(&[0; 4]), bad(0));
+
+        let mut too_many = encode_layers(&[]);
+        too_many[0] = MAX_LAYERS as u8 + 1;
+        assert_eq!(decode_layers(&too_many), bad(0));
+
+        //Slots cut short.
+        let payload = encode_layers(&[layer("a", 0), layer("b", 0)]);
+        let second = 8 + LAYER_ENTRY_LEN + 16;
+        assert_eq!(decode_layers(&payload[..payload.len() - 1]), bad(second));
+        //Entry cut short.
+        assert_eq!(decode_layers(&payload[..second + 10]), bad(second));
+
+        let mut no_slots = layer("a", 0);
+        no_slots.slots.clear();
+        assert_eq!(
+            decode_layers(&encode_layers(&[no_slots])),
+            Err(HeaderError::BadSlotCount(0))
+        );
+        let mut too_many_slots = layer("a", 0);
+        too_many_slots.slots = vec![1; MAX_SLOTS + 1];
+        assert_eq!(
+            decode_layers(&encode_layers(&[too_many_slots])),
+            Err(HeaderError::BadSlotCount(9))
+        );
+
+        assert_eq!(decode_layers(&encode_layers(&[layer("", 0)])), bad(8));
+        let mut payload = encode_layers(&[layer("a", 0)]);
+        payload[8] = 0xff;
+        assert_eq!(decode_layers(&payload), bad(8));
+    }
+}
@@ -0,0 +1,676 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use super::{
+    dirty::{SlotDirty, decode_dirty},
+    header::{
+        self, HeaderError, MMFHeader, Negotiated, feature, find_section, flag, read_flags,
+        read_heartbeat, section,
+    },
+    hit_test::{HitTest, decode_hit_test},
+    layers::{Layer, LayerList, decode_layers},
+    liveness::{LivenessProbe, ProbeContext},
+    stall::{Fade, StallDetector, StallEvent},
+};
+use crate::config::StallConfig;
+
+/*
+ *
+ * The link with the producer, as a state machine. Plain Rust, no windows: everything touching
+ * the OS goes through a LinkBackend, so the MMF thread can be driven by something else.
+ *
+ *   Disconnected  Nothing mapped, the producer isn't known to be running
+ *   Mapping       The producer runs, its header can't be mapped yet
+ *   Handshaking   The header is mapped, waiting for a header we understand
+ *   Streaming     Frames are decoded and handed to the renderer
+ *   Stalled       Like Streaming, but the heartbeat stopped. The overlay fades out
+ *   Closing       The producer is gone, everything is being released
+ *
+ * Disconnected and Mapping both try to map the header on every step, since some probes
+ * (heartbeat) can only tell the producer is alive from what it writes there.
+ * Once the producer has been seen alive, the probe saying otherwise closes the link.
+ *
+ * */
+
+//How often the link steps when the producer doesn't wake us up.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Disconnected,
+    Mapping,
+    Handshaking,
+    Streaming,
+    Stalled,
+    Closing,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LinkState::Disconnected => "Disconnected",
+            LinkState::Mapping => "Mapping",
+            LinkState::Handshaking => "Handshaking",
+            LinkState::Streaming => "Streaming",
+            LinkState::Stalled => "Stalled",
+            LinkState::Closing => "Closing",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+//Everything the renderer needs from one read of the header.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub negotiated: Negotiated,
+    //Every layer to draw, in drawing order. The base slots of the header are the "main" layer.
+    pub layers: LayerList,
+    pub dirty: Option<SlotDirty>,
+    pub hit_test: Option<HitTest>,
+    pub fade: Fade,
+    //Some when the producer changed its keyboard capture request.
+    pub capture: Option<bool>,
+}
+
+//Side effects of the link.
+pub trait LinkBackend {
+    ///Blocks until the producer wakes us up, or timeout elapsed.
+    fn wait(&mut self, timeout: Duration);
+    fn now(&self) -> Instant;
+    ///Producer that paired with us through the discovery table, if any.
+    fn producer_pid(&self) -> Option<u32>;
+    ///Maps the producer's header. False if it doesn't exist (yet).
+    fn map_header(&mut self) -> bool;
+    ///Calls f with the mapped header. None if it isn't mapped, the renderer may have
+    ///given up on it.
+    fn with_header<R>(&mut self, f: impl FnOnce(&[u8]) -> R) -> Option<R>;
+    ///Releases the header without touching it, the producer is refused.
+    fn unmap_header(&mut self);
+    ///Hands a frame to the renderer.
+    fn publish(&mut self, frame: Frame);
+    ///Writes our version and dimensions into the header, once the handshake is done.
+    fn send_dimensions(&mut self);
+    ///Clears and releases everything shared with the producer, which is gone.
+    fn shutdown(&mut self);
+    fn restart_producer(&mut self);
+}
+
+pub struct Link<B: LinkBackend> {
+    state: LinkState,
+    backend: B,
+    probe: Box<dyn LivenessProbe>,
+    stall: StallDetector,
+    restart_on_stall: bool,
+    //Whether the probe said the producer was alive since the link was last closed.
+    producer_alive: bool,
+    heartbeat: Option<u64>,
+    //Capture only follows changes of the producer's flag, so the escape hatch sticks until
+    //the producer asks again.
+    capture_requested: bool,
+    //Last decoding error, so a mismatched producer doesn't flood the logs.
+    last_error: Option<HeaderError>,
+}
+
+impl<B: LinkBackend> Link<B> {
+    pub fn new(backend: B, probe: Box<dyn LivenessProbe>, stall: &StallConfig) -> Link<B> {
+        log::info!(
+            "Checking that the producer is alive with the {} probe.",
+            probe.name()
+        );
+        Link {
+            state: LinkState::Disconnected,
+            backend,
+            probe,
+            stall: StallDetector::new(
+                Duration::from_millis(stall.timeout_ms),
+                Duration::from_millis(stall.fade_ms),
+            ),
+            restart_on_stall: stall.restart_blish,
+            producer_alive: false,
+            heartbeat: None,
+            capture_requested: false,
+            last_error: None,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    ///Waits for the producer, then does whatever the current state requires.
+    pub fn step(&mut self) {
+        self.backend.wait(POLL_INTERVAL);
+        let context = ProbeContext {
+            now: self.backend.now(),
+            heartbeat: self.heartbeat,
+            producer_pid: self.backend.producer_pid(),
+        };
+        let alive = self.probe.is_alive(&context);
+        if !alive && self.producer_alive {
+            log::info!("The producer is gone ({} probe).", self.probe.name());
+            self.close();
+            return;
+        }
+        self.producer_alive = alive;
+
+        match self.state {
+            LinkState::Disconnected | LinkState::Mapping | LinkState::Closing => {
+                if self.backend.map_header() {
+                    self.transition(LinkState::Handshaking);
+                    self.read();
+                } else if alive {
+                    self.transition(LinkState::Mapping);
+                }
+            }
+            LinkState::Handshaking | LinkState::Streaming | LinkState::Stalled => self.read(),
+        }
+    }
+
+    fn transition(&mut self, to: LinkState) {
+        if self.state != to {
+            log::info!("Producer link: {} -> {}", self.state, to);
+            self.state = to;
+        }
+    }
+
+    fn close(&mut self) {
+        self.transition(LinkState::Closing);
+        self.backend.shutdown();
+        self.reset();
+        self.transition(LinkState::Disconnected);
+    }
+
+    fn reset(&mut self) {
+        self.producer_alive = false;
+        self.heartbeat = None;
+        self.capture_requested = false;
+        self.last_error = None;
+        self.stall.update(None, self.backend.now());
+    }
+
+    //Logs an error unless it is the same as the last one.
+    fn report(&mut self, what: &str, e: HeaderError) {
+        if self.last_error != Some(e) {
+            log::error!("{}: {}", what, e);
+            self.last_error = Some(e);
+        }
+    }
+
+    fn read(&mut self) {
+        let Some(read) = self.backend.with_header(read_frame) else {
+            //Unmapped behind our back, start over.
+            self.heartbeat = None;
+            self.capture_requested = false;
+            self.transition(LinkState::Mapping);
+            return;
+        };
+        let (decoded, negotiated, sections) = match read {
+            Ok(read) => read,
+            //The producer hasn't written anything yet, or is starting over.
+            Err(HeaderError::Uninitialized) => {
+                self.transition(LinkState::Handshaking);
+                return;
+            }
+            //Anything else means we can't understand the producer. Refuse to draw
+            //anything instead of reading garbage, and retry on the next wake up.
+            Err(e) => {
+                self.report("Incompatible producer", e);
+                self.backend.unmap_header();
+                self.heartbeat = None;
+                self.capture_requested = false;
+                self.transition(LinkState::Mapping);
+                return;
+            }
+        };
+
+        let handshake = self.state == LinkState::Handshaking;
+        if handshake {
+            if decoded.version != header::PROTOCOL_VERSION {
+                log::warn!(
+                    "Producer speaks protocol version {}, this DLL speaks {}. Using version {}.",
+                    decoded.version,
+                    header::PROTOCOL_VERSION,
+                    negotiated.version
+                );
+            }
+            log::info!(
+                "Connected to producer. Version: {} Features: 0x{:x} Slots: {}",
+                negotiated.version,
+                negotiated.features,
+                decoded.slot_count
+            );
+            self.last_error = None;
+        }
+
+        let mut layers = vec![Layer::main(decoded.slots(), decoded.index)];
+        match sections.layers {
+            Ok(extra) => layers.extend(extra.unwrap_or_default()),
+            //Still draw the main layer, there is no reason to punish it.
+            Err(e) => self.report("Ignoring the extra layers", e),
+        }
+        let dirty = match sections.dirty {
+            Ok(entries) => entries
+                .unwrap_or_default()
+                .into_iter()
+                .find(|e| e.slot == decoded.index),
+            //Without them the whole texture is simply drawn, like before.
+            Err(e) => {
+                self.report("Ignoring the dirty rectangles", e);
+                None
+            }
+        };
+        let hit_test = match sections.hit_test {
+            Ok(hit_test) => hit_test,
+            //Everything goes to the game then, which is how it used to be.
+            Err(e) => {
+                self.report("Ignoring the hit-test data", e);
+                None
+            }
+        };
+        let flags = match sections.flags {
+            Ok(flags) => flags,
+            Err(e) => {
+                self.report("Ignoring the producer's flags", e);
+                0
+            }
+        };
+        match sections.heartbeat {
+            Ok(heartbeat) => self.heartbeat = heartbeat.map(|h| h.counter),
+            Err(e) => self.report("Ignoring the producer's heartbeat", e),
+        }
+
+        let requested = flags & flag::KEYBOARD_CAPTURE != 0;
+        let capture = (requested != self.capture_requested).then_some(requested);
+        self.capture_requested = requested;
+
+        let now = self.backend.now();
+        match self.stall.update(self.heartbeat, now) {
+            Some(StallEvent::Stalled { since }) => {
+                log::warn!(
+                    "The producer hasn't ticked for {} ms, hiding the overlay.",
+                    since.as_millis()
+                );
+                self.transition(LinkState::Stalled);
+                if self.restart_on_stall {
+                    self.backend.restart_producer();
+                }
+            }
+            Some(StallEvent::Recovered { after }) => {
+                log::info!(
+                    "The producer is ticking again after {} ms.",
+                    after.as_millis()
+                );
+                self.transition(LinkState::Streaming);
+            }
+            None if handshake => self.transition(LinkState::Streaming),
+            None => {}
+        }
+
+        self.backend.publish(Frame {
+            negotiated,
+            layers: LayerList::new(layers),
+            dirty,
+            hit_test,
+            fade: self.stall.fade(),
+            capture,
+        });
+        if handshake {
+            self.backend.send_dimensions();
+        }
+    }
+}
+
+//Optional sections of one read, each decoded on its own so a bad one doesn't take the
+//others down.
+struct Sections {
+    layers: Result<Option<Vec<Layer>>, HeaderError>,
+    dirty: Result<Option<Vec<SlotDirty>>, HeaderError>,
+    hit_test: Result<Option<HitTest>, HeaderError>,
+    flags: Result<u32, HeaderError>,
+    heartbeat: Result<Option<header::Heartbeat>, HeaderError>,
+}
+
+fn read_frame(data: &[u8]) -> Result<(MMFHeader, Negotiated, Sections), HeaderError> {
+    let decoded = MMFHeader::decode(data)?;
+    let negotiated = decoded.negotiate(data.len())?;
+    let optional = |feature: u32, tag: u32| {
+        if negotiated.features & feature == 0 {
+            return Ok(None);
+        }
+        find_section(data, &decoded, &negotiated, tag)
+    };
+    let sections = Sections {
+        layers: optional(feature::LAYERS, section::LAYERS)
+            .and_then(|payload| payload.map(decode_layers).transpose()),
+        dirty: optional(feature::DIRTY_RECTS, section::DIRTY_RECTS)
+            .and_then(|payload| payload.map(decode_dirty).transpose()),
+        hit_test: optional(feature::HIT_TEST, section::HIT_TEST)
+            .and_then(|payload| payload.map(decode_hit_test).transpose()),
+        flags: read_flags(data, &decoded, &negotiated),
+        heartbeat: read_heartbeat(data, &decoded, &negotiated),
+    };
+    Ok((decoded, negotiated, sections))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        sync::{Arc, atomic::AtomicBool, atomic::Ordering},
+    };
+
+    use super::*;
+    use crate::ui::{
+        header::{slots_end, write_section},
+        liveness::{FakeProbe, HeartbeatProbe},
+    };
+
+    const HEADER_LEN: usize = 128;
+
+    //Everything the link did, and what the producer wrote.
+    #[derive(Default)]
+    struct Script {
+        now: Option<Instant>,
+        //The producer's header, None while it doesn't exist.
+        producer: Option<Vec<u8>>,
+        mapped: bool,
+        published: Vec<Frame>,
+        dimensions_sent: usize,
+        unmapped: usize,
+        shutdowns: usize,
+        restarts: usize,
+    }
+
+    #[derive(Clone, Default)]
+    struct FakeBackend(Rc<RefCell<Script>>);
+
+    impl LinkBackend for FakeBackend {
+        fn wait(&mut self, _: Duration) {}
+        fn now(&self) -> Instant {
+            *self.0.borrow_mut().now.get_or_insert_with(Instant::now)
+        }
+        fn producer_pid(&self) -> Option<u32> {
+            None
+        }
+        fn map_header(&mut self) -> bool {
+            let mut script = self.0.borrow_mut();
+            script.mapped = script.producer.is_some();
+            script.mapped
+        }
+        fn with_header<R>(&mut self, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+            let script = self.0.borrow();
+            if !script.mapped {
+                return None;
+            }
+            script.producer.as_deref().map(f)
+        }
+        fn unmap_header(&mut self) {
+            let mut script = self.0.borrow_mut();
+            script.mapped = false;
+            script.unmapped += 1;
+        }
+        fn publish(&mut self, frame: Frame) {
+            self.0.borrow_mut().published.push(frame);
+        }
+        fn send_dimensions(&mut self) {
+            self.0.borrow_mut().dimensions_sent += 1;
+        }
+        fn shutdown(&mut self) {
+            let mut script = self.0.borrow_mut();
+            script.mapped = false;
+            script.shutdowns += 1;
+        }
+        fn restart_producer(&mut self) {
+            self.0.borrow_mut().restarts += 1;
+        }
+    }
+
+    impl FakeBackend {
+        fn advance(&self, ms: u64) {
+            let now = self.now();
+            self.0.borrow_mut().now = Some(now + Duration::from_millis(ms));
+        }
+        fn write(&self, producer: Option<Vec<u8>>) {
+            self.0.borrow_mut().producer = producer;
+        }
+    }
+
+    //What a producer writes, with a heartbeat section if it sends one.
+    fn producer(heartbeat: Option<u64>, flags: u32) -> Vec<u8> {
+        let mut header = MMFHeader {
+            magic: header::HEADER_MAGIC,
+            version: header::PROTOCOL_VERSION,
+            header_len: HEADER_LEN as u16,
+            features: feature::FLAGS,
+            sections_offset: slots_end(2) as u16,
+            ..Default::default()
+        };
+        header.set_slots(&[0x1000, 0x2000]).unwrap();
+        let mut data = vec![0; HEADER_LEN];
+        let mut at = write_section(
+            &mut data,
+            slots_end(2),
+            section::FLAGS,
+            &flags.to_le_bytes(),
+        )
+        .unwrap();
+        if let Some(counter) = heartbeat {
+            header.features |= feature::HEARTBEAT;
+            let mut payload = counter.to_le_bytes().to_vec();
+            payload.extend_from_slice(&0u64.to_le_bytes());
+            at = write_section(&mut data, at, section::HEARTBEAT, &payload).unwrap();
+        }
+        assert!(at <= HEADER_LEN);
+        header.encode(&mut data).unwrap();
+        data
+    }
+
+    fn stall_config(timeout_ms: u64) -> StallConfig {
+        StallConfig {
+            timeout_ms,
+            fade_ms: 100,
+            restart_blish: false,
+        }
+    }
+
+    fn fake_link() -> (Link<FakeBackend>, FakeBackend, Arc<AtomicBool>) {
+        let backend = FakeBackend::default();
+        let probe = FakeProbe::default();
+        let alive = probe.alive.clone();
+        let link = Link::new(backend.clone(), Box::new(probe), &stall_config(0));
+        (link, backend, alive)
+    }
+
+    #[test]
+    fn waits_for_the_producer_to_be_seen_alive() {
+        let (mut link, backend, alive) = fake_link();
+        //Nothing to close, the producer was never there.
+        link.step();
+        assert_eq!(link.state(), LinkState::Disconnected);
+        alive.store(true, Ordering::Relaxed);
+        link.step();
+        assert_eq!(link.state(), LinkState::Mapping);
+        backend.write(Some(producer(None, 0)));
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        assert_eq!(backend.0.borrow().shutdowns, 0);
+    }
+
+    #[test]
+    fn fake_probe_loss_closes_the_link() {
+        let (mut link, backend, alive) = fake_link();
+        alive.store(true, Ordering::Relaxed);
+        backend.write(Some(producer(None, 0)));
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+
+        alive.store(false, Ordering::Relaxed);
+        backend.write(None);
+        link.step();
+        assert_eq!(link.state(), LinkState::Disconnected);
+        assert_eq!(backend.0.borrow().shutdowns, 1);
+        //Closed once, the producer has to come back first.
+        link.step();
+        assert_eq!(link.state(), LinkState::Disconnected);
+        assert_eq!(backend.0.borrow().shutdowns, 1);
+    }
+
+    #[test]
+    fn heartbeat_probe_closes_the_link_after_its_timeout() {
+        let backend = FakeBackend::default();
+        let probe = HeartbeatProbe::new(Duration::from_millis(1000));
+        let mut link = Link::new(backend.clone(), Box::new(probe), &stall_config(0));
+        backend.write(Some(producer(Some(1), 0)));
+        //The probe has no heartbeat to judge until the header is read.
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        link.step();
+        backend.advance(999);
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        backend.advance(1);
+        link.step();
+        assert_eq!(link.state(), LinkState::Disconnected);
+        assert_eq!(backend.0.borrow().shutdowns, 1);
+
+        //Ticking again reconnects.
+        backend.write(Some(producer(Some(2), 0)));
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+    }
+
+    #[test]
+    fn full_lifecycle() {
+        let backend = FakeBackend::default();
+        let probe = FakeProbe::default();
+        let alive = probe.alive.clone();
+        let mut link = Link::new(backend.clone(), Box::new(probe), &stall_config(500));
+        let script = || backend.0.borrow();
+
+        link.step();
+        assert_eq!(link.state(), LinkState::Disconnected);
+
+        alive.store(true, Ordering::Relaxed);
+        link.step();
+        assert_eq!(link.state(), LinkState::Mapping);
+
+        //Created, but nothing written yet.
+        backend.write(Some(vec![0; HEADER_LEN]));
+        link.step();
+        assert_eq!(link.state(), LinkState::Handshaking);
+        assert!(script().published.is_empty());
+
+        backend.write(Some(producer(Some(1), 0)));
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        assert_eq!(script().published.len(), 1);
+        assert_eq!(script().dimensions_sent, 1);
+        let frame = script().published[0].clone();
+        assert_eq!(frame.negotiated.version, header::PROTOCOL_VERSION);
+        assert_eq!(frame.layers.iter().next().unwrap().slots, [0x1000, 0x2000]);
+        assert_eq!(frame.fade.start, None);
+
+        backend.advance(499);
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        backend.advance(1);
+        link.step();
+        assert_eq!(link.state(), LinkState::Stalled);
+        assert!(script().published.last().unwrap().fade.start.is_some());
+        assert_eq!(script().restarts, 0);
+
+        backend.write(Some(producer(Some(2), 0)));
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        assert_eq!(script().published.last().unwrap().fade.start, None);
+        //The handshake isn't done again.
+        assert_eq!(script().dimensions_sent, 1);
+
+        //Closing only lasts while the backend shuts down.
+        alive.store(false, Ordering::Relaxed);
+        backend.write(None);
+        link.step();
+        assert_eq!(link.state(), LinkState::Disconnected);
+        assert_eq!(script().shutdowns, 1);
+        assert!(!script().mapped);
+    }
+
+    #[test]
+    fn restarts_a_stalled_producer_if_asked() {
+        let backend = FakeBackend::default();
+        let config = StallConfig {
+            restart_blish: true,
+            ..stall_config(500)
+        };
+        let mut link = Link::new(backend.clone(), Box::new(FakeProbe::default()), &config);
+        backend.write(Some(producer(Some(1), 0)));
+        link.step();
+        backend.advance(500);
+        link.step();
+        assert_eq!(link.state(), LinkState::Stalled);
+        //Only once per stall.
+        link.step();
+        assert_eq!(backend.0.borrow().restarts, 1);
+    }
+
+    #[test]
+    fn refuses_incompatible_producers() {
+        let (mut link, backend, _) = fake_link();
+        let mut incompatible = producer(None, 0);
+        incompatible[0] ^= 0xFF;
+        backend.write(Some(incompatible));
+        link.step();
+        assert_eq!(link.state(), LinkState::Mapping);
+        assert_eq!(backend.0.borrow().unmapped, 1);
+        assert!(backend.0.borrow().published.is_empty());
+
+        backend.write(Some(producer(None, 0)));
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        assert_eq!(backend.0.borrow().dimensions_sent, 1);
+    }
+
+    #[test]
+    fn remaps_a_header_unmapped_behind_its_back() {
+        let (mut link, backend, _) = fake_link();
+        backend.write(Some(producer(None, 0)));
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        backend.0.borrow_mut().mapped = false;
+        link.step();
+        assert_eq!(link.state(), LinkState::Mapping);
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        assert_eq!(backend.0.borrow().dimensions_sent, 2);
+    }
+
+    #[test]
+    fn capture_follows_changes_of_the_flag() {
+        let (mut link, backend, _) = fake_link();
+        //State after the step, and the capture change published by it.
+        let mut step = |flags: u32| {
+            backend.write(Some(producer(None, flags)));
+            link.step();
+            let published = backend.0.borrow().published.last().unwrap().capture;
+            (link.state(), published)
+        };
+        let on = flag::KEYBOARD_CAPTURE;
+        assert_eq!(step(0), (LinkState::Streaming, None));
+        assert_eq!(step(on), (LinkState::Streaming, Some(true)));
+        assert_eq!(step(on), (LinkState::Streaming, None));
+        assert_eq!(step(0), (LinkState::Streaming, Some(false)));
+        assert_eq!(step(on), (LinkState::Streaming, Some(true)));
+
+        //Forgotten once the header goes away, so the request is passed on again.
+        backend.0.borrow_mut().mapped = false;
+        let published = backend.0.borrow().published.len();
+        assert_eq!(step(on), (LinkState::Mapping, Some(true)));
+        assert_eq!(backend.0.borrow().published.len(), published);
+        assert_eq!(step(on), (LinkState::Streaming, Some(true)));
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/*
+ *
+ * Ways to tell whether the producer is still running. Plain Rust, no windows: the probes
+ * touching the OS live next to the MMF thread, and get whatever else they need through
+ * ProbeContext.
+ *
+ * */
+
+//What the MMF thread knows about the producer when asking a probe.
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeContext {
+    pub now: Instant,
+    //Last heartbeat counter read from the header, if the producer sends one.
+    pub heartbeat: Option<u64>,
+    //Producer that paired with us through the discovery table, if any.
+    pub producer_pid: Option<u32>,
+}
+
+pub trait LivenessProbe: Send {
+    fn is_alive(&mut self, context: &ProbeContext) -> bool;
+    //Used in logs.
+    fn name(&self) -> &'static str;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeKind {
+    //The producer holds a named mutex while it runs.
+    NamedMutex,
+    //The producer increments a counter in the header.
+    Heartbeat,
+    //The producer's process, found through the discovery table, is still running.
+    Process,
+}
+
+impl ProbeKind {
+    pub fn from_name(name: &str) -> Option<ProbeKind> {
+        match name {
+            "mutex" => Some(ProbeKind::NamedMutex),
+            "heartbeat" => Some(ProbeKind::Heartbeat),
+            "process" => Some(ProbeKind::Process),
+            _ => None,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            ProbeKind::NamedMutex => "mutex",
+            ProbeKind::Heartbeat => "heartbeat",
+            ProbeKind::Process => "process",
+        }
+    }
+}
+
+//Alive as long as the heartbeat counter changed less than timeout ago.
+#[derive(Debug)]
+pub struct HeartbeatProbe {
+    timeout: Duration,
+    last: Option<(u64, Instant)>,
+}
+
+impl HeartbeatProbe {
+    pub fn new(timeout: Duration) -> HeartbeatProbe {
+        HeartbeatProbe {
+            timeout,
+            last: None,
+        }
+    }
+}
+
+impl LivenessProbe for HeartbeatProbe {
+    fn is_alive(&mut self, context: &ProbeContext) -> bool {
+        let Some(heartbeat) = context.heartbeat else {
+            self.last = None;
+            return false;
+        };
+        match self.last {
+            Some((last, _)) if last == heartbeat => {}
+            _ => self.last = Some((heartbeat, context.now)),
+        }
+        self.last
+            .is_some_and(|(_, since)| context.now.duration_since(since) < self.timeout)
+    }
+    fn name(&self) -> &'static str {
+        ProbeKind::Heartbeat.name()
+    }
+}
+
+//Answers whatever the test tells it to.
+#[derive(Debug, Clone, Default)]
+pub struct FakeProbe {
+    pub alive: Arc<AtomicBool>,
+}
+
+impl LivenessProbe for FakeProbe {
+    fn is_alive(&mut self, _: &ProbeContext) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+    fn name(&self) -> &'static str {
+        "fake"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(now: Instant, heartbeat: Option<u64>) -> ProbeContext {
+        ProbeContext {
+            now,
+            heartbeat,
+            producer_pid: None,
+        }
+    }
+
+    #[test]
+    fn heartbeat_times_out() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut probe = HeartbeatProbe::new(Duration::from_millis(100));
+        assert!(!probe.is_alive(&context(at(0), None)));
+        assert!(probe.is_alive(&context(at(0), Some(1))));
+        assert!(probe.is_alive(&context(at(99), Some(1))));
+        assert!(!probe.is_alive(&context(at(100), Some(1))));
+        //Any change brings it back, even going backwards.
+        assert!(probe.is_alive(&context(at(150), Some(0))));
+        assert!(probe.is_alive(&context(at(249), Some(0))));
+        assert!(!probe.is_alive(&context(at(250), Some(0))));
+    }
+
+    #[test]
+    fn heartbeat_restarts_after_disappearing() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut probe = HeartbeatProbe::new(Duration::from_millis(100));
+        assert!(probe.is_alive(&context(at(0), Some(7))));
+        assert!(!probe.is_alive(&context(at(50), None)));
+        //Same counter as before, but the timeout starts over.
+        assert!(probe.is_alive(&context(at(140), Some(7))));
+        assert!(!probe.is_alive(&context(at(240), Some(7))));
+    }
+
+    #[test]
+    fn fake_probe_follows_its_clones() {
+        let mut probe = FakeProbe::default();
+        let alive = probe.alive.clone();
+        assert!(!probe.is_alive(&context(Instant::now(), None)));
+        alive.store(true, Ordering::Relaxed);
+        assert!(probe.is_alive(&context(Instant::now(), None)));
+    }
+
+    #[test]
+    fn probe_kind_names() {
+        for kind in [
+            ProbeKind::NamedMutex,
+            ProbeKind::Heartbeat,
+            ProbeKind::Process,
+        ] {
+            assert_eq!(ProbeKind::from_name(kind.name()), Some(kind));
+        }
+        assert_eq!(ProbeKind::from_name("Mutex"), None);
+    }
+}
@@ -1,219 +1,277 @@
 use std::{
-    ffi::OsStr,
-    os::windows::ffi::OsStrExt,
-    ptr::null_mut,
-    slice::{from_raw_parts, from_raw_parts_mut},
-    sync::{atomic::Ordering, Arc, RwLock},
-    time::Duration,
+    io,
+    sync::{Arc, Mutex, RwLock, atomic::Ordering},
+    time::{Duration, Instant},
 };
 
-use windows::{
-    core::{w, PCWSTR}, Win32::{
-        Foundation::{CloseHandle, BOOL, HANDLE, RECT},
-        System::{
-            Memory::{
-                MapViewOfFile, OpenFileMappingW, UnmapViewOfFile, FILE_MAP_ALL_ACCESS, MEMORY_MAPPED_VIEW_ADDRESS
-            },
-            Threading::{self, CreateEventW, OpenMutexW, SetEvent, WaitForSingleObject, INFINITE},
-        }, UI::WindowsAndMessaging::GetClientRect,
-    }
+use crate::{
+    config::config,
+    debug::restart_blish,
+    platform::{Event, SharedMemory, platform},
+    shutdown::{is_shutting_down, spawn_worker},
 };
 
-use crate::utils::get_mainwindow_hwnd;
-
-use super::{HEADER_NAME, HEADER_SIZE, MMF_DATA, OVERLAY_STATE, UPDATE_SCHEDULED};
+use super::{
+    KEYBOARD_CAPTURE, MMF_DATA, UPDATE_SCHEDULED,
+    dirty::SlotDirty,
+    discovery::{DISCOVERY_LEN, DiscoveryTable, record_state},
+    header::{self, Negotiated},
+    hit_test::HitTest,
+    layers::LayerList,
+    link::{Frame, Link, LinkBackend},
+    liveness::{HeartbeatProbe, LivenessProbe, ProbeContext, ProbeKind},
+    object_names,
+    stall::Fade,
+};
 
+//The discovery table and our record in it, kept until the record is withdrawn.
+struct Discovery {
+    table: Arc<dyn SharedMemory>,
+    index: usize,
+    pid: u32,
+}
+static DISCOVERY: Mutex<Option<Discovery>> = Mutex::new(None);
 
-#[derive(Debug)]
 pub struct MMFData {
-    header: Option<MEMORY_MAPPED_VIEW_ADDRESS>,
-    file_mapping: Option<HANDLE>,
-    pub index: u32,
-    pub addr1: u64,
-    pub addr2: u64,
+    //The producer's header. The view can be bigger than the header, never read past it.
+    header: Option<Arc<dyn SharedMemory>>,
+    //Set once the producer's header has been validated. Nothing is read or written before that.
+    pub negotiated: Option<Negotiated>,
+    //Every layer to draw, in drawing order. The base slots of the header are the "main" layer.
+    pub layers: LayerList,
+    //What changed in the slot the main layer is about to draw, if the producer says so.
+    pub dirty: Option<SlotDirty>,
+    //Interactive parts of the overlay. Clicks there don't reach the game.
+    pub hit_test: Option<HitTest>,
+    //Fade out of the overlay while the producer is stalled. See ui::stall.
+    pub fade: Fade,
     pub is_blish_alive: bool,
-    resize_event: HANDLE,
+    //Closed once the thread stops.
+    resize_event: Option<Arc<dyn Event>>,
 }
-unsafe impl Send for MMFData {}
-unsafe impl Sync for MMFData {}
 
-///This thread runs forever, updating the MMF data so as to not block present()
+///This thread runs until shutdown, updating the MMF data so as to not block present()
 ///With this current method, it takes 0-500 nanoseconds to get the lock in present().
 ///The performance impact is therefore unnoticable. However, it's important that the
 ///write lock is ONLY KEPT ALIVE AS LITTLE AS POSSIBLE. In other words, it should only be
 ///locked when directly reading or writing from MMFData, no other functions should be called
 ///while the lock is held. If more speed is required, use double buffering.
-pub fn start_mmf_thread() {
-    std::thread::spawn(|| {
-        if MMF_DATA.get().is_none() {
-            MMF_DATA
-                .set(Arc::new(RwLock::new(MMFData {
-                    header: None,
-                    file_mapping: None,
-                    index: 0,
-                    addr1: 0,
-                    addr2: 0,
-                    is_blish_alive: false,
-                    resize_event: unsafe{CreateEventW(None, true, false, w!("Global\\BlishHUD_ResizeEvent")).expect("Could not create resize event")}
-                })))
+///Fails if the resize event can't be created, nothing is started then.
+pub fn start_mmf_thread() -> io::Result<()> {
+    if MMF_DATA.get().is_none() {
+        let resize_event = platform().create_event(&object_names().resize_event, true)?;
+        MMF_DATA
+            .set(Arc::new(RwLock::new(MMFData {
+                header: None,
+                negotiated: None,
+                layers: LayerList::default(),
+                dirty: None,
+                hit_test: None,
+                fade: Fade::default(),
+                is_blish_alive: false,
+                resize_event: Some(resize_event),
+            })))
+            .ok();
+    }
+    spawn_worker("mmf", || {
+        let wake_event = platform()
+            .create_event(&object_names().wake_event, false)
+            .ok();
+        //Both events exist, producers can now find us.
+        publish_discovery_record().ok();
+
+        let backend = PlatformBackend { wake_event };
+        let mut link = Link::new(backend, create_probe(), &config().stall);
+        while !is_shutting_down() {
+            link.step();
+        }
+        //Closes the wake event.
+        drop(link);
+        withdraw_discovery_record();
+        release_mmf_data();
+        log::info!("MMF thread stopped.");
+    })
+}
+
+//The link's side effects, on the platform's shared memory and events.
+struct PlatformBackend {
+    wake_event: Option<Arc<dyn Event>>,
+}
+
+impl LinkBackend for PlatformBackend {
+    fn wait(&mut self, timeout: Duration) {
+        if let Some(event) = &self.wake_event {
+            event.wait(timeout);
+        } else {
+            self.wake_event = platform()
+                .create_event(&object_names().wake_event, false)
                 .ok();
+            std::thread::sleep(Duration::from_millis(100));
         }
-        let event_name = w!("Global\\BlishHUD_WakeEvent");
-        let mut wake_event = unsafe {CreateEventW(None, false, false, event_name)};
-
-        loop {
-            if let Ok(event) = wake_event {
-                unsafe {WaitForSingleObject(event, INFINITE)};
-            } else {
-                wake_event = unsafe {CreateEventW(None, false, false, event_name)};
-                std::thread::sleep(Duration::from_millis(100));
-                continue;
-            }
-            //Get data locally so we can drop the lock
-            let mut mmfdata = MMF_DATA.get().unwrap().write().unwrap();
-            let mut blish_alive = mmfdata.is_blish_alive;
-            let mut header = mmfdata.header;
-            let mut mapping = mmfdata.file_mapping;
-            let mut send_dimensions = false;
-            drop(mmfdata);
-
-            //Handle blish opening/closing
-            if !is_blish_alive() {
-                //It just got closed/crashed
-                if blish_alive {
-                    cleanup_shutdown();
-                    continue;
-                }
-                blish_alive = false;
-            } else {
-                blish_alive = true;
-            }
-            if header.is_none() {
-                if let Ok((_header, _mapping)) = open_header_mmf() {
-                    header = Some(_header);
-                    mapping = Some(_mapping);
-                    send_dimensions = true;
-                }
-            }
+    }
 
-            if let Some(ptr) = header {
-                let mut update_textures = false;
-                //Read into local variables, we don't want to lock mmfdata yet
-                //since MMF reads are "slow" compared to assigning to a struct.
-                let ptr = ptr.Value as *mut u8;
-                let data = unsafe { from_raw_parts(ptr, HEADER_SIZE) };
-                let index = u32::from_le_bytes(data[8..12].try_into().unwrap());
-                let addr1 = u64::from_le_bytes(data[12..20].try_into().unwrap());
-                let addr2 = u64::from_le_bytes(data[20..28].try_into().unwrap());
-
-                
-
-                //Lock real quick while copying the data (should be very fast)
-                mmfdata = MMF_DATA.get().unwrap().write().unwrap();
-
-                //Textures changed on the other side
-                if addr1 != mmfdata.addr1 || addr1 != mmfdata.addr1 {
-                    update_textures = true;
-                }
-
-                mmfdata.header = header;
-                mmfdata.file_mapping = mapping;
-                mmfdata.is_blish_alive = blish_alive;
-                mmfdata.index = index;
-                mmfdata.addr1 = addr1;
-                mmfdata.addr2 = addr2;
-                drop(mmfdata);
-
-                if update_textures {
-                    UPDATE_SCHEDULED.store(true, Ordering::Relaxed);
-                }
-
-                if send_dimensions {
-                    //Get the initial dimensions to send to MMF
-                    let mut rect = RECT::default();
-                    unsafe {
-                        GetClientRect(get_mainwindow_hwnd().expect("Could not get main window handle (initial dimensions)"), &mut rect);
-                        let width  = rect.right - rect.left;
-                        let height = rect.bottom - rect.top;
-                        set_mmf_dimensions(width as u32, height as u32);
-                    }
-                }
-            }
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn producer_pid(&self) -> Option<u32> {
+        paired_producer_pid()
+    }
+
+    fn map_header(&mut self) -> bool {
+        let Ok(header) = platform().open_shared_memory(&object_names().header) else {
+            return false;
+        };
+        MMF_DATA.get().unwrap().write().unwrap().header = Some(header);
+        true
+    }
+
+    fn with_header<R>(&mut self, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        //Keep our own reference, we don't want to keep mmfdata locked
+        //since MMF reads are "slow" compared to assigning to a struct.
+        let header = MMF_DATA.get().unwrap().read().unwrap().header.clone()?;
+        Some(f(header.bytes()))
+    }
+
+    fn unmap_header(&mut self) {
+        let mut mmfdata = MMF_DATA.get().unwrap().write().unwrap();
+        //Unmapped once the last reference is gone.
+        mmfdata.header = None;
+        mmfdata.negotiated = None;
+        mmfdata.is_blish_alive = false;
+        drop(mmfdata);
+        KEYBOARD_CAPTURE.store(false, Ordering::Relaxed);
+    }
+
+    fn publish(&mut self, frame: Frame) {
+        //Lock real quick while copying the data (should be very fast)
+        let mut mmfdata = MMF_DATA.get().unwrap().write().unwrap();
+
+        //Textures changed on the other side
+        let update_textures = !frame
+            .layers
+            .iter()
+            .map(|l| (&l.name, &l.slots))
+            .eq(mmfdata.layers.iter().map(|l| (&l.name, &l.slots)));
 
+        mmfdata.negotiated = Some(frame.negotiated);
+        mmfdata.is_blish_alive = true;
+        mmfdata.layers = frame.layers;
+        mmfdata.dirty = frame.dirty;
+        mmfdata.hit_test = frame.hit_test;
+        mmfdata.fade = frame.fade;
+        drop(mmfdata);
+
+        if let Some(capture) = frame.capture {
+            KEYBOARD_CAPTURE.store(capture, Ordering::Relaxed);
         }
-    });
-}
+        if update_textures {
+            UPDATE_SCHEDULED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn send_dimensions(&mut self) {
+        //Get the initial dimensions to send to MMF
+        match platform().window_size() {
+            Some((width, height)) => set_mmf_dimensions(width, height),
+            None => log::error!("Could not get the size of the game's window."),
+        }
+    }
 
+    fn shutdown(&mut self) {
+        cleanup_shutdown();
+    }
+
+    fn restart_producer(&mut self) {
+        //Takes a while, and the link must keep going.
+        if let Err(e) = spawn_worker("restart-producer", restart_blish) {
+            log::error!("Could not restart the producer: {}", e);
+        }
+    }
+}
 
-///Sets the new dimensions in MMF and notifies the source via windows events.
+///Sets the new dimensions in MMF and notifies the source via an event.
 pub fn set_mmf_dimensions(w: u32, h: u32) {
     let mmfdata = MMF_DATA.get().unwrap().write().unwrap();
-    if let Some(header) = mmfdata.header {
-        let data: &mut [u8] = unsafe { from_raw_parts_mut(header.Value as *mut u8, HEADER_SIZE) };
-        data[0..4].copy_from_slice(&w.to_le_bytes());
-        data[4..8].copy_from_slice(&h.to_le_bytes());
+    //Never write into a header we don't understand.
+    if mmfdata.negotiated.is_none() {
+        return;
+    }
+    if let Some(header) = &mmfdata.header {
+        let data = unsafe { header.bytes_mut() };
+        header::write_consumer_version(data);
+        header::write_dimensions(data, w, h);
 
         //Set resize event
-        unsafe {SetEvent(mmfdata.resize_event).expect("Could not Set the resize event");}
+        if let Some(event) = &mmfdata.resize_event {
+            event.set();
+        }
     }
 }
 
+//Probe selected in the config.
+fn create_probe() -> Box<dyn LivenessProbe> {
+    let ipc = &config().ipc;
+    match ipc.liveness {
+        ProbeKind::NamedMutex => Box::new(NamedMutexProbe {
+            name: object_names().alive_mutex.clone(),
+        }),
+        ProbeKind::Heartbeat => Box::new(HeartbeatProbe::new(Duration::from_millis(
+            ipc.heartbeat_timeout_ms,
+        ))),
+        ProbeKind::Process => Box::new(ProcessProbe),
+    }
+}
 
 //Simply pings the mutex in the blish fork, to check if it's still up and hasn't crashed.
-pub fn is_blish_alive() -> bool {
-    let name: Vec<u16> = "Global\\blish_isalive_mutex"
-        .encode_utf16()
-        .chain(Some(0))
-        .collect();
-
-    unsafe {
-        match OpenMutexW(
-            Threading::SYNCHRONIZATION_ACCESS_RIGHTS(0x00100000),
-            false,
-            PCWSTR(name.as_ptr()),
-        ) {
-            Ok(handle) => {
-                CloseHandle(handle).ok();
-                true
-            }
-            Err(e) => {
-                let err = e.code().0 as u32;
-                match err {
-                    2 | 123 => false,
-                    5 => true,
-                    _ => false,
-                }
-            }
-        }
+struct NamedMutexProbe {
+    name: String,
+}
+
+impl LivenessProbe for NamedMutexProbe {
+    fn is_alive(&mut self, _: &ProbeContext) -> bool {
+        platform().mutex_exists(&self.name)
+    }
+    fn name(&self) -> &'static str {
+        ProbeKind::NamedMutex.name()
+    }
+}
+
+//Checks on the process of the producer that paired with us through the discovery table.
+struct ProcessProbe;
+
+impl LivenessProbe for ProcessProbe {
+    fn is_alive(&mut self, context: &ProbeContext) -> bool {
+        context
+            .producer_pid
+            .is_some_and(|pid| platform().is_process_alive(pid))
+    }
+    fn name(&self) -> &'static str {
+        ProbeKind::Process.name()
     }
 }
 
 pub fn cleanup_shutdown() {
     if let Some(mmfdata) = MMF_DATA.get() {
         let mut mmfdata = mmfdata.write().unwrap();
-        if let (Some(view), Some(_)) = (mmfdata.header, mmfdata.file_mapping) {
-            unsafe {
-                std::ptr::write_bytes(view.Value, 0, HEADER_SIZE);
-            }
-        }
-        if let Some(view) = mmfdata.header.take() {
-            unsafe {
-                UnmapViewOfFile(view).ok();
-            }
-        }
-        if let Some(hmap) = mmfdata.file_mapping.take() {
-            unsafe {
-                CloseHandle(hmap).ok();
+        if let Some(header) = mmfdata.header.take() {
+            //Never write into a header we don't understand. The rest of it is the producer's.
+            if mmfdata.negotiated.is_some() {
+                header::clear_consumer_fields(unsafe { header.bytes_mut() });
             }
         }
         //mmfdata.height = 0;
         //mmfdata.width = 0;
+        mmfdata.negotiated = None;
         mmfdata.is_blish_alive = false;
-        mmfdata.index = 0;
-        mmfdata.addr1 = 0;
-        mmfdata.addr2 = 0;
+        mmfdata.layers.clear();
+        mmfdata.dirty = None;
+        mmfdata.hit_test = None;
+        mmfdata.fade = Fade::default();
     }
-    if let Some(state) = OVERLAY_STATE.get() {
+    KEYBOARD_CAPTURE.store(false, Ordering::Relaxed);
+    #[cfg(windows)]
+    if let Some(state) = super::OVERLAY_STATE.get() {
         let mut lock = state.lock().unwrap();
         let state = lock.as_mut();
         if let Some(state) = state {
@@ -222,20 +280,183 @@ pub fn cleanup_shutdown() {
     }
 }
 
-fn open_header_mmf() -> Result<(MEMORY_MAPPED_VIEW_ADDRESS, HANDLE), ()> {
-    unsafe {
-        let wide_name: Vec<u16> = OsStr::new(HEADER_NAME)
-            .encode_wide()
-            .chain(std::iter::once(0))
-            .collect();
-        let file_mapping =
-            OpenFileMappingW(FILE_MAP_ALL_ACCESS.0, BOOL(0), PCWSTR(wide_name.as_ptr())).ok();
-        if let Some(map) = file_mapping {
-            let view = MapViewOfFile(map, FILE_MAP_ALL_ACCESS, 0, 0, HEADER_SIZE);
-            if view.Value != null_mut() {
-                return Ok((view, map));
-            }
+//Unmaps the header and closes the resize event. Unlike cleanup_shutdown, the header is left
+//as it is: the producer sees us leave through the discovery table, not through its header.
+fn release_mmf_data() {
+    if let Some(mmfdata) = MMF_DATA.get() {
+        let mut mmfdata = mmfdata.write().unwrap();
+        mmfdata.header = None;
+        mmfdata.negotiated = None;
+        mmfdata.is_blish_alive = false;
+        mmfdata.layers.clear();
+        mmfdata.dirty = None;
+        mmfdata.hit_test = None;
+        mmfdata.resize_event = None;
+    }
+    KEYBOARD_CAPTURE.store(false, Ordering::Relaxed);
+}
+
+//Creates (or opens) the discovery table and publishes the names of our objects in it.
+fn publish_discovery_record() -> Result<(), ()> {
+    let names = object_names();
+    let table = platform()
+        .create_shared_memory(&names.discovery, DISCOVERY_LEN)
+        .map_err(|e| {
+            log::error!(
+                "Could not create the discovery table {}: {}",
+                names.discovery,
+                e
+            )
+        })?;
+    //The mapping stays open for the whole session, as long as our record is in it.
+    let pid = platform().current_pid();
+    let index = {
+        let table = DiscoveryTable::new(table.words()).unwrap();
+        if !table.init() {
+            log::error!(
+                "The discovery table {} is from an incompatible version.",
+                names.discovery
+            );
+            return Err(());
+        }
+        table.claim(pid, &names.prefix, |pid| platform().is_process_alive(pid))
+    };
+    let Some(index) = index else {
+        log::error!("No free record in the discovery table {}.", names.discovery);
+        return Err(());
+    };
+    log::info!(
+        "Published discovery record {} with prefix {}.",
+        index,
+        names.prefix
+    );
+    *DISCOVERY.lock().unwrap() = Some(Discovery { table, index, pid });
+    Ok(())
+}
+
+//Producer that claimed our discovery record, if any.
+fn paired_producer_pid() -> Option<u32> {
+    let discovery = DISCOVERY.lock().unwrap();
+    let discovery = discovery.as_ref()?;
+    let record = DiscoveryTable::new(discovery.table.words())?.record(discovery.index)?;
+    (record.pid == discovery.pid
+        && record.state == record_state::PAIRED
+        && record.producer_pid != 0)
+        .then_some(record.producer_pid)
+}
+
+///Removes our record from the discovery table, so no producer tries to pair with us anymore.
+///The table is unmapped. Does nothing the second time.
+pub fn withdraw_discovery_record() {
+    let Some(discovery) = DISCOVERY.lock().unwrap().take() else {
+        return;
+    };
+    if let Some(table) = DiscoveryTable::new(discovery.table.words()) {
+        table.release(discovery.index, discovery.pid);
+    }
+    log::info!("Withdrew discovery record {}.", discovery.index);
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::{
+        platform::{Platform, default_mock},
+        ui::{
+            discovery::DISCOVERY_RECORDS,
+            header::{HEADER_MAGIC, MMFHeader, PROTOCOL_VERSION, feature},
+            link::LinkState,
+        },
+    };
+
+    //The whole consumer side, on the mock, with the test playing the producer.
+    #[test]
+    fn pairs_and_streams_on_the_mock_platform() {
+        let mock = default_mock();
+        mock.set_window_size(Some((1280, 720)));
+        let names = object_names();
+        let resize_event = platform().create_event(&names.resize_event, true).unwrap();
+        MMF_DATA.get_or_init(|| {
+            Arc::new(RwLock::new(MMFData {
+                header: None,
+                negotiated: None,
+                layers: LayerList::default(),
+                dirty: None,
+                hit_test: None,
+                fade: Fade::default(),
+                is_blish_alive: false,
+                resize_event: Some(resize_event),
+            }))
+        });
+        publish_discovery_record().unwrap();
+
+        //The producer finds our record and pairs with it.
+        let producer = mock.spawn_process("Blish HUD.exe");
+        let discovery = producer.open_shared_memory(&names.discovery).unwrap();
+        let table = DiscoveryTable::new(discovery.words()).unwrap();
+        let index = (0..DISCOVERY_RECORDS)
+            .find(|i| table.record(*i).unwrap().pid == mock.current_pid())
+            .unwrap();
+        assert_eq!(table.record(index).unwrap().prefix, names.prefix);
+        assert_eq!(paired_producer_pid(), None);
+        assert!(table.pair(index, producer.current_pid()));
+        assert_eq!(paired_producer_pid(), Some(producer.current_pid()));
+
+        let alive = producer.create_mutex(&names.alive_mutex).unwrap();
+        let shared = producer.create_shared_memory(&names.header, 4096).unwrap();
+        let mut written = MMFHeader {
+            magic: HEADER_MAGIC,
+            version: PROTOCOL_VERSION,
+            header_len: 64,
+            features: feature::RESIZE_EVENT,
+            ..Default::default()
+        };
+        written.set_slots(&[0x10, 0x20]).unwrap();
+        written.encode(unsafe { shared.bytes_mut() }).unwrap();
+
+        //Already set, so the link never waits.
+        let wake_event = platform()
+            .create_event("Local\\mmf_test_wake", true)
+            .unwrap();
+        wake_event.set();
+        let backend = PlatformBackend {
+            wake_event: Some(wake_event),
+        };
+        let mut link = Link::new(backend, create_probe(), &config().stall);
+        link.step();
+        assert_eq!(link.state(), LinkState::Streaming);
+        {
+            let mmfdata = MMF_DATA.get().unwrap().read().unwrap();
+            assert!(mmfdata.is_blish_alive);
+            assert_eq!(mmfdata.negotiated.unwrap().version, PROTOCOL_VERSION);
+            assert_eq!(mmfdata.layers.iter().next().unwrap().slots, [0x10, 0x20]);
         }
-        Err(())
+        let read = MMFHeader::decode(shared.bytes()).unwrap();
+        assert_eq!(
+            (read.consumer_version, read.width, read.height),
+            (PROTOCOL_VERSION, 1280, 720)
+        );
+        let resized = producer.open_event(&names.resize_event).unwrap();
+        assert!(resized.wait(Duration::ZERO));
+
+        //The producer quits.
+        drop(alive);
+        producer.exit_process(producer.current_pid());
+        let context = ProbeContext {
+            now: Instant::now(),
+            heartbeat: None,
+            producer_pid: paired_producer_pid(),
+        };
+        assert!(!ProcessProbe.is_alive(&context));
+        link.step();
+        assert_eq!(link.state(), LinkState::Disconnected);
+        assert!(MMF_DATA.get().unwrap().read().unwrap().header.is_none());
+        let read = MMFHeader::decode(shared.bytes()).unwrap();
+        assert_eq!((read.consumer_version, read.width, read.height), (0, 0, 0));
+        assert_eq!(read.slots(), [0x10, 0x20]);
+
+        withdraw_discovery_record();
+        assert_eq!(table.record(index).unwrap().pid, 0);
+        assert_eq!(paired_producer_pid(), None);
     }
 }
@@ -1,23 +1,86 @@
-use std::sync::{Arc, Mutex, OnceLock, RwLock, atomic::AtomicBool};
+#[cfg(windows)]
+use std::sync::Mutex;
+use std::sync::{
+    Arc, OnceLock, RwLock,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
+use discovery::ObjectNames;
 use mmf::MMFData;
+#[cfg(windows)]
 use rendering::{OverlayState, detoured_present};
+#[cfg(windows)]
 use windows::{Win32::Graphics::Dxgi::IDXGISwapChain, core::HRESULT};
 
+use crate::{config::config, platform::platform};
+
 pub static MMF_DATA: OnceLock<Arc<RwLock<MMFData>>> = OnceLock::new();
+#[cfg(windows)]
 pub static OVERLAY_STATE: OnceLock<Mutex<Option<OverlayState>>> = OnceLock::new();
+static OBJECT_NAMES: OnceLock<ObjectNames> = OnceLock::new();
 
+pub mod dirty;
+pub mod discovery;
+pub mod frame_plan;
+pub mod header;
+pub mod hit_test;
+pub mod layers;
+pub mod link;
+pub mod liveness;
 pub mod mmf;
+#[cfg(windows)]
 mod rendering;
+pub mod stall;
 
-//Handle to MMFs. Currently named "HEADER" because the previous version used a body as well
-static HEADER_NAME: &str = "BlishHUD_Header";
-
-//See C# for description
-const HEADER_SIZE: usize = 28;
+///Names of the kernel objects shared with the producer of this game instance.
+pub fn object_names() -> &'static ObjectNames {
+    OBJECT_NAMES.get_or_init(|| {
+        let ipc = &config().ipc;
+        ObjectNames::new(&ipc.namespace, ipc.global, platform().current_pid())
+    })
+}
 
 pub static UPDATE_SCHEDULED: AtomicBool = AtomicBool::new(false);
+//Calls of the present hook so far. Logged with every JSON record.
+pub static FRAME_NUMBER: AtomicU64 = AtomicU64::new(0);
+//Set while a text field of the overlay has focus. Keystrokes then only go to the producer.
+pub static KEYBOARD_CAPTURE: AtomicBool = AtomicBool::new(false);
+//Set while the overlay is faded out because the producer stalled, clicks then go to the game.
+pub static OVERLAY_HIDDEN: AtomicBool = AtomicBool::new(false);
+
+///Leaves keyboard capture until the producer asks for it again.
+///Returns false if it wasn't active.
+pub fn release_keyboard_capture() -> bool {
+    let was_active = KEYBOARD_CAPTURE.swap(false, Ordering::Relaxed);
+    if was_active {
+        log::info!("Keyboard capture released.");
+    }
+    was_active
+}
+
+///True if the pixel belongs to an interactive part of the overlay, according to the producer.
+pub fn is_overlay_pixel(x: i32, y: i32) -> bool {
+    let Some(mmfdata) = MMF_DATA.get() else {
+        return false;
+    };
+    let mmfdata = mmfdata.read().unwrap();
+    mmfdata.is_blish_alive
+        && !OVERLAY_HIDDEN.load(Ordering::Relaxed)
+        && mmfdata.hit_test.as_ref().is_some_and(|h| h.hit(x, y))
+}
+
+///Releases every D3D object of the overlay. Only once present can't be called anymore.
+#[cfg(windows)]
+pub fn release_overlay_state() {
+    let Some(state) = OVERLAY_STATE.get() else {
+        return;
+    };
+    if let Some(state) = state.lock().unwrap().take() {
+        state.release();
+    }
+}
 
+#[cfg(windows)]
 pub fn get_detoured_present() -> impl Fn(IDXGISwapChain, u32, u32) -> HRESULT {
     detoured_present
 }
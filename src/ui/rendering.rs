@@ -1,8 +1,7 @@
 use std::{
-    sync::{
-        Mutex,
-        atomic::{AtomicBool, Ordering},
-    },
+    collections::HashMap,
+    ffi::c_void,
+    sync::{Mutex, atomic::Ordering},
     time::Instant,
 };
 
@@ -12,27 +11,44 @@ use windows::{
         Graphics::{
             Direct3D::{D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST, D3D11_SRV_DIMENSION_TEXTURE2D},
             Direct3D11::{
-                D3D11_BLEND_DESC, D3D11_BLEND_INV_SRC_ALPHA, D3D11_BLEND_ONE, D3D11_BLEND_OP_ADD,
-                D3D11_BLEND_SRC_ALPHA, D3D11_BLEND_ZERO, D3D11_COLOR_WRITE_ENABLE_ALL,
-                D3D11_COMPARISON_NEVER, D3D11_FILTER_MIN_MAG_MIP_LINEAR, D3D11_FLOAT32_MAX,
-                D3D11_SAMPLER_DESC, D3D11_SHADER_RESOURCE_VIEW_DESC, D3D11_TEXTURE_ADDRESS_CLAMP,
-                D3D11_VIEWPORT, ID3D11BlendState, ID3D11Device, ID3D11DeviceContext,
-                ID3D11PixelShader, ID3D11RenderTargetView, ID3D11SamplerState,
+                D3D11_BIND_CONSTANT_BUFFER, D3D11_BIND_SHADER_RESOURCE, D3D11_BLEND_DESC,
+                D3D11_BLEND_INV_SRC_ALPHA, D3D11_BLEND_ONE, D3D11_BLEND_OP_ADD,
+                D3D11_BLEND_SRC_ALPHA, D3D11_BLEND_ZERO, D3D11_BOX, D3D11_BUFFER_DESC,
+                D3D11_COLOR_WRITE_ENABLE_ALL, D3D11_COMPARISON_NEVER,
+                D3D11_FILTER_MIN_MAG_MIP_LINEAR, D3D11_FLOAT32_MAX, D3D11_SAMPLER_DESC,
+                D3D11_SHADER_RESOURCE_VIEW_DESC, D3D11_TEXTURE_ADDRESS_CLAMP, D3D11_TEXTURE2D_DESC,
+                D3D11_USAGE_DEFAULT, D3D11_VIEWPORT, ID3D11BlendState, ID3D11Buffer, ID3D11Device,
+                ID3D11DeviceContext, ID3D11PixelShader, ID3D11RenderTargetView, ID3D11SamplerState,
                 ID3D11ShaderResourceView, ID3D11Texture2D, ID3D11VertexShader,
             },
-            Dxgi::{Common::DXGI_FORMAT_R8G8B8A8_UNORM, DXGI_SWAP_CHAIN_DESC, IDXGISwapChain},
+            Dxgi::{
+                Common::{DXGI_FORMAT_R8G8B8A8_UNORM, DXGI_SAMPLE_DESC},
+                DXGI_SWAP_CHAIN_DESC, IDXGISwapChain,
+            },
         },
     },
     core::{Error, HRESULT},
 };
 
 use crate::{
+    attach::AttachError,
     debug::{
         DEBUG_FEATURES,
+        debug_overlay::{OVERLAY_HEIGHT, OVERLAY_WIDTH, panel_facts, with_new_panel},
         statistics::{self, send_statistic},
     },
     hooks::present_hook,
-    ui::{MMF_DATA, UPDATE_SCHEDULED, mmf::cleanup_shutdown},
+    shutdown::{PRESENT_CALLS, is_shutting_down},
+    ui::{
+        FRAME_NUMBER, MMF_DATA, OVERLAY_HIDDEN, UPDATE_SCHEDULED,
+        dirty::{CopyPlan, SlotDirty, plan_copy},
+        frame_plan::{
+            DrawPlan, FrameAction, FramePlanner, FrameSnapshot, OpenFailure, StateFacts,
+            SwapchainFacts,
+        },
+        layers::{MAIN_LAYER, Placement},
+        mmf::cleanup_shutdown,
+    },
 };
 
 use super::OVERLAY_STATE;
@@ -40,7 +56,9 @@ use super::OVERLAY_STATE;
 //Ultra basic shader.
 //Have to be compiled on windows with fxc.
 static VS_OVERLAY: &[u8] = include_bytes!("vs.cso");
-static PS_OVERLAY: &[u8] = include_bytes!("ps.cso");
+//Compiled from layer_ps.hlsl, with fxc /T ps_5_0 /E main. D3DCompile isn't used at runtime,
+//d3dcompiler_47.dll is often missing or broken under wine.
+static PS_LAYER: &[u8] = include_bytes!("layer_ps.cso");
 
 //Contains DirectX related stuff that can be reused over many frames.
 pub struct OverlayState {
@@ -48,59 +66,93 @@ pub struct OverlayState {
     pub height: u32,
     device: ID3D11Device,
     context: ID3D11DeviceContext,
-    overlay_textures: [Option<ID3D11Texture2D>; 2],
-    shader_resource_views: [Option<ID3D11ShaderResourceView>; 2],
+    //Opened shared textures of every layer, by layer name.
+    layer_textures: HashMap<String, LayerTextures>,
     render_target_view: Option<ID3D11RenderTargetView>,
     blend_state: ID3D11BlendState,
     sampler_state: ID3D11SamplerState,
     vertex_shader: ID3D11VertexShader,
     pixel_shader: ID3D11PixelShader,
-    viewport: D3D11_VIEWPORT,
+    constant_buffer: ID3D11Buffer,
     blend_factor: [f32; 4],
+    //Texture of the debug panel, None if it couldn't be created.
+    panel: Option<PanelTexture>,
+}
+
+//The debug panel, uploaded from the image drawn by debug::debug_overlay.
+struct PanelTexture {
+    texture: ID3D11Texture2D,
+    shader_resource_view: ID3D11ShaderResourceView,
+    //Revision of the image it holds, 0 before the first upload.
+    revision: u64,
+}
+
+//Shared textures of a single layer, one per slot announced by the producer.
+struct LayerTextures {
+    //Handles these were opened from, to know when the producer changed them.
+    slots: Vec<u64>,
+    overlay_textures: Vec<Option<ID3D11Texture2D>>,
+    shader_resource_views: Vec<Option<ID3D11ShaderResourceView>>,
+    //Only used when the producer sends dirty rectangles.
+    composition: Option<Composition>,
+}
+
+impl LayerTextures {
+    //View to sample from. The composition texture if it is up to date, the slot otherwise.
+    fn view(&self, index: usize, dirty: Option<&SlotDirty>) -> Option<ID3D11ShaderResourceView> {
+        if let (Some(dirty), Some(composition)) = (dirty, self.composition.as_ref())
+            && composition.frame == Some(dirty.frame)
+        {
+            return Some(composition.shader_resource_view.clone());
+        }
+        self.shader_resource_views.get(index)?.clone()
+    }
+}
+
+//Persistent copy of a layer's content. Only the dirty regions of the slots get copied into it.
+struct Composition {
+    texture: ID3D11Texture2D,
+    shader_resource_view: ID3D11ShaderResourceView,
+    width: u32,
+    height: u32,
+    //Producer frame currently held by the texture, None until the first full copy.
+    frame: Option<u64>,
+}
+
+//Must match the cbuffer in layer_ps.hlsl. Constant buffers are multiples of 16 bytes.
+#[repr(C)]
+struct LayerParams {
+    uv_rect: [f32; 4],
+    opacity: f32,
+    padding: [f32; 3],
 }
 
 impl OverlayState {
     pub fn resize(&mut self, swapchain: &IDXGISwapChain) {
-        let mut desc = DXGI_SWAP_CHAIN_DESC::default();
-        unsafe {
-            swapchain.GetDesc(&mut desc).ok();
-        }
-        self.viewport = D3D11_VIEWPORT {
-            TopLeftX: 0.0,
-            TopLeftY: 0.0,
-            Width: desc.BufferDesc.Width as f32,
-            Height: desc.BufferDesc.Height as f32,
-            MinDepth: 0.0,
-            MaxDepth: 1.0,
-        };
-        self.width = desc.BufferDesc.Width;
-        self.height = desc.BufferDesc.Height;
+        (self.width, self.height) = back_buffer_size(swapchain);
 
         self.render_target_view = create_render_target_view(swapchain, &self.device);
     }
     pub fn shutdown(&mut self) {
-        self.overlay_textures = [None, None];
-        self.shader_resource_views = [None, None];
+        self.layer_textures.clear();
         self.render_target_view.take();
 
         self.width = 0;
         self.height = 0;
 
-        self.viewport = D3D11_VIEWPORT {
-            TopLeftX: 0.0,
-            TopLeftY: 0.0,
-            Width: 0.0,
-            Height: 0.0,
-            MinDepth: 0.0,
-            MaxDepth: 1.0,
-        };
-
         self.blend_factor = [0.0; 4];
     }
+    //Unbinds our view from the game's context, the COM objects are released when dropped.
+    pub fn release(self) {
+        unsafe { self.context.PSSetShaderResources(0, Some(&[None])) };
+    }
 }
 
 ///This is our big present hook. Draws shared textures as an overlay.
 pub fn detoured_present(swapchain: IDXGISwapChain, sync_interval: u32, flags: u32) -> HRESULT {
+    //Includes the original present, we return into this function.
+    let _call = PRESENT_CALLS.enter();
+    FRAME_NUMBER.fetch_add(1, Ordering::Relaxed);
     let start = Instant::now();
     //Macro to make it less ugly to return early.
     macro_rules! return_present {
@@ -108,86 +160,149 @@ pub fn detoured_present(swapchain: IDXGISwapChain, sync_interval: u32, flags: u3
             return present_hook.call(swapchain, sync_interval, flags)
         };
     }
-    if !DEBUG_FEATURES.rendering_enabled.load(Ordering::Relaxed) {
-        unsafe { return_present!() }
+    //Until the teardown removes the hook.
+    if is_shutting_down() {
+        unsafe { return_present!() };
     }
     unsafe {
-        if OVERLAY_STATE.get().is_none() {
-            initialize_overlay_state(&swapchain);
-        }
+        let mut lock = OVERLAY_STATE
+            .get_or_init(|| Mutex::new(None))
+            .lock()
+            .unwrap();
+        let mmfdata = MMF_DATA.get().unwrap().read().unwrap();
+
+        let textures = lock.as_ref().map(|state| {
+            state
+                .layer_textures
+                .iter()
+                .map(|(name, textures)| (name.as_str(), textures.slots.as_slice()))
+                .collect()
+        });
+        let facts = SwapchainFacts {
+            rendering_enabled: DEBUG_FEATURES.rendering_enabled.load(Ordering::Relaxed),
+            state: lock
+                .as_ref()
+                .zip(textures)
+                .map(|(state, textures)| StateFacts {
+                    width: state.width,
+                    height: state.height,
+                    device_removed: state.device.GetDeviceRemovedReason().is_err(),
+                    textures,
+                }),
+            back_buffer: back_buffer_size(&swapchain),
+            panel: panel_facts(),
+        };
+        let snapshot = FrameSnapshot {
+            is_blish_alive: mmfdata.is_blish_alive,
+            layers: &mmfdata.layers,
+            dirty: mmfdata.dirty.as_ref(),
+            fade: mmfdata.fade.opacity(Instant::now()),
+            update_scheduled: UPDATE_SCHEDULED.load(Ordering::Relaxed),
+        };
+        let plan = FramePlanner.plan(&facts, &snapshot);
 
         //Check if we need to cache stuff over again
-        let mut lock = OVERLAY_STATE.get().unwrap().lock().unwrap();
-        let recreate = if let Some(state) = lock.as_ref() {
-            if state.width == 0 || state.height == 0 {
-                true
-            } else {
-                state.device.GetDeviceRemovedReason().is_err()
+        if plan.recreate_state {
+            match create_overlay_state(&swapchain) {
+                Ok(state) => *lock = Some(state),
+                //Trying again every frame won't help.
+                Err(e) => {
+                    log::error!("Nothing can be drawn, unloading: {}", e);
+                    drop(mmfdata);
+                    drop(lock);
+                    crate::unload();
+                    return_present!();
+                }
             }
-        } else {
-            true
-        };
-        if recreate {
-            drop(lock);
-            initialize_overlay_state(&swapchain);
-            lock = OVERLAY_STATE.get().unwrap().lock().unwrap();
         }
-
-        let mut state = lock.as_mut().unwrap();
-
-        let mmfdata = MMF_DATA.get().unwrap().read().unwrap();
-
-        //Which texture we should draw
-        let texture_idx = mmfdata.index as usize;
-
-        //Bad data, don't render that frame.
-        if !mmfdata.is_blish_alive || mmfdata.addr1 == 0 || mmfdata.addr2 == 0 {
-            return_present!();
+        if let Some(hidden) = plan.hidden {
+            OVERLAY_HIDDEN.store(hidden, Ordering::Relaxed);
         }
+        let panel = plan.panel;
+        let plan = match plan.action {
+            FrameAction::Draw(plan) => plan,
+            //Only the debug panel.
+            FrameAction::Skip(_) if panel.is_some() => DrawPlan::default(),
+            FrameAction::Skip(_) => return_present!(),
+        };
+        let state = lock.as_mut().unwrap();
 
         //Resize occured
-        if UPDATE_SCHEDULED.load(Ordering::Relaxed) {
+        if plan.resize {
             UPDATE_SCHEDULED.store(false, Ordering::Relaxed);
             state.resize(&swapchain);
-            if update_textures(&mut state, [mmfdata.addr1, mmfdata.addr2]).is_err() {
-                state.context.PSSetShaderResources(0, Some(&[None]));
-                drop(mmfdata);
-                drop(lock);
-                cleanup_shutdown();
-                return_present!();
+        }
+        for name in &plan.forget {
+            state.layer_textures.remove(name);
+        }
+
+        //Open the textures of new layers, or layers whose textures changed.
+        for name in &plan.open {
+            let layer = mmfdata.layers.get(name).unwrap();
+            match open_layer_textures(&state.device, &layer.slots) {
+                Ok(textures) => {
+                    state.layer_textures.insert(layer.name.clone(), textures);
+                }
+                Err(()) => match FramePlanner.open_failed(name) {
+                    OpenFailure::Shutdown => {
+                        state.context.PSSetShaderResources(0, Some(&[None]));
+                        drop(mmfdata);
+                        drop(lock);
+                        cleanup_shutdown();
+                        return_present!();
+                    }
+                    OpenFailure::Remember => {
+                        log::error!("Could not open the textures of layer {}", layer.name);
+                        state.layer_textures.insert(
+                            layer.name.clone(),
+                            LayerTextures {
+                                slots: layer.slots.clone(),
+                                overlay_textures: Vec::new(),
+                                shader_resource_views: Vec::new(),
+                                composition: None,
+                            },
+                        );
+                    }
+                },
             }
         }
 
-        //Make sure SRV is valid
-        if state.shader_resource_views[texture_idx].is_none() {
-            return_present!();
+        //Copy what changed into the composition texture of the main layer.
+        if let (Some(index), Some(dirty)) = (plan.compose, mmfdata.dirty.as_ref())
+            && let Some(textures) = state.layer_textures.get_mut(MAIN_LAYER)
+        {
+            update_composition(&state.device, &state.context, textures, index, dirty);
         }
 
         let ctx = &state.context;
 
-        ctx.RSSetViewports(Some(&[state.viewport]));
         ctx.OMSetBlendState(&state.blend_state, Some(&state.blend_factor), 0xffffffff);
-        ctx.OMSetRenderTargets(Some(&[state.render_target_view.clone()]), None);
+        ctx.OMSetRenderTargets(Some(std::slice::from_ref(&state.render_target_view)), None);
 
         //Shaders
         ctx.VSSetShader(&state.vertex_shader, None);
         ctx.PSSetShader(&state.pixel_shader, None);
-
-        // Bind SRV and sampler
-        ctx.PSSetShaderResources(
-            0,
-            Some(&[Some(
-                state.shader_resource_views[texture_idx]
-                    .as_ref()
-                    .unwrap()
-                    .clone(),
-            )]),
-        );
+        ctx.PSSetConstantBuffers(0, Some(&[Some(state.constant_buffer.clone())]));
         ctx.PSSetSamplers(0, Some(&[Some(state.sampler_state.clone())]));
-
-        // Draw full-screen triangle
         ctx.IASetPrimitiveTopology(D3D11_PRIMITIVE_TOPOLOGY_TRIANGLELIST);
-        ctx.Draw(3, 0);
+
+        //Back to front
+        for draw in &plan.draws {
+            //Make sure SRV is valid. The index comes from the producer so it can't be trusted.
+            let dirty = mmfdata.dirty.as_ref().filter(|_| draw.dirty);
+            let Some(srv) = state
+                .layer_textures
+                .get(&draw.name)
+                .and_then(|t| t.view(draw.index, dirty))
+            else {
+                continue;
+            };
+            draw_layer(state, srv, &draw.placement, draw.opacity);
+        }
+        //On top of everything
+        if let Some(placement) = &panel {
+            draw_panel(state, placement);
+        }
 
         //Stats
         let frame_time_custom = start.elapsed().as_nanos() as u32;
@@ -202,97 +317,287 @@ pub fn detoured_present(swapchain: IDXGISwapChain, sync_interval: u32, flags: u3
             statistics::debug_stat::FRAME_TIME_DIFF,
             frame_time_total - frame_time_custom,
         );
-        return result;
+        result
     }
 }
 
-//Updates the texture from the shared resource.
-fn update_textures(state: &mut OverlayState, texture_ptrs: [u64; 2]) -> Result<(), ()> {
-    state.overlay_textures = [None, None];
-    state.shader_resource_views = [None, None];
+//Opens the shared textures of a layer, one per slot.
+fn open_layer_textures(device: &ID3D11Device, texture_ptrs: &[u64]) -> Result<LayerTextures, ()> {
+    let mut textures = LayerTextures {
+        slots: texture_ptrs.to_vec(),
+        overlay_textures: vec![None; texture_ptrs.len()],
+        shader_resource_views: vec![None; texture_ptrs.len()],
+        composition: None,
+    };
 
-    for i in 0..2 {
+    for (i, ptr) in texture_ptrs.iter().enumerate() {
         unsafe {
-            if let Err(e) = state.device.OpenSharedResource(
-                HANDLE(texture_ptrs[i] as isize),
-                &mut state.overlay_textures[i] as *mut _,
+            if let Err(e) = device.OpenSharedResource(
+                HANDLE(*ptr as isize),
+                &mut textures.overlay_textures[i] as *mut _,
             ) {
-                log::error!("{}", e.to_string());
+                log::error!("{}", e);
                 return Err(());
             }
         };
-        let tex = state.overlay_textures[i].as_ref().unwrap();
-        let mut srv: Option<ID3D11ShaderResourceView> = None;
-
-        let desc = D3D11_SHADER_RESOURCE_VIEW_DESC {
-            Format: DXGI_FORMAT_R8G8B8A8_UNORM,
-            ViewDimension: D3D11_SRV_DIMENSION_TEXTURE2D,
-            Anonymous: windows::Win32::Graphics::Direct3D11::D3D11_SHADER_RESOURCE_VIEW_DESC_0 {
-                Texture2D: windows::Win32::Graphics::Direct3D11::D3D11_TEX2D_SRV {
-                    MostDetailedMip: 0,
-                    MipLevels: 1,
-                },
+        let tex = textures.overlay_textures[i].as_ref().unwrap();
+        textures.shader_resource_views[i] = Some(create_shader_resource_view(device, tex)?);
+    }
+    Ok(textures)
+}
+
+fn create_shader_resource_view(
+    device: &ID3D11Device,
+    texture: &ID3D11Texture2D,
+) -> Result<ID3D11ShaderResourceView, ()> {
+    let mut srv: Option<ID3D11ShaderResourceView> = None;
+
+    let desc = D3D11_SHADER_RESOURCE_VIEW_DESC {
+        Format: DXGI_FORMAT_R8G8B8A8_UNORM,
+        ViewDimension: D3D11_SRV_DIMENSION_TEXTURE2D,
+        Anonymous: windows::Win32::Graphics::Direct3D11::D3D11_SHADER_RESOURCE_VIEW_DESC_0 {
+            Texture2D: windows::Win32::Graphics::Direct3D11::D3D11_TEX2D_SRV {
+                MostDetailedMip: 0,
+                MipLevels: 1,
             },
-        };
+        },
+    };
 
-        unsafe {
-            if let Err(e) = state
-                .device
-                .CreateShaderResourceView(tex, Some(&desc), Some(&mut srv))
-            {
-                log::error!("{}", e.to_string());
-                return Err(());
+    unsafe {
+        if let Err(e) = device.CreateShaderResourceView(texture, Some(&desc), Some(&mut srv)) {
+            log::error!("{}", e);
+            return Err(());
+        }
+    }
+    srv.ok_or(())
+}
+
+//Creates a texture matching the given slot, which dirty regions can then be copied into.
+fn create_composition(device: &ID3D11Device, slot: &ID3D11Texture2D) -> Result<Composition, ()> {
+    let mut desc = D3D11_TEXTURE2D_DESC::default();
+    unsafe { slot.GetDesc(&mut desc) };
+    //Same size, format and mips as the slot, CopyResource requires it.
+    desc.Usage = D3D11_USAGE_DEFAULT;
+    desc.BindFlags = D3D11_BIND_SHADER_RESOURCE.0 as u32;
+    desc.CPUAccessFlags = 0;
+    desc.MiscFlags = 0;
+
+    let mut texture: Option<ID3D11Texture2D> = None;
+    unsafe {
+        if let Err(e) = device.CreateTexture2D(&desc, None, Some(&mut texture)) {
+            log::error!("Could not create the composition texture: {}", e);
+            return Err(());
+        }
+    }
+    let texture = texture.ok_or(())?;
+    let shader_resource_view = create_shader_resource_view(device, &texture)?;
+    Ok(Composition {
+        texture,
+        shader_resource_view,
+        width: desc.Width,
+        height: desc.Height,
+        frame: None,
+    })
+}
+
+//Brings the composition texture up to date with the slot the producer wants drawn,
+//copying only the regions that changed when possible.
+fn update_composition(
+    device: &ID3D11Device,
+    ctx: &ID3D11DeviceContext,
+    textures: &mut LayerTextures,
+    index: usize,
+    dirty: &SlotDirty,
+) {
+    let Some(Some(slot)) = textures.overlay_textures.get(index) else {
+        return;
+    };
+    if textures.composition.is_none() {
+        textures.composition = create_composition(device, slot).ok();
+    }
+    let Some(composition) = textures.composition.as_mut() else {
+        return;
+    };
+
+    match plan_copy(
+        composition.frame,
+        dirty,
+        composition.width,
+        composition.height,
+    ) {
+        CopyPlan::Nothing => {}
+        CopyPlan::Full => unsafe { ctx.CopyResource(&composition.texture, slot) },
+        CopyPlan::Regions(rects) => {
+            for rect in rects {
+                let region = D3D11_BOX {
+                    left: rect.x as u32,
+                    top: rect.y as u32,
+                    front: 0,
+                    right: rect.right() as u32,
+                    bottom: rect.bottom() as u32,
+                    back: 1,
+                };
+                unsafe {
+                    ctx.CopySubresourceRegion(
+                        &composition.texture,
+                        0,
+                        rect.x as u32,
+                        rect.y as u32,
+                        0,
+                        slot,
+                        0,
+                        Some(&region),
+                    )
+                };
             }
         }
-        state.shader_resource_views[i] = srv;
     }
-    Ok(())
+    composition.frame = Some(dirty.frame);
+}
+
+//Draws one layer. Everything that is shared between layers must already be bound.
+fn draw_layer(
+    state: &OverlayState,
+    srv: ID3D11ShaderResourceView,
+    placement: &Placement,
+    opacity: f32,
+) {
+    let ctx = &state.context;
+    let viewport = D3D11_VIEWPORT {
+        TopLeftX: placement.visible.x as f32,
+        TopLeftY: placement.visible.y as f32,
+        Width: placement.visible.width as f32,
+        Height: placement.visible.height as f32,
+        MinDepth: 0.0,
+        MaxDepth: 1.0,
+    };
+    let params = LayerParams {
+        uv_rect: [
+            placement.uv.u,
+            placement.uv.v,
+            placement.uv.width,
+            placement.uv.height,
+        ],
+        opacity,
+        padding: [0.0; 3],
+    };
+    unsafe {
+        ctx.UpdateSubresource(
+            &state.constant_buffer,
+            0,
+            None,
+            &params as *const LayerParams as *const c_void,
+            0,
+            0,
+        );
+        ctx.RSSetViewports(Some(&[viewport]));
+        ctx.PSSetShaderResources(0, Some(&[Some(srv)]));
+
+        // Draw full-screen triangle, clipped to the viewport
+        ctx.Draw(3, 0);
+    }
+}
+
+//Uploads the debug panel if it changed since the last upload, then draws it like a layer.
+fn draw_panel(state: &mut OverlayState, placement: &Placement) {
+    let Some(panel) = state.panel.as_mut() else {
+        return;
+    };
+    let ctx = &state.context;
+    with_new_panel(panel.revision, |pixels, revision| {
+        unsafe {
+            ctx.UpdateSubresource(
+                &panel.texture,
+                0,
+                None,
+                pixels.as_ptr() as *const c_void,
+                (OVERLAY_WIDTH * 4) as u32,
+                0,
+            )
+        };
+        panel.revision = revision;
+    });
+    let srv = panel.shader_resource_view.clone();
+    draw_layer(state, srv, placement, 1.0);
 }
 
 fn get_device_and_context(
     swapchain: &IDXGISwapChain,
 ) -> Result<(ID3D11Device, ID3D11DeviceContext), ()> {
     unsafe {
-        if let Ok(device) = swapchain.GetDevice::<ID3D11Device>() {
-            if let Ok(ctx) = (device).GetImmediateContext() {
-                return Ok((device, ctx));
-            }
+        if let Ok(device) = swapchain.GetDevice::<ID3D11Device>()
+            && let Ok(ctx) = device.GetImmediateContext()
+        {
+            return Ok((device, ctx));
         }
     }
     Err(())
 }
 
-fn initialize_overlay_state(swapchain: &IDXGISwapChain) {
-    let (device, context) =
-        get_device_and_context(swapchain).expect("Could not get device and context from swapchain");
-    let state = OverlayState {
-        width: 0,
-        height: 0,
+//Size of the back buffer, what the overlay is drawn on.
+fn back_buffer_size(swapchain: &IDXGISwapChain) -> (u32, u32) {
+    let mut desc = DXGI_SWAP_CHAIN_DESC::default();
+    unsafe {
+        swapchain.GetDesc(&mut desc).ok();
+    }
+    (desc.BufferDesc.Width, desc.BufferDesc.Height)
+}
+
+fn create_overlay_state(swapchain: &IDXGISwapChain) -> Result<OverlayState, AttachError> {
+    let (device, context) = get_device_and_context(swapchain).map_err(|()| {
+        AttachError::Rendering("could not get device and context from swapchain".to_string())
+    })?;
+    let rendering = |e: Error| AttachError::Rendering(e.to_string());
+    let (width, height) = back_buffer_size(swapchain);
+    Ok(OverlayState {
+        width,
+        height,
         device: device.clone(),
         context: context.clone(),
-        blend_state: create_blend_state(&device).unwrap(),
-        sampler_state: create_sampler_state(&device).unwrap(),
-        vertex_shader: create_vertex_shader(&device).unwrap(),
-        pixel_shader: create_pixel_shader(&device).unwrap(),
-        overlay_textures: [None, None],
-        shader_resource_views: [None, None],
-        viewport: D3D11_VIEWPORT {
-            TopLeftX: 0.0,
-            TopLeftY: 0.0,
-            Width: 0 as f32,
-            Height: 0 as f32,
-            MinDepth: 0.0,
-            MaxDepth: 1.0,
-        },
+        blend_state: create_blend_state(&device).map_err(rendering)?,
+        sampler_state: create_sampler_state(&device).map_err(rendering)?,
+        vertex_shader: create_vertex_shader(&device).map_err(rendering)?,
+        pixel_shader: create_pixel_shader(&device).map_err(rendering)?,
+        constant_buffer: create_constant_buffer(&device).map_err(rendering)?,
+        layer_textures: HashMap::new(),
         render_target_view: create_render_target_view(swapchain, &device),
         blend_factor: [0.0f32, 0.0f32, 0.0f32, 0.0f32],
+        //Only for debugging, drawing the overlay doesn't depend on it.
+        panel: create_panel_texture(&device).ok(),
+    })
+}
+
+//Creates the texture the debug panel is uploaded to.
+fn create_panel_texture(device: &ID3D11Device) -> Result<PanelTexture, ()> {
+    let desc = D3D11_TEXTURE2D_DESC {
+        Width: OVERLAY_WIDTH as u32,
+        Height: OVERLAY_HEIGHT as u32,
+        MipLevels: 1,
+        ArraySize: 1,
+        Format: DXGI_FORMAT_R8G8B8A8_UNORM,
+        SampleDesc: DXGI_SAMPLE_DESC {
+            Count: 1,
+            Quality: 0,
+        },
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_SHADER_RESOURCE.0 as u32,
+        CPUAccessFlags: 0,
+        MiscFlags: 0,
     };
-    let overlay_state = OVERLAY_STATE.get_or_init(|| Mutex::new(None));
-    if let Ok(mut lock) = overlay_state.lock() {
-        *lock = Some(state);
-    } else {
-        log::error!("Poisoned OVERLAY_STATE mutex.");
+    let mut texture: Option<ID3D11Texture2D> = None;
+    unsafe {
+        if let Err(e) = device.CreateTexture2D(&desc, None, Some(&mut texture)) {
+            log::error!("Could not create the debug panel texture: {}", e);
+            return Err(());
+        }
     }
+    let texture = texture.ok_or(())?;
+    let shader_resource_view = create_shader_resource_view(device, &texture)?;
+    Ok(PanelTexture {
+        texture,
+        shader_resource_view,
+        revision: 0,
+    })
 }
 
 pub fn create_render_target_view(
@@ -323,15 +628,32 @@ pub fn create_vertex_shader(device: &ID3D11Device) -> Result<ID3D11VertexShader,
     Ok(vs.unwrap())
 }
 
-///Creates the pixel shader to be used to display the overlay. Will be reused forever.
+///Creates the pixel shader used for every layer. Will be reused forever.
 pub fn create_pixel_shader(device: &ID3D11Device) -> Result<ID3D11PixelShader, Error> {
     let mut ps: Option<ID3D11PixelShader> = None;
     unsafe {
-        device.CreatePixelShader(PS_OVERLAY, None, Some(&mut ps))?;
+        device.CreatePixelShader(PS_LAYER, None, Some(&mut ps))?;
     }
     Ok(ps.unwrap())
 }
 
+///Creates the constant buffer holding the LayerParams. Updated before drawing each layer.
+pub fn create_constant_buffer(device: &ID3D11Device) -> Result<ID3D11Buffer, Error> {
+    let desc = D3D11_BUFFER_DESC {
+        ByteWidth: size_of::<LayerParams>() as u32,
+        Usage: D3D11_USAGE_DEFAULT,
+        BindFlags: D3D11_BIND_CONSTANT_BUFFER.0 as u32,
+        CPUAccessFlags: 0,
+        MiscFlags: 0,
+        StructureByteStride: 0,
+    };
+    let mut buffer: Option<ID3D11Buffer> = None;
+    unsafe {
+        device.CreateBuffer(&desc, None, Some(&mut buffer))?;
+    }
+    Ok(buffer.unwrap())
+}
+
 ///Creates the SamplerState to be used to display the overlay. Will be reused forever.
 pub fn create_sampler_state(device: &ID3D11Device) -> Result<ID3D11SamplerState, Error> {
     let sampler_desc = D3D11_SAMPLER_DESC {
@@ -0,0 +1,250 @@
+use std::time::{Duration, Instant};
+
+/*
+ *
+ * Detection of a producer that is still running but stopped publishing. Plain Rust, no windows.
+ *
+ * The producer increments the heartbeat counter (header::section::HEARTBEAT) on every tick.
+ * When it doesn't change for the stall timeout, the producer is considered frozen and the
+ * overlay fades out, instead of showing its last frame forever. Any change of the counter,
+ * including going backwards (the producer restarted), brings it back.
+ *
+ * Producers that don't send a heartbeat are never considered stalled.
+ *
+ * */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallEvent {
+    //The counter didn't change for this long.
+    Stalled { since: Duration },
+    //The counter changed again after being stalled for this long.
+    Recovered { after: Duration },
+}
+
+#[derive(Debug)]
+pub struct StallDetector {
+    //None disables the detection.
+    timeout: Option<Duration>,
+    fade: Duration,
+    //Last counter seen, and when it changed to that value.
+    last: Option<(u64, Instant)>,
+    stalled: bool,
+}
+
+impl StallDetector {
+    ///A zero timeout disables the detection. The overlay disappears fade after the timeout.
+    pub fn new(timeout: Duration, fade: Duration) -> StallDetector {
+        StallDetector {
+            timeout: (!timeout.is_zero()).then_some(timeout),
+            fade,
+            last: None,
+            stalled: false,
+        }
+    }
+
+    ///Feeds the last counter read from the header, None if there is none.
+    ///Returns an event when the producer stalls or recovers.
+    pub fn update(&mut self, counter: Option<u64>, now: Instant) -> Option<StallEvent> {
+        let timeout = self.timeout?;
+        //Nothing to judge without a heartbeat, disconnections are handled elsewhere.
+        let Some(counter) = counter else {
+            self.last = None;
+            self.stalled = false;
+            return None;
+        };
+        match self.last {
+            Some((last, since)) if last == counter => {
+                let elapsed = now.saturating_duration_since(since);
+                if !self.stalled && elapsed >= timeout {
+                    self.stalled = true;
+                    return Some(StallEvent::Stalled { since: elapsed });
+                }
+                None
+            }
+            previous => {
+                self.last = Some((counter, now));
+                if !self.stalled {
+                    return None;
+                }
+                self.stalled = false;
+                let after = previous.map_or(Duration::ZERO, |(_, since)| {
+                    now.saturating_duration_since(since)
+                });
+                Some(StallEvent::Recovered { after })
+            }
+        }
+    }
+
+    ///Fade of the overlay, to be evaluated by the renderer every frame.
+    pub fn fade(&self) -> Fade {
+        let start = match (self.timeout, self.last, self.stalled) {
+            (Some(timeout), Some((_, since)), true) => Some(since + timeout),
+            _ => None,
+        };
+        Fade {
+            start,
+            duration: self.fade,
+        }
+    }
+}
+
+//Fade out of a stalled overlay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Fade {
+    //When the producer stalled, None while it ticks.
+    pub start: Option<Instant>,
+    pub duration: Duration,
+}
+
+impl Fade {
+    ///Multiplier for the opacity of the overlay. 1 while the producer ticks, down to 0 once
+    ///it has been stalled for the fade duration.
+    pub fn opacity(&self, now: Instant) -> f32 {
+        let Some(start) = self.start else {
+            return 1.0;
+        };
+        if self.duration.is_zero() {
+            return 0.0;
+        }
+        let fading = now.saturating_duration_since(start);
+        (1.0 - fading.as_secs_f32() / self.duration.as_secs_f32()).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const FADE: Duration = Duration::from_secs(1);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn ticking_producers_never_stall() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(TIMEOUT, FADE);
+        for i in 0..100 {
+            let now = start + ms(i * 1000);
+            assert_eq!(detector.update(Some(i), now), None);
+            assert_eq!(detector.fade().opacity(now), 1.0);
+        }
+    }
+
+    #[test]
+    fn stalls_once_the_timeout_passes() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(TIMEOUT, FADE);
+        assert_eq!(detector.update(Some(7), start), None);
+        assert_eq!(detector.update(Some(7), start + ms(4999)), None);
+        assert_eq!(
+            detector.update(Some(7), start + ms(5200)),
+            Some(StallEvent::Stalled { since: ms(5200) })
+        );
+        //Only reported once.
+        assert_eq!(detector.update(Some(7), start + ms(9000)), None);
+        assert_eq!(detector.fade().start, Some(start + TIMEOUT));
+    }
+
+    #[test]
+    fn recovers_when_the_counter_moves() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(TIMEOUT, FADE);
+        detector.update(Some(7), start);
+        detector.update(Some(7), start + ms(6000));
+        //Backwards counts, the producer restarted.
+        assert_eq!(
+            detector.update(Some(0), start + ms(8000)),
+            Some(StallEvent::Recovered { after: ms(8000) })
+        );
+        assert_eq!(detector.fade().start, None);
+        assert_eq!(detector.update(Some(1), start + ms(9000)), None);
+        //The timeout starts over from the last change.
+        assert_eq!(detector.update(Some(1), start + ms(13000)), None);
+        assert_eq!(
+            detector.update(Some(1), start + ms(14000)),
+            Some(StallEvent::Stalled { since: ms(5000) })
+        );
+    }
+
+    #[test]
+    fn missing_counters_are_never_stalled() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(TIMEOUT, FADE);
+        for i in 0..20 {
+            assert_eq!(detector.update(None, start + ms(i * 1000)), None);
+        }
+        //Losing the counter while stalled clears the stall, without an event.
+        detector.update(Some(3), start);
+        detector.update(Some(3), start + ms(6000));
+        assert_eq!(detector.update(None, start + ms(7000)), None);
+        assert_eq!(detector.fade().start, None);
+        //The counter coming back starts a new timeout.
+        assert_eq!(detector.update(Some(3), start + ms(8000)), None);
+        assert_eq!(detector.update(Some(3), start + ms(12000)), None);
+    }
+
+    #[test]
+    fn zero_timeout_disables_detection() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(Duration::ZERO, FADE);
+        for i in 0..10 {
+            assert_eq!(detector.update(Some(1), start + ms(i * 10_000)), None);
+        }
+        assert_eq!(
+            detector.fade(),
+            Fade {
+                start: None,
+                duration: FADE
+            }
+        );
+    }
+
+    #[test]
+    fn fades_out_over_the_fade_duration() {
+        let start = Instant::now();
+        let fade = Fade {
+            start: Some(start),
+            duration: FADE,
+        };
+        assert_eq!(fade.opacity(start - ms(100)), 1.0);
+        assert_eq!(fade.opacity(start), 1.0);
+        assert_eq!(fade.opacity(start + ms(250)), 0.75);
+        assert_eq!(fade.opacity(start + ms(500)), 0.5);
+        assert_eq!(fade.opacity(start + FADE), 0.0);
+        assert_eq!(fade.opacity(start + ms(60_000)), 0.0);
+        assert_eq!(Fade::default().opacity(start), 1.0);
+    }
+
+    #[test]
+    fn zero_fade_hides_right_away() {
+        let start = Instant::now();
+        let fade = Fade {
+            start: Some(start),
+            duration: Duration::ZERO,
+        };
+        assert_eq!(fade.opacity(start), 0.0);
+        assert_eq!(fade.opacity(start + ms(1)), 0.0);
+        assert_eq!(
+            Fade {
+                start: None,
+                duration: Duration::ZERO
+            }
+            .opacity(start),
+            1.0
+        );
+    }
+
+    #[test]
+    fn detector_fade_starts_at_the_timeout() {
+        let start = Instant::now();
+        let mut detector = StallDetector::new(TIMEOUT, FADE);
+        detector.update(Some(1), start);
+        detector.update(Some(1), start + ms(5500));
+        let fade = detector.fade();
+        assert_eq!(fade.opacity(start + ms(5500)), 0.5);
+        assert_eq!(fade.opacity(start + ms(6000)), 0.0);
+    }
+}
@@ -89,9 +89,14 @@ pub fn get_mainwindow_hwnd() -> Option<HWND> {
     }
 }
 
+///Null-terminated wide string, keep it alive as long as the pointer is used.
+pub fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
 fn to_pcwstr(s: &str) -> PCWSTR {
     //Create null-terminated u16 array as is the wide character standard.
-    let wide: Vec<u16> = OsStr::new(s).encode_wide().chain(Some(0)).collect();
+    let wide = to_wide(s);
     let ptr = wide.as_ptr();
 
     std::mem::forget(wide);
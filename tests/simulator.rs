@@ -0,0 +1,98 @@
+//Runs the DLL's MMF thread and the simulator against the same MockPlatform, and checks what
+//the consumer published for the producer to see.
+#![cfg(not(windows))]
+
+use std::time::{Duration, Instant};
+
+use external_dx11_overlay::{
+    platform::{Platform, default_mock},
+    shutdown::{join_workers, request_shutdown},
+    simulator::{ConnectError, ConsumerAction, Simulator, SimulatorConfig},
+    ui::{
+        discovery::{DISCOVERY_RECORDS, DiscoveryTable, record_state},
+        header::{MMFHeader, PROTOCOL_VERSION},
+        mmf::start_mmf_thread,
+        object_names,
+    },
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[test]
+fn the_consumer_answers_the_simulator() {
+    let game = default_mock();
+    game.set_window_size(Some((1280, 720)));
+    start_mmf_thread().unwrap();
+
+    let producer = game.spawn_process("Blish HUD.exe");
+    let config = SimulatorConfig {
+        game_pid: Some(game.current_pid()),
+        input_address: None,
+        ..Default::default()
+    };
+    //The MMF thread publishes its discovery record once it's up.
+    let deadline = Instant::now() + TIMEOUT;
+    let mut simulator = loop {
+        match Simulator::connect(&producer, &config) {
+            Ok(simulator) => break simulator,
+            Err(ConnectError::NoGame) if Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            Err(e) => panic!("could not connect: {}", e),
+        }
+    };
+
+    //Paired through the discovery table, with the names the game published.
+    assert_eq!(simulator.names().prefix, object_names().prefix);
+    let discovery = producer
+        .open_shared_memory(&object_names().discovery)
+        .unwrap();
+    let table = DiscoveryTable::new(discovery.words()).unwrap();
+    let record = (0..DISCOVERY_RECORDS)
+        .filter_map(|index| table.record(index))
+        .find(|record| record.pid == game.current_pid())
+        .unwrap();
+    assert_eq!(record.state, record_state::PAIRED);
+    assert_eq!(record.producer_pid, producer.current_pid());
+    assert_eq!(record.prefix, object_names().prefix);
+
+    //The consumer handshakes, then sends the window size.
+    let mut actions = Vec::new();
+    let deadline = Instant::now() + TIMEOUT;
+    while actions.len() < 2 && Instant::now() < deadline {
+        simulator.tick();
+        actions.extend(simulator.poll());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(
+        actions,
+        [
+            ConsumerAction::Handshake {
+                version: PROTOCOL_VERSION
+            },
+            ConsumerAction::Resized {
+                width: 1280,
+                height: 720
+            },
+        ]
+    );
+    let header = producer
+        .open_shared_memory(&simulator.names().header)
+        .unwrap();
+    let read = MMFHeader::decode(header.bytes()).unwrap();
+    assert_eq!(
+        (read.consumer_version, read.width, read.height),
+        (PROTOCOL_VERSION, 1280, 720)
+    );
+    //The resize was answered with new textures.
+    assert_eq!(read.slots(), simulator.slots());
+
+    //The game leaves, its record goes with it.
+    request_shutdown();
+    assert!(join_workers(TIMEOUT));
+    assert!(
+        (0..DISCOVERY_RECORDS)
+            .filter_map(|index| table.record(index))
+            .all(|record| record.pid != game.current_pid())
+    );
+}